@@ -8,6 +8,9 @@ use std::process::exit;
 
 use serde_json::{json, to_string_pretty};
 use press::gpt::{GPTHeader, GPTPartitionEntryArray, is_gpt};
+use press::luks::LuksHeader;
+use press::md::MdSuperblock;
+use press::probe::probe_range;
 
 fn main() -> Result<(), Box<std::error::Error>> {
     let args: Vec<String> = std::env::args().collect();
@@ -29,10 +32,43 @@ fn main() -> Result<(), Box<std::error::Error>> {
     let gpt_partitions = GPTPartitionEntryArray::from_reader(
         &mut fp, &gpt_header, 512).unwrap();
 
+    let mut partition_signatures = Vec::new();
+    for (index, partition) in gpt_partitions.partitions.iter().enumerate() {
+        if partition.partition_type_guid == uuid::Uuid::nil() {
+            continue;
+        }
+        let start = partition.starting_lba * 512;
+        let size = (partition.ending_lba + 1 - partition.starting_lba) * 512;
+        // A damaged superblock or header is reported with the partition, the
+        // other partitions are still dumped
+        let mut errors = Vec::new();
+        let raid_superblock = MdSuperblock::from_reader(&mut fp, start, size)
+            .unwrap_or_else(|e| {
+                errors.push(format!("md superblock: {}", e));
+                None
+            });
+        let luks_header = LuksHeader::from_reader(&mut fp, start)
+            .unwrap_or_else(|e| {
+                errors.push(format!("LUKS header: {}", e));
+                None
+            });
+        let mut signatures = json!({
+            "partition": index + 1,
+            "signatures": probe_range(&mut fp, start, size)?,
+            "raidSuperblock": raid_superblock,
+            "luksHeader": luks_header
+        });
+        if !errors.is_empty() {
+            signatures["error"] = json!(errors.join("; "));
+        }
+        partition_signatures.push(signatures);
+    }
+
     println!("{}", to_string_pretty(&json!(
         {
             "gptHeader": gpt_header.json_value(),
-            "gptPartitions": gpt_partitions.json_value()
+            "gptPartitions": gpt_partitions.json_value(),
+            "partitionSignatures": partition_signatures
         })).unwrap());
 
     Ok(())
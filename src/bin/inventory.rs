@@ -0,0 +1,23 @@
+// Dumps a JSON inventory of the disks attached to the system, or of the
+// system described by a copy of /sys and /run/udev under the given root
+
+extern crate press;
+extern crate serde;
+
+use serde_json::to_string_pretty;
+
+use press::inventory::Inventory;
+use press::sysfs::SysRoot;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<String> = std::env::args().collect();
+
+    let root = match args.get(1) {
+        Some(path) => SysRoot::new(path),
+        None => SysRoot::default()
+    };
+
+    let inventory = Inventory::discover_from(&root)?;
+    println!("{}", to_string_pretty(&inventory)?);
+    Ok(())
+}
@@ -0,0 +1,11 @@
+// Prints the JSON Schema of the press configuration document
+
+extern crate press;
+
+use serde_json::to_string_pretty;
+
+use press::PressConfiguration;
+
+fn main() {
+    println!("{}", to_string_pretty(&PressConfiguration::json_schema()).unwrap());
+}
@@ -4,8 +4,9 @@ use crate::gpt::*;
 use crate::mbr::*;
 use crate::udev;
 use crate::sysfs;
+use crate::probe;
 
-pub type BlockDeviceResult = Result<BlockDevice, Box<std::error::Error>>;
+pub type BlockDeviceResult = Result<BlockDevice, Box<dyn std::error::Error>>;
 
 #[derive(Debug, serde::Serialize)]
 pub enum PartitionTable {
@@ -16,26 +17,55 @@ pub enum PartitionTable {
 #[derive(Debug, serde::Serialize)]
 pub struct BlockDevice {
     pub geometry: sysfs::BlockDeviceGeometry,
+    pub attributes: sysfs::BlockDeviceAttributes,
     pub partition_table: Option<PartitionTable>,
-    pub gpt_partition_array: Option<GPTPartitionEntryArray>
+    pub gpt_partition_array: Option<GPTPartitionEntryArray>,
+    pub signatures: Vec<probe::Signature>
 }
 
 
 impl BlockDevice {
+    /// Assembles a device (/dev/sda) from the udev database and sysfs found
+    /// under root, rather than the live system
+    pub fn assemble_from(root: &sysfs::SysRoot, device: &str) -> BlockDeviceResult {
+        let name = device.trim_start_matches("/dev/");
+        let sys_device_path = root.device_path(name);
+        let sys_device_path = sys_device_path.to_str().unwrap();
+        Ok(BlockDevice {
+            geometry: sysfs::BlockDeviceGeometry::from_device(sys_device_path)?,
+            attributes: sysfs::BlockDeviceAttributes::from_device(sys_device_path)?,
+            partition_table: None,
+            gpt_partition_array: None,
+            signatures: Vec::new()
+        })
+    }
+
     pub fn assemble(device: &str) -> BlockDeviceResult {
         let udev_info = udev::get_block_devices_with_property(
             "DEVNAME", device)?;
         // Add find one method to udev::
         let udev_info = udev_info.first().unwrap();
+        let sys_device_path = sysfs::kernel_path_to_sys(
+            // Add accessor for properties
+            udev_info.properties().get("DEVPATH").unwrap());
         let sysfs_geom = sysfs::BlockDeviceGeometry::from_device(
-            &sysfs::kernel_path_to_sys(
-                // Add accessor for properties
-                udev_info.properties().get("DEVPATH").unwrap()
-            ))?;
+            &sys_device_path)?;
+        let sysfs_attributes = sysfs::BlockDeviceAttributes::from_device(
+            &sys_device_path)?;
+        // Probing needs read access to the device node, which is not fatal
+        let signatures = match probe::probe_device(std::path::Path::new(device)) {
+            Ok(signatures) => signatures,
+            Err(e) => {
+                warn!("could not probe {}: {}", device, e);
+                Vec::new()
+            }
+        };
         Ok(BlockDevice {
             geometry: sysfs_geom,
+            attributes: sysfs_attributes,
             partition_table: None,
-            gpt_partition_array: None
+            gpt_partition_array: None,
+            signatures
         })
     }
 }
\ No newline at end of file
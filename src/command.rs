@@ -0,0 +1,110 @@
+//! Runs the external tools press drives: mkfs, mdadm, lvm, cryptsetup, mount
+//! and btrfs. Command lines are logged and a failure carries the exit status
+//! and whatever the tool wrote to stderr. No command is started once a
+//! signal has been received, see `signal`.
+
+use std::error::Error;
+use std::fmt;
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+use crate::signal;
+
+// Error Boiler plate
+#[derive(Debug)]
+pub struct CommandError {
+    details: String
+}
+
+impl CommandError {
+    pub fn new(msg: &str) -> Self {
+        Self {
+            details: msg.to_string()
+        }
+    }
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.details)
+    }
+}
+
+impl Error for CommandError {
+    fn description(&self) -> &str {
+        &self.details
+    }
+}
+
+pub type CommandResult<T> = Result<T, CommandError>;
+// End Error boiler plate
+
+/// Runs program to completion
+pub fn run(program: &str, arguments: &[String]) -> CommandResult<()> {
+    run_with_input(program, arguments, None)
+}
+
+/// Runs program to completion, writing input to its stdin. Without input
+/// stdin is /dev/null so a tool which prompts fails instead of hanging.
+pub fn run_with_input(program: &str, arguments: &[String], input: Option<&str>) -> CommandResult<()> {
+    check_interrupted(program, arguments)?;
+    execute(program, arguments, input).map(|_| ())
+}
+
+/// Runs program to completion and returns what it wrote. stdin is /dev/null.
+pub fn output(program: &str, arguments: &[String]) -> CommandResult<Output> {
+    check_interrupted(program, arguments)?;
+    execute(program, arguments, None)
+}
+
+/// Runs program to completion even after a signal, for undoing work such
+/// as unmounting
+pub fn run_cleanup(program: &str, arguments: &[String]) -> CommandResult<()> {
+    execute(program, arguments, None).map(|_| ())
+}
+
+fn check_interrupted(program: &str, arguments: &[String]) -> CommandResult<()> {
+    signal::check_interrupted().map_err(|e| CommandError::new(
+        &format!("not running {} {}: {}", program, arguments.join(" "), e)))
+}
+
+fn execute(program: &str, arguments: &[String], input: Option<&str>) -> CommandResult<Output> {
+    let command_line = format!("{} {}", program, arguments.join(" "));
+    debug!("Running {}", command_line);
+    let mut child = Command::new(program)
+        .args(arguments)
+        .stdin(if input.is_some() { Stdio::piped() } else { Stdio::null() })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| CommandError::new(&format!("could not run {}: {}", program, e)))?;
+    if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+        stdin.write_all(input.as_bytes())
+            .map_err(|e| CommandError::new(&format!("could not write to {}: {}", program, e)))?;
+    }
+    let output = child.wait_with_output()
+        .map_err(|e| CommandError::new(&format!("could not run {}: {}", program, e)))?;
+    if !output.status.success() {
+        return Err(CommandError::new(&format!("{} failed ({}): {}",
+            command_line, output.status, String::from_utf8_lossy(&output.stderr).trim())))
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run() {
+        run("true", &[]).unwrap();
+        run_with_input("grep", &["-q".to_owned(), "press".to_owned()], Some("press\n")).unwrap();
+        assert_eq!(output("echo", &["press".to_owned()]).unwrap().stdout, b"press\n");
+        // stdin is /dev/null, cat does not wait for the terminal
+        assert!(output("cat", &[]).unwrap().stdout.is_empty());
+        assert_eq!(run("sh", &["-c".to_owned(), "echo oops >&2; exit 3".to_owned()])
+            .unwrap_err().to_string(), "sh -c echo oops >&2; exit 3 failed (exit status: 3): oops");
+        assert!(run("/nonexistent/press", &[]).unwrap_err().to_string()
+            .starts_with("could not run /nonexistent/press: "));
+    }
+}
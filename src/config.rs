@@ -0,0 +1,931 @@
+extern crate serde;
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::path::Path;
+
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use crate::layout::LayoutOptions;
+use crate::layout::crypt::Encryption;
+use crate::layout::fs::{FileSystem, MountBy};
+use crate::layout::lvm::VolumeGroup;
+use crate::layout::partition::{PartitionTable, SECTOR_SIZE};
+use crate::layout::raid::RaidArray;
+use crate::layout::selector::DiskSelector;
+use crate::target::image::DiskImage;
+use crate::target::rootfs::Tarball;
+
+// Error Boiler plate
+#[derive(Debug)]
+pub struct ValidationError {
+    errors: Vec<String>
+}
+
+impl ValidationError {
+    pub fn errors(&self) -> &[String] {
+        &self.errors
+    }
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.errors.join("\n"))
+    }
+}
+
+impl Error for ValidationError {
+    fn description(&self) -> &str {
+        "invalid configuration"
+    }
+}
+/// A configuration document which could not be deserialized, with the
+/// location of the problem when the format reports it
+#[derive(Debug)]
+pub struct ConfigError {
+    format: ConfigFormat,
+    path: Option<String>,
+    line: Option<usize>,
+    column: Option<usize>,
+    message: String
+}
+
+impl ConfigError {
+    pub fn line(&self) -> Option<usize> {
+        self.line
+    }
+
+    pub fn column(&self) -> Option<usize> {
+        self.column
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    fn with_path(mut self, path: &Path) -> Self {
+        self.path = Some(path.to_string_lossy().into_owned());
+        self
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref path) = self.path {
+            write!(f, "{}:", path)?;
+        }
+        match (self.line, self.column) {
+            (Some(line), Some(column)) => write!(f, "{}:{}: ", line, column)?,
+            _ => if self.path.is_some() { write!(f, " ")? }
+        }
+        write!(f, "invalid {} configuration: {}", self.format.name(), self.message)
+    }
+}
+
+impl Error for ConfigError {
+    fn description(&self) -> &str {
+        &self.message
+    }
+}
+// End Error boiler plate
+
+/// Supported configuration document formats
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConfigFormat {
+    Json,
+    Yaml,
+    Toml
+}
+
+impl ConfigFormat {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ConfigFormat::Json => "JSON",
+            ConfigFormat::Yaml => "YAML",
+            ConfigFormat::Toml => "TOML"
+        }
+    }
+
+    pub fn from_path(path: &Path) -> Option<ConfigFormat> {
+        let extension = path.extension()?.to_string_lossy().to_ascii_lowercase();
+        match extension.as_str() {
+            "json" => Some(ConfigFormat::Json),
+            "yaml" | "yml" => Some(ConfigFormat::Yaml),
+            "toml" => Some(ConfigFormat::Toml),
+            _ => None
+        }
+    }
+
+    /// Guess the format from the first meaningful line of the document. A
+    /// configuration is always a map, so a leading [ can only be a TOML table
+    pub fn detect(data: &str) -> ConfigFormat {
+        let line = data.lines()
+            .map(|l| l.trim())
+            .find(|l| !l.is_empty() && !l.starts_with('#'))
+            .unwrap_or("");
+        if line.starts_with('{') {
+            ConfigFormat::Json
+        } else if line.starts_with('[') || is_toml_assignment(line) {
+            ConfigFormat::Toml
+        } else {
+            ConfigFormat::Yaml
+        }
+    }
+}
+
+// key = value, where YAML would use key: value
+fn is_toml_assignment(line: &str) -> bool {
+    match line.find('=') {
+        Some(index) => {
+            let key = line[..index].trim();
+            !key.is_empty() && key.chars().all(|c|
+                c.is_ascii_alphanumeric() || "_-.\"' ".contains(c))
+        },
+        None => false
+    }
+}
+
+// The number of single character edits needed to turn one string into another
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + if ca == *cb { 0 } else { 1 };
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Appends the closest expected name to serde's unknown field and variant
+/// errors, which are formatted as: unknown field `x`, expected one of `a`, `b`
+fn suggest(message: String) -> String {
+    if !message.contains("unknown field") && !message.contains("unknown variant") {
+        return message
+    }
+    // Names are the odd pieces of the message split on backticks
+    let names: Vec<&str> = message.split('`').skip(1).step_by(2).collect();
+    let (unknown, expected) = match names.split_first() {
+        Some(split) => split,
+        None => return message
+    };
+    let closest = expected.iter()
+        .map(|name| (edit_distance(unknown, name), name))
+        .min();
+    match closest {
+        Some((distance, name)) if distance <= (name.len() / 3).max(2) =>
+            format!("{}, did you mean `{}`?", message, name),
+        _ => message
+    }
+}
+
+// serde_json and serde_yaml append the location to their messages, it is
+// reported separately
+fn strip_location(message: String) -> String {
+    match message.rfind(" at line ") {
+        Some(index) => message[..index].to_owned(),
+        None => message
+    }
+}
+
+fn line_column(data: &str, offset: usize) -> (usize, usize) {
+    let before = &data[..offset.min(data.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before.len() - before.rfind('\n').map(|i| i + 1).unwrap_or(0) + 1;
+    (line, column)
+}
+
+/// The complete description of a deployment: the disks to partition and the
+/// raid arrays, volume groups and file systems built on top of them
+#[derive(Debug, Default, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct PressConfiguration {
+    #[serde(default)]
+    pub disks: Vec<PartitionTable>,
+    #[serde(default)]
+    pub raid_arrays: Vec<RaidArray>,
+    #[serde(default)]
+    pub volume_groups: Vec<VolumeGroup>,
+    /// The root file system tarball extracted into the target
+    pub rootfs: Option<Tarball>,
+    /// Raw images written to whole disks
+    #[serde(default)]
+    pub images: Vec<DiskImage>,
+    #[serde(skip)]
+    pub layout_options: Option<LayoutOptions>
+}
+
+/// Something which can be referenced by name from elsewhere in the
+/// configuration
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Device {
+    /// A partition on the disk with the given index
+    Partition(usize),
+    RaidArray,
+    VolumeGroup,
+    LogicalVolume
+}
+
+impl Device {
+    fn kind(&self) -> &'static str {
+        match self {
+            Device::Partition(_) => "partition",
+            Device::RaidArray => "raid array",
+            Device::VolumeGroup => "volume group",
+            Device::LogicalVolume => "logical volume"
+        }
+    }
+}
+
+fn describe_disk(index: usize, table: &PartitionTable) -> String {
+    match table.target {
+        Some(DiskSelector::Path(ref path)) => format!("disk {} ({})", index, path),
+        _ => format!("disk {}", index)
+    }
+}
+
+impl PressConfiguration {
+    /// Reads a configuration file, the format is taken from the extension
+    /// when it is known and detected from the content otherwise
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<PressConfiguration, Box<dyn Error>> {
+        let path = path.as_ref();
+        let data = std::fs::read_to_string(path)?;
+        let format = ConfigFormat::from_path(path)
+            .unwrap_or_else(|| ConfigFormat::detect(&data));
+        let configuration = PressConfiguration::parse(&data, format)
+            .map_err(|e| e.with_path(path))?;
+        configuration.validate()?;
+        Ok(configuration)
+    }
+
+    /// Parses and validates a document in any supported format
+    pub fn from_str_detect(data: &str) -> Result<PressConfiguration, Box<dyn Error>> {
+        PressConfiguration::from_str_format(data, ConfigFormat::detect(data))
+    }
+
+    pub fn from_str_format(data: &str, format: ConfigFormat)
+            -> Result<PressConfiguration, Box<dyn Error>> {
+        let configuration = PressConfiguration::parse(data, format)?;
+        configuration.validate()?;
+        Ok(configuration)
+    }
+
+    pub fn from_json(data: &str) -> Result<PressConfiguration, Box<dyn Error>> {
+        PressConfiguration::from_str_format(data, ConfigFormat::Json)
+    }
+
+    /// The JSON Schema of the configuration document, for use by editors
+    pub fn json_schema() -> serde_json::Value {
+        serde_json::to_value(schemars::schema_for!(PressConfiguration)).unwrap()
+    }
+
+    /// Deserializes without validating
+    pub fn parse(data: &str, format: ConfigFormat) -> Result<PressConfiguration, ConfigError> {
+        let error = |line, column, message| ConfigError {
+            format,
+            path: None,
+            line,
+            column,
+            message: suggest(message)
+        };
+        match format {
+            ConfigFormat::Json => serde_json::from_str(data).map_err(|e| {
+                let (line, column) = (e.line(), e.column());
+                error(Some(line), Some(column), strip_location(e.to_string()))
+            }),
+            ConfigFormat::Yaml => serde_yaml::from_str(data).map_err(|e| {
+                let location = e.location();
+                error(location.as_ref().map(|l| l.line()),
+                      location.as_ref().map(|l| l.column()),
+                      strip_location(e.to_string()))
+            }),
+            ConfigFormat::Toml => toml::from_str(data).map_err(|e| {
+                let location = e.span().map(|span| line_column(data, span.start));
+                error(location.map(|l| l.0), location.map(|l| l.1),
+                      e.message().to_owned())
+            })
+        }
+    }
+
+    /// Checks the configuration as a whole, every reference must resolve and
+    /// no device may be used twice. All problems are reported at once.
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        let mut errors = Vec::new();
+        let devices = self.declared_devices(&mut errors);
+        self.check_tables(&mut errors);
+        self.check_targets(&mut errors);
+        self.check_references(&devices, &mut errors);
+        self.check_raid_arrays(&mut errors);
+        self.check_encryption(&mut errors);
+        self.check_volume_groups(&devices, &mut errors);
+        self.check_mount_points(&mut errors);
+        self.check_file_systems(&mut errors);
+        self.check_images(&mut errors);
+        self.check_rootfs(&mut errors);
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ValidationError { errors })
+        }
+    }
+
+    /// Every named device along with the file system it holds, if any
+    fn named_devices(&self) -> Vec<(&str, Device, Option<&FileSystem>)> {
+        let mut named = Vec::new();
+        for (index, table) in self.disks.iter().enumerate() {
+            for partition in table.partitions.iter() {
+                if let Some(ref name) = partition.name {
+                    named.push((name.as_str(), Device::Partition(index),
+                        partition.file_system.as_ref()));
+                }
+            }
+        }
+        for array in self.raid_arrays.iter() {
+            named.push((array.name.as_str(), Device::RaidArray, array.file_system.as_ref()));
+        }
+        for group in self.volume_groups.iter() {
+            named.push((group.name.as_str(), Device::VolumeGroup, None));
+            for volume in group.logical_volumes.iter() {
+                named.push((volume.name.as_str(), Device::LogicalVolume,
+                    volume.file_system.as_ref()));
+            }
+        }
+        named
+    }
+
+    /// Every device which holds a file system, partitions without a name are
+    /// described by their position
+    fn file_system_devices(&self) -> Vec<(String, Device, &FileSystem)> {
+        let mut devices = Vec::new();
+        for (index, table) in self.disks.iter().enumerate() {
+            for (number, partition) in table.partitions.iter().enumerate() {
+                if let Some(ref file_system) = partition.file_system {
+                    let name = partition.name.clone().unwrap_or_else(|| format!(
+                        "partition {} of {}", number + 1, describe_disk(index, table)));
+                    devices.push((name, Device::Partition(index), file_system));
+                }
+            }
+        }
+        for array in self.raid_arrays.iter() {
+            if let Some(ref file_system) = array.file_system {
+                devices.push((array.name.clone(), Device::RaidArray, file_system));
+            }
+        }
+        for group in self.volume_groups.iter() {
+            for volume in group.logical_volumes.iter() {
+                if let Some(ref file_system) = volume.file_system {
+                    devices.push((volume.name.clone(), Device::LogicalVolume, file_system));
+                }
+            }
+        }
+        devices
+    }
+
+    /// Every device declared as a LUKS container, partitions without a name
+    /// are described by their position
+    fn encrypted_devices(&self) -> Vec<(String, &Encryption)> {
+        let mut encrypted = Vec::new();
+        for (index, table) in self.disks.iter().enumerate() {
+            for (number, partition) in table.partitions.iter().enumerate() {
+                if let Some(ref encryption) = partition.encryption {
+                    let name = partition.name.clone().unwrap_or_else(|| format!(
+                        "partition {} of {}", number + 1, describe_disk(index, table)));
+                    encrypted.push((name, encryption));
+                }
+            }
+        }
+        for array in self.raid_arrays.iter() {
+            if let Some(ref encryption) = array.encryption {
+                encrypted.push((array.name.clone(), encryption));
+            }
+        }
+        for group in self.volume_groups.iter() {
+            for volume in group.logical_volumes.iter() {
+                if let Some(ref encryption) = volume.encryption {
+                    encrypted.push((volume.name.clone(), encryption));
+                }
+            }
+        }
+        encrypted
+    }
+
+    fn declared_devices(&self, errors: &mut Vec<String>)
+            -> HashMap<&str, (Device, Option<&FileSystem>)> {
+        let mut devices = HashMap::new();
+        for (name, device, file_system) in self.named_devices() {
+            if let Some((existing, _)) = devices.insert(name, (device, file_system)) {
+                errors.push(format!("{} is declared as both a {} and a {}",
+                    name, existing.kind(), device.kind()));
+            }
+        }
+        devices
+    }
+
+    fn check_tables(&self, errors: &mut Vec<String>) {
+        for (index, table) in self.disks.iter().enumerate() {
+            let mut table_errors = table.check(SECTOR_SIZE);
+            if let Some(DiskSelector::Rules(ref rules)) = table.target {
+                table_errors.extend(rules.check());
+            }
+            for error in table_errors {
+                errors.push(format!("{}: {}", describe_disk(index, table), error));
+            }
+        }
+    }
+
+    fn check_targets(&self, errors: &mut Vec<String>) {
+        let mut targets: HashMap<&str, usize> = HashMap::new();
+        for (index, table) in self.disks.iter().enumerate() {
+            if let Some(DiskSelector::Path(ref path)) = table.target {
+                if let Some(other) = targets.insert(path.as_str(), index) {
+                    errors.push(format!("disks {} and {} both target {}",
+                        other, index, path));
+                }
+            }
+        }
+        let mut images: HashMap<&str, usize> = HashMap::new();
+        for (index, image) in self.images.iter().enumerate() {
+            if let Some(DiskSelector::Path(ref path)) = image.target {
+                if let Some(disk) = targets.get(path.as_str()) {
+                    errors.push(format!("disk {} and image {} both target {}", disk, index, path));
+                }
+                if let Some(other) = images.insert(path.as_str(), index) {
+                    errors.push(format!("images {} and {} both target {}", other, index, path));
+                }
+            }
+        }
+    }
+
+    fn check_references(&self, devices: &HashMap<&str, (Device, Option<&FileSystem>)>,
+            errors: &mut Vec<String>) {
+        // Every (consumer, device) pair, raid members before physical volumes
+        // and partition tables written to arrays
+        let mut references: Vec<(String, &str)> = Vec::new();
+        for array in self.raid_arrays.iter() {
+            for member in array.members.iter().chain(array.spares.iter()) {
+                references.push((array.name.clone(), member));
+            }
+        }
+        for group in self.volume_groups.iter() {
+            for volume in group.physical_volumes.iter() {
+                references.push((group.name.clone(), volume));
+            }
+        }
+        for (index, table) in self.disks.iter().enumerate() {
+            if let Some(DiskSelector::Path(ref path)) = table.target {
+                if let Some(array) = self.raid_arrays.iter().find(|a| a.is_known_as(path)) {
+                    references.push((describe_disk(index, table), &array.name));
+                }
+            }
+        }
+
+        // The array, volume group or disk which consumes each device
+        let mut consumers: HashMap<&str, String> = HashMap::new();
+        for (consumer, name) in references {
+            match devices.get(name) {
+                None => errors.push(format!(
+                    "{} references {} which is not declared", consumer, name)),
+                Some((device, _)) if !matches!(device,
+                        Device::Partition(_) | Device::RaidArray) => errors.push(format!(
+                    "{} references {} which is a {}, only partitions and raid arrays \
+                    can be used", consumer, name, device.kind())),
+                Some(_) if name == consumer.as_str() => errors.push(format!(
+                    "{} references itself", consumer)),
+                Some((_, file_system)) => {
+                    if file_system.is_some() {
+                        errors.push(format!(
+                            "{} is used by {} and cannot also hold a file system",
+                            name, consumer));
+                    }
+                    if let Some(other) = consumers.get(name) {
+                        errors.push(format!("{} is used by both {} and {}",
+                            name, other, consumer));
+                    } else {
+                        consumers.insert(name, consumer);
+                    }
+                }
+            }
+        }
+
+        // Redundancy is lost when two members of an array share a disk
+        for array in self.raid_arrays.iter() {
+            let mut disks: HashMap<usize, &str> = HashMap::new();
+            for member in array.members.iter().chain(array.spares.iter()) {
+                if let Some((Device::Partition(index), _)) = devices.get(member.as_str()) {
+                    if let Some(other) = disks.insert(*index, member) {
+                        errors.push(format!("members {} and {} of {} are both on {}",
+                            other, member, array.name,
+                            describe_disk(*index, &self.disks[*index])));
+                    }
+                }
+            }
+        }
+    }
+
+    fn check_raid_arrays(&self, errors: &mut Vec<String>) {
+        for array in self.raid_arrays.iter() {
+            for error in array.check() {
+                errors.push(format!("{}: {}", array.name, error));
+            }
+        }
+    }
+
+    fn check_encryption(&self, errors: &mut Vec<String>) {
+        let mut names: HashMap<&str, String> = HashMap::new();
+        for (device, encryption) in self.encrypted_devices() {
+            for error in encryption.check() {
+                errors.push(format!("{}: {}", device, error));
+            }
+            if let Some(other) = names.insert(encryption.name.as_str(), device.clone()) {
+                errors.push(format!("{} and {} are both opened as /dev/mapper/{}",
+                    other, device, encryption.name));
+            }
+        }
+    }
+
+    fn check_volume_groups(&self, devices: &HashMap<&str, (Device, Option<&FileSystem>)>,
+            errors: &mut Vec<String>) {
+        for group in self.volume_groups.iter() {
+            let group_errors = group.check();
+            if !group_errors.is_empty() {
+                for error in group_errors {
+                    errors.push(format!("{}: {}", group.name, error));
+                }
+                continue
+            }
+            // Capacity can be checked up front when every physical volume is
+            // a partition of a known size
+            let sizes: Option<Vec<u64>> = group.physical_volumes.iter()
+                .map(|name| match devices.get(name.as_str()) {
+                    Some((Device::Partition(index), _)) => self.disks[*index].partitions.iter()
+                        .find(|partition| partition.name.as_deref() == Some(name.as_str()))
+                        .and_then(|partition| partition.size.fixed_bytes()),
+                    _ => None
+                })
+                .collect();
+            if let Some(sizes) = sizes {
+                if let Err(e) = group.plan(&sizes) {
+                    errors.push(e.to_string());
+                }
+            }
+        }
+    }
+
+    fn check_file_systems(&self, errors: &mut Vec<String>) {
+        let encrypted = self.encrypted_devices();
+        for (name, device, file_system) in self.file_system_devices() {
+            for error in file_system.check() {
+                errors.push(format!("{}: {}", name, error));
+            }
+            // The partition UUID of an encrypted partition names the container
+            let partition = matches!(device, Device::Partition(_)) &&
+                !encrypted.iter().any(|(device, _)| *device == name);
+            if file_system.mount_by == Some(MountBy::Partuuid) && !partition {
+                errors.push(format!("{}: mount_by partuuid requires an unencrypted partition",
+                    name));
+            }
+        }
+    }
+
+    fn check_images(&self, errors: &mut Vec<String>) {
+        for (index, image) in self.images.iter().enumerate() {
+            for error in image.check() {
+                errors.push(format!("image {}: {}", index, error));
+            }
+        }
+    }
+
+    fn check_rootfs(&self, errors: &mut Vec<String>) {
+        if let Some(ref tarball) = self.rootfs {
+            for error in tarball.check() {
+                errors.push(format!("rootfs: {}", error));
+            }
+            if !self.file_system_devices().iter()
+                    .flat_map(|(_, _, fs)| fs.mounts())
+                    .any(|mount| mount.mount_point == "/") {
+                errors.push("rootfs: no file system is mounted at /".to_owned());
+            }
+        }
+    }
+
+    fn check_mount_points(&self, errors: &mut Vec<String>) {
+        let mut mount_points: HashMap<String, String> = HashMap::new();
+        for (name, _, file_system) in self.file_system_devices() {
+            for mount in file_system.mounts() {
+                if let Some(other) = mount_points.insert(mount.mount_point.clone(), name.clone()) {
+                    errors.push(format!("{} and {} are both mounted at {}",
+                        other, name, mount.mount_point));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static CONFIGURATION: &str = r#"
+        {
+            "disks": [
+                {
+                    "target": "/dev/sda",
+                    "partitions": [
+                        {
+                            "name": "esp",
+                            "size": "512MiB",
+                            "file_system": {"fs_type": "vfat", "mount_point": "/boot/efi"}
+                        },
+                        {"name": "raid_a", "size": "100GiB"}
+                    ]
+                },
+                {
+                    "target": {"serial": "S4EMNX0M912345"},
+                    "partitions": [
+                        {"name": "swap", "size": "8GiB"},
+                        {"name": "raid_b", "size": "100GiB"}
+                    ]
+                }
+            ],
+            "raid_arrays": [
+                {"name": "md0", "level": "raid1", "members": ["raid_a", "raid_b"]}
+            ],
+            "volume_groups": [
+                {
+                    "name": "vg0",
+                    "physical_volumes": ["md0"],
+                    "logical_volumes": [
+                        {
+                            "name": "root",
+                            "size": "50GiB",
+                            "file_system": {"fs_type": "ext4", "mount_point": "/"}
+                        },
+                        {
+                            "name": "home",
+                            "size": "40GiB",
+                            "file_system": {"fs_type": "xfs", "mount_point": "/home"}
+                        }
+                    ]
+                }
+            ]
+        }
+    "#;
+
+    fn fixture(name: &str) -> std::path::PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/config").join(name)
+    }
+
+    fn parse_error(data: &str, format: ConfigFormat) -> ConfigError {
+        PressConfiguration::parse(data, format).unwrap_err()
+    }
+
+    fn errors(data: &str) -> Vec<String> {
+        let configuration: PressConfiguration = serde_json::from_str(data).unwrap();
+        match configuration.validate() {
+            Ok(()) => Vec::new(),
+            Err(e) => e.errors().to_vec()
+        }
+    }
+
+    #[test]
+    fn test_valid() {
+        let configuration = PressConfiguration::from_json(CONFIGURATION).unwrap();
+        assert_eq!(configuration.disks.len(), 2);
+        assert_eq!(configuration.raid_arrays[0].members, vec!["raid_a", "raid_b"]);
+        assert_eq!(configuration.volume_groups[0].logical_volumes.len(), 2);
+    }
+
+    #[test]
+    fn test_cross_references() {
+        let data = CONFIGURATION
+            .replace(r#"["raid_a", "raid_b"]"#, r#"["raid_a", "esp", "missing"]"#)
+            .replace(r#"["md0"]"#, r#"["md0", "raid_a", "root"]"#);
+        assert_eq!(errors(&data), vec![
+            "esp is used by md0 and cannot also hold a file system",
+            "md0 references missing which is not declared",
+            "raid_a is used by both md0 and vg0",
+            "vg0 references root which is a logical volume, only partitions and \
+            raid arrays can be used",
+            "members raid_a and esp of md0 are both on disk 0 (/dev/sda)",
+        ]);
+    }
+
+    #[test]
+    fn test_duplicates() {
+        let data = CONFIGURATION
+            .replace(r#""name": "swap""#, r#""name": "md0""#)
+            .replace(r#""target": {"serial": "S4EMNX0M912345"}"#, r#""target": "/dev/sda""#)
+            .replace(r#""mount_point": "/home""#, r#""mount_point": "/""#);
+        assert_eq!(errors(&data), vec![
+            "md0 is declared as both a partition and a raid array",
+            "disks 0 and 1 both target /dev/sda",
+            "root and home are both mounted at /",
+        ]);
+
+        // Partitions without a name are described by their position
+        let data = CONFIGURATION.replace(r#"{"name": "swap", "size": "8GiB"},"#, r#"
+            {"size": "8GiB", "file_system": {"fs_type": "ext4", "mount_point": "/srv"}},
+            {"size": "8GiB", "file_system": {"fs_type": "xfs", "mount_point": "/srv"}},"#);
+        assert_eq!(errors(&data), vec![
+            "partition 1 of disk 1 and partition 2 of disk 1 are both mounted at /srv",
+        ]);
+    }
+
+    #[test]
+    fn test_suggestions() {
+        let error = parse_error(r#"{"disks": [{"partiton_start": "1MiB"}]}"#,
+            ConfigFormat::Json);
+        assert!(error.message().ends_with(", did you mean `partition_start`?"), "{}", error);
+
+        let data = "raid_arrays:\n  - name: md0\n    level: riad1\n    members: []\n";
+        let error = parse_error(data, ConfigFormat::Yaml);
+        assert!(error.message().ends_with(", did you mean `raid1`?"), "{}", error);
+
+        let error = parse_error(r#"{"disks": [{"wibble": 1}]}"#, ConfigFormat::Json);
+        assert!(!error.message().contains("did you mean"), "{}", error);
+    }
+
+    #[test]
+    fn test_volume_groups() {
+        let data = CONFIGURATION.replace(r#"["md0"]"#, r#"["swap"]"#);
+        assert_eq!(errors(&data), vec![
+            "vg0: logical volumes need 23040 extents of 4194304 bytes but only 2047 \
+            are available",
+        ]);
+        let data = CONFIGURATION
+            .replace(r#""size": "40GiB""#, r#""size": "fill""#)
+            .replace(r#""size": "50GiB""#, r#""size": "fill""#);
+        assert_eq!(errors(&data), vec![
+            "vg0: only one logical volume may fill the remaining space",
+        ]);
+    }
+
+    #[test]
+    fn test_raid_arrays() {
+        let data = CONFIGURATION.replace(r#""level": "raid1""#, r#""level": "raid5""#);
+        assert_eq!(errors(&data), vec!["md0: raid5 needs at least 3 members, 2 given"]);
+
+        // An array partitioned like a disk
+        let data = CONFIGURATION
+            .replace(r#""disks": ["#, r#""disks": [
+                {"target": "/dev/md/md0", "partitions": [{"name": "data", "size": "fill"}]},"#);
+        assert_eq!(errors(&data), vec!["md0 is used by both vg0 and disk 0 (/dev/md/md0)"]);
+        let data = data.replace(r#"["md0"]"#, r#"["data"]"#);
+        assert!(errors(&data).is_empty());
+    }
+
+    #[test]
+    fn test_encryption() {
+        let data = CONFIGURATION
+            .replace(r#"{"name": "raid_a", "size": "100GiB"}"#,
+                r#"{"name": "raid_a", "size": "100GiB",
+                    "encryption": {"name": "crypt", "key": {"keyfile": "/etc/keys/a"}}}"#)
+            .replace(r#""name": "home",
+                            "size": "40GiB","#,
+                r#""name": "home",
+                            "size": "40GiB",
+                            "encryption": {"name": "crypt", "key": {"passphrase": ""}},"#);
+        assert_eq!(errors(&data), vec![
+            "home: the passphrase is empty",
+            "raid_a and home are both opened as /dev/mapper/crypt",
+        ]);
+        let data = data.replace(r#""passphrase": """#, r#""passphrase_env": "HOME_KEY""#)
+            .replace(r#""name": "crypt", "key": {"passphrase_env""#,
+                r#""name": "crypt_home", "key": {"passphrase_env""#);
+        assert!(errors(&data).is_empty());
+    }
+
+    #[test]
+    fn test_mount_by() {
+        let data = CONFIGURATION
+            .replace(r#""fs_type": "vfat", "mount_point": "/boot/efi""#,
+                r#""fs_type": "vfat", "mount_point": "/boot/efi", "mount_by": "partuuid""#)
+            .replace(r#""fs_type": "ext4", "mount_point": "/""#,
+                r#""fs_type": "ext4", "mount_point": "/", "mount_by": "partuuid""#);
+        assert_eq!(errors(&data), vec!["root: mount_by partuuid requires an unencrypted partition"]);
+        let data = data.replace(r#""mount_by": "partuuid""#, r#""mount_by": "label""#);
+        assert_eq!(errors(&data), vec![
+            "esp: mount_by label requires a label",
+            "root: mount_by label requires a label",
+        ]);
+    }
+
+    #[test]
+    fn test_rootfs() {
+        let data = CONFIGURATION.replace(r#""raid_arrays": ["#,
+            r#""rootfs": {"path": "/srv/rootfs.tar.zst", "checksum": "sha256:00"},
+            "raid_arrays": ["#);
+        assert_eq!(errors(&data), vec!["rootfs: a sha256 digest is 64 hex digits"]);
+        let data = data.replace(r#""mount_point": "/"}"#, r#""mount_point": "/srv"}"#)
+            .replace("sha256:00", &format!("sha256:{}", "0".repeat(64)));
+        assert_eq!(errors(&data), vec!["rootfs: no file system is mounted at /"]);
+
+        // A root partition without a name
+        let data = data.replace(r#"{"name": "swap", "size": "8GiB"},"#, r#"
+            {"size": "fill", "file_system": {"fs_type": "ext4", "mount_point": "/"}},"#);
+        assert!(errors(&data).is_empty());
+    }
+
+    #[test]
+    fn test_images() {
+        let data = CONFIGURATION.replace(r#""raid_arrays": ["#,
+            r#""images": [
+                {"path": "/srv/disk.img", "target": "/dev/sda"},
+                {"path": "/srv/disk.img.xz", "target": "/dev/sdb"},
+                {"path": "", "target": "/dev/sdb", "compression": "xz"}
+            ],
+            "raid_arrays": ["#);
+        assert_eq!(errors(&data), vec![
+            "disk 0 and image 0 both target /dev/sda",
+            "images 1 and 2 both target /dev/sdb",
+            "image 2: the image path is empty",
+        ]);
+    }
+
+    #[test]
+    fn test_semantic_checks() {
+        let data = CONFIGURATION
+            .replace(r#""name": "swap", "size": "8GiB""#, r#""name": "swap", "size": "fill""#)
+            .replace(r#""name": "raid_b", "size": "100GiB""#, r#""name": "raid_b", "size": "fill""#)
+            .replace(r#""target": "/dev/sda","#, r#""target": "/dev/sda", "alignment": 1000,"#)
+            .replace(r#"{"serial": "S4EMNX0M912345"}"#, r#"{"model": "SAMSUNG[", "min_size": "2TB", "max_size": "1TB"}"#);
+        let errors = errors(&data);
+        assert_eq!(errors.len(), 4);
+        assert_eq!(errors[0], "disk 0 (/dev/sda): alignment of 1000 bytes is not a power \
+            of two multiple of the 512 byte sector size");
+        assert_eq!(errors[1], "disk 1: only one partition may fill the remaining space");
+        assert!(errors[2].starts_with("disk 1: invalid model expression SAMSUNG["));
+        assert_eq!(errors[3], "disk 1: min_size 2000000000000 is larger than max_size 1000000000000");
+    }
+
+    #[test]
+    fn test_json_schema() {
+        let schema = PressConfiguration::json_schema();
+        let table = &schema["definitions"]["PartitionTable"];
+        assert_eq!(table["additionalProperties"], false);
+        assert!(table["properties"]["partition_start"].is_object());
+        assert_eq!(schema["definitions"]["RaidLevel"]["enum"][1], "raid1");
+        assert!(schema["definitions"]["DiskSelector"]["anyOf"].is_array());
+    }
+
+    #[test]
+    fn test_formats() {
+        let json = PressConfiguration::from_path(fixture("press.json")).unwrap();
+        let yaml = PressConfiguration::from_path(fixture("press.yaml")).unwrap();
+        let toml = PressConfiguration::from_path(fixture("press.toml")).unwrap();
+        assert_eq!(format!("{:?}", json), format!("{:?}", yaml));
+        assert_eq!(format!("{:?}", json), format!("{:?}", toml));
+    }
+
+    #[test]
+    fn test_detect() {
+        for name in ["press.json", "press.yaml", "press.toml"].iter() {
+            let data = std::fs::read_to_string(fixture(name)).unwrap();
+            let format = ConfigFormat::detect(&data);
+            assert_eq!(Some(format), ConfigFormat::from_path(Path::new(name)));
+            PressConfiguration::from_str_detect(&data).unwrap();
+        }
+        assert_eq!(ConfigFormat::detect("disks = []"), ConfigFormat::Toml);
+        assert_eq!(ConfigFormat::detect("# disks: []\ndisks: []"), ConfigFormat::Yaml);
+    }
+
+    #[test]
+    fn test_size_error_location() {
+        let data = "disks:\n  - partitions:\n      - name: esp\n        size: 512Mb?\n";
+        let error = parse_error(data, ConfigFormat::Yaml);
+        assert_eq!((error.line(), error.column()), (Some(4), Some(15)));
+        assert!(error.message().contains("invalid size \"512Mb?\""), "{}", error);
+
+        let data = "[[disks]]\n\n[[disks.partitions]]\nname = \"esp\"\nsize = \"512 bytes\"\n";
+        let error = parse_error(data, ConfigFormat::Toml);
+        assert_eq!((error.line(), error.column()), (Some(5), Some(8)));
+        assert!(error.message().contains("bytes is not a supported suffix"), "{}", error);
+
+        let data = "{\n  \"disks\": [{\"partition_start\": \"1 XiB\"}]\n}";
+        let error = parse_error(data, ConfigFormat::Json);
+        assert_eq!(error.line(), Some(2));
+        assert_eq!(error.to_string(), format!(
+            "2:{}: invalid JSON configuration: invalid size \"1 XiB\": \
+            XiB is not a supported suffix", error.column().unwrap()));
+    }
+
+    #[test]
+    fn test_unknown_field_location() {
+        let data = "disks:\n  - partiton_start: 1MiB\n";
+        let error = parse_error(data, ConfigFormat::Yaml);
+        assert_eq!(error.line(), Some(2));
+        assert!(error.message().starts_with("disks[0]: unknown field `partiton_start`"),
+            "{}", error);
+
+        let data = "[[raid_arrays]]\nname = \"md0\"\nlevel = \"raid1\"\nmembers = []\nchunk = 4\n";
+        let error = parse_error(data, ConfigFormat::Toml);
+        assert_eq!(error.line(), Some(5));
+        assert!(error.message().starts_with("unknown field `chunk`"), "{}", error);
+    }
+}
@@ -2,11 +2,15 @@ extern crate byteorder;
 extern crate serde;
 extern crate uuid;
 
+use std::io::{Read, Seek, SeekFrom, Write};
+
 use byteorder::{LittleEndian, ByteOrder};
+use crc::crc32;
 use serde::Serialize;
 use serde_json::json;
 use uuid::Uuid;
 
+use crate::mbr::{has_mbr, is_mbr_protective};
 use crate::sysfs::BlockDeviceGeometry;
 
 pub static GPT_SIGNATURE: u64 = 0x5452415020494645;
@@ -27,6 +31,17 @@ pub fn uuid_from_le_bytes(bytes: &[u8]) -> Uuid {
         &bytes[8..]).unwrap()
 }
 
+/// The mixed endian byte order GUIDs are stored in
+pub fn uuid_to_le_bytes(uuid: &Uuid) -> [u8; 16] {
+    let (d1, d2, d3, d4) = uuid.as_fields();
+    let mut bytes = [0u8; 16];
+    LittleEndian::write_u32(&mut bytes[..4], d1);
+    LittleEndian::write_u16(&mut bytes[4..6], d2);
+    LittleEndian::write_u16(&mut bytes[6..8], d3);
+    bytes[8..].copy_from_slice(d4);
+    bytes
+}
+
 // check if the buffer contains a gpt signature
 // buffer starts at LBA 0
 pub fn is_gpt(buffer: &[u8], lba_size: usize) -> bool {
@@ -39,7 +54,7 @@ pub fn get_disk_guid(buffer: &[u8], lba_size: usize) -> Uuid {
     uuid_from_le_bytes(&buffer[offset..offset+16])
 }
 
-#[derive(Debug, Default, Serialize)]
+#[derive(Debug, Default, Clone, Serialize)]
 pub struct GPTHeader {
     pub signature: u64,
     pub revision: u32,
@@ -62,10 +77,17 @@ pub fn gpt_header_as_bytes(header: &GPTHeader) -> Vec<u8> {
     LittleEndian::write_u64(&mut bytes[..8], header.signature);
     LittleEndian::write_u32(&mut bytes[8..12], header.revision);
     LittleEndian::write_u32(&mut bytes[12..16], header.header_size);
-    LittleEndian::write_u32(&mut bytes[16..20], 0); // HeaderCRC32
+    LittleEndian::write_u32(&mut bytes[16..20], header.header_crc32);
     LittleEndian::write_u32(&mut bytes[20..24], 0); // Reserved Bits
     LittleEndian::write_u64(&mut bytes[24..32], header.current_lba);
     LittleEndian::write_u64(&mut bytes[32..40], header.backup_lba);
+    LittleEndian::write_u64(&mut bytes[40..48], header.first_usable_lba);
+    LittleEndian::write_u64(&mut bytes[48..56], header.last_uasable_lba);
+    bytes[56..72].copy_from_slice(&uuid_to_le_bytes(&header.guid));
+    LittleEndian::write_u64(&mut bytes[72..80], header.partition_entry_lba);
+    LittleEndian::write_u32(&mut bytes[80..84], header.number_of_partions);
+    LittleEndian::write_u32(&mut bytes[84..88], header.size_of_partition);
+    LittleEndian::write_u32(&mut bytes[88..92], header.partition_entry_crc32);
     bytes
 }
 
@@ -114,6 +136,19 @@ impl GPTHeader {
         }
     }
 
+    /// The crc32 of the header with the crc field zeroed
+    pub fn calc_crc32(&self) -> u32 {
+        let mut header = self.clone();
+        header.header_crc32 = 0;
+        crc32::checksum_ieee(&gpt_header_as_bytes(&header))
+    }
+
+    /// The number of LBAs the partition entry array takes up
+    pub fn entry_array_lbas(&self, lba_size: u64) -> u64 {
+        let bytes = self.number_of_partions as u64 * self.size_of_partition as u64;
+        bytes.div_ceil(lba_size)
+    }
+
     pub fn from_reader<R>(reader: &mut R, lba_size: u32) -> Result<GPTHeader, std::io::Error> 
         where R: std::io::Read + std::io::Seek {
             let mut gpt_header_buffer = vec![0 as u8;512];
@@ -243,3 +278,180 @@ impl GPTPartitionEntryArray {
         })
     }
 }
+
+fn invalid_data(msg: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
+}
+
+fn read_lbas<D: Read + Seek>(device: &mut D, lba: u64, count: u64, lba_size: u64)
+        -> Result<Vec<u8>, std::io::Error> {
+    let mut buffer = vec![0u8; (count * lba_size) as usize];
+    device.seek(SeekFrom::Start(lba * lba_size))?;
+    device.read_exact(&mut buffer)?;
+    Ok(buffer)
+}
+
+fn write_header<D: Write + Seek>(device: &mut D, header: &GPTHeader, lba_size: u64)
+        -> Result<(), std::io::Error> {
+    let mut buffer = gpt_header_as_bytes(header);
+    buffer.resize(lba_size as usize, 0);
+    device.seek(SeekFrom::Start(header.current_lba * lba_size))?;
+    device.write_all(&buffer)
+}
+
+/// Moves the backup GPT to the end of a disk which is larger than the one
+/// the table was written for, a disk image written to a bigger disk for
+/// instance. The primary header is updated to match, the space past the old
+/// end becomes usable and the old backup header is cleared. Returns false
+/// when there is no GPT or the backup is already at the end.
+pub fn relocate_backup<D>(device: &mut D, size: u64, lba_size: u64) -> Result<bool, std::io::Error>
+        where D: Read + Write + Seek {
+    let mut primary = GPTHeader::from_slice(&read_lbas(device, 1, 1, lba_size)?);
+    if primary.signature != GPT_SIGNATURE {
+        return Ok(false)
+    }
+    if primary.calc_crc32() != primary.header_crc32 {
+        return Err(invalid_data("the primary GPT header checksum is invalid".to_owned()))
+    }
+    let last_lba = size / lba_size - 1;
+    if primary.backup_lba == last_lba {
+        return Ok(false)
+    }
+    if primary.backup_lba > last_lba {
+        return Err(invalid_data(format!("the backup GPT at LBA {} is past the end of the disk",
+            primary.backup_lba)))
+    }
+    let entry_lbas = primary.entry_array_lbas(lba_size);
+    let mut entries = read_lbas(device, primary.partition_entry_lba, entry_lbas, lba_size)?;
+    entries.truncate(primary.number_of_partions as usize * primary.size_of_partition as usize);
+    if crc32::checksum_ieee(&entries) != primary.partition_entry_crc32 {
+        return Err(invalid_data("the GPT partition entry checksum is invalid".to_owned()))
+    }
+
+    let old_backup_lba = primary.backup_lba;
+    primary.backup_lba = last_lba;
+    primary.last_uasable_lba = last_lba - entry_lbas - 1;
+    primary.header_crc32 = primary.calc_crc32();
+    let mut backup = primary.clone();
+    backup.current_lba = last_lba;
+    backup.backup_lba = 1;
+    backup.partition_entry_lba = last_lba - entry_lbas;
+    backup.header_crc32 = backup.calc_crc32();
+
+    // Cleared first, the new entries overlap it when the disk grew only a little
+    device.seek(SeekFrom::Start(old_backup_lba * lba_size))?;
+    device.write_all(&vec![0u8; lba_size as usize])?;
+    device.seek(SeekFrom::Start(backup.partition_entry_lba * lba_size))?;
+    device.write_all(&entries)?;
+    write_header(device, &backup, lba_size)?;
+    write_header(device, &primary, lba_size)?;
+
+    // The protective partition covers the whole disk, as far as 32 bits go
+    let mut mbr = read_lbas(device, 0, 1, lba_size)?;
+    if has_mbr(&mbr) && is_mbr_protective(&mbr) {
+        let sectors = (size / lba_size - 1).min(u32::MAX as u64) as u32;
+        LittleEndian::write_u32(&mut mbr[446 + 12..446 + 16], sectors);
+        device.seek(SeekFrom::Start(0))?;
+        device.write_all(&mbr[..512])?;
+    }
+    info!("Moved the backup GPT from LBA {} to {}", old_backup_lba, last_lba);
+    Ok(true)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// A disk of lbas 512 byte sectors with a protective MBR, a primary and
+    /// a backup GPT and a single partition from LBA 2048 to the last usable
+    pub(crate) fn gpt_disk(lbas: u64) -> Vec<u8> {
+        let lba_size = 512;
+        let mut disk = vec![0u8; (lbas * lba_size) as usize];
+        disk[450] = crate::mbr::PROTECTIVE_MBR_OSTYPE;
+        LittleEndian::write_u32(&mut disk[454..458], 1);
+        LittleEndian::write_u32(&mut disk[458..462], (lbas - 1) as u32);
+        LittleEndian::write_u16(&mut disk[510..512], 0xaa55);
+
+        let mut entries = vec![0u8; (GPT_MAX_PART * GPT_PARTITION_SIZE) as usize];
+        let linux = Uuid::parse_str("0fc63daf-8483-4772-8e79-3d69d8477de4").unwrap();
+        entries[..16].copy_from_slice(&uuid_to_le_bytes(&linux));
+        entries[16..32].copy_from_slice(&uuid_to_le_bytes(&Uuid::new_v4()));
+        LittleEndian::write_u64(&mut entries[32..40], 2048);
+        LittleEndian::write_u64(&mut entries[40..48], lbas - 34);
+
+        let mut primary = GPTHeader {
+            signature: GPT_SIGNATURE,
+            revision: GPT_REVISION,
+            header_size: GPT_HEADERSIZE,
+            current_lba: 1,
+            backup_lba: lbas - 1,
+            first_usable_lba: 34,
+            last_uasable_lba: lbas - 34,
+            guid: Uuid::new_v4(),
+            partition_entry_lba: 2,
+            number_of_partions: GPT_MAX_PART,
+            size_of_partition: GPT_PARTITION_SIZE,
+            partition_entry_crc32: crc32::checksum_ieee(&entries),
+            ..Default::default()
+        };
+        primary.header_crc32 = primary.calc_crc32();
+        let mut backup = primary.clone();
+        backup.current_lba = lbas - 1;
+        backup.backup_lba = 1;
+        backup.partition_entry_lba = lbas - 33;
+        backup.header_crc32 = backup.calc_crc32();
+        for (header, entry_offset) in [(primary, 1024usize), (backup, ((lbas - 33) * 512) as usize)].iter() {
+            let offset = (header.current_lba * lba_size) as usize;
+            disk[offset..offset + 92].copy_from_slice(&gpt_header_as_bytes(header));
+            disk[*entry_offset..*entry_offset + entries.len()].copy_from_slice(&entries);
+        }
+        disk
+    }
+
+    #[test]
+    fn test_header_bytes() {
+        let disk = gpt_disk(4096);
+        let header = GPTHeader::from_slice(&disk[512..]);
+        assert_eq!(gpt_header_as_bytes(&header), &disk[512..512 + 92]);
+        assert_eq!(header.calc_crc32(), header.header_crc32);
+        assert_eq!(header.entry_array_lbas(512), 32);
+        assert_eq!(header.entry_array_lbas(4096), 4);
+    }
+
+    #[test]
+    fn test_relocate_backup() {
+        let mut disk = gpt_disk(4096);
+        let entries = disk[1024..1024 + 16384].to_vec();
+        // Grown by less than the size of the entry array
+        for lbas in [4096 + 20, 8192].iter() {
+            disk.resize(*lbas as usize * 512, 0);
+            let mut device = Cursor::new(disk);
+            assert!(relocate_backup(&mut device, lbas * 512, 512).unwrap());
+            assert!(!relocate_backup(&mut device, lbas * 512, 512).unwrap());
+            disk = device.into_inner();
+
+            let primary = GPTHeader::from_slice(&disk[512..]);
+            assert_eq!((primary.backup_lba, primary.last_uasable_lba), (lbas - 1, lbas - 34));
+            assert_eq!(primary.calc_crc32(), primary.header_crc32);
+            let backup = GPTHeader::from_slice(&disk[(*lbas as usize - 1) * 512..]);
+            assert_eq!(backup.signature, GPT_SIGNATURE);
+            assert_eq!((backup.current_lba, backup.backup_lba, backup.partition_entry_lba),
+                       (lbas - 1, 1, lbas - 33));
+            assert_eq!(backup.calc_crc32(), backup.header_crc32);
+            assert_eq!(backup.guid, primary.guid);
+            let offset = (lbas - 33) as usize * 512;
+            assert!(disk[offset..offset + 16384] == entries[..]);
+            assert_eq!(LittleEndian::read_u32(&disk[458..462]), (lbas - 1) as u32);
+        }
+        // The header of the original backup is gone
+        assert!(disk[4095 * 512..4096 * 512].iter().all(|b| *b == 0));
+
+        assert!(!relocate_backup(&mut Cursor::new(vec![0u8; 8192 * 512]), 8192 * 512, 512).unwrap());
+        let mut disk = gpt_disk(4096);
+        disk[512 + 40] ^= 1;
+        disk.resize(8192 * 512, 0);
+        assert_eq!(relocate_backup(&mut Cursor::new(disk), 8192 * 512, 512).unwrap_err().to_string(),
+            "the primary GPT header checksum is invalid");
+    }
+}
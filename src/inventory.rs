@@ -0,0 +1,368 @@
+extern crate serde;
+
+use std::path::Path;
+
+use serde::Serialize;
+
+use crate::lvm2::PhysicalVolume;
+use crate::md::MdSuperblock;
+use crate::sysfs::{self, SysRoot, BlockDeviceGeometry, BlockDeviceAttributes, Transport};
+use crate::udev::{self, UdevDatabase, UdevBlockDeviceInfo};
+
+// sysfs reports offsets and sizes in 512 byte units regardless of the
+// logical block size of the device
+const SYSFS_SECTOR_SIZE: u64 = 512;
+
+/// A partition on a disk, as found on the system
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Partition {
+    pub name: String,
+    pub devnode: String,
+    pub number: u32,
+    /// Offset from the start of the disk in bytes
+    pub start: u64,
+    pub size: u64,
+    pub by_id: Vec<String>,
+    pub by_path: Vec<String>,
+    pub part_uuid: Option<String>,
+    pub part_label: Option<String>,
+    pub fs_type: Option<String>,
+    pub fs_uuid: Option<String>,
+    pub fs_label: Option<String>,
+    /// The volume group of an LVM physical volume, read from its metadata
+    pub volume_group: Option<String>,
+    /// The superblock of an md member
+    pub raid_member: Option<MdSuperblock>,
+    /// Devices stacked on this partition (md0, dm-1, ...)
+    pub holders: Vec<String>
+}
+
+/// A whole block device, populated from both the udev database and sysfs.
+/// udev provides the stable names (by-id, by-path, serial, wwn), sysfs the
+/// geometry and hardware characteristics
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Disk {
+    pub name: String,
+    pub devnode: String,
+    pub devpath: Option<String>,
+    pub by_id: Vec<String>,
+    pub by_path: Vec<String>,
+    /// udev ID_PATH, pci-0000:00:17.0-ata-1 for instance
+    pub id_path: Option<String>,
+    /// /dev/md links of an md array, named after the array
+    pub md_links: Vec<String>,
+    pub size: u64,
+    pub serial: Option<String>,
+    pub wwn: Option<String>,
+    pub model: Option<String>,
+    pub vendor: Option<String>,
+    pub bus: Option<String>,
+    pub transport: Transport,
+    pub rotational: bool,
+    pub removable: bool,
+    pub read_only: bool,
+    pub partition_table_type: Option<String>,
+    pub geometry: BlockDeviceGeometry,
+    pub partitions: Vec<Partition>,
+    /// The volume group of a disk used whole as an LVM physical volume
+    pub volume_group: Option<String>,
+    /// The superblock of a disk used whole as an md member
+    pub raid_member: Option<MdSuperblock>,
+    pub holders: Vec<String>,
+    /// Devices this disk is built from, for md and device mapper devices
+    pub slaves: Vec<String>
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Inventory {
+    pub disks: Vec<Disk>
+}
+
+fn links_with_prefix(info: &UdevBlockDeviceInfo, prefix: &str) -> Vec<String> {
+    info.devlinks()
+        .into_iter()
+        .filter(|l| l.starts_with(prefix))
+        .map(|l| l.to_owned())
+        .collect()
+}
+
+fn property(info: &UdevBlockDeviceInfo, name: &str) -> Option<String> {
+    info.property(name)
+        .filter(|v| !v.is_empty())
+        .map(udev::unescape)
+}
+
+fn read_holders(sys_device_path: &Path) -> Vec<String> {
+    read_device_links(sys_device_path, "holders")
+}
+
+fn read_slaves(sys_device_path: &Path) -> Vec<String> {
+    read_device_links(sys_device_path, "slaves")
+}
+
+fn read_device_links(sys_device_path: &Path, directory: &str) -> Vec<String> {
+    let mut holders: Vec<String> = match std::fs::read_dir(sys_device_path.join(directory)) {
+        Ok(entries) => entries
+            .filter_map(|e| e.ok())
+            .map(|e| e.file_name().to_string_lossy().into_owned())
+            .filter(|name| !name.starts_with('.'))
+            .collect(),
+        Err(_) => Vec::new()
+    };
+    holders.sort();
+    holders
+}
+
+// Only devices udev identified as physical volumes are opened
+fn read_volume_group(root: &SysRoot, info: &UdevBlockDeviceInfo) -> Option<String> {
+    if property(info, "ID_FS_TYPE").as_deref() != Some("LVM2_member") {
+        return None
+    }
+    let devnode = root.root().join(info.name().trim_start_matches('/'));
+    match PhysicalVolume::from_device(&devnode) {
+        Ok(pv) => pv.and_then(|pv| pv.volume_group).map(|vg| vg.name),
+        Err(e) => {
+            debug!("could not read LVM2 metadata of {}: {}", devnode.display(), e);
+            None
+        }
+    }
+}
+
+// Only devices udev identified as md members are opened
+fn read_raid_member(root: &SysRoot, info: &UdevBlockDeviceInfo) -> Option<MdSuperblock> {
+    if property(info, "ID_FS_TYPE").as_deref() != Some("linux_raid_member") {
+        return None
+    }
+    let devnode = root.root().join(info.name().trim_start_matches('/'));
+    match MdSuperblock::from_device(&devnode) {
+        Ok(superblock) => superblock,
+        Err(e) => {
+            debug!("could not read the md superblock of {}: {}", devnode.display(), e);
+            None
+        }
+    }
+}
+
+// A member is stale when no md device is stacked on it
+fn is_stale_member(raid_member: &Option<MdSuperblock>, holders: &[String]) -> bool {
+    raid_member.is_some() && !holders.iter().any(|h| h.starts_with("md"))
+}
+
+impl Partition {
+    /// Whether the partition holds the superblock of an md array which is
+    /// not assembled, left over from a previous installation
+    pub fn is_stale_raid_member(&self) -> bool {
+        is_stale_member(&self.raid_member, &self.holders)
+    }
+
+    fn from_device(db: &UdevDatabase, name: &str) -> Result<Partition, Box<dyn std::error::Error>> {
+        let sys_device_path = db.root().device_path(name);
+        let info = db.get_block_device(name)?;
+        Ok(Partition {
+            name: name.to_owned(),
+            devnode: info.name().to_owned(),
+            number: sysfs::read_u64(&sys_device_path.join("partition"))? as u32,
+            start: sysfs::read_u64(&sys_device_path.join("start"))? * SYSFS_SECTOR_SIZE,
+            size: sysfs::read_u64(&sys_device_path.join("size"))? * SYSFS_SECTOR_SIZE,
+            by_id: links_with_prefix(&info, "/dev/disk/by-id/"),
+            by_path: links_with_prefix(&info, "/dev/disk/by-path/"),
+            part_uuid: property(&info, "ID_PART_ENTRY_UUID"),
+            part_label: property(&info, "ID_PART_ENTRY_NAME"),
+            fs_type: property(&info, "ID_FS_TYPE"),
+            fs_uuid: property(&info, "ID_FS_UUID"),
+            fs_label: property(&info, "ID_FS_LABEL"),
+            volume_group: read_volume_group(db.root(), &info),
+            raid_member: read_raid_member(db.root(), &info),
+            holders: read_holders(&sys_device_path)
+        })
+    }
+}
+
+impl Disk {
+    fn from_device(db: &UdevDatabase, name: &str) -> Result<Disk, Box<dyn std::error::Error>> {
+        let sys_device_path = db.root().device_path(name);
+        let sys_device_str = sys_device_path.to_string_lossy();
+        let info = db.get_block_device(name)?;
+        let geometry = BlockDeviceGeometry::from_device(&sys_device_str)?;
+        let attributes = BlockDeviceAttributes::from_device(&sys_device_str)?;
+
+        // Partitions are subdirectories of the disk with a partition attribute
+        let mut partition_names: Vec<String> = std::fs::read_dir(&sys_device_path)?
+            .filter_map(|e| e.ok())
+            .filter(|e| e.path().join("partition").exists())
+            .map(|e| e.file_name().to_string_lossy().into_owned())
+            .collect();
+        partition_names.sort();
+        let mut partitions = Vec::with_capacity(partition_names.len());
+        for partition_name in partition_names.iter() {
+            partitions.push(Partition::from_device(db, partition_name)?);
+        }
+        partitions.sort_by_key(|p| p.number);
+
+        Ok(Disk {
+            name: name.to_owned(),
+            devnode: info.name().to_owned(),
+            devpath: property(&info, "DEVPATH"),
+            by_id: links_with_prefix(&info, "/dev/disk/by-id/"),
+            by_path: links_with_prefix(&info, "/dev/disk/by-path/"),
+            id_path: property(&info, "ID_PATH"),
+            md_links: links_with_prefix(&info, "/dev/md/"),
+            size: geometry.size,
+            serial: property(&info, "ID_SERIAL_SHORT")
+                .or(attributes.serial),
+            wwn: property(&info, "ID_WWN_WITH_EXTENSION")
+                .or_else(|| property(&info, "ID_WWN"))
+                .or(attributes.wwid),
+            // udev replaces spaces with underscores, prefer the raw value
+            model: attributes.model
+                .or_else(|| property(&info, "ID_MODEL")),
+            vendor: attributes.vendor
+                .or_else(|| property(&info, "ID_VENDOR")),
+            bus: property(&info, "ID_BUS"),
+            transport: attributes.transport,
+            rotational: attributes.rotational,
+            removable: attributes.removable,
+            read_only: attributes.read_only,
+            partition_table_type: property(&info, "ID_PART_TABLE_TYPE"),
+            geometry,
+            partitions,
+            volume_group: read_volume_group(db.root(), &info),
+            raid_member: read_raid_member(db.root(), &info),
+            holders: read_holders(&sys_device_path),
+            slaves: read_slaves(&sys_device_path)
+        })
+    }
+
+    /// ID_PATH patterns of LUNs presented over fibre channel, iSCSI or
+    /// through SAS expanders, which are commonly shared between hosts
+    pub fn is_fabric_attached(&self) -> bool {
+        if self.transport.is_fabric() {
+            return true
+        }
+        match self.id_path {
+            Some(ref id_path) => id_path.starts_with("ip-") ||
+                ["-fc-", "-iscsi-", "-sas-exp"].iter().any(|p| id_path.contains(p)),
+            None => false
+        }
+    }
+
+    pub fn is_stale_raid_member(&self) -> bool {
+        is_stale_member(&self.raid_member, &self.holders)
+    }
+
+    /// Every name this disk is known by: kernel name, devnode and symlinks
+    pub fn names(&self) -> Vec<&str> {
+        let mut names = vec![self.name.as_str(), self.devnode.as_str()];
+        names.extend(self.by_id.iter().map(|l| l.as_str()));
+        names.extend(self.by_path.iter().map(|l| l.as_str()));
+        names.extend(self.md_links.iter().map(|l| l.as_str()));
+        names
+    }
+
+    pub fn is_known_as(&self, name: &str) -> bool {
+        self.names().contains(&name)
+    }
+}
+
+impl Inventory {
+    /// Discover the disks attached to the running system
+    pub fn discover() -> Result<Inventory, Box<dyn std::error::Error>> {
+        Inventory::discover_from(&SysRoot::default())
+    }
+
+    pub fn discover_from(root: &SysRoot) -> Result<Inventory, Box<dyn std::error::Error>> {
+        let db = UdevDatabase::new(root.clone());
+        let mut disks = Vec::new();
+        for entry in root.block_devices()? {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let disk = Disk::from_device(&db, &name)?;
+            // Empty card readers, optical drives and detached loop devices
+            if disk.size == 0 {
+                continue;
+            }
+            disks.push(disk);
+        }
+        Ok(Inventory {
+            disks
+        })
+    }
+
+    /// Whether a disk, or any of the devices it is assembled from, is
+    /// attached over a storage fabric
+    pub fn is_fabric_attached(&self, disk: &Disk) -> bool {
+        disk.is_fabric_attached() || disk.slaves.iter()
+            .filter_map(|slave| self.find(slave).or_else(|| self.find_by_partition(slave)))
+            .any(|d| self.is_fabric_attached(d))
+    }
+
+    /// Finds the disk a partition (sda1) belongs to
+    pub fn find_by_partition(&self, name: &str) -> Option<&Disk> {
+        self.disks.iter().find(|d| d.partitions.iter().any(|p| p.name == name))
+    }
+
+    /// Finds a disk by kernel name, devnode or any of its symlinks
+    pub fn find(&self, name: &str) -> Option<&Disk> {
+        self.disks.iter().find(|d| d.is_known_as(name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sysfs::fixture_root;
+
+    #[test]
+    fn test_discover() {
+        let inventory = Inventory::discover_from(&fixture_root()).unwrap();
+        let names: Vec<&str> = inventory.disks.iter().map(|d| d.name.as_str()).collect();
+        assert_eq!(names, vec!["dm-0", "loop0", "md0", "nvme0n1", "sda", "sdb", "sdc"]);
+
+        let sda = inventory.find("/dev/disk/by-path/pci-0000:00:17.0-ata-1").unwrap();
+        assert_eq!(sda.devnode, "/dev/sda");
+        assert_eq!(sda.serial.as_deref(), Some("S3Z1NB0K123456A"));
+        assert_eq!(sda.wwn.as_deref(), Some("0x5002538e40a1b2c3"));
+        assert_eq!(sda.model.as_deref(), Some("Samsung SSD 860"));
+        assert_eq!(sda.bus.as_deref(), Some("ata"));
+        assert_eq!(sda.size, 976773168 * 512);
+        assert!(!sda.rotational);
+        assert_eq!(sda.partition_table_type.as_deref(), Some("gpt"));
+
+        assert_eq!(sda.partitions.len(), 2);
+        let esp = &sda.partitions[0];
+        assert_eq!(esp.start, 2048 * 512);
+        assert_eq!(esp.part_label.as_deref(), Some("EFI System Partition"));
+        assert_eq!(esp.fs_type.as_deref(), Some("vfat"));
+        assert_eq!(sda.partitions[1].holders, vec!["md0"]);
+    }
+
+    #[test]
+    fn test_holders() {
+        let inventory = Inventory::discover_from(&fixture_root()).unwrap();
+        let sdb = inventory.find("sdb").unwrap();
+        assert_eq!(sdb.holders, vec!["dm-0"]);
+        assert!(sdb.is_fabric_attached());
+        assert_eq!(sdb.transport, Transport::FibreChannel);
+        let mpatha = inventory.find("/dev/disk/by-id/dm-name-mpatha").unwrap();
+        assert_eq!(mpatha.slaves, vec!["sdb", "sdc"]);
+        assert!(!mpatha.is_fabric_attached());
+        assert!(inventory.is_fabric_attached(mpatha));
+
+        // sda2 is a member of the assembled md0
+        use crate::layout::raid::MetadataVersion;
+        use crate::md::tests::superblock_v1;
+        let superblock = MdSuperblock::from_slice(&superblock_v1(0, 0),
+            MetadataVersion::V1_2, 4096).ok();
+        let sda = inventory.find("sda").unwrap();
+        assert!(!is_stale_member(&superblock, &sda.partitions[1].holders));
+        assert!(is_stale_member(&superblock, &[]));
+        assert!(!is_stale_member(&None, &[]));
+
+        let md0 = inventory.find("/dev/md/press:0").unwrap();
+        assert_eq!(md0.name, "md0");
+        assert_eq!(md0.slaves, vec!["nvme0n1p2", "sda2"]);
+        assert!(!inventory.is_fabric_attached(md0));
+    }
+}
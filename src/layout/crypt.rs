@@ -0,0 +1,325 @@
+extern crate serde;
+
+use std::error::Error;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use crate::command;
+
+// Error Boiler plate
+#[derive(Debug)]
+pub struct CryptError {
+    details: String
+}
+
+impl CryptError {
+    pub fn new(msg: &str) -> Self {
+        Self {
+            details: msg.to_string()
+        }
+    }
+}
+
+impl fmt::Display for CryptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.details)
+    }
+}
+
+impl Error for CryptError {
+    fn description(&self) -> &str {
+        &self.details
+    }
+}
+
+pub type CryptResult<T> = Result<T, CryptError>;
+// End Error boiler plate
+
+pub static DEFAULT_CIPHER: &str = "aes-xts-plain64";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum LuksVersion {
+    Luks1,
+    #[default]
+    Luks2
+}
+
+impl LuksVersion {
+    pub fn name(&self) -> &'static str {
+        match self {
+            LuksVersion::Luks1 => "luks1",
+            LuksVersion::Luks2 => "luks2"
+        }
+    }
+}
+
+/// The key derivation function protecting the keyslot
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Pbkdf {
+    Pbkdf2,
+    Argon2i,
+    Argon2id
+}
+
+impl Pbkdf {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Pbkdf::Pbkdf2 => "pbkdf2",
+            Pbkdf::Argon2i => "argon2i",
+            Pbkdf::Argon2id => "argon2id"
+        }
+    }
+}
+
+/// Where the key of the first keyslot comes from
+#[derive(Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum KeySource {
+    /// A key file, the same path is used by crypttab on the target
+    Keyfile(String),
+    /// A passphrase, which is asked for at boot
+    Passphrase(String),
+    /// The name of an environment variable of press which holds the
+    /// passphrase, which is asked for at boot
+    PassphraseEnv(String)
+}
+
+// Passphrases are kept out of logs
+impl fmt::Debug for KeySource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeySource::Keyfile(path) => f.debug_tuple("Keyfile").field(path).finish(),
+            KeySource::Passphrase(_) => f.debug_tuple("Passphrase").field(&"<redacted>").finish(),
+            KeySource::PassphraseEnv(name) => f.debug_tuple("PassphraseEnv").field(name).finish()
+        }
+    }
+}
+
+impl KeySource {
+    /// The key file given to cryptsetup, - for a passphrase written to stdin
+    fn key_file(&self) -> &str {
+        match self {
+            KeySource::Keyfile(path) => path,
+            _ => "-"
+        }
+    }
+
+    fn passphrase(&self) -> CryptResult<Option<String>> {
+        match self {
+            KeySource::Keyfile(_) => Ok(None),
+            KeySource::Passphrase(passphrase) => Ok(Some(passphrase.clone())),
+            KeySource::PassphraseEnv(name) => std::env::var(name)
+                .map(Some)
+                .map_err(|_| CryptError::new(&format!(
+                    "the passphrase variable {} is not set", name)))
+        }
+    }
+}
+
+/// A LUKS container holding the file system or physical volume of the
+/// device it is declared on
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Encryption {
+    /// The device mapper name, the container is opened as /dev/mapper/NAME
+    pub name: String,
+    #[serde(default)]
+    pub version: LuksVersion,
+    /// aes-xts-plain64 when not given
+    pub cipher: Option<String>,
+    /// The volume key size in bits, cryptsetup's default when not given
+    pub key_size: Option<u32>,
+    pub pbkdf: Option<Pbkdf>,
+    pub key: KeySource,
+    /// Extra crypttab options, discard for instance
+    #[serde(default)]
+    pub options: Vec<String>
+}
+
+/// A line of /etc/crypttab
+#[derive(Debug, Clone, PartialEq)]
+pub struct CrypttabEntry {
+    pub name: String,
+    /// UUID=... of the LUKS header
+    pub device: String,
+    /// none when the passphrase is asked for
+    pub key_file: String,
+    pub options: Vec<String>
+}
+
+impl fmt::Display for CrypttabEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} {} {}", self.name, self.device, self.key_file, self.options.join(","))
+    }
+}
+
+impl Encryption {
+    /// The device node of the opened container
+    pub fn device_path(&self) -> PathBuf {
+        Path::new("/dev/mapper").join(&self.name)
+    }
+
+    pub fn check(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if self.name.is_empty() || self.name.contains('/') ||
+                self.name.chars().any(char::is_whitespace) {
+            errors.push(format!("\"{}\" is not a valid device mapper name", self.name));
+        }
+        if let Some(key_size) = self.key_size {
+            if key_size == 0 || !key_size.is_multiple_of(8) {
+                errors.push(format!("key size of {} bits is not a multiple of 8", key_size));
+            }
+        }
+        if self.version == LuksVersion::Luks1 {
+            if let Some(pbkdf) = self.pbkdf.filter(|p| *p != Pbkdf::Pbkdf2) {
+                errors.push(format!("luks1 does not support {}", pbkdf.name()));
+            }
+        }
+        match self.key {
+            KeySource::Keyfile(ref path) if !path.starts_with('/') =>
+                errors.push(format!("key file {} is not an absolute path", path)),
+            KeySource::Passphrase(ref passphrase) if passphrase.is_empty() =>
+                errors.push("the passphrase is empty".to_owned()),
+            _ => ()
+        }
+        errors
+    }
+
+    /// The cryptsetup commands which format the device and open the container
+    pub fn commands(&self, device: &Path) -> Vec<(String, Vec<String>)> {
+        let device = device.to_string_lossy().into_owned();
+        let mut format = vec![
+            "luksFormat".to_owned(),
+            "--batch-mode".to_owned(),
+            "--type".to_owned(), self.version.name().to_owned(),
+            "--cipher".to_owned(), self.cipher.as_deref().unwrap_or(DEFAULT_CIPHER).to_owned()
+        ];
+        if let Some(key_size) = self.key_size {
+            format.extend(vec!["--key-size".to_owned(), key_size.to_string()]);
+        }
+        if let Some(pbkdf) = self.pbkdf {
+            format.extend(vec!["--pbkdf".to_owned(), pbkdf.name().to_owned()]);
+        }
+        format.extend(vec![
+            "--key-file".to_owned(), self.key.key_file().to_owned(), device.clone()
+        ]);
+        let open = vec![
+            "open".to_owned(),
+            "--type".to_owned(), self.version.name().to_owned(),
+            "--key-file".to_owned(), self.key.key_file().to_owned(),
+            device, self.name.clone()
+        ];
+        vec![("cryptsetup".to_owned(), format), ("cryptsetup".to_owned(), open)]
+    }
+
+    /// Formats and opens the container, returning the device node of the
+    /// opened container
+    pub fn create(&self, device: &Path) -> CryptResult<PathBuf> {
+        let errors = self.check();
+        if !errors.is_empty() {
+            return Err(CryptError::new(&format!("{}: {}", self.name, errors.join(", "))))
+        }
+        let passphrase = self.key.passphrase()?;
+        info!("Creating {} container {} on {}", self.version.name(), self.name,
+              device.display());
+        for (program, arguments) in self.commands(device) {
+            command::run_with_input(&program, &arguments, passphrase.as_deref())
+                .map_err(|e| CryptError::new(&e.to_string()))?;
+        }
+        Ok(self.device_path())
+    }
+
+    /// The crypttab entry of the container, given the UUID of its LUKS header
+    pub fn crypttab_entry(&self, uuid: &str) -> CrypttabEntry {
+        let key_file = match self.key {
+            KeySource::Keyfile(ref path) => path.clone(),
+            _ => "none".to_owned()
+        };
+        let mut options = vec!["luks".to_owned()];
+        options.extend(self.options.iter().cloned());
+        CrypttabEntry {
+            name: self.name.clone(),
+            device: format!("UUID={}", uuid),
+            key_file,
+            options
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::testing::command_lines;
+
+    static ENCRYPTION: &str = r#"{
+        "name": "data",
+        "cipher": "aes-xts-plain64",
+        "key_size": 512,
+        "pbkdf": "argon2id",
+        "key": {"keyfile": "/etc/keys/data.key"},
+        "options": ["discard"]
+    }"#;
+
+    fn encryption(json: &str) -> Encryption {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn test_de() {
+        let encryption = encryption(ENCRYPTION);
+        assert_eq!(encryption.version, LuksVersion::Luks2);
+        assert!(encryption.check().is_empty());
+        let passphrase = self::encryption(
+            r#"{"name": "home", "key": {"passphrase": "correct horse"}}"#);
+        assert!(!format!("{:?}", passphrase).contains("correct horse"));
+        assert!(serde_json::from_str::<Encryption>(
+            r#"{"name": "home", "key": {"password": "x"}}"#).is_err());
+    }
+
+    #[test]
+    fn test_check() {
+        let encryption = encryption(r#"{
+            "name": "my data", "version": "luks1", "key_size": 500, "pbkdf": "argon2i",
+            "key": {"keyfile": "data.key"}
+        }"#);
+        assert_eq!(encryption.check(), vec![
+            "\"my data\" is not a valid device mapper name",
+            "key size of 500 bits is not a multiple of 8",
+            "luks1 does not support argon2i",
+            "key file data.key is not an absolute path",
+        ]);
+    }
+
+    #[test]
+    fn test_commands() {
+        let commands = command_lines(&encryption(ENCRYPTION).commands(Path::new("/dev/sda3")));
+        assert_eq!(commands, vec![
+            "cryptsetup luksFormat --batch-mode --type luks2 --cipher aes-xts-plain64 \
+            --key-size 512 --pbkdf argon2id --key-file /etc/keys/data.key /dev/sda3",
+            "cryptsetup open --type luks2 --key-file /etc/keys/data.key /dev/sda3 data",
+        ]);
+
+        let encryption = encryption(
+            r#"{"name": "home", "key": {"passphrase_env": "PRESS_TEST_UNSET_PASSPHRASE"}}"#);
+        let (_, arguments) = &encryption.commands(Path::new("/dev/md/md0"))[1];
+        assert_eq!(arguments.join(" "), "open --type luks2 --key-file - /dev/md/md0 home");
+        assert_eq!(encryption.create(Path::new("/dev/md/md0")).unwrap_err().to_string(),
+            "the passphrase variable PRESS_TEST_UNSET_PASSPHRASE is not set");
+    }
+
+    #[test]
+    fn test_crypttab_entry() {
+        let uuid = "5f0c8a6e-2a1d-4e7b-9c3f-8b1e2d4a6c90";
+        assert_eq!(encryption(ENCRYPTION).crypttab_entry(uuid).to_string(),
+            "data UUID=5f0c8a6e-2a1d-4e7b-9c3f-8b1e2d4a6c90 /etc/keys/data.key luks,discard");
+        let encryption = encryption(r#"{"name": "home", "key": {"passphrase": "x"}}"#);
+        assert_eq!(encryption.crypttab_entry(uuid).to_string(),
+            "home UUID=5f0c8a6e-2a1d-4e7b-9c3f-8b1e2d4a6c90 none luks");
+        assert_eq!(encryption.device_path(), PathBuf::from("/dev/mapper/home"));
+    }
+}
@@ -0,0 +1,295 @@
+use std::path::{Component, Path};
+
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use crate::command;
+use crate::size::Size;
+use super::{FileSystem, FileSystemType, FileSystemError, FileSystemResult,
+            MakeFileSystem, parse_uuid};
+
+static COMPRESSION_ALGORITHMS: &[(&str, Option<(u32, u32)>)] = &[
+    ("zlib", Some((1, 9))),
+    ("lzo", None),
+    ("zstd", Some((1, 15)))
+];
+
+/// A btrfs subvolume, created below the top level of the file system
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Subvolume {
+    /// The path of the subvolume from the top level, @home for instance
+    pub path: String,
+    /// Where the subvolume is mounted in the target system
+    pub mount_point: Option<String>,
+    /// Mount options in addition to those of the file system
+    pub mount_options: Option<String>,
+    /// Compression of new files in the subvolume, zstd:3 for instance
+    pub compression: Option<String>,
+    /// Mounted when no subvol= option is given
+    #[serde(default)]
+    pub default: bool,
+    /// A quota group limit, quota must be enabled on the file system
+    pub quota_limit: Option<Size>
+}
+
+/// Checks an algorithm with an optional level: zlib, zlib:9, lzo, zstd:3
+pub fn check_compression(compression: &str) -> Option<String> {
+    let mut split = compression.splitn(2, ':');
+    let algorithm = split.next().unwrap_or_default();
+    let levels = match COMPRESSION_ALGORITHMS.iter().find(|(name, _)| *name == algorithm) {
+        Some((_, levels)) => levels,
+        None => return Some(format!("unknown btrfs compression algorithm {}", algorithm))
+    };
+    match (split.next(), levels) {
+        (None, _) => None,
+        (Some(level), Some((min, max))) => match level.parse::<u32>() {
+            Ok(level) if level >= *min && level <= *max => None,
+            _ => Some(format!("{} compression levels are {} to {}", algorithm, min, max))
+        },
+        (Some(_), None) => Some(format!("{} compression has no levels", algorithm))
+    }
+}
+
+impl Subvolume {
+    fn check(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        let path = Path::new(&self.path);
+        if self.path.is_empty() || path.is_absolute() ||
+                path.components().any(|c| !matches!(c, Component::Normal(_))) {
+            errors.push(format!("subvolume path {} is not relative to the top level", self.path));
+        }
+        if let Some(ref compression) = self.compression {
+            errors.extend(check_compression(compression));
+        }
+        errors
+    }
+}
+
+/// Problems with the btrfs specific options
+pub fn check_subvolumes(file_system: &FileSystem) -> Vec<String> {
+    let mut errors = Vec::new();
+    let mut paths: Vec<&str> = Vec::new();
+    for subvolume in file_system.subvolumes.iter() {
+        errors.extend(subvolume.check());
+        if paths.contains(&subvolume.path.as_str()) {
+            errors.push(format!("subvolume {} is declared twice", subvolume.path));
+        }
+        paths.push(&subvolume.path);
+        if subvolume.quota_limit.is_some() && !file_system.quota {
+            errors.push(format!("subvolume {} has a quota limit but quota is not enabled",
+                subvolume.path));
+        }
+    }
+    if file_system.subvolumes.iter().filter(|s| s.default).count() > 1 {
+        errors.push("only one subvolume may be the default".to_owned());
+    }
+    if let Some(ref compression) = file_system.compression {
+        errors.extend(check_compression(compression));
+    }
+    errors
+}
+
+/// The commands which create the subvolumes of a file system mounted at
+/// top_level, with their compression, quota limits and the default subvolume.
+/// Subvolumes are created parents first, the directories leading up to a
+/// nested subvolume are created along the way.
+pub fn subvolume_commands(file_system: &FileSystem, top_level: &Path) -> Vec<(String, Vec<String>)> {
+    let command = |program: &str, arguments: &[&str]| (program.to_owned(),
+        arguments.iter().map(|a| a.to_string()).collect::<Vec<String>>());
+    let btrfs = |arguments: &[&str]| command("btrfs", arguments);
+    let mut commands = Vec::new();
+    if file_system.quota {
+        commands.push(btrfs(&["quota", "enable", &top_level.to_string_lossy()]));
+    }
+    let mut subvolumes: Vec<&Subvolume> = file_system.subvolumes.iter().collect();
+    subvolumes.sort_by_key(|subvolume| Path::new(&subvolume.path).components().count());
+    for subvolume in subvolumes {
+        let path = top_level.join(&subvolume.path);
+        let created = |parent: &Path| parent == top_level ||
+            file_system.subvolumes.iter().any(|s| top_level.join(&s.path) == parent);
+        if let Some(parent) = path.parent().filter(|parent| !created(parent)) {
+            commands.push(command("mkdir", &["-p", &parent.to_string_lossy()]));
+        }
+        let path = path.to_string_lossy();
+        commands.push(btrfs(&["subvolume", "create", &path]));
+        if let Some(ref compression) = subvolume.compression {
+            commands.push(btrfs(&["property", "set", &path, "compression", compression]));
+        }
+        if let Some(ref limit) = subvolume.quota_limit {
+            commands.push(btrfs(&["qgroup", "limit", &limit.bytes().to_string(), &path]));
+        }
+        if subvolume.default {
+            commands.push(btrfs(&["subvolume", "set-default", &path]));
+        }
+    }
+    commands
+}
+
+/// Mounts the top level of a new file system on a temporary directory and
+/// creates its subvolumes. The file system is unmounted whether or not
+/// every subvolume could be created.
+pub fn create_subvolumes(file_system: &FileSystem, device: &Path) -> FileSystemResult<()> {
+    if file_system.subvolumes.is_empty() && !file_system.quota {
+        return Ok(())
+    }
+    let top_level = std::env::temp_dir().join(format!("press-btrfs-{}", std::process::id()));
+    std::fs::create_dir_all(&top_level).map_err(|e| FileSystemError::new(
+        &format!("could not create {}: {}", top_level.display(), e)))?;
+    let top_level_str = top_level.to_string_lossy().into_owned();
+    let run = |program: &str, arguments: &[String]| command::run(program, arguments)
+        .map_err(|e| FileSystemError::new(&e.to_string()));
+    let mounted = run("mount", &["-t".to_owned(), "btrfs".to_owned(), "-o".to_owned(),
+        "subvolid=5".to_owned(), device.to_string_lossy().into_owned(), top_level_str.clone()]);
+    if let Err(e) = mounted {
+        let _ = std::fs::remove_dir(&top_level);
+        return Err(e)
+    }
+
+    info!("Creating {} btrfs subvolumes on {}", file_system.subvolumes.len(), device.display());
+    let result = subvolume_commands(file_system, &top_level).iter()
+        .try_for_each(|(program, arguments)| run(program, arguments));
+    let unmounted = command::run_cleanup("umount", &[top_level_str])
+        .map_err(|e| FileSystemError::new(&e.to_string()));
+    let _ = std::fs::remove_dir(&top_level);
+    result.and(unmounted)
+}
+
+pub struct MkfsBtrfs;
+
+impl MakeFileSystem for MkfsBtrfs {
+    fn command(&self) -> &'static str {
+        "mkfs.btrfs"
+    }
+
+    fn arguments(&self, file_system: &FileSystem, device: &Path) -> FileSystemResult<Vec<String>> {
+        let fs_type = FileSystemType::Btrfs;
+        if file_system.inode_ratio.is_some() {
+            return Err(FileSystemError::unsupported(fs_type, "inode_ratio"))
+        }
+        if file_system.reserved_blocks_percent.is_some() {
+            return Err(FileSystemError::unsupported(fs_type, "reserved_blocks_percent"))
+        }
+
+        let mut arguments = vec!["-f".to_owned()];
+        if let Some(ref label) = file_system.label {
+            arguments.extend(vec!["-L".to_owned(), label.to_owned()]);
+        }
+        if let Some(ref uuid) = file_system.uuid {
+            let uuid = parse_uuid(fs_type, uuid)?;
+            arguments.extend(vec!["-U".to_owned(), uuid.to_string()]);
+        }
+        if let Some(ref block_size) = file_system.block_size {
+            arguments.extend(vec!["-s".to_owned(), block_size.bytes().to_string()]);
+        }
+        if !file_system.features.is_empty() {
+            arguments.extend(vec!["-O".to_owned(), file_system.features.join(",")]);
+        }
+        arguments.extend(file_system.extra_options.iter().cloned());
+        arguments.push(device.to_string_lossy().into_owned());
+        Ok(arguments)
+    }
+
+    fn finish(&self, file_system: &FileSystem, device: &Path) -> FileSystemResult<()> {
+        create_subvolumes(file_system, device)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::testing::{command_lines, file_system};
+
+    static FILE_SYSTEM: &str = r#"{
+        "fs_type": "btrfs",
+        "label": "system",
+        "mount_options": "noatime",
+        "compression": "zstd:3",
+        "quota": true,
+        "subvolumes": [
+            {"path": "@", "mount_point": "/", "default": true},
+            {"path": "@home", "mount_point": "/home", "quota_limit": "100GiB"},
+            {"path": "@snapshots", "mount_point": "/.snapshots", "compression": "lzo"},
+            {"path": "@/var/lib/machines"}
+        ]
+    }"#;
+
+    #[test]
+    fn test_check() {
+        assert!(file_system(FILE_SYSTEM).check().is_empty());
+        let errors = file_system(r#"{
+            "fs_type": "btrfs", "compression": "zstd:20",
+            "subvolumes": [
+                {"path": "/@", "default": true},
+                {"path": "@home", "default": true, "compression": "lz4", "quota_limit": "1GiB"},
+                {"path": "@home/../@"},
+                {"path": "@home"}
+            ]
+        }"#).check();
+        assert_eq!(errors, vec![
+            "subvolume path /@ is not relative to the top level",
+            "unknown btrfs compression algorithm lz4",
+            "subvolume @home has a quota limit but quota is not enabled",
+            "subvolume path @home/../@ is not relative to the top level",
+            "subvolume @home is declared twice",
+            "only one subvolume may be the default",
+            "zstd compression levels are 1 to 15",
+        ]);
+        assert_eq!(check_compression("lzo:1").as_deref(), Some("lzo compression has no levels"));
+    }
+
+    #[test]
+    fn test_subvolume_commands() {
+        let commands = command_lines(
+            &subvolume_commands(&file_system(FILE_SYSTEM), Path::new("/mnt")));
+        assert_eq!(commands, vec![
+            "btrfs quota enable /mnt",
+            "btrfs subvolume create /mnt/@",
+            "btrfs subvolume set-default /mnt/@",
+            "btrfs subvolume create /mnt/@home",
+            "btrfs qgroup limit 107374182400 /mnt/@home",
+            "btrfs subvolume create /mnt/@snapshots",
+            "btrfs property set /mnt/@snapshots compression lzo",
+            "mkdir -p /mnt/@/var/lib",
+            "btrfs subvolume create /mnt/@/var/lib/machines",
+        ]);
+
+        // Parents are created first, whatever the order of declaration
+        let commands = command_lines(&subvolume_commands(&file_system(r#"{
+            "fs_type": "btrfs",
+            "subvolumes": [{"path": "@/srv"}, {"path": "@"}]
+        }"#), Path::new("/mnt")));
+        assert_eq!(commands, vec![
+            "btrfs subvolume create /mnt/@",
+            "btrfs subvolume create /mnt/@/srv",
+        ]);
+    }
+
+    #[test]
+    fn test_mounts() {
+        let options = |json: &str| -> Vec<String> {
+            file_system(json).mounts().iter().map(|m| m.options.join(",")).collect()
+        };
+        assert_eq!(options(r#"{
+            "fs_type": "btrfs", "mount_point": "/srv/pool",
+            "subvolumes": [{"path": "@data", "mount_point": "/srv/data"}]
+        }"#), vec!["subvolid=5", "subvol=@data"]);
+        assert_eq!(options(r#"{
+            "fs_type": "btrfs", "mount_point": "/srv/pool",
+            "subvolumes": [{"path": "@data", "default": true}]
+        }"#), vec![""]);
+    }
+
+    #[test]
+    fn test_fstab_entries() {
+        let entries: Vec<String> = file_system(FILE_SYSTEM).fstab_entries("UUID=7d0e")
+            .iter()
+            .map(|entry| entry.to_string())
+            .collect();
+        assert_eq!(entries, vec![
+            "UUID=7d0e / btrfs noatime,compress=zstd:3,subvol=@ 0 0",
+            "UUID=7d0e /home btrfs noatime,compress=zstd:3,subvol=@home 0 0",
+            "UUID=7d0e /.snapshots btrfs noatime,compress=zstd:3,subvol=@snapshots 0 0",
+        ]);
+    }
+}
@@ -1,2 +1,355 @@
-pub struct EXT;
+extern crate byteorder;
+extern crate serde;
+extern crate uuid;
 
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use byteorder::{LittleEndian, ByteOrder};
+use serde::Serialize;
+use uuid::Uuid;
+
+use super::{FileSystem, FileSystemType, FileSystemError, FileSystemResult,
+            MakeFileSystem, parse_uuid};
+
+pub static EXT_SUPERBLOCK_OFFSET: u64 = 1024;
+pub static EXT_SUPERBLOCK_SIZE: usize = 1024;
+pub static EXT_MAGIC: u16 = 0xEF53;
+/// Blocks are 1024 << s_log_block_size bytes, at most 64KiB
+pub static EXT_MAX_LOG_BLOCK_SIZE: u32 = 6;
+
+const COMPAT_HAS_JOURNAL: u32 = 0x4;
+const INCOMPAT_EXTENTS: u32 = 0x40;
+const INCOMPAT_64BIT: u32 = 0x80;
+const INCOMPAT_FLEX_BG: u32 = 0x200;
+const RO_COMPAT_HUGE_FILE: u32 = 0x8;
+const RO_COMPAT_GDT_CSUM: u32 = 0x10;
+const RO_COMPAT_DIR_NLINK: u32 = 0x20;
+const RO_COMPAT_EXTRA_ISIZE: u32 = 0x40;
+const RO_COMPAT_METADATA_CSUM: u32 = 0x400;
+
+const STATE_CLEAN: u16 = 0x1;
+
+static COMPAT_FEATURES: &[(u32, &str)] = &[
+    (0x1, "dir_prealloc"), (0x2, "imagic_inodes"), (COMPAT_HAS_JOURNAL, "has_journal"),
+    (0x8, "ext_attr"), (0x10, "resize_inode"), (0x20, "dir_index"),
+    (0x200, "sparse_super2"), (0x400, "fast_commit"), (0x800, "stable_inodes"),
+    (0x1000, "orphan_file")
+];
+
+static INCOMPAT_FEATURES: &[(u32, &str)] = &[
+    (0x1, "compression"), (0x2, "filetype"), (0x4, "needs_recovery"),
+    (0x8, "journal_dev"), (0x10, "meta_bg"), (INCOMPAT_EXTENTS, "extent"),
+    (INCOMPAT_64BIT, "64bit"), (0x100, "mmp"), (INCOMPAT_FLEX_BG, "flex_bg"),
+    (0x400, "ea_inode"), (0x1000, "dirdata"), (0x2000, "metadata_csum_seed"),
+    (0x4000, "large_dir"), (0x8000, "inline_data"), (0x10000, "encrypt"),
+    (0x20000, "casefold")
+];
+
+static RO_COMPAT_FEATURES: &[(u32, &str)] = &[
+    (0x1, "sparse_super"), (0x2, "large_file"), (0x4, "btree_dir"),
+    (RO_COMPAT_HUGE_FILE, "huge_file"), (RO_COMPAT_GDT_CSUM, "uninit_bg"),
+    (RO_COMPAT_DIR_NLINK, "dir_nlink"), (RO_COMPAT_EXTRA_ISIZE, "extra_isize"),
+    (0x100, "quota"), (0x200, "bigalloc"), (RO_COMPAT_METADATA_CSUM, "metadata_csum"),
+    (0x800, "replica"), (0x1000, "read-only"), (0x2000, "project"),
+    (0x8000, "verity"), (0x10000, "orphan_present")
+];
+
+// check if the buffer contains an ext superblock
+// buffer starts at the superblock, offset 1024 of the device
+pub fn is_ext(buffer: &[u8]) -> bool {
+    buffer.len() >= 58 && LittleEndian::read_u16(&buffer[56..58]) == EXT_MAGIC &&
+        LittleEndian::read_u32(&buffer[24..28]) <= EXT_MAX_LOG_BLOCK_SIZE
+}
+
+// Strings in the superblock are fixed length and null padded
+fn read_string(data: &[u8]) -> String {
+    let end = data.iter().position(|b| *b == 0).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..end]).into_owned()
+}
+
+fn feature_names(flags: u32, names: &[(u32, &str)]) -> Vec<String> {
+    names.iter()
+        .filter(|(flag, _)| flags & flag != 0)
+        .map(|(_, name)| name.to_string())
+        .collect()
+}
+
+/// The ext2/3/4 superblock
+#[derive(Debug, Default, Serialize)]
+pub struct EXT {
+    pub inodes_count: u32,
+    pub blocks_count: u64,
+    pub reserved_blocks_count: u64,
+    pub free_blocks_count: u64,
+    pub free_inodes_count: u32,
+    pub first_data_block: u32,
+    pub block_size: u32,
+    pub blocks_per_group: u32,
+    pub inodes_per_group: u32,
+    pub mount_time: u32,
+    pub write_time: u32,
+    pub magic: u16,
+    pub state: u16,
+    pub errors: u16,
+    pub rev_level: u32,
+    pub inode_size: u16,
+    pub feature_compat: u32,
+    pub feature_incompat: u32,
+    pub feature_ro_compat: u32,
+    pub uuid: Uuid,
+    pub volume_name: String,
+    pub last_mounted: String
+}
+
+impl EXT {
+    // slice containing a superblock starting at index 0
+    pub fn from_slice(data: &[u8]) -> FileSystemResult<EXT> {
+        if data.len() < EXT_SUPERBLOCK_SIZE {
+            return Err(FileSystemError::new("the ext superblock is too small"))
+        }
+        let log_block_size = LittleEndian::read_u32(&data[24..28]);
+        if log_block_size > EXT_MAX_LOG_BLOCK_SIZE {
+            return Err(FileSystemError::new(&format!(
+                "invalid ext block size 1024 << {}", log_block_size)))
+        }
+        let feature_incompat = LittleEndian::read_u32(&data[96..100]);
+        // The high 32 bits of the block counts are only valid with 64bit
+        let count = |lo: usize, hi: usize| {
+            let mut count = LittleEndian::read_u32(&data[lo..lo+4]) as u64;
+            if feature_incompat & INCOMPAT_64BIT != 0 {
+                count |= (LittleEndian::read_u32(&data[hi..hi+4]) as u64) << 32;
+            }
+            count
+        };
+        Ok(EXT {
+            inodes_count: LittleEndian::read_u32(&data[..4]),
+            blocks_count: count(4, 336),
+            reserved_blocks_count: count(8, 340),
+            free_blocks_count: count(12, 344),
+            free_inodes_count: LittleEndian::read_u32(&data[16..20]),
+            first_data_block: LittleEndian::read_u32(&data[20..24]),
+            block_size: 1024 << log_block_size,
+            blocks_per_group: LittleEndian::read_u32(&data[32..36]),
+            inodes_per_group: LittleEndian::read_u32(&data[40..44]),
+            mount_time: LittleEndian::read_u32(&data[44..48]),
+            write_time: LittleEndian::read_u32(&data[48..52]),
+            magic: LittleEndian::read_u16(&data[56..58]),
+            state: LittleEndian::read_u16(&data[58..60]),
+            errors: LittleEndian::read_u16(&data[60..62]),
+            rev_level: LittleEndian::read_u32(&data[76..80]),
+            inode_size: LittleEndian::read_u16(&data[88..90]),
+            feature_compat: LittleEndian::read_u32(&data[92..96]),
+            feature_incompat,
+            feature_ro_compat: LittleEndian::read_u32(&data[100..104]),
+            // The uuid is stored in standard (big endian) byte order
+            uuid: Uuid::from_slice(&data[104..120]).unwrap(),
+            volume_name: read_string(&data[120..136]),
+            last_mounted: read_string(&data[136..200])
+        })
+    }
+
+    /// Reads the superblock of the file system starting at the current
+    /// position of the reader
+    pub fn from_reader<R>(reader: &mut R) -> Result<EXT, std::io::Error>
+        where R: Read + Seek {
+            let start = reader.stream_position()?;
+            let mut buffer = vec![0u8; EXT_SUPERBLOCK_SIZE];
+            reader.seek(SeekFrom::Start(start + EXT_SUPERBLOCK_OFFSET))?;
+            reader.read_exact(&mut buffer)?;
+            if !is_ext(&buffer) {
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData,
+                    "no ext superblock found"))
+            }
+            EXT::from_slice(&buffer).map_err(|e| std::io::Error::new(
+                std::io::ErrorKind::InvalidData, e.to_string()))
+    }
+
+    pub fn from_device(device: &Path) -> Result<EXT, std::io::Error> {
+        EXT::from_reader(&mut std::fs::File::open(device)?)
+    }
+
+    /// Which revision of ext created the file system, by the features in use
+    pub fn fs_type(&self) -> FileSystemType {
+        if self.feature_incompat & (INCOMPAT_EXTENTS | INCOMPAT_64BIT | INCOMPAT_FLEX_BG) != 0 ||
+                self.feature_ro_compat & (RO_COMPAT_HUGE_FILE | RO_COMPAT_GDT_CSUM |
+                    RO_COMPAT_DIR_NLINK | RO_COMPAT_EXTRA_ISIZE | RO_COMPAT_METADATA_CSUM) != 0 {
+            FileSystemType::Ext4
+        } else if self.feature_compat & COMPAT_HAS_JOURNAL != 0 {
+            FileSystemType::Ext3
+        } else {
+            FileSystemType::Ext2
+        }
+    }
+
+    pub fn features(&self) -> Vec<String> {
+        let mut features = feature_names(self.feature_compat, COMPAT_FEATURES);
+        features.extend(feature_names(self.feature_incompat, INCOMPAT_FEATURES));
+        features.extend(feature_names(self.feature_ro_compat, RO_COMPAT_FEATURES));
+        features
+    }
+
+    pub fn is_clean(&self) -> bool {
+        self.state & STATE_CLEAN != 0
+    }
+
+    pub fn size(&self) -> u64 {
+        self.blocks_count * self.block_size as u64
+    }
+
+    /// Checks the label and uuid against those which were requested
+    pub fn verify(&self, label: Option<&str>, uuid: Option<&Uuid>) -> FileSystemResult<()> {
+        if let Some(label) = label {
+            if self.volume_name != label {
+                return Err(FileSystemError::new(format!(
+                    "expected label {} but found {}", label, self.volume_name).as_str()))
+            }
+        }
+        if let Some(uuid) = uuid {
+            if &self.uuid != uuid {
+                return Err(FileSystemError::new(format!(
+                    "expected uuid {} but found {}", uuid, self.uuid).as_str()))
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Creates ext2, ext3 and ext4 file systems
+pub struct Mke2fs {
+    fs_type: FileSystemType
+}
+
+impl Mke2fs {
+    pub fn new(fs_type: FileSystemType) -> Self {
+        Self {
+            fs_type
+        }
+    }
+}
+
+impl MakeFileSystem for Mke2fs {
+    fn command(&self) -> &'static str {
+        "mke2fs"
+    }
+
+    fn arguments(&self, file_system: &FileSystem, device: &Path) -> FileSystemResult<Vec<String>> {
+        // -F is required to create a file system on a whole disk or an image
+        let mut arguments = vec!["-t".to_owned(), self.fs_type.name().to_owned(),
+                                 "-F".to_owned(), "-q".to_owned()];
+        if let Some(ref label) = file_system.label {
+            arguments.extend(vec!["-L".to_owned(), label.to_owned()]);
+        }
+        if let Some(ref uuid) = file_system.uuid {
+            let uuid = parse_uuid(self.fs_type, uuid)?;
+            arguments.extend(vec!["-U".to_owned(), uuid.to_string()]);
+        }
+        if let Some(ref block_size) = file_system.block_size {
+            arguments.extend(vec!["-b".to_owned(), block_size.bytes().to_string()]);
+        }
+        if let Some(inode_ratio) = file_system.inode_ratio {
+            arguments.extend(vec!["-i".to_owned(), inode_ratio.to_string()]);
+        }
+        if let Some(reserved) = file_system.reserved_blocks_percent {
+            arguments.extend(vec!["-m".to_owned(), reserved.to_string()]);
+        }
+        if !file_system.features.is_empty() {
+            arguments.extend(vec!["-O".to_owned(), file_system.features.join(",")]);
+        }
+        arguments.extend(file_system.extra_options.iter().cloned());
+        arguments.push(device.to_string_lossy().into_owned());
+        Ok(arguments)
+    }
+
+    fn verify(&self, file_system: &FileSystem, device: &Path) -> FileSystemResult<()> {
+        let superblock = EXT::from_device(device).map_err(|e| FileSystemError::new(
+            format!("could not read the superblock of {}: {}", device.display(), e).as_str()))?;
+        let uuid = match file_system.uuid {
+            Some(ref uuid) => Some(parse_uuid(self.fs_type, uuid)?),
+            None => None
+        };
+        superblock.verify(file_system.label.as_deref(), uuid.as_ref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn superblock() -> Vec<u8> {
+        let mut data = vec![0u8; EXT_SUPERBLOCK_SIZE];
+        LittleEndian::write_u32(&mut data[0..4], 65536);
+        LittleEndian::write_u32(&mut data[4..8], 0x10);
+        LittleEndian::write_u32(&mut data[12..16], 0x8);
+        LittleEndian::write_u32(&mut data[24..28], 2);
+        LittleEndian::write_u16(&mut data[56..58], EXT_MAGIC);
+        LittleEndian::write_u16(&mut data[58..60], 1);
+        LittleEndian::write_u32(&mut data[92..96], COMPAT_HAS_JOURNAL);
+        LittleEndian::write_u32(&mut data[96..100], INCOMPAT_EXTENTS | INCOMPAT_64BIT);
+        LittleEndian::write_u32(&mut data[100..104], RO_COMPAT_METADATA_CSUM);
+        data[104..120].copy_from_slice(
+            Uuid::parse_str("6a7b8c9d-aebf-40c1-92d3-e4f5a6b7c8d9").unwrap().as_bytes());
+        data[120..124].copy_from_slice(b"root");
+        data[136..138].copy_from_slice(b"/\0");
+        LittleEndian::write_u32(&mut data[336..340], 0x1);
+        LittleEndian::write_u32(&mut data[344..348], 0x1);
+        data
+    }
+
+    #[test]
+    fn test_from_slice() {
+        let data = superblock();
+        assert!(is_ext(&data));
+        let ext = EXT::from_slice(&data).unwrap();
+        assert_eq!(ext.blocks_count, 0x1_0000_0010);
+        assert_eq!(ext.free_blocks_count, 0x1_0000_0008);
+        assert_eq!(ext.block_size, 4096);
+        assert_eq!(ext.volume_name, "root");
+        assert_eq!(ext.last_mounted, "/");
+        assert_eq!(ext.uuid.to_string(), "6a7b8c9d-aebf-40c1-92d3-e4f5a6b7c8d9");
+        assert_eq!(ext.fs_type(), FileSystemType::Ext4);
+        assert_eq!(ext.features(), vec!["has_journal", "extent", "64bit", "metadata_csum"]);
+        assert!(ext.is_clean());
+        assert!(ext.verify(Some("root"), Some(&ext.uuid)).is_ok());
+        assert!(ext.verify(Some("home"), None).is_err());
+
+        assert!(EXT::from_slice(&data[..512]).is_err());
+        let mut data = data;
+        LittleEndian::write_u32(&mut data[24..28], 40);
+        assert!(!is_ext(&data));
+        assert_eq!(EXT::from_slice(&data).unwrap_err().to_string(),
+            "invalid ext block size 1024 << 40");
+    }
+
+    #[test]
+    fn test_from_reader() {
+        let mut image = vec![0u8; 4096];
+        image[1024..2048].copy_from_slice(&superblock());
+        let ext = EXT::from_reader(&mut std::io::Cursor::new(image)).unwrap();
+        assert_eq!(ext.inodes_count, 65536);
+        assert!(EXT::from_reader(&mut std::io::Cursor::new(vec![0u8; 4096])).is_err());
+    }
+
+    #[test]
+    fn test_created() {
+        if std::process::Command::new("mke2fs").arg("-V").output().is_err() {
+            return
+        }
+        let image = std::env::temp_dir().join(format!("press-ext-{}.img", std::process::id()));
+        std::fs::File::create(&image).unwrap().set_len(32 << 20).unwrap();
+
+        let mut ext3 = FileSystem::new(FileSystemType::Ext3);
+        ext3.label = Some("boot".to_owned());
+        ext3.uuid = Some("3c1f9a2e-5b7d-4e8f-a6c4-2d1e0f9b8a7c".to_owned());
+        ext3.block_size = Some(crate::size::Size::new(1024));
+        ext3.create(&image).unwrap();
+
+        let superblock = EXT::from_device(&image).unwrap();
+        assert_eq!(superblock.fs_type(), FileSystemType::Ext3);
+        assert_eq!(superblock.block_size, 1024);
+        assert_eq!(superblock.size(), 32 << 20);
+        assert!(superblock.is_clean());
+
+        std::fs::remove_file(&image).unwrap();
+    }
+}
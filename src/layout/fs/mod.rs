@@ -1,32 +1,510 @@
 extern crate serde;
 pub mod ext;
+pub mod xfs;
+pub mod btrfs;
+pub mod vfat;
+pub mod swap;
+pub mod ntfs;
 
+use std::error::Error;
+use std::fmt;
+use std::path::Path;
+use std::os::unix::process::ExitStatusExt;
+use std::process::{ExitStatus, Output};
+
+use schemars::JsonSchema;
 use serde::Deserialize;
 
-pub use ext::EXT;
+use crate::command;
+use crate::signal;
+use crate::size::Size;
+
+pub use ext::{EXT, Mke2fs};
+pub use xfs::MkfsXfs;
+pub use btrfs::{MkfsBtrfs, Subvolume};
+pub use vfat::{FatOptions, FatType, FatVolume, MkfsVfat};
+pub use swap::{Mkswap, SwapHeader};
+pub use ntfs::Mkntfs;
 
-// from the playground .. Maybe something like this?
-// struct FileSystem {
-//     name: &'static str
-// }
+// Error Boiler plate
+#[derive(Debug)]
+pub struct FileSystemError {
+    details: String
+}
 
-// static EXT4: FileSystem = FileSystem { name: "EXT4" };
+impl FileSystemError {
+    pub fn new(msg: &str) -> Self {
+        Self {
+            details: msg.to_string()
+        }
+    }
 
-// fn main() {
-//     println!("Hello, world!");
-//     println!("{}", &EXT4.name );
-// }
+    /// An option which the file system tool has no equivalent for
+    pub fn unsupported(fs_type: FileSystemType, option: &str) -> Self {
+        Self::new(format!("{} is not supported by {}", option, fs_type.name()).as_str())
+    }
+}
 
-// Or maybe just a file system enum
-// and implment a method call `command` which will match the
-// file system name and output the command
-// of coarse there are command line options that belong in struct as well
-// gonna have to thing on this more
+impl fmt::Display for FileSystemError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.details)
+    }
+}
+
+impl Error for FileSystemError {
+    fn description(&self) -> &str {
+        &self.details
+    }
+}
+
+type FileSystemResult<T> = Result<T, FileSystemError>;
+// End Error boiler plate
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum FileSystemType {
+    Ext2,
+    Ext3,
+    Ext4,
+    Xfs,
+    Btrfs,
+    Vfat,
+    Swap,
+    Ntfs
+}
+
+impl FileSystemType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            FileSystemType::Ext2 => "ext2",
+            FileSystemType::Ext3 => "ext3",
+            FileSystemType::Ext4 => "ext4",
+            FileSystemType::Xfs => "xfs",
+            FileSystemType::Btrfs => "btrfs",
+            FileSystemType::Vfat => "vfat",
+            FileSystemType::Swap => "swap",
+            FileSystemType::Ntfs => "ntfs"
+        }
+    }
+
+    /// The longest label the file system can store
+    pub fn max_label_length(&self) -> usize {
+        match self {
+            FileSystemType::Ext2 | FileSystemType::Ext3 | FileSystemType::Ext4 => 16,
+            FileSystemType::Xfs => 12,
+            FileSystemType::Btrfs => 255,
+            FileSystemType::Vfat => 11,
+            FileSystemType::Swap => 16,
+            FileSystemType::Ntfs => 128
+        }
+    }
+}
 
-#[derive(Debug, Deserialize)]
+/// How fstab identifies the device a file system is on
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum MountBy {
+    /// The file system UUID
+    #[default]
+    Uuid,
+    /// The GPT partition UUID, or the MBR disk signature and partition number
+    Partuuid,
+    /// The file system label
+    Label
+}
+
+impl MountBy {
+    /// The fstab tag: UUID, PARTUUID or LABEL
+    pub fn tag(&self) -> &'static str {
+        match self {
+            MountBy::Uuid => "UUID",
+            MountBy::Partuuid => "PARTUUID",
+            MountBy::Label => "LABEL"
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct FileSystem {
     /// The file system type (ext4, ntfs, etc)
-    pub fs_type: String,
+    pub fs_type: FileSystemType,
     /// The path to the file system command (mke2fs, mkntfs, etc)
-    pub command_path: String,
+    pub command_path: Option<String>,
+    pub label: Option<String>,
+    /// A UUID, or for vfat a volume id in the form 1A2B-3C4D
+    pub uuid: Option<String>,
+    pub block_size: Option<Size>,
+    /// Bytes per inode, ext only
+    pub inode_ratio: Option<u64>,
+    /// File system features to enable, as understood by the mkfs tool
+    #[serde(default)]
+    pub features: Vec<String>,
+    /// Percentage of blocks reserved for the super user, ext only
+    pub reserved_blocks_percent: Option<f64>,
+    /// Additional arguments passed to the mkfs tool as is
+    #[serde(default)]
+    pub extra_options: Vec<String>,
+    /// Where the file system is mounted in the target system
+    pub mount_point: Option<String>,
+    /// Mount options, as they appear in fstab
+    pub mount_options: Option<String>,
+    /// How fstab identifies the device, uuid unless set
+    pub mount_by: Option<MountBy>,
+    /// The compress= mount option, zstd:3 for instance, btrfs only
+    pub compression: Option<String>,
+    /// Enables quota groups, btrfs only
+    #[serde(default)]
+    pub quota: bool,
+    /// Subvolumes created below the top level, btrfs only
+    #[serde(default)]
+    pub subvolumes: Vec<Subvolume>
+}
+
+/// Where a file system, or one of its btrfs subvolumes, is mounted in the
+/// target system
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mount {
+    pub mount_point: String,
+    pub fs_type: FileSystemType,
+    /// Mount options, including subvol= for a subvolume
+    pub options: Vec<String>
+}
+
+/// A line of /etc/fstab
+#[derive(Debug, Clone, PartialEq)]
+pub struct FstabEntry {
+    /// The device, UUID=... for instance
+    pub spec: String,
+    pub file: String,
+    pub vfs_type: String,
+    pub options: String,
+    pub dump: u32,
+    pub pass: u32
+}
+
+impl fmt::Display for FstabEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} {} {} {} {}", self.spec, self.file, self.vfs_type, self.options,
+               self.dump, self.pass)
+    }
+}
+
+/// Builds the command line which creates a file system
+pub trait MakeFileSystem {
+    /// The tool used when no command path is configured
+    fn command(&self) -> &'static str;
+
+    /// Arguments for creating the configured file system on device
+    fn arguments(&self, file_system: &FileSystem, device: &Path) -> FileSystemResult<Vec<String>>;
+
+    /// Confirms a newly created file system has the requested properties
+    fn verify(&self, _file_system: &FileSystem, _device: &Path) -> FileSystemResult<()> {
+        Ok(())
+    }
+
+    /// Creates the file system natively, for the file systems press can
+    /// write itself. None when the mkfs tool has to be run.
+    fn format(&self, _file_system: &FileSystem, _device: &Path) -> Option<FileSystemResult<()>> {
+        None
+    }
+
+    /// Sets up the contents of a newly created file system, btrfs
+    /// subvolumes for instance
+    fn finish(&self, _file_system: &FileSystem, _device: &Path) -> FileSystemResult<()> {
+        Ok(())
+    }
+}
+
+impl FileSystem {
+    pub fn new(fs_type: FileSystemType) -> Self {
+        Self {
+            fs_type,
+            command_path: None,
+            label: None,
+            uuid: None,
+            block_size: None,
+            inode_ratio: None,
+            features: Vec::new(),
+            reserved_blocks_percent: None,
+            extra_options: Vec::new(),
+            mount_point: None,
+            mount_options: None,
+            mount_by: None,
+            compression: None,
+            quota: false,
+            subvolumes: Vec::new()
+        }
+    }
+
+    pub fn maker(&self) -> Box<dyn MakeFileSystem> {
+        match self.fs_type {
+            FileSystemType::Ext2 | FileSystemType::Ext3 | FileSystemType::Ext4 =>
+                Box::new(Mke2fs::new(self.fs_type)),
+            FileSystemType::Xfs => Box::new(MkfsXfs),
+            FileSystemType::Btrfs => Box::new(MkfsBtrfs),
+            FileSystemType::Vfat => Box::new(MkfsVfat),
+            FileSystemType::Swap => Box::new(Mkswap),
+            FileSystemType::Ntfs => Box::new(Mkntfs)
+        }
+    }
+
+    /// The program and its arguments
+    pub fn command_line(&self, device: &Path) -> FileSystemResult<(String, Vec<String>)> {
+        let maker = self.maker();
+        let program = self.command_path.clone()
+            .unwrap_or_else(|| maker.command().to_owned());
+        Ok((program, maker.arguments(self, device)?))
+    }
+
+    /// Problems with the options which can be found before running anything
+    pub fn check(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if let Some(ref label) = self.label {
+            if label.len() > self.fs_type.max_label_length() {
+                errors.push(format!("label {} is longer than the {} characters {} allows",
+                    label, self.fs_type.max_label_length(), self.fs_type.name()));
+            }
+        }
+        if self.mount_by == Some(MountBy::Label) && self.label.is_none() {
+            errors.push("mount_by label requires a label".to_owned());
+        }
+        if let Err(e) = self.maker().arguments(self, Path::new("/dev/null")) {
+            errors.push(e.to_string());
+        }
+        if self.fs_type == FileSystemType::Btrfs {
+            errors.extend(btrfs::check_subvolumes(self));
+        } else {
+            let btrfs_options = [
+                ("compression", self.compression.is_some()),
+                ("quota", self.quota),
+                ("subvolumes", !self.subvolumes.is_empty())
+            ];
+            for (option, _) in btrfs_options.iter().filter(|(_, set)| *set) {
+                errors.push(FileSystemError::unsupported(self.fs_type, option).to_string());
+            }
+        }
+        errors
+    }
+
+    /// Where the file system and its subvolumes are mounted, in the order
+    /// they are declared. Swap is never mounted.
+    pub fn mounts(&self) -> Vec<Mount> {
+        if self.fs_type == FileSystemType::Swap {
+            return Vec::new()
+        }
+        let split = |options: &Option<String>| -> Vec<String> {
+            options.iter()
+                .flat_map(|options| options.split(','))
+                .map(|option| option.trim().to_owned())
+                .filter(|option| !option.is_empty())
+                .collect()
+        };
+        let mut options = split(&self.mount_options);
+        if let Some(ref compression) = self.compression {
+            options.push(format!("compress={}", compression));
+        }
+
+        let mut mounts = Vec::new();
+        if let Some(ref mount_point) = self.mount_point {
+            let mut options = options.clone();
+            // The top level, unless a subvolume is made the default and is
+            // mounted here instead
+            if !self.subvolumes.is_empty() && !self.subvolumes.iter().any(|s| s.default) {
+                options.push("subvolid=5".to_owned());
+            }
+            mounts.push(Mount { mount_point: mount_point.clone(), fs_type: self.fs_type, options });
+        }
+        for subvolume in self.subvolumes.iter() {
+            if let Some(ref mount_point) = subvolume.mount_point {
+                let mut options = options.clone();
+                options.extend(split(&subvolume.mount_options));
+                options.push(format!("subvol={}", subvolume.path));
+                mounts.push(Mount { mount_point: mount_point.clone(), fs_type: self.fs_type, options });
+            }
+        }
+        mounts
+    }
+
+    /// The fstab entries of the file system, given how the device is
+    /// identified: UUID=..., PARTUUID=... or LABEL=...
+    pub fn fstab_entries(&self, spec: &str) -> Vec<FstabEntry> {
+        if self.fs_type == FileSystemType::Swap {
+            return vec![FstabEntry {
+                spec: spec.to_owned(),
+                file: "none".to_owned(),
+                vfs_type: "swap".to_owned(),
+                options: "defaults".to_owned(),
+                dump: 0,
+                pass: 0
+            }]
+        }
+        self.mounts().into_iter()
+            .map(|mount| {
+                // Only file systems with a boot time fsck are checked
+                let pass = match self.fs_type {
+                    FileSystemType::Ext2 | FileSystemType::Ext3 | FileSystemType::Ext4 |
+                    FileSystemType::Vfat => if mount.mount_point == "/" { 1 } else { 2 },
+                    _ => 0
+                };
+                FstabEntry {
+                    spec: spec.to_owned(),
+                    vfs_type: self.fs_type.name().to_owned(),
+                    options: if mount.options.is_empty() {
+                        "defaults".to_owned()
+                    } else {
+                        mount.options.join(",")
+                    },
+                    file: mount.mount_point,
+                    dump: 0,
+                    pass
+                }
+            })
+            .collect()
+    }
+
+    /// Runs the mkfs tool against device, the output is captured and the
+    /// tool's stderr is included in the error when it fails. File systems
+    /// press can write natively are, unless a command path or extra options
+    /// are configured, created without the tool.
+    pub fn create(&self, device: &Path) -> FileSystemResult<Output> {
+        signal::check_interrupted().map_err(|e| FileSystemError::new(&format!(
+            "not creating {} on {}: {}", self.fs_type.name(), device.display(), e)))?;
+        if self.command_path.is_none() && self.extra_options.is_empty() {
+            if let Some(result) = self.maker().format(self, device) {
+                info!("Creating {} on {}", self.fs_type.name(), device.display());
+                result?;
+                self.maker().verify(self, device)?;
+                self.maker().finish(self, device)?;
+                return Ok(Output {
+                    status: ExitStatus::from_raw(0),
+                    stdout: Vec::new(),
+                    stderr: Vec::new()
+                })
+            }
+        }
+        let (program, arguments) = self.command_line(device)?;
+        info!("Creating {} on {}", self.fs_type.name(), device.display());
+        let output = command::output(&program, &arguments)
+            .map_err(|e| FileSystemError::new(&e.to_string()))?;
+        self.maker().verify(self, device)?;
+        self.maker().finish(self, device)?;
+        Ok(output)
+    }
+}
+
+/// Parses a configured uuid, for tools which require a standard uuid
+pub fn parse_uuid(fs_type: FileSystemType, value: &str) -> FileSystemResult<uuid::Uuid> {
+    uuid::Uuid::parse_str(value).map_err(|_| FileSystemError::new(
+        format!("{} is not a valid uuid for {}", value, fs_type.name()).as_str()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+    use crate::layout::testing::{self, file_system};
+
+    fn command_line(json: &str) -> String {
+        let (program, arguments) = file_system(json)
+            .command_line(Path::new("/dev/sda1")).unwrap();
+        testing::command_line(&program, &arguments)
+    }
+
+    #[test]
+    fn test_command_lines() {
+        assert_eq!(command_line(r#"{
+            "fs_type": "ext4", "label": "root",
+            "uuid": "6a7b8c9d-aebf-40c1-92d3-e4f5a6b7c8d9", "block_size": "4KiB",
+            "inode_ratio": 16384, "features": ["^has_journal", "metadata_csum"],
+            "reserved_blocks_percent": 1
+        }"#), "mke2fs -t ext4 -F -q -L root -U 6a7b8c9d-aebf-40c1-92d3-e4f5a6b7c8d9 \
+            -b 4096 -i 16384 -m 1 -O ^has_journal,metadata_csum /dev/sda1");
+        assert_eq!(command_line(r#"{
+            "fs_type": "xfs", "label": "home", "uuid": "6a7b8c9d-aebf-40c1-92d3-e4f5a6b7c8d9",
+            "features": ["reflink=1"], "command_path": "/sbin/mkfs.xfs"
+        }"#), "/sbin/mkfs.xfs -f -L home -m uuid=6a7b8c9d-aebf-40c1-92d3-e4f5a6b7c8d9 \
+            -m reflink=1 /dev/sda1");
+        assert_eq!(command_line(r#"{"fs_type": "btrfs", "label": "data", "features": ["quota"]}"#),
+            "mkfs.btrfs -f -L data -O quota /dev/sda1");
+        assert_eq!(command_line(r#"{
+            "fs_type": "vfat", "label": "EFI", "uuid": "1A2B-3C4D", "features": ["fat32"]
+        }"#), "mkfs.vfat -F 32 -n EFI -i 1A2B3C4D /dev/sda1");
+        assert_eq!(command_line(r#"{"fs_type": "swap", "label": "swap"}"#),
+            "mkswap -L swap /dev/sda1");
+        assert_eq!(command_line(r#"{"fs_type": "ntfs", "label": "Windows", "block_size": 4096}"#),
+            "mkntfs -F -Q -L Windows -c 4096 /dev/sda1");
+    }
+
+    #[test]
+    fn test_check() {
+        let errors = file_system(r#"{
+            "fs_type": "xfs", "label": "a_long_xfs_label", "reserved_blocks_percent": 5
+        }"#).check();
+        assert_eq!(errors, vec![
+            "label a_long_xfs_label is longer than the 12 characters xfs allows",
+            "reserved_blocks_percent is not supported by xfs",
+        ]);
+        assert_eq!(file_system(r#"{"fs_type": "vfat", "uuid": "not-an-id"}"#).check(),
+            vec!["not-an-id is not a valid volume id for vfat, expected XXXX-XXXX"]);
+        assert!(file_system(r#"{"fs_type": "vfat", "label": "efi", "features": ["fat12"]}"#)
+            .check().is_empty());
+        assert_eq!(file_system(r#"{"fs_type": "vfat", "label": "EFI*"}"#).check(),
+            vec!["vfat: EFI* is not a valid label"]);
+        // 4 GiB and 512 bytes, which is 512 once truncated to 32 bits
+        assert_eq!(file_system(r#"{"fs_type": "vfat", "block_size": 4294967808}"#).check(),
+            vec!["vfat: cluster size 4294967808 is not a power of two between 512 and 32768"]);
+        assert_eq!(file_system(r#"{"fs_type": "ext4", "uuid": "nope"}"#).check(),
+            vec!["nope is not a valid uuid for ext4"]);
+        assert_eq!(file_system(r#"{"fs_type": "ext4", "mount_by": "label"}"#).check(),
+            vec!["mount_by label requires a label"]);
+        assert_eq!(file_system(r#"{
+            "fs_type": "xfs", "compression": "zstd", "subvolumes": [{"path": "@"}]
+        }"#).check(), vec![
+            "compression is not supported by xfs",
+            "subvolumes is not supported by xfs",
+        ]);
+    }
+
+    #[test]
+    fn test_fstab_entries() {
+        let entries: Vec<String> = [
+            (r#"{"fs_type": "ext4", "mount_point": "/"}"#, "UUID=6a7b"),
+            (r#"{"fs_type": "vfat", "mount_point": "/boot/efi", "mount_options": "umask=0077"}"#,
+             "PARTUUID=0c1d"),
+            (r#"{"fs_type": "xfs", "mount_point": "/srv", "mount_options": "noatime, nodev"}"#,
+             "LABEL=srv"),
+            (r#"{"fs_type": "swap"}"#, "UUID=9e8f"),
+            (r#"{"fs_type": "ext4"}"#, "UUID=0000")
+        ].iter()
+            .flat_map(|(json, spec)| file_system(json).fstab_entries(spec))
+            .map(|entry| entry.to_string())
+            .collect();
+        assert_eq!(entries, vec![
+            "UUID=6a7b / ext4 defaults 0 1",
+            "PARTUUID=0c1d /boot/efi vfat umask=0077 0 2",
+            "LABEL=srv /srv xfs noatime,nodev 0 0",
+            "UUID=9e8f none swap defaults 0 0",
+        ]);
+    }
+
+    #[test]
+    fn test_create() {
+        if Command::new("mke2fs").arg("-V").output().is_err() {
+            return
+        }
+        let image = std::env::temp_dir().join(format!("press-mkfs-{}.img", std::process::id()));
+        std::fs::File::create(&image).unwrap().set_len(16 << 20).unwrap();
+
+        let mut ext4 = FileSystem::new(FileSystemType::Ext4);
+        ext4.label = Some("press".to_owned());
+        ext4.create(&image).unwrap();
+
+        ext4.features = vec!["not_a_feature".to_owned()];
+        let error = ext4.create(&image).unwrap_err().to_string();
+        assert!(error.starts_with("mke2fs -t ext4"), "{}", error);
+        assert!(error.contains("not_a_feature"), "{}", error);
+
+        std::fs::remove_file(&image).unwrap();
+    }
 }
@@ -0,0 +1,39 @@
+use std::path::Path;
+
+use super::{FileSystem, FileSystemType, FileSystemError, FileSystemResult, MakeFileSystem};
+
+pub struct Mkntfs;
+
+impl MakeFileSystem for Mkntfs {
+    fn command(&self) -> &'static str {
+        "mkntfs"
+    }
+
+    fn arguments(&self, file_system: &FileSystem, device: &Path) -> FileSystemResult<Vec<String>> {
+        let fs_type = FileSystemType::Ntfs;
+        if file_system.uuid.is_some() {
+            return Err(FileSystemError::unsupported(fs_type, "uuid"))
+        }
+        if file_system.inode_ratio.is_some() {
+            return Err(FileSystemError::unsupported(fs_type, "inode_ratio"))
+        }
+        if file_system.reserved_blocks_percent.is_some() {
+            return Err(FileSystemError::unsupported(fs_type, "reserved_blocks_percent"))
+        }
+        if !file_system.features.is_empty() {
+            return Err(FileSystemError::unsupported(fs_type, "features"))
+        }
+
+        // Without -Q mkntfs zeroes the whole device
+        let mut arguments = vec!["-F".to_owned(), "-Q".to_owned()];
+        if let Some(ref label) = file_system.label {
+            arguments.extend(vec!["-L".to_owned(), label.to_owned()]);
+        }
+        if let Some(ref block_size) = file_system.block_size {
+            arguments.extend(vec!["-c".to_owned(), block_size.bytes().to_string()]);
+        }
+        arguments.extend(file_system.extra_options.iter().cloned());
+        arguments.push(device.to_string_lossy().into_owned());
+        Ok(arguments)
+    }
+}
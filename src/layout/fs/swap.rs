@@ -0,0 +1,277 @@
+use std::convert::TryFrom;
+use std::fs::OpenOptions;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use byteorder::{ByteOrder, LittleEndian};
+use uuid::Uuid;
+
+use super::{FileSystem, FileSystemType, FileSystemError, FileSystemResult,
+            MakeFileSystem, parse_uuid};
+
+pub static SWAP_MAGIC: &[u8] = b"SWAPSPACE2";
+pub static SWAP_VERSION: u32 = 1;
+// mkswap refuses anything smaller than ten pages
+static SWAP_MIN_PAGES: u64 = 10;
+static SWAP_LABEL_SIZE: usize = 16;
+
+pub struct Mkswap;
+
+fn io_error(e: std::io::Error) -> FileSystemError {
+    FileSystemError::new(format!("swap: {}", e).as_str())
+}
+
+/// The page size of the running kernel, which swap headers are sized by
+pub fn page_size() -> u32 {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as u32 }
+}
+
+// The configured block size, which is the page size of the header
+fn configured_page_size(file_system: &FileSystem) -> FileSystemResult<u32> {
+    match file_system.block_size {
+        Some(ref block_size) => u32::try_from(block_size.bytes()).map_err(|_| FileSystemError::new(
+            format!("swap: {} is not a valid page size", block_size.bytes()).as_str())),
+        None => Ok(page_size())
+    }
+}
+
+// check if the page contains a v1 swap header
+// page starts at the beginning of the swap area
+pub fn is_swap(page: &[u8]) -> bool {
+    page.len() >= 4096 && &page[page.len() - 10..] == SWAP_MAGIC
+}
+
+/// The swap v1 header, which occupies the first page of a swap area
+#[derive(Debug, Clone, PartialEq)]
+pub struct SwapHeader {
+    pub page_size: u32,
+    pub version: u32,
+    pub last_page: u32,
+    pub bad_pages: u32,
+    pub uuid: Uuid,
+    pub label: String
+}
+
+impl SwapHeader {
+    /// A header for a swap area of size bytes
+    pub fn new(size: u64, page_size: u32) -> FileSystemResult<SwapHeader> {
+        if !page_size.is_power_of_two() || page_size < 4096 {
+            return Err(FileSystemError::new(format!(
+                "swap: {} is not a valid page size", page_size).as_str()))
+        }
+        let pages = size / page_size as u64;
+        if pages < SWAP_MIN_PAGES {
+            return Err(FileSystemError::new(format!(
+                "swap: {} bytes is too small, at least {} pages are required",
+                size, SWAP_MIN_PAGES).as_str()))
+        }
+        Ok(SwapHeader {
+            page_size,
+            version: SWAP_VERSION,
+            // The kernel can not address more pages than this anyway
+            last_page: std::cmp::min(pages - 1, u32::MAX as u64) as u32,
+            bad_pages: 0,
+            uuid: Uuid::new_v4(),
+            label: String::new()
+        })
+    }
+
+    // slice containing the first page of a swap area
+    pub fn from_slice(page: &[u8]) -> SwapHeader {
+        if !is_swap(page) {
+            panic!("Provided page does not contain a swap header")
+        }
+        let label = &page[1052..1052 + SWAP_LABEL_SIZE];
+        let end = label.iter().position(|b| *b == 0).unwrap_or(label.len());
+        SwapHeader {
+            page_size: page.len() as u32,
+            version: LittleEndian::read_u32(&page[1024..1028]),
+            last_page: LittleEndian::read_u32(&page[1028..1032]),
+            bad_pages: LittleEndian::read_u32(&page[1032..1036]),
+            uuid: Uuid::from_slice(&page[1036..1052]).unwrap(),
+            label: String::from_utf8_lossy(&label[..end]).into_owned()
+        }
+    }
+
+    /// Reads the header of the swap area starting at offset, trying each
+    /// page size swap may have been created with
+    pub fn from_reader<R>(reader: &mut R, offset: u64) -> Result<SwapHeader, std::io::Error>
+            where R: Read + Seek {
+        let mut page = vec![0u8; 65536];
+        reader.seek(SeekFrom::Start(offset))?;
+        let length = reader.read(&mut page)?;
+        for page_size in &[4096, 8192, 16384, 65536] {
+            if *page_size <= length && is_swap(&page[..*page_size]) {
+                return Ok(SwapHeader::from_slice(&page[..*page_size]))
+            }
+        }
+        Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "no swap header found"))
+    }
+
+    /// The size of the usable swap area in bytes, excluding the header
+    pub fn size(&self) -> u64 {
+        self.last_page as u64 * self.page_size as u64
+    }
+
+    pub fn as_bytes(&self) -> FileSystemResult<Vec<u8>> {
+        if self.label.len() > SWAP_LABEL_SIZE {
+            return Err(FileSystemError::new(format!(
+                "swap: label {} is longer than {} characters", self.label, SWAP_LABEL_SIZE).as_str()))
+        }
+        let mut page = vec![0u8; self.page_size as usize];
+        LittleEndian::write_u32(&mut page[1024..1028], self.version);
+        LittleEndian::write_u32(&mut page[1028..1032], self.last_page);
+        LittleEndian::write_u32(&mut page[1032..1036], self.bad_pages);
+        page[1036..1052].copy_from_slice(self.uuid.as_bytes());
+        page[1052..1052 + self.label.len()].copy_from_slice(self.label.as_bytes());
+        let magic = self.page_size as usize - SWAP_MAGIC.len();
+        page[magic..].copy_from_slice(SWAP_MAGIC);
+        Ok(page)
+    }
+
+    /// Writes the header to the start of the swap area at offset, clearing
+    /// anything else in the first page
+    pub fn write<W>(&self, writer: &mut W, offset: u64) -> FileSystemResult<()>
+            where W: Write + Seek {
+        let page = self.as_bytes()?;
+        writer.seek(SeekFrom::Start(offset)).map_err(io_error)?;
+        writer.write_all(&page).map_err(io_error)?;
+        writer.flush().map_err(io_error)
+    }
+}
+
+impl MakeFileSystem for Mkswap {
+    fn command(&self) -> &'static str {
+        "mkswap"
+    }
+
+    fn arguments(&self, file_system: &FileSystem, device: &Path) -> FileSystemResult<Vec<String>> {
+        let fs_type = FileSystemType::Swap;
+        if file_system.inode_ratio.is_some() {
+            return Err(FileSystemError::unsupported(fs_type, "inode_ratio"))
+        }
+        if file_system.reserved_blocks_percent.is_some() {
+            return Err(FileSystemError::unsupported(fs_type, "reserved_blocks_percent"))
+        }
+        if !file_system.features.is_empty() {
+            return Err(FileSystemError::unsupported(fs_type, "features"))
+        }
+
+        let mut arguments = Vec::new();
+        if let Some(ref label) = file_system.label {
+            arguments.extend(vec!["-L".to_owned(), label.to_owned()]);
+        }
+        if let Some(ref uuid) = file_system.uuid {
+            let uuid = parse_uuid(fs_type, uuid)?;
+            arguments.extend(vec!["-U".to_owned(), uuid.to_string()]);
+        }
+        // The swap header is sized by the page size
+        if file_system.block_size.is_some() {
+            arguments.extend(vec!["-p".to_owned(), configured_page_size(file_system)?.to_string()]);
+        }
+        arguments.extend(file_system.extra_options.iter().cloned());
+        arguments.push(device.to_string_lossy().into_owned());
+        Ok(arguments)
+    }
+
+    fn verify(&self, file_system: &FileSystem, device: &Path) -> FileSystemResult<()> {
+        let mut file = std::fs::File::open(device).map_err(io_error)?;
+        let header = SwapHeader::from_reader(&mut file, 0).map_err(|e| FileSystemError::new(
+            format!("could not read the swap header of {}: {}", device.display(), e).as_str()))?;
+        if let Some(ref label) = file_system.label {
+            if &header.label != label {
+                return Err(FileSystemError::new(format!(
+                    "expected label {} but found {}", label, header.label).as_str()))
+            }
+        }
+        if let Some(ref uuid) = file_system.uuid {
+            let uuid = parse_uuid(FileSystemType::Swap, uuid)?;
+            if header.uuid != uuid {
+                return Err(FileSystemError::new(format!(
+                    "expected uuid {} but found {}", uuid, header.uuid).as_str()))
+            }
+        }
+        Ok(())
+    }
+
+    fn format(&self, file_system: &FileSystem, device: &Path) -> Option<FileSystemResult<()>> {
+        Some(self.arguments(file_system, device).and_then(|_| {
+            let mut file = OpenOptions::new().read(true).write(true).open(device)
+                .map_err(|e| FileSystemError::new(
+                    format!("could not open {}: {}", device.display(), e).as_str()))?;
+            let size = file.seek(SeekFrom::End(0)).map_err(io_error)?;
+            let mut header = SwapHeader::new(size, configured_page_size(file_system)?)?;
+            if let Some(ref label) = file_system.label {
+                header.label = label.to_owned();
+            }
+            if let Some(ref uuid) = file_system.uuid {
+                header.uuid = parse_uuid(FileSystemType::Swap, uuid)?;
+            }
+            header.write(&mut file, 0)?;
+            file.sync_all().map_err(io_error)
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::process::Command;
+    use crate::probe::probe_range;
+
+    #[test]
+    fn test_header() {
+        assert!(SwapHeader::new(36864, 4096).is_err());
+        assert!(SwapHeader::new(1 << 20, 1000).is_err());
+
+        let mut header = SwapHeader::new((1 << 20) + 100, 4096).unwrap();
+        header.label = "swap".to_owned();
+        assert_eq!(header.last_page, 255);
+        assert_eq!(header.size(), (1 << 20) - 4096);
+
+        // Written into the middle of a disk, as for a partition
+        let mut disk = Cursor::new(vec![0xffu8; 4 << 20]);
+        header.write(&mut disk, 1 << 20).unwrap();
+        assert_eq!(SwapHeader::from_reader(&mut disk, 1 << 20).unwrap(), header);
+        assert!(SwapHeader::from_reader(&mut disk, 0).is_err());
+
+        let signatures = probe_range(&mut disk, 1 << 20, 1 << 20).unwrap();
+        assert_eq!(signatures[0].fs_type, "swap");
+        assert_eq!(signatures[0].uuid, Some(header.uuid.to_string()));
+        assert_eq!(signatures[0].label.as_deref(), Some("swap"));
+
+        header.label = "a label too long!".to_owned();
+        assert!(header.as_bytes().is_err());
+
+        // 4 GiB and 4 KiB is not taken for a 4 KiB page
+        let mut swap = FileSystem::new(FileSystemType::Swap);
+        swap.block_size = Some(serde_json::from_str("4294971392").unwrap());
+        assert_eq!(swap.check(), vec!["swap: 4294971392 is not a valid page size"]);
+    }
+
+    #[test]
+    fn test_format() {
+        let image = std::env::temp_dir().join(format!("press-swap-{}.img", std::process::id()));
+        std::fs::File::create(&image).unwrap().set_len(8 << 20).unwrap();
+
+        let mut swap = FileSystem::new(FileSystemType::Swap);
+        swap.label = Some("swap".to_owned());
+        swap.uuid = Some("8d1c2b3a-4e5f-4a6b-9c7d-0e1f2a3b4c5d".to_owned());
+        swap.create(&image).unwrap();
+
+        let mut file = std::fs::File::open(&image).unwrap();
+        let header = SwapHeader::from_reader(&mut file, 0).unwrap();
+        assert_eq!(header.page_size, page_size());
+        assert_eq!(header.last_page, (8 << 20) / page_size() - 1);
+        assert_eq!(header.uuid.to_string(), "8d1c2b3a-4e5f-4a6b-9c7d-0e1f2a3b4c5d");
+
+        // swaplabel reads back what was written, when it is installed
+        if let Ok(output) = Command::new("swaplabel").arg(&image).output() {
+            let output = String::from_utf8_lossy(&output.stdout).into_owned();
+            assert!(output.contains("LABEL: swap"), "{}", output);
+            assert!(output.contains("8d1c2b3a-4e5f-4a6b-9c7d-0e1f2a3b4c5d"), "{}", output);
+        }
+        std::fs::remove_file(&image).unwrap();
+    }
+}
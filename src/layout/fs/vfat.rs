@@ -0,0 +1,1113 @@
+use std::convert::TryFrom;
+use std::fs::OpenOptions;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use super::{FileSystem, FileSystemType, FileSystemError, FileSystemResult, MakeFileSystem};
+
+pub struct MkfsVfat;
+
+/// Parses a FAT volume id, 1A2B-3C4D, to the 8 hex digits mkfs.vfat expects
+pub fn parse_volume_id(value: &str) -> FileSystemResult<String> {
+    let hex: String = value.chars().filter(|c| *c != '-').collect();
+    if value.len() != 9 || value.as_bytes()[4] != b'-' || hex.len() != 8 ||
+            !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(FileSystemError::new(format!(
+            "{} is not a valid volume id for vfat, expected XXXX-XXXX", value).as_str()))
+    }
+    Ok(hex.to_ascii_uppercase())
+}
+
+impl MakeFileSystem for MkfsVfat {
+    fn command(&self) -> &'static str {
+        "mkfs.vfat"
+    }
+
+    fn arguments(&self, file_system: &FileSystem, device: &Path) -> FileSystemResult<Vec<String>> {
+        let fs_type = FileSystemType::Vfat;
+        if file_system.inode_ratio.is_some() {
+            return Err(FileSystemError::unsupported(fs_type, "inode_ratio"))
+        }
+        if file_system.reserved_blocks_percent.is_some() {
+            return Err(FileSystemError::unsupported(fs_type, "reserved_blocks_percent"))
+        }
+
+        let mut arguments = Vec::new();
+        // The only features of FAT are the size of its allocation table
+        for feature in file_system.features.iter() {
+            match feature.to_ascii_lowercase().as_str() {
+                "fat12" => arguments.extend(vec!["-F".to_owned(), "12".to_owned()]),
+                "fat16" => arguments.extend(vec!["-F".to_owned(), "16".to_owned()]),
+                "fat32" => arguments.extend(vec!["-F".to_owned(), "32".to_owned()]),
+                _ => return Err(FileSystemError::unsupported(
+                    fs_type, format!("feature {}", feature).as_str()))
+            }
+        }
+        if let Some(ref label) = file_system.label {
+            if !label.to_ascii_uppercase().bytes().all(is_short_name_char) {
+                return Err(FileSystemError::new(format!(
+                    "vfat: {} is not a valid label", label).as_str()))
+            }
+            arguments.extend(vec!["-n".to_owned(), label.to_owned()]);
+        }
+        if let Some(ref uuid) = file_system.uuid {
+            arguments.extend(vec!["-i".to_owned(), parse_volume_id(uuid)?]);
+        }
+        // The block size is the cluster size, given to mkfs.vfat in sectors
+        if let Some(ref block_size) = file_system.block_size {
+            let cluster_size = check_cluster_size(block_size.bytes())?;
+            arguments.extend(vec!["-s".to_owned(), (cluster_size / SECTOR_SIZE).to_string()]);
+        }
+        arguments.extend(file_system.extra_options.iter().cloned());
+        arguments.push(device.to_string_lossy().into_owned());
+        Ok(arguments)
+    }
+
+    /// FAT12 is left to mkfs.vfat
+    fn format(&self, file_system: &FileSystem, device: &Path) -> Option<FileSystemResult<()>> {
+        if file_system.features.iter().any(|feature| feature.eq_ignore_ascii_case("fat12")) {
+            return None
+        }
+        Some(FatOptions::from_file_system(file_system).and_then(|options| {
+            let mut device = OpenOptions::new().read(true).write(true).open(device)
+                .map_err(|e| FileSystemError::new(
+                    format!("could not open {}: {}", device.display(), e).as_str()))?;
+            options.format(&mut device)?;
+            device.sync_all().map_err(io_error)
+        }))
+    }
+}
+
+const SECTOR_SIZE: u32 = 512;
+const FAT_COUNT: u32 = 2;
+const FAT16_ROOT_ENTRIES: u32 = 512;
+const FAT16_MIN_CLUSTERS: u32 = 4085;
+const FAT16_MAX_CLUSTERS: u32 = 65524;
+const FAT32_MIN_CLUSTERS: u32 = 65525;
+const FAT32_MAX_CLUSTERS: u32 = 0x0fff_fff5;
+const MAX_CLUSTER_SIZE: u32 = 32768;
+
+const FAT32_EOC: u32 = 0x0fff_ffff;
+const FAT16_EOC: u32 = 0xffff;
+
+const ENTRY_SIZE: usize = 32;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = 0x0f;
+const ENTRY_FREE: u8 = 0x00;
+const ENTRY_DELETED: u8 = 0xe5;
+
+fn io_error(e: std::io::Error) -> FileSystemError {
+    FileSystemError::new(format!("vfat: {}", e).as_str())
+}
+
+fn check_cluster_size(cluster_size: u64) -> FileSystemResult<u32> {
+    match u32::try_from(cluster_size) {
+        Ok(size) if size.is_power_of_two() && (SECTOR_SIZE..=MAX_CLUSTER_SIZE).contains(&size) => Ok(size),
+        _ => Err(FileSystemError::new(format!(
+            "vfat: cluster size {} is not a power of two between 512 and 32768",
+            cluster_size).as_str()))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FatType {
+    Fat16,
+    Fat32
+}
+
+impl FatType {
+    // Cluster sizes following the Microsoft defaults for each volume size
+    fn default_cluster_size(&self, size: u64) -> u32 {
+        let mib = size >> 20;
+        match self {
+            FatType::Fat32 if mib <= 260 => 512,
+            FatType::Fat32 if mib <= 8192 => 4096,
+            FatType::Fat32 if mib <= 16384 => 8192,
+            FatType::Fat32 if mib <= 32768 => 16384,
+            FatType::Fat16 if mib <= 128 => 2048,
+            FatType::Fat16 if mib <= 256 => 4096,
+            FatType::Fat16 if mib <= 512 => 8192,
+            FatType::Fat16 if mib <= 1024 => 16384,
+            _ => MAX_CLUSTER_SIZE
+        }
+    }
+}
+
+/// The layout of a FAT volume, in sectors
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FatGeometry {
+    pub fat_type: FatType,
+    pub sectors_per_cluster: u32,
+    pub reserved_sectors: u32,
+    pub fat_sectors: u32,
+    pub root_entries: u32,
+    pub total_sectors: u32,
+    pub clusters: u32
+}
+
+impl FatGeometry {
+    fn new(fat_type: FatType, total_sectors: u32, cluster_size: u32) -> FatGeometry {
+        let sectors_per_cluster = cluster_size / SECTOR_SIZE;
+        let (reserved_sectors, root_entries) = match fat_type {
+            FatType::Fat16 => (1, FAT16_ROOT_ENTRIES),
+            FatType::Fat32 => (32, 0)
+        };
+        let root_sectors = root_entries * ENTRY_SIZE as u32 / SECTOR_SIZE;
+        // The FAT size calculation from the FAT specification, which may
+        // over allocate the table by a few sectors but never under allocates
+        let available = total_sectors.saturating_sub(reserved_sectors + root_sectors);
+        let mut divisor = 256 * sectors_per_cluster + FAT_COUNT;
+        if fat_type == FatType::Fat32 {
+            divisor /= 2;
+        }
+        let fat_sectors = available.div_ceil(divisor);
+        let data_sectors = available.saturating_sub(FAT_COUNT * fat_sectors);
+        FatGeometry {
+            fat_type,
+            sectors_per_cluster,
+            reserved_sectors,
+            fat_sectors,
+            root_entries,
+            total_sectors,
+            clusters: data_sectors / sectors_per_cluster
+        }
+    }
+
+    fn from_boot_sector(data: &[u8]) -> FileSystemResult<FatGeometry> {
+        if data[510..512] != [0x55, 0xaa] || LittleEndian::read_u16(&data[11..13]) != SECTOR_SIZE as u16 {
+            return Err(FileSystemError::new("vfat: no FAT16 or FAT32 boot sector found"))
+        }
+        let fat_type = if &data[82..90] == b"FAT32   " {
+            FatType::Fat32
+        } else if &data[54..62] == b"FAT16   " {
+            FatType::Fat16
+        } else {
+            return Err(FileSystemError::new("vfat: no FAT16 or FAT32 boot sector found"))
+        };
+        let sectors_per_cluster = data[13] as u32;
+        let reserved_sectors = LittleEndian::read_u16(&data[14..16]) as u32;
+        let root_entries = LittleEndian::read_u16(&data[17..19]) as u32;
+        let total_sectors = match LittleEndian::read_u16(&data[19..21]) {
+            0 => LittleEndian::read_u32(&data[32..36]),
+            sectors => sectors as u32
+        };
+        let fat_sectors = match LittleEndian::read_u16(&data[22..24]) {
+            0 => LittleEndian::read_u32(&data[36..40]),
+            sectors => sectors as u32
+        };
+        if sectors_per_cluster == 0 || data[16] as u32 != FAT_COUNT {
+            return Err(FileSystemError::new("vfat: unsupported FAT layout"))
+        }
+        let root_sectors = root_entries * ENTRY_SIZE as u32 / SECTOR_SIZE;
+        let data_sectors = FAT_COUNT.checked_mul(fat_sectors)
+            .and_then(|sectors| sectors.checked_add(reserved_sectors + root_sectors))
+            .and_then(|sectors| total_sectors.checked_sub(sectors))
+            .ok_or_else(|| FileSystemError::new("vfat: the FAT layout does not fit the volume"))?;
+        Ok(FatGeometry {
+            fat_type,
+            sectors_per_cluster,
+            reserved_sectors,
+            fat_sectors,
+            root_entries,
+            total_sectors,
+            clusters: data_sectors / sectors_per_cluster
+        })
+    }
+
+    fn is_valid(&self) -> bool {
+        match self.fat_type {
+            FatType::Fat16 => (FAT16_MIN_CLUSTERS..=FAT16_MAX_CLUSTERS).contains(&self.clusters),
+            FatType::Fat32 => (FAT32_MIN_CLUSTERS..=FAT32_MAX_CLUSTERS).contains(&self.clusters)
+        }
+    }
+
+    pub fn cluster_size(&self) -> u64 {
+        (self.sectors_per_cluster * SECTOR_SIZE) as u64
+    }
+
+    fn fat_offset(&self, copy: u32) -> u64 {
+        (self.reserved_sectors as u64 + copy as u64 * self.fat_sectors as u64) * SECTOR_SIZE as u64
+    }
+
+    fn root_offset(&self) -> u64 {
+        self.fat_offset(FAT_COUNT)
+    }
+
+    fn data_offset(&self) -> u64 {
+        self.root_offset() + self.root_entries as u64 * ENTRY_SIZE as u64
+    }
+
+    fn cluster_offset(&self, cluster: u32) -> u64 {
+        self.data_offset() + (cluster as u64 - 2) * self.cluster_size()
+    }
+
+    fn end_of_chain(&self) -> u32 {
+        match self.fat_type {
+            FatType::Fat16 => FAT16_EOC,
+            FatType::Fat32 => FAT32_EOC
+        }
+    }
+}
+
+/// Options for formatting a FAT16 or FAT32 file system without mkfs.vfat
+#[derive(Debug, Default)]
+pub struct FatOptions {
+    fat_type: Option<FatType>,
+    cluster_size: Option<u32>,
+    label: Option<String>,
+    volume_id: Option<u32>
+}
+
+impl FatOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// FAT32 is used whenever the volume is large enough, FAT16 otherwise
+    pub fn fat_type(mut self, fat_type: FatType) -> Self {
+        self.fat_type = Some(fat_type);
+        self
+    }
+
+    pub fn cluster_size(mut self, cluster_size: u32) -> Self {
+        self.cluster_size = Some(cluster_size);
+        self
+    }
+
+    pub fn label(mut self, label: &str) -> Self {
+        self.label = Some(label.to_owned());
+        self
+    }
+
+    pub fn volume_id(mut self, volume_id: u32) -> Self {
+        self.volume_id = Some(volume_id);
+        self
+    }
+
+    pub fn from_file_system(file_system: &FileSystem) -> FileSystemResult<Self> {
+        let mut options = FatOptions::new();
+        for feature in file_system.features.iter() {
+            options = match feature.to_ascii_lowercase().as_str() {
+                "fat16" => options.fat_type(FatType::Fat16),
+                "fat32" => options.fat_type(FatType::Fat32),
+                _ => return Err(FileSystemError::unsupported(
+                    FileSystemType::Vfat, format!("feature {}", feature).as_str()))
+            };
+        }
+        // Labels are stored in upper case, as mkfs.vfat does
+        if let Some(ref label) = file_system.label {
+            options = options.label(&label.to_ascii_uppercase());
+        }
+        if let Some(ref uuid) = file_system.uuid {
+            options = options.volume_id(u32::from_str_radix(&parse_volume_id(uuid)?, 16).unwrap());
+        }
+        if let Some(ref block_size) = file_system.block_size {
+            options = options.cluster_size(check_cluster_size(block_size.bytes())?);
+        }
+        Ok(options)
+    }
+
+    fn geometry(&self, size: u64) -> FileSystemResult<FatGeometry> {
+        if let Some(cluster_size) = self.cluster_size {
+            check_cluster_size(cluster_size as u64)?;
+        }
+        let total_sectors = std::cmp::min(size / SECTOR_SIZE as u64, u32::MAX as u64) as u32;
+        let fat_types = match self.fat_type {
+            Some(fat_type) => vec![fat_type],
+            None => vec![FatType::Fat32, FatType::Fat16]
+        };
+        for fat_type in fat_types {
+            let mut cluster_size = self.cluster_size
+                .unwrap_or_else(|| fat_type.default_cluster_size(size));
+            loop {
+                let geometry = FatGeometry::new(fat_type, total_sectors, cluster_size);
+                if geometry.is_valid() {
+                    return Ok(geometry)
+                }
+                // Larger clusters are tried for FAT16 volumes with too many
+                if fat_type == FatType::Fat32 || self.cluster_size.is_some() ||
+                        geometry.clusters < FAT16_MIN_CLUSTERS || cluster_size == MAX_CLUSTER_SIZE {
+                    break
+                }
+                cluster_size *= 2;
+            }
+        }
+        Err(FileSystemError::new(format!(
+            "vfat: {} bytes is not a valid size for a {} volume", size,
+            match self.fat_type {
+                Some(FatType::Fat16) => "FAT16",
+                Some(FatType::Fat32) => "FAT32",
+                None => "FAT16 or FAT32"
+            }).as_str()))
+    }
+
+    fn boot_sector(&self, geometry: &FatGeometry, volume_id: u32, label: &[u8; 11]) -> Vec<u8> {
+        let mut data = vec![0u8; SECTOR_SIZE as usize];
+        data[3..11].copy_from_slice(b"MSWIN4.1");
+        LittleEndian::write_u16(&mut data[11..13], SECTOR_SIZE as u16);
+        data[13] = geometry.sectors_per_cluster as u8;
+        LittleEndian::write_u16(&mut data[14..16], geometry.reserved_sectors as u16);
+        data[16] = FAT_COUNT as u8;
+        LittleEndian::write_u16(&mut data[17..19], geometry.root_entries as u16);
+        if geometry.fat_type == FatType::Fat16 && geometry.total_sectors < 0x10000 {
+            LittleEndian::write_u16(&mut data[19..21], geometry.total_sectors as u16);
+        } else {
+            LittleEndian::write_u32(&mut data[32..36], geometry.total_sectors);
+        }
+        data[21] = 0xf8;
+        LittleEndian::write_u16(&mut data[24..26], 63);
+        LittleEndian::write_u16(&mut data[26..28], 255);
+        let ebr = match geometry.fat_type {
+            FatType::Fat16 => {
+                data[..3].copy_from_slice(&[0xeb, 0x3c, 0x90]);
+                LittleEndian::write_u16(&mut data[22..24], geometry.fat_sectors as u16);
+                36
+            },
+            FatType::Fat32 => {
+                data[..3].copy_from_slice(&[0xeb, 0x58, 0x90]);
+                LittleEndian::write_u32(&mut data[36..40], geometry.fat_sectors);
+                // root directory cluster, FSInfo sector and backup boot sector
+                LittleEndian::write_u32(&mut data[44..48], 2);
+                LittleEndian::write_u16(&mut data[48..50], 1);
+                LittleEndian::write_u16(&mut data[50..52], 6);
+                64
+            }
+        };
+        data[ebr] = 0x80;
+        data[ebr + 2] = 0x29;
+        LittleEndian::write_u32(&mut data[ebr+3..ebr+7], volume_id);
+        data[ebr+7..ebr+18].copy_from_slice(label);
+        data[ebr+18..ebr+26].copy_from_slice(match geometry.fat_type {
+            FatType::Fat16 => b"FAT16   ",
+            FatType::Fat32 => b"FAT32   "
+        });
+        data[510..512].copy_from_slice(&[0x55, 0xaa]);
+        data
+    }
+
+    /// Writes an empty file system covering the whole of device
+    pub fn format<F>(&self, device: &mut F) -> FileSystemResult<FatGeometry>
+            where F: Read + Write + Seek {
+        let size = device.seek(SeekFrom::End(0)).map_err(io_error)?;
+        let geometry = self.geometry(size)?;
+
+        let mut label = [b' '; 11];
+        if let Some(ref name) = self.label {
+            if name.len() > label.len() || !name.bytes().all(is_short_name_char) {
+                return Err(FileSystemError::new(format!(
+                    "vfat: {} is not a valid label", name).as_str()))
+            }
+            label[..name.len()].copy_from_slice(name.as_bytes());
+        } else {
+            label.copy_from_slice(b"NO NAME    ");
+        }
+        let volume_id = self.volume_id.unwrap_or_else(|| {
+            LittleEndian::read_u32(&uuid::Uuid::new_v4().as_bytes()[..4])
+        });
+        info!("Formatting {:?} with {} clusters of {} bytes", geometry.fat_type,
+              geometry.clusters, geometry.cluster_size());
+
+        // Everything up to the data region, plus the FAT32 root directory
+        let mut metadata_size = geometry.data_offset();
+        if geometry.fat_type == FatType::Fat32 {
+            metadata_size += geometry.cluster_size();
+        }
+        device.seek(SeekFrom::Start(0)).map_err(io_error)?;
+        let zeros = vec![0u8; 1 << 20];
+        let mut remaining = metadata_size;
+        while remaining > 0 {
+            let length = std::cmp::min(remaining, zeros.len() as u64) as usize;
+            device.write_all(&zeros[..length]).map_err(io_error)?;
+            remaining -= length as u64;
+        }
+
+        let boot_sector = self.boot_sector(&geometry, volume_id, &label);
+        write_at(device, 0, &boot_sector)?;
+        if geometry.fat_type == FatType::Fat32 {
+            write_at(device, 6 * SECTOR_SIZE as u64, &boot_sector)?;
+        }
+
+        let mut volume = FatVolume::open(device)?;
+        if self.label.is_some() {
+            let (date, time) = dos_timestamp(SystemTime::now());
+            let mut entry = [0u8; ENTRY_SIZE];
+            entry[..11].copy_from_slice(&label);
+            entry[11] = ATTR_VOLUME_ID;
+            LittleEndian::write_u16(&mut entry[22..24], time);
+            LittleEndian::write_u16(&mut entry[24..26], date);
+            let root = volume.root();
+            volume.write_entries(&root, 0, &entry)?;
+        }
+        volume.flush()?;
+        Ok(geometry)
+    }
+}
+
+fn write_at<F: Write + Seek>(device: &mut F, offset: u64, data: &[u8]) -> FileSystemResult<()> {
+    device.seek(SeekFrom::Start(offset)).map_err(io_error)?;
+    device.write_all(data).map_err(io_error)
+}
+
+fn read_at<F: Read + Seek>(device: &mut F, offset: u64, length: usize) -> FileSystemResult<Vec<u8>> {
+    let mut data = vec![0u8; length];
+    device.seek(SeekFrom::Start(offset)).map_err(io_error)?;
+    device.read_exact(&mut data).map_err(io_error)?;
+    Ok(data)
+}
+
+// Converts to the FAT date and time, which have no time zone, as UTC
+fn dos_timestamp(time: SystemTime) -> (u16, u16) {
+    let seconds = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let days = (seconds / 86400) as i64;
+    let seconds = seconds % 86400;
+    // Days to the civil calendar, from Howard Hinnant's date algorithms
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    if year < 1980 {
+        return ((1 << 5) | 1, 0)
+    }
+    let date = (((year - 1980) << 9) | (month << 5) | day) as u16;
+    let time = (((seconds / 3600) << 11) | ((seconds % 3600 / 60) << 5) | (seconds % 60 / 2)) as u16;
+    (date, time)
+}
+
+fn is_short_name_char(c: u8) -> bool {
+    c.is_ascii_uppercase() || c.is_ascii_digit() || b"!#$%&'()-@^_`{}~ ".contains(&c)
+}
+
+// The 8.3 name, when name can be stored without a long name
+fn short_name(name: &str) -> Option<[u8; 11]> {
+    let (base, extension) = match name.rfind('.') {
+        Some(dot) => (&name[..dot], &name[dot + 1..]),
+        None => (name, "")
+    };
+    if base.is_empty() || base.len() > 8 || extension.len() > 3 || name == "." || name == ".." ||
+            !base.bytes().chain(extension.bytes()).all(|c| is_short_name_char(c) && c != b' ') {
+        return None
+    }
+    let mut short = [b' '; 11];
+    short[..base.len()].copy_from_slice(base.as_bytes());
+    short[8..8 + extension.len()].copy_from_slice(extension.as_bytes());
+    Some(short)
+}
+
+// A unique 8.3 alias for a long name, BASIS~N.EXT
+fn short_alias(name: &str, existing: &[[u8; 11]]) -> FileSystemResult<[u8; 11]> {
+    let convert = |part: &str| -> Vec<u8> {
+        part.to_ascii_uppercase().bytes()
+            .filter(|c| *c != b' ' && *c != b'.')
+            .map(|c| if is_short_name_char(c) { c } else { b'_' })
+            .collect()
+    };
+    let (base, extension) = match name.rfind('.') {
+        Some(dot) if dot > 0 => (convert(&name[..dot]), convert(&name[dot + 1..])),
+        _ => (convert(name), Vec::new())
+    };
+    for n in 1..1_000_000u32 {
+        let tail = format!("~{}", n);
+        let keep = std::cmp::min(base.len(), 8 - tail.len());
+        let mut alias = [b' '; 11];
+        alias[..keep].copy_from_slice(&base[..keep]);
+        alias[keep..keep + tail.len()].copy_from_slice(tail.as_bytes());
+        let extension = &extension[..std::cmp::min(extension.len(), 3)];
+        alias[8..8 + extension.len()].copy_from_slice(extension);
+        if !existing.contains(&alias) {
+            return Ok(alias)
+        }
+    }
+    Err(FileSystemError::new(format!("vfat: no short name is available for {}", name).as_str()))
+}
+
+fn short_name_checksum(name: &[u8]) -> u8 {
+    name.iter().fold(0u8, |sum, c| (if sum & 1 != 0 { 0x80u8 } else { 0 })
+        .wrapping_add(sum >> 1).wrapping_add(*c))
+}
+
+fn display_short_name(name: &[u8]) -> String {
+    let base = String::from_utf8_lossy(&name[..8]).trim_end().to_owned();
+    let extension = String::from_utf8_lossy(&name[8..11]).trim_end().to_owned();
+    if extension.is_empty() {
+        base
+    } else {
+        format!("{}.{}", base, extension)
+    }
+}
+
+// Long name entries for name, in the order they are stored on disk
+fn long_name_entries(name: &str, alias: &[u8; 11]) -> Vec<u8> {
+    let mut characters: Vec<u16> = name.encode_utf16().collect();
+    if !characters.len().is_multiple_of(13) {
+        characters.push(0);
+        characters.resize(characters.len().div_ceil(13) * 13, 0xffff);
+    }
+    let checksum = short_name_checksum(alias);
+    let count = characters.len() / 13;
+    let mut entries = Vec::with_capacity(count * ENTRY_SIZE);
+    for sequence in (1..=count).rev() {
+        let mut entry = [0u8; ENTRY_SIZE];
+        entry[0] = sequence as u8 | if sequence == count { 0x40 } else { 0 };
+        entry[11] = ATTR_LONG_NAME;
+        entry[13] = checksum;
+        let part = &characters[(sequence - 1) * 13..sequence * 13];
+        let offsets = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+        for (c, offset) in part.iter().zip(offsets.iter()) {
+            LittleEndian::write_u16(&mut entry[*offset..*offset + 2], *c);
+        }
+        entries.extend_from_slice(&entry);
+    }
+    entries
+}
+
+fn long_name_part(entry: &[u8]) -> Vec<u16> {
+    [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30].iter()
+        .map(|offset| LittleEndian::read_u16(&entry[*offset..*offset + 2]))
+        .take_while(|c| *c != 0 && *c != 0xffff)
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Directory {
+    // The fixed size FAT16 root directory
+    Root,
+    Cluster(u32)
+}
+
+#[derive(Debug, Clone)]
+struct DirectoryEntry {
+    name: String,
+    short_name: [u8; 11],
+    attributes: u8,
+    cluster: u32,
+    size: u32
+}
+
+/// A formatted FAT volume, which files can be copied into. The allocation
+/// table is kept in memory and written out by `flush`.
+pub struct FatVolume<'a, F> {
+    device: &'a mut F,
+    geometry: FatGeometry,
+    root_cluster: u32,
+    fat: Vec<u32>
+}
+
+impl<'a, F> FatVolume<'a, F> where F: Read + Write + Seek {
+    pub fn open(device: &'a mut F) -> FileSystemResult<Self> {
+        let boot_sector = read_at(device, 0, SECTOR_SIZE as usize)?;
+        let geometry = FatGeometry::from_boot_sector(&boot_sector)?;
+        if !geometry.is_valid() {
+            return Err(FileSystemError::new(&format!(
+                "vfat: {} clusters is out of range for the FAT type", geometry.clusters)))
+        }
+        let entries = geometry.clusters as usize + 2;
+        let entry_size = match geometry.fat_type {
+            FatType::Fat16 => 2,
+            FatType::Fat32 => 4
+        };
+        if (geometry.fat_sectors as u64 * SECTOR_SIZE as u64) < (entries * entry_size) as u64 {
+            return Err(FileSystemError::new(&format!(
+                "vfat: the FAT is too small for {} clusters", geometry.clusters)))
+        }
+        let table = read_at(device, geometry.fat_offset(0), entries * entry_size)?;
+        let mut fat: Vec<u32> = match geometry.fat_type {
+            FatType::Fat16 => table.chunks_exact(2)
+                .map(|e| LittleEndian::read_u16(e) as u32).collect(),
+            FatType::Fat32 => table.chunks_exact(4)
+                .map(|e| LittleEndian::read_u32(e) & 0x0fff_ffff).collect()
+        };
+        let root_cluster = match geometry.fat_type {
+            FatType::Fat16 => 0,
+            FatType::Fat32 => LittleEndian::read_u32(&boot_sector[44..48])
+        };
+        if geometry.fat_type == FatType::Fat32 && (root_cluster < 2 || root_cluster as usize >= fat.len()) {
+            return Err(FileSystemError::new(&format!(
+                "vfat: the root directory cluster {} is out of range", root_cluster)))
+        }
+        // A freshly formatted table only has the reserved entries to set
+        if fat[0] == 0 {
+            fat[0] = geometry.end_of_chain() & !0x7;
+            fat[1] = geometry.end_of_chain();
+            if geometry.fat_type == FatType::Fat32 {
+                fat[root_cluster as usize] = geometry.end_of_chain();
+            }
+        }
+        Ok(FatVolume { device, geometry, root_cluster, fat })
+    }
+
+    pub fn geometry(&self) -> &FatGeometry {
+        &self.geometry
+    }
+
+    pub fn free_clusters(&self) -> u32 {
+        self.fat[2..].iter().filter(|e| **e == 0).count() as u32
+    }
+
+    fn root(&self) -> Directory {
+        match self.geometry.fat_type {
+            FatType::Fat16 => Directory::Root,
+            FatType::Fat32 => Directory::Cluster(self.root_cluster)
+        }
+    }
+
+    fn is_end_of_chain(&self, entry: u32) -> bool {
+        entry >= (self.geometry.end_of_chain() & !0x7)
+    }
+
+    fn chain(&self, first: u32) -> Vec<u32> {
+        let mut chain = Vec::new();
+        let mut cluster = first;
+        while cluster >= 2 && (cluster as usize) < self.fat.len() && chain.len() < self.fat.len() {
+            chain.push(cluster);
+            if self.is_end_of_chain(self.fat[cluster as usize]) {
+                break
+            }
+            cluster = self.fat[cluster as usize];
+        }
+        chain
+    }
+
+    fn allocate(&mut self, count: usize) -> FileSystemResult<Vec<u32>> {
+        let clusters: Vec<u32> = (2..self.fat.len() as u32)
+            .filter(|c| self.fat[*c as usize] == 0)
+            .take(count)
+            .collect();
+        if clusters.len() < count {
+            return Err(FileSystemError::new("vfat: no space left on the volume"))
+        }
+        for pair in clusters.windows(2) {
+            self.fat[pair[0] as usize] = pair[1];
+        }
+        if let Some(last) = clusters.last() {
+            self.fat[*last as usize] = self.geometry.end_of_chain();
+        }
+        Ok(clusters)
+    }
+
+    fn zero_cluster(&mut self, cluster: u32) -> FileSystemResult<()> {
+        let zeros = vec![0u8; self.geometry.cluster_size() as usize];
+        write_at(self.device, self.geometry.cluster_offset(cluster), &zeros)
+    }
+
+    fn read_directory(&mut self, directory: &Directory) -> FileSystemResult<Vec<u8>> {
+        match directory {
+            Directory::Root => read_at(self.device, self.geometry.root_offset(),
+                self.geometry.root_entries as usize * ENTRY_SIZE),
+            Directory::Cluster(first) => {
+                let mut data = Vec::new();
+                for cluster in self.chain(*first) {
+                    data.extend(read_at(self.device, self.geometry.cluster_offset(cluster),
+                        self.geometry.cluster_size() as usize)?);
+                }
+                Ok(data)
+            }
+        }
+    }
+
+    fn write_entries(&mut self, directory: &Directory, slot: usize, entries: &[u8]) -> FileSystemResult<()> {
+        for (index, entry) in entries.chunks(ENTRY_SIZE).enumerate() {
+            let position = ((slot + index) * ENTRY_SIZE) as u64;
+            let offset = match directory {
+                Directory::Root => self.geometry.root_offset() + position,
+                Directory::Cluster(first) => {
+                    let cluster_size = self.geometry.cluster_size();
+                    let cluster = self.chain(*first)[(position / cluster_size) as usize];
+                    self.geometry.cluster_offset(cluster) + position % cluster_size
+                }
+            };
+            write_at(self.device, offset, entry)?;
+        }
+        Ok(())
+    }
+
+    fn entries(&mut self, directory: &Directory) -> FileSystemResult<Vec<DirectoryEntry>> {
+        let data = self.read_directory(directory)?;
+        let mut entries = Vec::new();
+        let mut long_name: Vec<(u8, u8, Vec<u16>)> = Vec::new();
+        for entry in data.chunks(ENTRY_SIZE) {
+            match entry[0] {
+                ENTRY_FREE => break,
+                ENTRY_DELETED => {
+                    long_name.clear();
+                    continue
+                },
+                _ => ()
+            }
+            if entry[11] == ATTR_LONG_NAME {
+                long_name.push((entry[0] & 0x3f, entry[13], long_name_part(entry)));
+                continue
+            }
+            let mut short_name = [0u8; 11];
+            short_name.copy_from_slice(&entry[..11]);
+            let checksum = short_name_checksum(&short_name);
+            let name = if !long_name.is_empty() && long_name.iter().all(|(_, sum, _)| *sum == checksum) {
+                long_name.sort_by_key(|(sequence, _, _)| *sequence);
+                let characters: Vec<u16> = long_name.iter()
+                    .flat_map(|(_, _, part)| part.iter().cloned()).collect();
+                String::from_utf16_lossy(&characters)
+            } else {
+                display_short_name(&short_name)
+            };
+            long_name.clear();
+            if entry[11] & ATTR_VOLUME_ID != 0 {
+                continue
+            }
+            entries.push(DirectoryEntry {
+                name,
+                short_name,
+                attributes: entry[11],
+                cluster: ((LittleEndian::read_u16(&entry[20..22]) as u32) << 16) |
+                    LittleEndian::read_u16(&entry[26..28]) as u32,
+                size: LittleEndian::read_u32(&entry[28..32])
+            });
+        }
+        Ok(entries)
+    }
+
+    fn find(&mut self, directory: &Directory, name: &str) -> FileSystemResult<Option<DirectoryEntry>> {
+        Ok(self.entries(directory)?.into_iter()
+            .find(|entry| entry.name.eq_ignore_ascii_case(name)))
+    }
+
+    fn add_entry(&mut self, directory: &Directory, name: &str, attributes: u8,
+                 cluster: u32, size: u32) -> FileSystemResult<()> {
+        if name.is_empty() || name.len() > 255 || name == "." || name == ".." ||
+                name.chars().any(|c| c < ' ' || "\\/:*?\"<>|".contains(c)) {
+            return Err(FileSystemError::new(format!("vfat: {} is not a valid name", name).as_str()))
+        }
+        let existing: Vec<[u8; 11]> = self.entries(directory)?.iter()
+            .map(|entry| entry.short_name).collect();
+        let (alias, mut entries) = match short_name(name) {
+            Some(alias) if !existing.contains(&alias) => (alias, Vec::new()),
+            _ => {
+                let alias = short_alias(name, &existing)?;
+                (alias, long_name_entries(name, &alias))
+            }
+        };
+        let (date, time) = dos_timestamp(SystemTime::now());
+        let mut entry = [0u8; ENTRY_SIZE];
+        entry[..11].copy_from_slice(&alias);
+        entry[11] = attributes;
+        LittleEndian::write_u16(&mut entry[14..16], time);
+        LittleEndian::write_u16(&mut entry[16..18], date);
+        LittleEndian::write_u16(&mut entry[18..20], date);
+        LittleEndian::write_u16(&mut entry[20..22], (cluster >> 16) as u16);
+        LittleEndian::write_u16(&mut entry[22..24], time);
+        LittleEndian::write_u16(&mut entry[24..26], date);
+        LittleEndian::write_u16(&mut entry[26..28], cluster as u16);
+        LittleEndian::write_u32(&mut entry[28..32], size);
+        entries.extend_from_slice(&entry);
+        let needed = entries.len() / ENTRY_SIZE;
+
+        loop {
+            let data = self.read_directory(directory)?;
+            let slots: Vec<u8> = data.chunks(ENTRY_SIZE).map(|entry| entry[0]).collect();
+            let mut run = 0;
+            for (slot, first) in slots.iter().enumerate() {
+                if *first == ENTRY_FREE || *first == ENTRY_DELETED {
+                    run += 1;
+                } else {
+                    run = 0;
+                }
+                if run == needed {
+                    return self.write_entries(directory, slot + 1 - needed, &entries)
+                }
+            }
+            match directory {
+                Directory::Root => return Err(FileSystemError::new(
+                    "vfat: the root directory is full")),
+                Directory::Cluster(first) => {
+                    let last = *self.chain(*first).last().unwrap();
+                    let cluster = self.allocate(1)?[0];
+                    self.fat[last as usize] = cluster;
+                    self.zero_cluster(cluster)?;
+                }
+            }
+        }
+    }
+
+    fn lookup(&mut self, path: &str, create: bool) -> FileSystemResult<Directory> {
+        let mut directory = self.root();
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            directory = match self.find(&directory, name)? {
+                Some(ref entry) if entry.attributes & ATTR_DIRECTORY != 0 => Directory::Cluster(entry.cluster),
+                Some(_) => return Err(FileSystemError::new(
+                    format!("vfat: {} is not a directory", name).as_str())),
+                None if create => {
+                    let cluster = self.allocate(1)?[0];
+                    self.zero_cluster(cluster)?;
+                    self.add_entry(&directory, name, ATTR_DIRECTORY, cluster, 0)?;
+                    // The parent of a directory in the root is recorded as 0
+                    let parent = match directory {
+                        Directory::Cluster(parent) if parent != self.root_cluster => parent,
+                        _ => 0
+                    };
+                    let new = Directory::Cluster(cluster);
+                    let mut dots = [0u8; ENTRY_SIZE * 2];
+                    for (index, target) in [cluster, parent].iter().enumerate() {
+                        let entry = &mut dots[index * ENTRY_SIZE..(index + 1) * ENTRY_SIZE];
+                        entry[..11].copy_from_slice(if index == 0 { b".          " } else { b"..         " });
+                        entry[11] = ATTR_DIRECTORY;
+                        LittleEndian::write_u16(&mut entry[20..22], (target >> 16) as u16);
+                        LittleEndian::write_u16(&mut entry[26..28], *target as u16);
+                    }
+                    self.write_entries(&new, 0, &dots)?;
+                    new
+                },
+                None => return Err(FileSystemError::new(
+                    format!("vfat: {} does not exist", name).as_str()))
+            };
+        }
+        Ok(directory)
+    }
+
+    fn split(path: &str) -> FileSystemResult<(&str, &str)> {
+        let path = path.trim_end_matches('/');
+        match path.rfind('/') {
+            Some(slash) if slash + 1 < path.len() => Ok((&path[..slash], &path[slash + 1..])),
+            None if !path.is_empty() => Ok(("", path)),
+            _ => Err(FileSystemError::new(format!("vfat: {} is not a valid path", path).as_str()))
+        }
+    }
+
+    /// Creates a directory and its parents, as mkdir -p
+    pub fn create_dir(&mut self, path: &str) -> FileSystemResult<()> {
+        self.lookup(path, true).map(|_| ())
+    }
+
+    /// Writes a new file, creating its parent directories
+    pub fn write_file(&mut self, path: &str, contents: &[u8]) -> FileSystemResult<()> {
+        let (parent, name) = Self::split(path)?;
+        let directory = self.lookup(parent, true)?;
+        if self.find(&directory, name)?.is_some() {
+            return Err(FileSystemError::new(format!("vfat: {} already exists", path).as_str()))
+        }
+        if contents.len() > u32::MAX as usize {
+            return Err(FileSystemError::new(format!("vfat: {} is too large", path).as_str()))
+        }
+        let cluster_size = self.geometry.cluster_size() as usize;
+        let clusters = self.allocate(contents.len().div_ceil(cluster_size))?;
+        for (cluster, data) in clusters.iter().zip(contents.chunks(cluster_size)) {
+            write_at(self.device, self.geometry.cluster_offset(*cluster), data)?;
+        }
+        self.add_entry(&directory, name, ATTR_ARCHIVE,
+                       clusters.first().cloned().unwrap_or(0), contents.len() as u32)
+    }
+
+    /// Copies a file from the local file system into the volume
+    pub fn copy_file(&mut self, source: &Path, path: &str) -> FileSystemResult<()> {
+        let contents = std::fs::read(source).map_err(|e| FileSystemError::new(
+            format!("vfat: could not read {}: {}", source.display(), e).as_str()))?;
+        self.write_file(path, &contents)
+    }
+
+    pub fn read_file(&mut self, path: &str) -> FileSystemResult<Vec<u8>> {
+        let (parent, name) = Self::split(path)?;
+        let directory = self.lookup(parent, false)?;
+        let entry = match self.find(&directory, name)? {
+            Some(ref entry) if entry.attributes & ATTR_DIRECTORY == 0 => entry.clone(),
+            _ => return Err(FileSystemError::new(format!("vfat: {} is not a file", path).as_str()))
+        };
+        let mut contents = Vec::with_capacity(entry.size as usize);
+        for cluster in self.chain(entry.cluster) {
+            contents.extend(read_at(self.device, self.geometry.cluster_offset(cluster),
+                self.geometry.cluster_size() as usize)?);
+        }
+        contents.truncate(entry.size as usize);
+        Ok(contents)
+    }
+
+    /// Names of the entries in a directory
+    pub fn list_dir(&mut self, path: &str) -> FileSystemResult<Vec<String>> {
+        let directory = self.lookup(path, false)?;
+        Ok(self.entries(&directory)?.into_iter()
+            .map(|entry| entry.name)
+            .filter(|name| name != "." && name != "..")
+            .collect())
+    }
+
+    /// Writes every copy of the allocation table and, for FAT32, the FSInfo
+    /// free cluster hints
+    pub fn flush(&mut self) -> FileSystemResult<()> {
+        let mut table = vec![0u8; (self.geometry.fat_sectors * SECTOR_SIZE) as usize];
+        for (index, entry) in self.fat.iter().enumerate() {
+            match self.geometry.fat_type {
+                FatType::Fat16 => LittleEndian::write_u16(&mut table[index * 2..index * 2 + 2], *entry as u16),
+                FatType::Fat32 => LittleEndian::write_u32(&mut table[index * 4..index * 4 + 4], *entry)
+            }
+        }
+        for copy in 0..FAT_COUNT {
+            write_at(self.device, self.geometry.fat_offset(copy), &table)?;
+        }
+        if self.geometry.fat_type == FatType::Fat32 {
+            let next_free = (2..self.fat.len()).find(|c| self.fat[*c] == 0).unwrap_or(0xffff_ffff);
+            let mut fs_info = vec![0u8; SECTOR_SIZE as usize];
+            LittleEndian::write_u32(&mut fs_info[..4], 0x4161_5252);
+            LittleEndian::write_u32(&mut fs_info[484..488], 0x6141_7272);
+            LittleEndian::write_u32(&mut fs_info[488..492], self.free_clusters());
+            LittleEndian::write_u32(&mut fs_info[492..496], next_free as u32);
+            LittleEndian::write_u32(&mut fs_info[508..512], 0xaa55_0000);
+            write_at(self.device, SECTOR_SIZE as u64, &fs_info)?;
+            write_at(self.device, 7 * SECTOR_SIZE as u64, &fs_info)?;
+        }
+        self.device.flush().map_err(io_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::process::Command;
+    use crate::probe::probe;
+
+    fn image(size: usize) -> Cursor<Vec<u8>> {
+        Cursor::new(vec![0u8; size])
+    }
+
+    #[test]
+    fn test_geometry() {
+        let fat32 = FatOptions::new().geometry(64 << 20).unwrap();
+        assert_eq!(fat32.fat_type, FatType::Fat32);
+        assert_eq!(fat32.cluster_size(), 512);
+        assert!(fat32.clusters >= FAT32_MIN_CLUSTERS);
+        // Entries for every cluster, plus the two reserved entries
+        assert!(fat32.fat_sectors as u64 * 128 >= fat32.clusters as u64 + 2);
+
+        let fat16 = FatOptions::new().geometry(16 << 20).unwrap();
+        assert_eq!(fat16.fat_type, FatType::Fat16);
+        assert!(fat16.fat_sectors as u64 * 256 >= fat16.clusters as u64 + 2);
+
+        assert!(FatOptions::new().fat_type(FatType::Fat32).geometry(16 << 20).is_err());
+        assert!(FatOptions::new().geometry(1 << 20).is_err());
+        assert!(FatOptions::new().cluster_size(1000).geometry(64 << 20).is_err());
+    }
+
+    #[test]
+    fn test_format() {
+        for (size, version) in &[(64 << 20, "FAT32"), (16 << 20, "FAT16")] {
+            let mut device = image(*size);
+            FatOptions::new().label("EFI").volume_id(0x1a2b_3c4d).format(&mut device).unwrap();
+            let signatures = probe(&mut device).unwrap();
+            assert_eq!(signatures.len(), 1);
+            assert_eq!(signatures[0].fs_type, "vfat");
+            assert_eq!(signatures[0].version.as_deref(), Some(*version));
+            assert_eq!(signatures[0].label.as_deref(), Some("EFI"));
+            assert_eq!(signatures[0].uuid.as_deref(), Some("1A2B-3C4D"));
+
+            let mut volume = FatVolume::open(&mut device).unwrap();
+            assert_eq!(volume.free_clusters(), volume.geometry().clusters -
+                if *version == "FAT32" { 1 } else { 0 });
+            assert!(volume.list_dir("/").unwrap().is_empty());
+        }
+        assert!(FatOptions::new().label("lower").format(&mut image(16 << 20)).is_err());
+
+        // A configured label is stored in upper case, FAT12 is left to mkfs.vfat
+        let file_system: FileSystem = serde_json::from_str(
+            r#"{"fs_type": "vfat", "label": "efi", "block_size": 4096}"#).unwrap();
+        let mut device = image(64 << 20);
+        FatOptions::from_file_system(&file_system).unwrap().format(&mut device).unwrap();
+        assert_eq!(probe(&mut device).unwrap()[0].label.as_deref(), Some("EFI"));
+        assert_eq!(FatVolume::open(&mut device).unwrap().geometry().cluster_size(), 4096);
+        let file_system: FileSystem = serde_json::from_str(
+            r#"{"fs_type": "vfat", "features": ["fat12"]}"#).unwrap();
+        assert!(MkfsVfat.format(&file_system, Path::new("/dev/null")).is_none());
+    }
+
+    #[test]
+    fn test_corrupt() {
+        let mut device = image(64 << 20);
+        FatOptions::new().format(&mut device).unwrap();
+        let corrupt = |offset: usize, value: u32| {
+            let mut data = device.get_ref().clone();
+            LittleEndian::write_u32(&mut data[offset..offset + 4], value);
+            let mut device = Cursor::new(data);
+            FatVolume::open(&mut device).map(|_| ()).unwrap_err().to_string()
+        };
+        // FAT sectors, root cluster and total sectors
+        assert_eq!(corrupt(36, u32::MAX), "vfat: the FAT layout does not fit the volume");
+        assert_eq!(corrupt(44, 0), "vfat: the root directory cluster 0 is out of range");
+        assert_eq!(corrupt(44, u32::MAX), "vfat: the root directory cluster 4294967295 is out of range");
+        assert!(corrupt(32, 1 << 20).starts_with("vfat: the FAT is too small for "));
+        assert!(corrupt(32, 40_000).ends_with("is out of range for the FAT type"));
+    }
+
+    #[test]
+    fn test_files() {
+        let mut device = image(64 << 20);
+        FatOptions::new().label("ESP").format(&mut device).unwrap();
+        let large: Vec<u8> = (0..100_000u32).map(|n| n as u8).collect();
+        {
+            let mut volume = FatVolume::open(&mut device).unwrap();
+            volume.write_file("/EFI/BOOT/BOOTX64.EFI", &large).unwrap();
+            volume.write_file("/EFI/press/grub.cfg", b"set timeout=5\n").unwrap();
+            volume.write_file("/EFI/press/empty", b"").unwrap();
+            // Enough entries to grow the directory past one cluster
+            for n in 0..40 {
+                volume.write_file(&format!("/EFI/press/a long file name {}.conf", n), b"x").unwrap();
+            }
+            assert!(volume.write_file("/EFI/press/GRUB.CFG", b"").is_err());
+            volume.flush().unwrap();
+        }
+
+        let mut volume = FatVolume::open(&mut device).unwrap();
+        assert_eq!(volume.read_file("/efi/boot/bootx64.efi").unwrap(), large);
+        assert_eq!(volume.read_file("/EFI/press/grub.cfg").unwrap(), b"set timeout=5\n");
+        assert!(volume.read_file("/EFI/press/empty").unwrap().is_empty());
+        assert_eq!(volume.read_file("/EFI/press/a long file name 39.conf").unwrap(), b"x");
+        assert_eq!(volume.list_dir("/").unwrap(), vec!["EFI"]);
+        let names = volume.list_dir("/EFI/press").unwrap();
+        assert_eq!(names.len(), 42);
+        assert_eq!(names[0], "grub.cfg");
+        assert!(volume.read_file("/EFI/missing").is_err());
+    }
+
+    #[test]
+    fn test_names() {
+        assert_eq!(&short_name("BOOTX64.EFI").unwrap(), b"BOOTX64 EFI");
+        assert!(short_name("grub.cfg").is_none());
+        assert!(short_name("a.b.c").is_none());
+        assert_eq!(&short_alias("grub.cfg", &[]).unwrap(), b"GRUB~1  CFG");
+        assert_eq!(&short_alias("grub.cfg", &[*b"GRUB~1  CFG"]).unwrap(), b"GRUB~2  CFG");
+        assert_eq!(&short_alias("a long file name.conf", &[]).unwrap(), b"ALONGF~1CON");
+        assert_eq!(short_name_checksum(b"GRUB~1  CFG"),
+            b"GRUB~1  CFG".iter().fold(0u8, |s, c| s.rotate_right(1).wrapping_add(*c)));
+        assert_eq!(dos_timestamp(UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000)),
+            ((43 << 9) | (11 << 5) | 14, (22 << 11) | (13 << 5) | 10));
+    }
+
+    #[test]
+    fn test_fsck() {
+        if Command::new("fsck.vfat").arg("--help").output().is_err() {
+            return
+        }
+        let image = std::env::temp_dir().join(format!("press-vfat-{}.img", std::process::id()));
+        for size in &[64u64 << 20, 16 << 20] {
+            std::fs::File::create(&image).unwrap().set_len(*size).unwrap();
+            let mut file_system = FileSystem::new(FileSystemType::Vfat);
+            file_system.label = Some("EFI".to_owned());
+            file_system.uuid = Some("1A2B-3C4D".to_owned());
+            MkfsVfat.format(&file_system, &image).unwrap().unwrap();
+            {
+                let mut device = OpenOptions::new().read(true).write(true).open(&image).unwrap();
+                let mut volume = FatVolume::open(&mut device).unwrap();
+                volume.write_file("/EFI/BOOT/BOOTX64.EFI", &[0x4d; 70000]).unwrap();
+                volume.write_file("/EFI/press/grub.cfg", b"set timeout=5\n").unwrap();
+                volume.flush().unwrap();
+            }
+            let output = Command::new("fsck.vfat").arg("-n").arg(&image).output().unwrap();
+            assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stdout));
+        }
+        std::fs::remove_file(&image).unwrap();
+    }
+}
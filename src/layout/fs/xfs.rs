@@ -0,0 +1,41 @@
+use std::path::Path;
+
+use super::{FileSystem, FileSystemType, FileSystemError, FileSystemResult,
+            MakeFileSystem, parse_uuid};
+
+pub struct MkfsXfs;
+
+impl MakeFileSystem for MkfsXfs {
+    fn command(&self) -> &'static str {
+        "mkfs.xfs"
+    }
+
+    fn arguments(&self, file_system: &FileSystem, device: &Path) -> FileSystemResult<Vec<String>> {
+        let fs_type = FileSystemType::Xfs;
+        if file_system.inode_ratio.is_some() {
+            return Err(FileSystemError::unsupported(fs_type, "inode_ratio"))
+        }
+        if file_system.reserved_blocks_percent.is_some() {
+            return Err(FileSystemError::unsupported(fs_type, "reserved_blocks_percent"))
+        }
+
+        let mut arguments = vec!["-f".to_owned()];
+        if let Some(ref label) = file_system.label {
+            arguments.extend(vec!["-L".to_owned(), label.to_owned()]);
+        }
+        if let Some(ref uuid) = file_system.uuid {
+            let uuid = parse_uuid(fs_type, uuid)?;
+            arguments.extend(vec!["-m".to_owned(), format!("uuid={}", uuid)]);
+        }
+        if let Some(ref block_size) = file_system.block_size {
+            arguments.extend(vec!["-b".to_owned(), format!("size={}", block_size.bytes())]);
+        }
+        // Features are metadata options, crc=1 or reflink=1 for instance
+        if !file_system.features.is_empty() {
+            arguments.extend(vec!["-m".to_owned(), file_system.features.join(",")]);
+        }
+        arguments.extend(file_system.extra_options.iter().cloned());
+        arguments.push(device.to_string_lossy().into_owned());
+        Ok(arguments)
+    }
+}
@@ -1,4 +1,9 @@
-#[derive(Default, Clone)]
+use crate::inventory::{Disk, Inventory};
+use crate::sysfs::Transport;
+use super::partition::PartitionTable;
+use super::selector::SelectionError;
+
+#[derive(Debug, Default, Clone)]
 pub struct LayoutOptions {
     use_fibre_channel: bool,
     loop_only: bool,
@@ -22,6 +27,14 @@ impl LayoutOptions {
         self
     }
 
+    pub fn allows_fibre_channel(&self) -> bool {
+        self.use_fibre_channel
+    }
+
+    pub fn is_loop_only(&self) -> bool {
+        self.loop_only
+    }
+
     pub fn layout(&self) -> Layout {
         Layout {
             options: LayoutOptions {
@@ -36,6 +49,26 @@ pub struct Layout {
     pub options: LayoutOptions
 }
 
+impl Layout {
+    /// The disks which may be selected as targets. Fabric attached LUNs are
+    /// excluded unless fibre channel is enabled, and only loop devices are
+    /// considered when the layout is restricted to them.
+    pub fn candidates<'a>(&self, inventory: &'a Inventory) -> Vec<&'a Disk> {
+        inventory.disks.iter()
+            .filter(|d| !self.options.loop_only || d.transport == Transport::Loop)
+            .filter(|d| self.options.use_fibre_channel || !inventory.is_fabric_attached(d))
+            .collect()
+    }
+
+    pub fn resolve_target<'a>(&self, table: &PartitionTable, inventory: &'a Inventory)
+            -> Result<&'a Disk, SelectionError> {
+        match table.target {
+            Some(ref selector) => selector.resolve(self.candidates(inventory)),
+            None => Err(SelectionError::new("the partition table has no target"))
+        }
+    }
+}
+
 
 
 #[test]
@@ -55,4 +88,28 @@ fn test_builder() {
     assert_eq!(layout.options.loop_only, false);
     assert_eq!(layout.options.use_fibre_channel, true);
 
+}
+
+#[test]
+fn test_candidates() {
+    let inventory = Inventory::discover_from(&crate::sysfs::fixture_root()).unwrap();
+    let names = |layout: Layout| -> Vec<String> {
+        layout.candidates(&inventory)
+            .iter()
+            .map(|d| d.name.clone())
+            .collect()
+    };
+
+    assert_eq!(names(LayoutOptions::new().layout()),
+        vec!["loop0", "md0", "nvme0n1", "sda"]);
+    assert_eq!(names(LayoutOptions::new().use_fibre_channel().layout()),
+        vec!["dm-0", "loop0", "md0", "nvme0n1", "sda", "sdb", "sdc"]);
+    assert_eq!(names(LayoutOptions::new().loop_only().layout()), vec!["loop0"]);
+
+    let table: PartitionTable = serde_json::from_str(
+        r#"{"target": {"removable": false, "pick": "largest"}}"#).unwrap();
+    let layout = LayoutOptions::new().loop_only().layout();
+    assert_eq!(layout.resolve_target(&table, &inventory).unwrap().name, "loop0");
+    let layout = LayoutOptions::new().layout();
+    assert_eq!(layout.resolve_target(&table, &inventory).unwrap().name, "nvme0n1");
 }
\ No newline at end of file
@@ -0,0 +1,379 @@
+extern crate serde;
+
+use std::error::Error;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use schemars::JsonSchema;
+use schemars::gen::SchemaGenerator;
+use schemars::schema::Schema;
+use serde::de::{self, Deserializer, Visitor};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::command;
+use crate::size::{Size, SizeVistor, SIZE_PATTERN};
+use super::crypt::Encryption;
+use super::fs::FileSystem;
+
+// Error Boiler plate
+#[derive(Debug)]
+pub struct LvmError {
+    details: String
+}
+
+impl LvmError {
+    pub fn new(msg: &str) -> Self {
+        Self {
+            details: msg.to_string()
+        }
+    }
+}
+
+impl fmt::Display for LvmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.details)
+    }
+}
+
+impl Error for LvmError {
+    fn description(&self) -> &str {
+        &self.details
+    }
+}
+
+pub type LvmResult<T> = Result<T, LvmError>;
+// End Error boiler plate
+
+/// The default extent size of vgcreate
+pub static DEFAULT_EXTENT_SIZE: u64 = 4 << 20;
+/// Space pvcreate reserves at the start of each physical volume for the
+/// label and metadata area
+pub static PV_METADATA_SIZE: u64 = 1 << 20;
+
+/// Logical representation of an LVM volume group
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct VolumeGroup {
+    pub name: String,
+    /// Names of the partitions or raid arrays used as physical volumes
+    pub physical_volumes: Vec<String>,
+    /// The physical extent size, 4MiB when not given
+    pub extent_size: Option<Size>,
+    #[serde(default)]
+    pub logical_volumes: Vec<LogicalVolume>
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct LogicalVolume {
+    pub name: String,
+    pub size: VolumeSize,
+    /// A LUKS container on the volume, which holds the file system
+    pub encryption: Option<Encryption>,
+    pub file_system: Option<FileSystem>
+}
+
+/// The size of a logical volume
+#[derive(Debug, PartialEq)]
+pub enum VolumeSize {
+    Fixed(Size),
+    /// A percentage of the whole volume group, "25%"
+    Percent(f64),
+    /// Whatever extents remain once every other volume has been allocated
+    Fill
+}
+
+struct VolumeSizeVisitor;
+
+impl<'de> Visitor<'de> for VolumeSizeVisitor {
+    type Value = VolumeSize;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a size, a percentage of the volume group or \"fill\"")
+    }
+
+    fn visit_str<E>(self, s: &str) -> Result<Self::Value, E>
+    where
+        E: de::Error {
+        // Lowercase only, as the schema has it
+        if s == "fill" {
+            return Ok(VolumeSize::Fill)
+        }
+        let s = s.trim();
+        if let Some(percent) = s.strip_suffix('%') {
+            return match percent.trim().parse::<f64>() {
+                Ok(percent) if percent > 0.0 && percent <= 100.0 => Ok(VolumeSize::Percent(percent)),
+                _ => Err(E::custom(format!(
+                    "invalid size \"{}\": percentages must be greater than 0 and at most 100", s)))
+            }
+        }
+        SizeVistor.visit_str(s).map(VolumeSize::Fixed)
+    }
+
+    fn visit_u64<E>(self, value: u64) -> Result<Self::Value, E>
+    where
+        E: de::Error {
+        SizeVistor.visit_u64(value).map(VolumeSize::Fixed)
+    }
+
+    fn visit_i64<E>(self, value: i64) -> Result<Self::Value, E>
+    where
+        E: de::Error {
+        SizeVistor.visit_i64(value).map(VolumeSize::Fixed)
+    }
+}
+
+impl<'de> Deserialize<'de> for VolumeSize {
+    fn deserialize<D>(deserializer: D) -> Result<VolumeSize, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_any(VolumeSizeVisitor)
+    }
+}
+
+impl JsonSchema for VolumeSize {
+    fn schema_name() -> String {
+        "VolumeSize".to_owned()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        serde_json::from_value(json!({
+            "description": "A size, a percentage of the volume group or \"fill\"",
+            "anyOf": [
+                {"type": "integer", "minimum": 0},
+                {"type": "string", "pattern": SIZE_PATTERN},
+                {"type": "string", "pattern": r"^\s*[0-9]+(\.[0-9]+)?\s*%\s*$"},
+                {"type": "string", "enum": ["fill"]}
+            ]
+        })).unwrap()
+    }
+}
+
+/// The extents given to each logical volume of a group
+#[derive(Debug, PartialEq)]
+pub struct VolumeGroupPlan {
+    pub name: String,
+    pub extent_size: u64,
+    pub total_extents: u64,
+    /// Logical volume names and their sizes in extents
+    pub logical_volumes: Vec<(String, u64)>
+}
+
+impl VolumeGroup {
+    pub fn extent_size(&self) -> u64 {
+        self.extent_size.as_ref().map(Size::bytes).unwrap_or(DEFAULT_EXTENT_SIZE)
+    }
+
+    /// Problems which can be found without knowing the size of the
+    /// physical volumes
+    pub fn check(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        let extent_size = self.extent_size();
+        if !extent_size.is_power_of_two() || extent_size < 1024 {
+            errors.push(format!("extent size of {} bytes is not a power of two of at \
+                least 1KiB", extent_size));
+        }
+        if self.physical_volumes.is_empty() {
+            errors.push("no physical volumes are given".to_owned());
+        }
+        if self.logical_volumes.iter().filter(|v| v.size == VolumeSize::Fill).count() > 1 {
+            errors.push("only one logical volume may fill the remaining space".to_owned());
+        }
+        let percent: f64 = self.logical_volumes.iter()
+            .filter_map(|v| match v.size {
+                VolumeSize::Percent(percent) => Some(percent),
+                _ => None
+            })
+            .sum();
+        if percent > 100.0 {
+            errors.push(format!("logical volumes are given {}% of the volume group", percent));
+        }
+        for volume in self.logical_volumes.iter() {
+            if volume.size == VolumeSize::Fixed(Size::new(0)) {
+                errors.push(format!("logical volume {} has a size of zero", volume.name));
+            }
+        }
+        errors
+    }
+
+    /// Allocates extents to each logical volume given the size in bytes of
+    /// each physical volume, failing when they do not fit
+    pub fn plan(&self, physical_volume_sizes: &[u64]) -> LvmResult<VolumeGroupPlan> {
+        let errors = self.check();
+        if !errors.is_empty() {
+            return Err(LvmError::new(&format!("{}: {}", self.name, errors.join(", "))))
+        }
+        let extent_size = self.extent_size();
+        let total_extents: u64 = physical_volume_sizes.iter()
+            .map(|size| size.saturating_sub(PV_METADATA_SIZE) / extent_size)
+            .sum();
+
+        // Fixed sizes are rounded up to whole extents and percentages down,
+        // as lvcreate does
+        let mut logical_volumes: Vec<(String, u64)> = self.logical_volumes.iter()
+            .map(|volume| (volume.name.clone(), match volume.size {
+                VolumeSize::Fixed(ref size) => size.bytes().div_ceil(extent_size),
+                VolumeSize::Percent(percent) => (total_extents as f64 * percent / 100.0) as u64,
+                VolumeSize::Fill => 0
+            }))
+            .collect();
+        let allocated: u64 = logical_volumes.iter().map(|(_, extents)| extents).sum();
+        if allocated > total_extents {
+            return Err(LvmError::new(&format!(
+                "{}: logical volumes need {} extents of {} bytes but only {} are available",
+                self.name, allocated, extent_size, total_extents)))
+        }
+        for (volume, (name, extents)) in self.logical_volumes.iter().zip(logical_volumes.iter_mut()) {
+            if volume.size == VolumeSize::Fill {
+                *extents = total_extents - allocated;
+            }
+            if *extents == 0 {
+                return Err(LvmError::new(&format!(
+                    "{}: no extents are left for logical volume {}", self.name, name)))
+            }
+        }
+        Ok(VolumeGroupPlan {
+            name: self.name.clone(),
+            extent_size,
+            total_extents,
+            logical_volumes
+        })
+    }
+}
+
+impl VolumeGroupPlan {
+    /// The device node of a logical volume in this group
+    pub fn device_path(&self, logical_volume: &str) -> PathBuf {
+        Path::new("/dev").join(&self.name).join(logical_volume)
+    }
+
+    /// The pvcreate, vgcreate and lvcreate commands which create the group
+    /// on the given physical volume devices
+    pub fn commands(&self, physical_volumes: &[PathBuf]) -> Vec<(String, Vec<String>)> {
+        let devices: Vec<String> = physical_volumes.iter()
+            .map(|device| device.to_string_lossy().into_owned())
+            .collect();
+        let mut commands = Vec::new();
+        for device in devices.iter() {
+            commands.push(("pvcreate".to_owned(),
+                vec!["--force".to_owned(), "--yes".to_owned(), device.clone()]));
+        }
+        let mut vgcreate = vec![
+            "--yes".to_owned(),
+            "--physicalextentsize".to_owned(), format!("{}k", self.extent_size / 1024),
+            self.name.clone()
+        ];
+        vgcreate.extend(devices);
+        commands.push(("vgcreate".to_owned(), vgcreate));
+        for (name, extents) in self.logical_volumes.iter() {
+            commands.push(("lvcreate".to_owned(), vec![
+                "--yes".to_owned(), "--wipesignatures".to_owned(), "y".to_owned(),
+                "--extents".to_owned(), extents.to_string(),
+                "--name".to_owned(), name.clone(), self.name.clone()
+            ]));
+        }
+        commands
+    }
+
+    /// Runs each command, stopping at the first which fails
+    pub fn execute(&self, physical_volumes: &[PathBuf]) -> LvmResult<()> {
+        info!("Creating volume group {} on {} physical volumes", self.name,
+              physical_volumes.len());
+        for (program, arguments) in self.commands(physical_volumes) {
+            command::run(&program, &arguments).map_err(|e| LvmError::new(&e.to_string()))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::testing::command_lines;
+
+    fn volume_group(json: &str) -> VolumeGroup {
+        serde_json::from_str(json).unwrap()
+    }
+
+    static GROUP: &str = r#"{
+        "name": "vg0",
+        "physical_volumes": ["pv1", "pv2"],
+        "extent_size": "8MiB",
+        "logical_volumes": [
+            {"name": "root", "size": "10GiB"},
+            {"name": "var", "size": "25%"},
+            {"name": "home", "size": "fill"}
+        ]
+    }"#;
+
+    #[test]
+    fn test_de() {
+        let group = volume_group(GROUP);
+        assert_eq!(group.extent_size(), 8 << 20);
+        assert_eq!(group.logical_volumes[1].size, VolumeSize::Percent(25.0));
+        assert_eq!(group.logical_volumes[2].size, VolumeSize::Fill);
+        assert!(serde_json::from_str::<VolumeSize>(r#""150%""#).is_err());
+        assert!(serde_json::from_str::<VolumeSize>(r#""lots""#).is_err());
+        assert!(serde_json::from_str::<VolumeSize>(r#""Fill""#).is_err());
+        assert_eq!(volume_group(r#"{"name": "vg1", "physical_volumes": ["md0"]}"#)
+            .extent_size(), DEFAULT_EXTENT_SIZE);
+    }
+
+    #[test]
+    fn test_plan() {
+        let group = volume_group(GROUP);
+        // 2 x 20GiB + the metadata area, 2560 extents each
+        let pv = (20 << 30) + PV_METADATA_SIZE;
+        let plan = group.plan(&[pv, pv]).unwrap();
+        assert_eq!(plan.total_extents, 5120);
+        assert_eq!(plan.logical_volumes, vec![
+            ("root".to_owned(), 1280), ("var".to_owned(), 1280), ("home".to_owned(), 2560)
+        ]);
+
+        let error = group.plan(&[8 << 30]).unwrap_err().to_string();
+        assert_eq!(error, "vg0: logical volumes need 1535 extents of 8388608 bytes but \
+            only 1023 are available");
+
+        // Nothing is left to fill
+        let group = volume_group(&GROUP.replace("25%", "30GiB"));
+        assert_eq!(group.plan(&[pv, pv]).unwrap_err().to_string(),
+            "vg0: no extents are left for logical volume home");
+    }
+
+    #[test]
+    fn test_check() {
+        let group = volume_group(r#"{
+            "name": "vg0", "physical_volumes": [], "extent_size": "3MiB",
+            "logical_volumes": [
+                {"name": "a", "size": "60%"}, {"name": "b", "size": "60%"},
+                {"name": "c", "size": "fill"}, {"name": "d", "size": "fill"},
+                {"name": "e", "size": 0}
+            ]
+        }"#);
+        assert_eq!(group.check(), vec![
+            "extent size of 3145728 bytes is not a power of two of at least 1KiB",
+            "no physical volumes are given",
+            "only one logical volume may fill the remaining space",
+            "logical volumes are given 120% of the volume group",
+            "logical volume e has a size of zero",
+        ]);
+    }
+
+    #[test]
+    fn test_commands() {
+        let plan = volume_group(GROUP).plan(&[(40 << 30) + PV_METADATA_SIZE]).unwrap();
+        let commands = command_lines(
+            &plan.commands(&[PathBuf::from("/dev/sda2"), PathBuf::from("/dev/md0")]));
+        assert_eq!(commands, vec![
+            "pvcreate --force --yes /dev/sda2",
+            "pvcreate --force --yes /dev/md0",
+            "vgcreate --yes --physicalextentsize 8192k vg0 /dev/sda2 /dev/md0",
+            "lvcreate --yes --wipesignatures y --extents 1280 --name root vg0",
+            "lvcreate --yes --wipesignatures y --extents 1280 --name var vg0",
+            "lvcreate --yes --wipesignatures y --extents 2560 --name home vg0",
+        ]);
+        assert_eq!(plan.device_path("root"), PathBuf::from("/dev/vg0/root"));
+    }
+}
@@ -1,5 +1,11 @@
 pub mod layout;
 pub mod partition;
 pub mod fs;
+pub mod selector;
+pub mod raid;
+pub mod lvm;
+pub mod crypt;
+#[cfg(test)]
+pub(crate) mod testing;
 
 pub use layout::LayoutOptions;
\ No newline at end of file
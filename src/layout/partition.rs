@@ -1,12 +1,15 @@
 extern crate serde;
 
+use schemars::JsonSchema;
 use serde::Deserialize;
 
-use crate::size::Size;
+use crate::size::{Size, SizeRequest};
+use super::crypt::Encryption;
 use super::fs::FileSystem;
+use super::selector::DiskSelector;
 
 // Supported partition tables
-#[derive(Debug, PartialEq, Deserialize)]
+#[derive(Debug, PartialEq, Deserialize, JsonSchema)]
 pub enum TableFormat {
     GPT,
     MBR
@@ -19,15 +22,16 @@ impl Default for TableFormat {
 }
 
 /// Logical representation of a partition table
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct PartitionTable {
     /// Which partition table
     #[serde(default)]
     pub table_type: TableFormat,
 
-    /// A target block device structure.
-    /// This field is populated once a physical device is selected
-    pub target: Option<String>,
+    /// The disk the table is written to, either a device path or rules
+    /// which are resolved against the disk inventory
+    pub target: Option<DiskSelector>,
 
     /// The partition table offset
     #[serde(default = "default_pt_size")]
@@ -42,6 +46,10 @@ pub struct PartitionTable {
     pub partitions: Vec<Partition>
 }
 
+// The smallest logical sector size in use, the sector size of the actual
+// target is only known once it has been selected
+pub const SECTOR_SIZE: u64 = 512;
+
 impl PartitionTable {
     pub fn new(format: TableFormat) -> Self {
         Self {
@@ -49,6 +57,32 @@ impl PartitionTable {
             .. Default::default()
         }
     }
+
+    /// Checks the table against the logical sector size of a device, every
+    /// problem found is returned
+    pub fn check(&self, sector_size: u64) -> Vec<String> {
+        let mut errors = Vec::new();
+        let alignment = self.alignment.bytes();
+        if alignment < sector_size || !alignment.is_power_of_two() ||
+                !alignment.is_multiple_of(sector_size) {
+            errors.push(format!("alignment of {} bytes is not a power of two multiple \
+                of the {} byte sector size", alignment, sector_size));
+        }
+        if !self.partition_start.bytes().is_multiple_of(sector_size) {
+            errors.push(format!("partition start of {} bytes is not a multiple of the \
+                {} byte sector size", self.partition_start.bytes(), sector_size));
+        }
+        if self.partitions.iter().filter(|p| p.size.is_fill()).count() > 1 {
+            errors.push("only one partition may fill the remaining space".to_owned());
+        }
+        for (index, partition) in self.partitions.iter().enumerate() {
+            if partition.size.fixed_bytes() == Some(0) {
+                errors.push(format!("partition {} has a size of zero",
+                    partition.name.as_deref().unwrap_or(&index.to_string())));
+            }
+        }
+        errors
+    }
 }
 
 impl Default for PartitionTable {
@@ -66,12 +100,16 @@ fn default_pt_size() -> Size {
     "1MiB".parse::<Size>().unwrap()
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct Partition {
     // The name of the partition
     pub name: Option<String>,
+    /// A LUKS container on the partition, which holds the file system
+    pub encryption: Option<Encryption>,
     pub file_system: Option<FileSystem>,
-    pub size: Size
+    /// A size, or "fill" for the remainder of the disk
+    pub size: SizeRequest
 }
 
 #[cfg(test)]
@@ -112,4 +150,27 @@ mod tests {
         println!("{:?}", partition_table);
         assert_eq!(partition_table.partitions[0].name, Some("pv1".to_owned()));
     }
+
+    #[test]
+    fn test_check() {
+        let data = r#"
+            {
+                "alignment": "3 KiB",
+                "partition_start": 1000,
+                "partitions": [
+                    {"name": "root", "size": "fill"},
+                    {"name": "empty", "size": 0},
+                    {"name": "home", "size": "fill"}
+                ]
+            }
+        "#;
+        let partition_table: PartitionTable = serde_json::from_str(data).unwrap();
+        assert_eq!(partition_table.check(SECTOR_SIZE), vec![
+            "alignment of 3072 bytes is not a power of two multiple of the 512 byte sector size",
+            "partition start of 1000 bytes is not a multiple of the 512 byte sector size",
+            "only one partition may fill the remaining space",
+            "partition empty has a size of zero",
+        ]);
+        assert!(PartitionTable::default().check(4096).is_empty());
+    }
 }
\ No newline at end of file
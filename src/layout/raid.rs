@@ -0,0 +1,395 @@
+extern crate serde;
+
+use std::error::Error;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use schemars::JsonSchema;
+use schemars::gen::SchemaGenerator;
+use schemars::schema::Schema;
+use serde::de::{self, Deserializer, Visitor};
+use serde::{Deserialize, Serialize, Serializer};
+use serde_json::json;
+
+use crate::command;
+use crate::size::Size;
+use crate::udev;
+use super::crypt::Encryption;
+use super::fs::FileSystem;
+
+// Error Boiler plate
+#[derive(Debug)]
+pub struct RaidError {
+    details: String
+}
+
+impl RaidError {
+    pub fn new(msg: &str) -> Self {
+        Self {
+            details: msg.to_string()
+        }
+    }
+}
+
+impl fmt::Display for RaidError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.details)
+    }
+}
+
+impl Error for RaidError {
+    fn description(&self) -> &str {
+        &self.details
+    }
+}
+
+pub type RaidResult<T> = Result<T, RaidError>;
+// End Error boiler plate
+
+/// How long to wait for udev to create the device node of a new array
+pub static DEVICE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Software raid levels supported by md
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum RaidLevel {
+    Raid0,
+    Raid1,
+    Raid5,
+    Raid6,
+    Raid10
+}
+
+impl RaidLevel {
+    pub fn name(&self) -> &'static str {
+        match self {
+            RaidLevel::Raid0 => "raid0",
+            RaidLevel::Raid1 => "raid1",
+            RaidLevel::Raid5 => "raid5",
+            RaidLevel::Raid6 => "raid6",
+            RaidLevel::Raid10 => "raid10"
+        }
+    }
+
+    /// The fewest active members mdadm will create an array with
+    pub fn min_members(&self) -> usize {
+        match self {
+            RaidLevel::Raid0 | RaidLevel::Raid1 | RaidLevel::Raid10 => 2,
+            RaidLevel::Raid5 => 3,
+            RaidLevel::Raid6 => 4
+        }
+    }
+
+    /// Whether data is striped across members in chunks
+    pub fn is_striped(&self) -> bool {
+        *self != RaidLevel::Raid1
+    }
+
+    pub fn is_redundant(&self) -> bool {
+        *self != RaidLevel::Raid0
+    }
+}
+
+/// The md superblock format. 0.90 and 1.0 are stored at the end of each
+/// member, which lets boot loaders read a raid1 member as a plain file system
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MetadataVersion {
+    V0_90,
+    V1_0,
+    V1_1,
+    V1_2
+}
+
+impl MetadataVersion {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MetadataVersion::V0_90 => "0.90",
+            MetadataVersion::V1_0 => "1.0",
+            MetadataVersion::V1_1 => "1.1",
+            MetadataVersion::V1_2 => "1.2"
+        }
+    }
+}
+
+struct MetadataVersionVisitor;
+
+impl<'de> Visitor<'de> for MetadataVersionVisitor {
+    type Value = MetadataVersion;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("an md metadata version: 0.90, 1.0, 1.1 or 1.2")
+    }
+
+    // YAML and TOML documents will often give the version as a number
+    fn visit_f64<E>(self, value: f64) -> Result<Self::Value, E>
+    where
+        E: de::Error {
+        match (value * 100.0).round() as i64 {
+            90 => Ok(MetadataVersion::V0_90),
+            100 => Ok(MetadataVersion::V1_0),
+            110 => Ok(MetadataVersion::V1_1),
+            120 => Ok(MetadataVersion::V1_2),
+            _ => Err(E::custom(format!("unsupported md metadata version {}", value)))
+        }
+    }
+
+    fn visit_u64<E>(self, value: u64) -> Result<Self::Value, E>
+    where
+        E: de::Error {
+        self.visit_f64(value as f64)
+    }
+
+    fn visit_i64<E>(self, value: i64) -> Result<Self::Value, E>
+    where
+        E: de::Error {
+        self.visit_f64(value as f64)
+    }
+
+    fn visit_str<E>(self, s: &str) -> Result<Self::Value, E>
+    where
+        E: de::Error {
+        match s.trim().parse::<f64>() {
+            Ok(value) => self.visit_f64(value),
+            Err(_) => Err(E::custom(format!("unsupported md metadata version \"{}\"", s)))
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for MetadataVersion {
+    fn deserialize<D>(deserializer: D) -> Result<MetadataVersion, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_any(MetadataVersionVisitor)
+    }
+}
+
+impl Serialize for MetadataVersion {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl JsonSchema for MetadataVersion {
+    fn schema_name() -> String {
+        "MetadataVersion".to_owned()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        serde_json::from_value(json!({
+            "description": "An md metadata version",
+            "anyOf": [
+                {"type": "string", "enum": ["0.90", "1.0", "1.1", "1.2"]},
+                {"type": "number", "enum": [0.9, 1.0, 1.1, 1.2]}
+            ]
+        })).unwrap()
+    }
+}
+
+/// Logical representation of an md array
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct RaidArray {
+    /// The array name, md0 for instance. The array is created as /dev/md/NAME
+    pub name: String,
+    pub level: RaidLevel,
+    /// The superblock format, mdadm's default (1.2) when not given
+    pub metadata: Option<MetadataVersion>,
+    /// The stripe chunk size of raid0, raid5, raid6 and raid10 arrays
+    pub chunk_size: Option<Size>,
+    /// Names of the partitions (or arrays) which are active members
+    pub members: Vec<String>,
+    /// Names of the partitions held as hot spares
+    #[serde(default)]
+    pub spares: Vec<String>,
+    /// A LUKS container on the array, which holds the file system
+    pub encryption: Option<Encryption>,
+    pub file_system: Option<FileSystem>
+}
+
+impl RaidArray {
+    /// The device node udev links to the array once it is assembled
+    pub fn device_path(&self) -> PathBuf {
+        Path::new("/dev/md").join(&self.name)
+    }
+
+    /// Whether a device path names this array
+    pub fn is_known_as(&self, path: &str) -> bool {
+        Path::new(path) == self.device_path()
+    }
+
+    /// Problems which can be found without knowing the member devices
+    pub fn check(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if self.name.is_empty() || self.name.contains('/') ||
+                self.name.chars().any(char::is_whitespace) {
+            errors.push(format!("\"{}\" is not a valid array name", self.name));
+        }
+        // The name is stored in the superblock as homehost:name
+        if self.name.len() > 32 {
+            errors.push("array names are limited to 32 characters".to_owned());
+        }
+        let min_members = self.level.min_members();
+        if self.members.len() < min_members {
+            errors.push(format!("{} needs at least {} members, {} given",
+                self.level.name(), min_members, self.members.len()));
+        }
+        if !self.spares.is_empty() && !self.level.is_redundant() {
+            errors.push(format!("{} cannot have spares", self.level.name()));
+        }
+        if let Some(ref chunk_size) = self.chunk_size {
+            let chunk_size = chunk_size.bytes();
+            if !self.level.is_striped() {
+                errors.push(format!("{} does not use a chunk size", self.level.name()));
+            } else if !chunk_size.is_power_of_two() || chunk_size < 4096 {
+                errors.push(format!("chunk size of {} bytes is not a power of two of at \
+                    least 4KiB", chunk_size));
+            }
+        }
+        errors
+    }
+
+    /// The mdadm command which creates the array from the given member and
+    /// spare devices
+    pub fn command(&self, members: &[PathBuf], spares: &[PathBuf]) -> (String, Vec<String>) {
+        let mut arguments = vec![
+            "--create".to_owned(),
+            self.device_path().to_string_lossy().into_owned(),
+            // Do not ask for confirmation when members hold old signatures
+            "--run".to_owned(),
+            format!("--level={}", self.level.name()),
+            format!("--raid-devices={}", members.len())
+        ];
+        if !spares.is_empty() {
+            arguments.push(format!("--spare-devices={}", spares.len()));
+        }
+        if let Some(metadata) = self.metadata {
+            arguments.push(format!("--metadata={}", metadata.as_str()));
+        }
+        if let Some(ref chunk_size) = self.chunk_size {
+            arguments.push(format!("--chunk={}K", chunk_size.bytes() / 1024));
+        }
+        // 0.90 superblocks have no room for a name
+        if self.metadata != Some(MetadataVersion::V0_90) {
+            arguments.push(format!("--name={}", self.name));
+        }
+        arguments.extend(members.iter().chain(spares.iter())
+            .map(|device| device.to_string_lossy().into_owned()));
+        ("mdadm".to_owned(), arguments)
+    }
+
+    /// Creates the array and waits for udev to create its device node,
+    /// which is returned
+    pub fn create(&self, members: &[PathBuf], spares: &[PathBuf]) -> RaidResult<PathBuf> {
+        let errors = self.check();
+        if !errors.is_empty() {
+            return Err(RaidError::new(&format!("{}: {}", self.name, errors.join(", "))))
+        }
+        if members.len() != self.members.len() || spares.len() != self.spares.len() {
+            return Err(RaidError::new(&format!(
+                "{}: {} members and {} spares are declared but {} and {} devices are given",
+                self.name, self.members.len(), self.spares.len(), members.len(), spares.len())))
+        }
+        info!("Creating {} array {} on {} devices", self.level.name(), self.name,
+              members.len() + spares.len());
+        let (program, arguments) = self.command(members, spares);
+        command::run(&program, &arguments).map_err(|e| RaidError::new(&e.to_string()))?;
+
+        let device = self.device_path();
+        if let Err(e) = udev::settle(DEVICE_TIMEOUT) {
+            warn!("udevadm settle failed: {}", e);
+        }
+        if !udev::wait_for_device(&device, DEVICE_TIMEOUT) {
+            return Err(RaidError::new(&format!("{} did not appear within {} seconds",
+                device.display(), DEVICE_TIMEOUT.as_secs())))
+        }
+        Ok(device)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::testing::command_line;
+
+    fn raid_array(json: &str) -> RaidArray {
+        serde_json::from_str(json).unwrap()
+    }
+
+    static ARRAY: &str = r#"{
+        "name": "md0",
+        "level": "raid5",
+        "metadata": "1.0",
+        "chunk_size": "256KiB",
+        "members": ["a", "b", "c"],
+        "spares": ["d"]
+    }"#;
+
+    #[test]
+    fn test_metadata_version() {
+        let array = raid_array(ARRAY);
+        assert_eq!(array.metadata, Some(MetadataVersion::V1_0));
+        assert_eq!(array.chunk_size.unwrap().bytes(), 256 << 10);
+        for (value, version) in [("\"0.90\"", MetadataVersion::V0_90), ("0.9", MetadataVersion::V0_90),
+                                 ("1", MetadataVersion::V1_0), ("1.2", MetadataVersion::V1_2)].iter() {
+            assert_eq!(serde_json::from_str::<MetadataVersion>(value).unwrap(), *version);
+        }
+        assert!(serde_json::from_str::<MetadataVersion>("1.3").is_err());
+        assert!(serde_json::from_str::<MetadataVersion>("\"ddf\"").is_err());
+        // Unquoted YAML versions are floats
+        let array: RaidArray = serde_yaml::from_str(
+            "name: md0\nlevel: raid1\nmetadata: 0.90\nmembers: [a, b]\n").unwrap();
+        assert_eq!(array.metadata, Some(MetadataVersion::V0_90));
+    }
+
+    #[test]
+    fn test_check() {
+        assert!(raid_array(ARRAY).check().is_empty());
+        let array = raid_array(r#"{
+            "name": "md/0", "level": "raid6", "chunk_size": "6KiB", "members": ["a", "b"]
+        }"#);
+        assert_eq!(array.check(), vec![
+            "\"md/0\" is not a valid array name",
+            "raid6 needs at least 4 members, 2 given",
+            "chunk size of 6144 bytes is not a power of two of at least 4KiB",
+        ]);
+        let array = raid_array(r#"{
+            "name": "md0", "level": "raid0", "members": ["a", "b"], "spares": ["c"]
+        }"#);
+        assert_eq!(array.check(), vec!["raid0 cannot have spares"]);
+        let array = raid_array(r#"{
+            "name": "md0", "level": "raid1", "chunk_size": "64KiB", "members": ["a", "b"]
+        }"#);
+        assert_eq!(array.check(), vec!["raid1 does not use a chunk size"]);
+    }
+
+    #[test]
+    fn test_command() {
+        let array = raid_array(ARRAY);
+        let devices: Vec<PathBuf> = ["/dev/sda2", "/dev/sdb2", "/dev/sdc2", "/dev/sdd2"].iter()
+            .map(PathBuf::from)
+            .collect();
+        let (program, arguments) = array.command(&devices[..3], &devices[3..]);
+        assert_eq!(command_line(&program, &arguments),
+            "mdadm --create /dev/md/md0 --run --level=raid5 --raid-devices=3 \
+            --spare-devices=1 --metadata=1.0 --chunk=256K --name=md0 \
+            /dev/sda2 /dev/sdb2 /dev/sdc2 /dev/sdd2");
+        assert!(array.is_known_as("/dev/md/md0"));
+        assert!(!array.is_known_as("md0"));
+
+        let array = raid_array(r#"{
+            "name": "boot", "level": "raid1", "metadata": 0.90, "members": ["a", "b"]
+        }"#);
+        let (_, arguments) = array.command(&devices[..2], &[]);
+        assert_eq!(arguments.join(" "), "--create /dev/md/boot --run --level=raid1 \
+            --raid-devices=2 --metadata=0.90 /dev/sda2 /dev/sdb2");
+
+        let error = array.create(&devices[..1], &[]).unwrap_err();
+        assert_eq!(error.to_string(),
+            "boot: 2 members and 0 spares are declared but 1 and 0 devices are given");
+    }
+}
@@ -0,0 +1,345 @@
+extern crate serde;
+extern crate regex;
+
+use std::fmt;
+use std::error::Error;
+
+use regex::Regex;
+use schemars::JsonSchema;
+use schemars::gen::SchemaGenerator;
+use schemars::schema::{Schema, SchemaObject, SubschemaValidation, InstanceType};
+use serde::Deserialize;
+use serde::de::{self, Visitor, Deserializer, MapAccess};
+
+use crate::inventory::Disk;
+use crate::size::Size;
+
+// Error Boiler plate
+#[derive(Debug)]
+pub struct SelectionError {
+    details: String
+}
+
+impl SelectionError {
+    pub fn new(msg: &str) -> Self {
+        Self {
+            details: msg.to_string()
+        }
+    }
+}
+
+impl fmt::Display for SelectionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.details)
+    }
+}
+
+impl Error for SelectionError {
+    fn description(&self) -> &str {
+        &self.details
+    }
+}
+
+type SelectionResult<T> = Result<T, SelectionError>;
+// End Error boiler plate
+
+/// When several disks satisfy the rules, which one to use
+#[derive(Debug, PartialEq, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Pick {
+    Smallest,
+    Largest,
+    /// The first disk in kernel name order
+    First
+}
+
+/// Rules describing the disk a partition table should be written to. Every
+/// rule which is set must match.
+#[derive(Debug, Default, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct SelectorRules {
+    pub serial: Option<String>,
+    pub wwn: Option<String>,
+    /// A /dev/disk/by-path link or udev ID_PATH
+    pub by_path: Option<String>,
+    /// A regular expression matched against the disk model
+    pub model: Option<String>,
+    pub rotational: Option<bool>,
+    pub removable: Option<bool>,
+    pub min_size: Option<Size>,
+    pub max_size: Option<Size>,
+    pub pick: Option<Pick>
+}
+
+/// Identifies a target disk, either by name (/dev/sda,
+/// /dev/disk/by-id/wwn-0x5002538e40a1b2c3) or by a set of rules resolved
+/// against the disk inventory.
+#[derive(Debug)]
+pub enum DiskSelector {
+    Path(String),
+    Rules(SelectorRules)
+}
+
+// Wwns are reported as 0x5002538e40a1b2c3 by udev and naa.5002538e40a1b2c3
+// by sysfs
+fn normalize_wwn(wwn: &str) -> String {
+    let wwn = wwn.trim().to_ascii_lowercase();
+    for prefix in ["0x", "naa.", "eui.", "t10."].iter() {
+        if let Some(stripped) = wwn.strip_prefix(prefix) {
+            return stripped.to_owned()
+        }
+    }
+    wwn
+}
+
+impl SelectorRules {
+    fn matches(&self, disk: &Disk, model: &Option<Regex>) -> bool {
+        if let Some(ref serial) = self.serial {
+            if disk.serial.as_ref() != Some(serial) {
+                return false
+            }
+        }
+        if let Some(ref wwn) = self.wwn {
+            match disk.wwn {
+                Some(ref w) if normalize_wwn(w) == normalize_wwn(wwn) => (),
+                _ => return false
+            }
+        }
+        if let Some(ref by_path) = self.by_path {
+            let short = by_path.trim_start_matches("/dev/disk/by-path/");
+            if disk.id_path.as_deref() != Some(short) &&
+                !disk.by_path.iter().any(|l| l.trim_start_matches("/dev/disk/by-path/") == short) {
+                return false
+            }
+        }
+        if let Some(ref model) = model {
+            match disk.model {
+                Some(ref m) if model.is_match(m) => (),
+                _ => return false
+            }
+        }
+        if let Some(rotational) = self.rotational {
+            if disk.rotational != rotational {
+                return false
+            }
+        }
+        if let Some(removable) = self.removable {
+            if disk.removable != removable {
+                return false
+            }
+        }
+        if let Some(ref min_size) = self.min_size {
+            if disk.size < min_size.bytes() {
+                return false
+            }
+        }
+        if let Some(ref max_size) = self.max_size {
+            if disk.size > max_size.bytes() {
+                return false
+            }
+        }
+        true
+    }
+
+    /// Problems which can be found without the disk inventory
+    pub fn check(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if let Some(ref model) = self.model {
+            if let Err(e) = Regex::new(model) {
+                errors.push(format!("invalid model expression {}: {}", model, e));
+            }
+        }
+        if let (Some(min_size), Some(max_size)) = (&self.min_size, &self.max_size) {
+            if min_size.bytes() > max_size.bytes() {
+                errors.push(format!("min_size {} is larger than max_size {}",
+                    min_size.bytes(), max_size.bytes()));
+            }
+        }
+        errors
+    }
+
+    fn describe(&self) -> String {
+        let mut rules = Vec::new();
+        if let Some(ref serial) = self.serial {
+            rules.push(format!("serial={}", serial));
+        }
+        if let Some(ref wwn) = self.wwn {
+            rules.push(format!("wwn={}", wwn));
+        }
+        if let Some(ref by_path) = self.by_path {
+            rules.push(format!("by_path={}", by_path));
+        }
+        if let Some(ref model) = self.model {
+            rules.push(format!("model=/{}/", model));
+        }
+        if let Some(rotational) = self.rotational {
+            rules.push(format!("rotational={}", rotational));
+        }
+        if let Some(removable) = self.removable {
+            rules.push(format!("removable={}", removable));
+        }
+        if let Some(ref min_size) = self.min_size {
+            rules.push(format!("min_size={}", min_size.bytes()));
+        }
+        if let Some(ref max_size) = self.max_size {
+            rules.push(format!("max_size={}", max_size.bytes()));
+        }
+        if let Some(ref pick) = self.pick {
+            rules.push(format!("pick={:?}", pick).to_ascii_lowercase());
+        }
+        if rules.is_empty() {
+            "any disk".to_owned()
+        } else {
+            rules.join(", ")
+        }
+    }
+}
+
+impl DiskSelector {
+    /// Resolves the selector to exactly one of the candidate disks
+    pub fn resolve<'a, I>(&self, candidates: I) -> SelectionResult<&'a Disk>
+            where I: IntoIterator<Item = &'a Disk> {
+        let (matches, description) = match self {
+            DiskSelector::Path(path) => {
+                let matches: Vec<&Disk> = candidates.into_iter()
+                    .filter(|d| d.is_known_as(path))
+                    .collect();
+                (matches, path.to_owned())
+            },
+            DiskSelector::Rules(rules) => {
+                let model = match rules.model {
+                    Some(ref m) => Some(Regex::new(m).map_err(|e| SelectionError::new(
+                        format!("invalid model expression {}: {}", m, e).as_str()))?),
+                    None => None
+                };
+                let mut matches: Vec<&Disk> = candidates.into_iter()
+                    .filter(|d| rules.matches(d, &model))
+                    .collect();
+                matches.sort_by(|a, b| a.name.cmp(&b.name));
+                let matches = match rules.pick {
+                    Some(Pick::First) => matches.into_iter().take(1).collect(),
+                    Some(Pick::Smallest) => {
+                        let size = matches.iter().map(|d| d.size).min();
+                        matches.into_iter().filter(|d| Some(d.size) == size).collect()
+                    },
+                    Some(Pick::Largest) => {
+                        let size = matches.iter().map(|d| d.size).max();
+                        matches.into_iter().filter(|d| Some(d.size) == size).collect()
+                    },
+                    None => matches
+                };
+                (matches, rules.describe())
+            }
+        };
+
+        match matches.len() {
+            0 => Err(SelectionError::new(
+                format!("no disk matches {}", description).as_str())),
+            1 => Ok(matches[0]),
+            _ => Err(SelectionError::new(
+                format!("{} disks match {}: {}", matches.len(), description,
+                    matches.iter()
+                        .map(|d| d.devnode.as_str())
+                        .collect::<Vec<&str>>()
+                        .join(", ")).as_str()))
+        }
+    }
+}
+
+// Deserializer
+struct DiskSelectorVisitor;
+
+impl<'de> Visitor<'de> for DiskSelectorVisitor {
+    type Value = DiskSelector;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter
+            .write_str("a device path or a map of disk selection rules")
+    }
+
+    fn visit_str<E>(self, s: &str) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error {
+        Ok(DiskSelector::Path(s.to_owned()))
+    }
+
+    fn visit_map<M>(self, map: M) -> Result<Self::Value, M::Error>
+    where
+        M: MapAccess<'de> {
+        Ok(DiskSelector::Rules(SelectorRules::deserialize(
+            de::value::MapAccessDeserializer::new(map))?))
+    }
+}
+
+impl<'de> Deserialize<'de> for DiskSelector {
+    fn deserialize<D>(deserializer: D) -> Result<DiskSelector, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_any(DiskSelectorVisitor)
+    }
+}
+
+impl JsonSchema for DiskSelector {
+    fn schema_name() -> String {
+        "DiskSelector".to_owned()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        let path = SchemaObject {
+            instance_type: Some(InstanceType::String.into()),
+            ..Default::default()
+        };
+        Schema::Object(SchemaObject {
+            subschemas: Some(Box::new(SubschemaValidation {
+                any_of: Some(vec![path.into(), gen.subschema_for::<SelectorRules>()]),
+                ..Default::default()
+            })),
+            ..Default::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inventory::Inventory;
+    use crate::sysfs::fixture_root;
+
+    fn select(json: &str) -> SelectionResult<String> {
+        let inventory = Inventory::discover_from(&fixture_root()).unwrap();
+        let selector: DiskSelector = serde_json::from_str(json).unwrap();
+        selector.resolve(inventory.disks.iter()).map(|d| d.name.clone())
+    }
+
+    #[test]
+    fn test_path() {
+        assert_eq!(select(r#""/dev/sda""#).unwrap(), "sda");
+        assert_eq!(select(r#""/dev/disk/by-id/nvme-eui.0025388991b12345""#).unwrap(),
+            "nvme0n1");
+        assert!(select(r#""/dev/sdz""#).is_err());
+    }
+
+    #[test]
+    fn test_rules() {
+        assert_eq!(select(r#"{"serial": "S4EMNX0M912345"}"#).unwrap(), "nvme0n1");
+        assert_eq!(select(r#"{"wwn": "naa.5002538e40a1b2c3"}"#).unwrap(), "sda");
+        assert_eq!(select(r#"{"by_path": "pci-0000:00:17.0-ata-1"}"#).unwrap(), "sda");
+        assert_eq!(select(r#"{"model": "^SAMSUNG MZ"}"#).unwrap(), "nvme0n1");
+        assert_eq!(select(r#"{"rotational": false, "min_size": "900GB"}"#).unwrap(),
+            "nvme0n1");
+        assert_eq!(select(r#"{"rotational": false, "pick": "smallest"}"#).unwrap(),
+            "loop0");
+        assert_eq!(select(r#"{"removable": false, "pick": "first"}"#).unwrap(), "dm-0");
+    }
+
+    #[test]
+    fn test_ambiguous() {
+        // Both paths of the multipath LUN and the map itself are the same size
+        let err = select(r#"{"model": "HSV210", "pick": "largest"}"#).unwrap_err();
+        assert!(err.to_string().starts_with("3 disks match"));
+        assert!(select(r#"{"max_size": "1 MiB"}"#).unwrap_err().to_string()
+            .starts_with("no disk matches"));
+        assert!(select(r#"{"model": "("}"#).is_err());
+    }
+}
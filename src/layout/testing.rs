@@ -0,0 +1,18 @@
+//! Helpers shared by the layout tests
+
+use super::fs::FileSystem;
+
+pub fn file_system(json: &str) -> FileSystem {
+    serde_json::from_str(json).unwrap()
+}
+
+/// A command as it would be typed
+pub fn command_line(program: &str, arguments: &[String]) -> String {
+    format!("{} {}", program, arguments.join(" "))
+}
+
+pub fn command_lines(commands: &[(String, Vec<String>)]) -> Vec<String> {
+    commands.iter()
+        .map(|(program, arguments)| command_line(program, arguments))
+        .collect()
+}
@@ -3,6 +3,8 @@
 //! LVM, and software RAID support. Press is written in Rust and compatible with
 //! most operating systems. 
 
+#[macro_use]
+extern crate log;
 extern crate byteorder;
 extern crate uuid;
 
@@ -10,12 +12,19 @@ pub mod mbr;
 pub mod gpt;
 pub mod sysfs;
 pub mod udev;
+pub mod inventory;
+pub mod loopdev;
+pub mod lvm2;
+pub mod luks;
+pub mod md;
+pub mod probe;
+pub mod wipe;
 pub mod block;
+pub mod command;
 pub mod layout;
 pub mod size;
+pub mod config;
+pub mod signal;
+pub mod target;
 
-use layout::LayoutOptions;
-
-struct PressConfiguration {
-    pub layout_options: Option<LayoutOptions>
-}
\ No newline at end of file
+pub use config::PressConfiguration;
\ No newline at end of file
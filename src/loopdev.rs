@@ -0,0 +1,307 @@
+extern crate libc;
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+// Methods for attaching image files to loop devices, see linux/loop.h
+static LOOP_CONTROL_PATH: &str = "/dev/loop-control";
+
+const LOOP_SET_FD: libc::c_ulong = 0x4C00;
+const LOOP_CLR_FD: libc::c_ulong = 0x4C01;
+const LOOP_SET_STATUS64: libc::c_ulong = 0x4C04;
+const LOOP_SET_BLOCK_SIZE: libc::c_ulong = 0x4C09;
+const LOOP_CONFIGURE: libc::c_ulong = 0x4C0A;
+const LOOP_CTL_GET_FREE: libc::c_ulong = 0x4C82;
+pub(crate) const BLKRRPART: libc::c_ulong = 0x125F;
+
+pub const LO_FLAGS_READ_ONLY: u32 = 1;
+pub const LO_FLAGS_AUTOCLEAR: u32 = 4;
+pub const LO_FLAGS_PARTSCAN: u32 = 8;
+
+const LO_NAME_SIZE: usize = 64;
+const LO_KEY_SIZE: usize = 32;
+
+// Another process may claim the free device between LOOP_CTL_GET_FREE
+// and attaching the backing file
+const ATTACH_ATTEMPTS: usize = 8;
+
+#[repr(C)]
+struct LoopInfo64 {
+    lo_device: u64,
+    lo_inode: u64,
+    lo_rdevice: u64,
+    lo_offset: u64,
+    lo_sizelimit: u64,
+    lo_number: u32,
+    lo_encrypt_type: u32,
+    lo_encrypt_key_size: u32,
+    lo_flags: u32,
+    lo_file_name: [u8; LO_NAME_SIZE],
+    lo_crypt_name: [u8; LO_NAME_SIZE],
+    lo_encrypt_key: [u8; LO_KEY_SIZE],
+    lo_init: [u64; 2]
+}
+
+#[repr(C)]
+struct LoopConfig {
+    fd: u32,
+    block_size: u32,
+    info: LoopInfo64,
+    reserved: [u64; 8]
+}
+
+impl LoopInfo64 {
+    fn new(backing_file: &Path, flags: u32, offset: u64, size_limit: u64) -> Self {
+        let mut file_name = [0u8; LO_NAME_SIZE];
+        let bytes = backing_file.to_string_lossy();
+        let bytes = bytes.as_bytes();
+        // The name is informational and must remain null terminated
+        let length = bytes.len().min(LO_NAME_SIZE - 1);
+        file_name[..length].copy_from_slice(&bytes[..length]);
+        LoopInfo64 {
+            lo_device: 0,
+            lo_inode: 0,
+            lo_rdevice: 0,
+            lo_offset: offset,
+            lo_sizelimit: size_limit,
+            lo_number: 0,
+            lo_encrypt_type: 0,
+            lo_encrypt_key_size: 0,
+            lo_flags: flags,
+            lo_file_name: file_name,
+            lo_crypt_name: [0; LO_NAME_SIZE],
+            lo_encrypt_key: [0; LO_KEY_SIZE],
+            lo_init: [0; 2]
+        }
+    }
+}
+
+pub(crate) fn ioctl(file: &File, request: libc::c_ulong, arg: libc::c_ulong) -> io::Result<libc::c_int> {
+    let rc = unsafe { libc::ioctl(file.as_raw_fd(), request as _, arg) };
+    if rc < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(rc)
+    }
+}
+
+/// Options used when attaching an image file to a loop device
+#[derive(Debug, Clone)]
+pub struct LoopOptions {
+    read_only: bool,
+    partition_scan: bool,
+    sector_size: Option<u32>,
+    offset: u64,
+    size_limit: u64
+}
+
+impl Default for LoopOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LoopOptions {
+    pub fn new() -> Self {
+        Self {
+            read_only: false,
+            partition_scan: true,
+            sector_size: None,
+            offset: 0,
+            size_limit: 0
+        }
+    }
+
+    pub fn read_only(&mut self) -> &mut Self {
+        self.read_only = true;
+        self
+    }
+
+    /// Do not have the kernel create partition devices (loop0p1 ...)
+    pub fn no_partition_scan(&mut self) -> &mut Self {
+        self.partition_scan = false;
+        self
+    }
+
+    /// The logical block size of the loop device, 512 or 4096 for instance
+    pub fn sector_size(&mut self, sector_size: u32) -> &mut Self {
+        self.sector_size = Some(sector_size);
+        self
+    }
+
+    pub fn offset(&mut self, offset: u64) -> &mut Self {
+        self.offset = offset;
+        self
+    }
+
+    pub fn size_limit(&mut self, size_limit: u64) -> &mut Self {
+        self.size_limit = size_limit;
+        self
+    }
+
+    fn flags(&self) -> u32 {
+        let mut flags = 0;
+        if self.read_only {
+            flags |= LO_FLAGS_READ_ONLY;
+        }
+        if self.partition_scan {
+            flags |= LO_FLAGS_PARTSCAN;
+        }
+        flags
+    }
+
+    /// Attaches the image to the first free loop device
+    pub fn attach<P: AsRef<Path>>(&self, image: P) -> io::Result<LoopDevice> {
+        let image = image.as_ref();
+        let backing_file = OpenOptions::new()
+            .read(true)
+            .write(!self.read_only)
+            .open(image)?;
+        let control = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(LOOP_CONTROL_PATH)?;
+
+        let mut last_error = None;
+        for _ in 0..ATTACH_ATTEMPTS {
+            let number = ioctl(&control, LOOP_CTL_GET_FREE, 0)?;
+            let path = PathBuf::from(format!("/dev/loop{}", number));
+            let device = OpenOptions::new()
+                .read(true)
+                .write(!self.read_only)
+                .open(&path)?;
+            match self.configure(&device, &backing_file, image) {
+                Ok(()) => return Ok(LoopDevice {
+                    path,
+                    number: number as u32,
+                    device,
+                    attached: true
+                }),
+                Err(e) => {
+                    if e.raw_os_error() == Some(libc::EBUSY) {
+                        last_error = Some(e);
+                        continue;
+                    }
+                    return Err(e)
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| io::Error::other("no free loop device")))
+    }
+
+    fn configure(&self, device: &File, backing_file: &File, image: &Path) -> io::Result<()> {
+        let info = LoopInfo64::new(image, self.flags(), self.offset, self.size_limit);
+        let config = LoopConfig {
+            fd: backing_file.as_raw_fd() as u32,
+            block_size: self.sector_size.unwrap_or(0),
+            info,
+            reserved: [0; 8]
+        };
+        match ioctl(device, LOOP_CONFIGURE, &config as *const LoopConfig as libc::c_ulong) {
+            Ok(_) => return Ok(()),
+            // LOOP_CONFIGURE was added in Linux 5.8
+            Err(ref e) if e.raw_os_error() == Some(libc::EINVAL) ||
+                e.raw_os_error() == Some(libc::ENOTTY) => (),
+            Err(e) => return Err(e)
+        }
+
+        ioctl(device, LOOP_SET_FD, backing_file.as_raw_fd() as libc::c_ulong)?;
+        let result = ioctl(device, LOOP_SET_STATUS64,
+                &config.info as *const LoopInfo64 as libc::c_ulong)
+            .and_then(|_| match self.sector_size {
+                Some(size) => ioctl(device, LOOP_SET_BLOCK_SIZE, size as libc::c_ulong),
+                None => Ok(0)
+            });
+        if let Err(e) = result {
+            let _ = ioctl(device, LOOP_CLR_FD, 0);
+            return Err(e)
+        }
+        Ok(())
+    }
+}
+
+/// An attached loop device, detached when dropped
+#[derive(Debug)]
+pub struct LoopDevice {
+    path: PathBuf,
+    number: u32,
+    device: File,
+    attached: bool
+}
+
+impl LoopDevice {
+    /// The device node, /dev/loop0 for instance
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn number(&self) -> u32 {
+        self.number
+    }
+
+    /// The kernel name used by sysfs and the disk inventory
+    pub fn name(&self) -> String {
+        format!("loop{}", self.number)
+    }
+
+    /// Asks the kernel to re-read the partition table after it was written
+    pub fn reread_partitions(&self) -> io::Result<()> {
+        ioctl(&self.device, BLKRRPART, 0).map(|_| ())
+    }
+
+    pub fn detach(mut self) -> io::Result<()> {
+        self.attached = false;
+        ioctl(&self.device, LOOP_CLR_FD, 0).map(|_| ())
+    }
+}
+
+impl Drop for LoopDevice {
+    fn drop(&mut self) {
+        if self.attached {
+            if let Err(e) = ioctl(&self.device, LOOP_CLR_FD, 0) {
+                warn!("Could not detach {}: {}", self.path.display(), e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_struct_layout() {
+        assert_eq!(std::mem::size_of::<LoopInfo64>(), 232);
+        assert_eq!(std::mem::size_of::<LoopConfig>(), 304);
+    }
+
+    #[test]
+    fn test_flags() {
+        assert_eq!(LoopOptions::new().flags(), LO_FLAGS_PARTSCAN);
+        assert_eq!(LoopOptions::new().read_only().no_partition_scan().flags(),
+            LO_FLAGS_READ_ONLY);
+    }
+
+    #[test]
+    fn test_attach() {
+        // Attaching requires CAP_SYS_ADMIN
+        if OpenOptions::new().write(true).open(LOOP_CONTROL_PATH).is_err() {
+            return
+        }
+        let image = std::env::temp_dir().join(format!("press-loop-{}.img", std::process::id()));
+        File::create(&image).unwrap().set_len(8 << 20).unwrap();
+
+        let device = LoopOptions::new().read_only().sector_size(4096).attach(&image).unwrap();
+        let sys = Path::new("/sys/block").join(device.name());
+        assert_eq!(crate::sysfs::read_u64(&sys.join("ro")).unwrap(), 1);
+        assert_eq!(crate::sysfs::read_u64(&sys.join("queue/logical_block_size")).unwrap(), 4096);
+        assert_eq!(crate::sysfs::read_string(&sys.join("loop/backing_file")).unwrap(),
+            image.to_string_lossy());
+        drop(device);
+        assert!(!sys.join("loop/backing_file").exists());
+
+        std::fs::remove_file(&image).unwrap();
+    }
+}
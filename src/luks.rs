@@ -0,0 +1,319 @@
+//! Reads LUKS1 and LUKS2 headers for inspection, without cryptsetup. LUKS1
+//! describes everything in its binary header. The binary header of LUKS2
+//! is followed by a JSON area describing the keyslots and segments.
+//! Header checksums are not verified.
+
+extern crate byteorder;
+extern crate serde;
+
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use byteorder::{BigEndian, ByteOrder};
+use serde::Serialize;
+use serde_json::Value;
+
+// Error Boiler plate
+#[derive(Debug)]
+pub struct LuksError {
+    details: String
+}
+
+impl LuksError {
+    pub fn new(msg: &str) -> Self {
+        Self {
+            details: msg.to_string()
+        }
+    }
+}
+
+impl fmt::Display for LuksError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.details)
+    }
+}
+
+impl Error for LuksError {
+    fn description(&self) -> &str {
+        &self.details
+    }
+}
+
+impl From<std::io::Error> for LuksError {
+    fn from(e: std::io::Error) -> Self {
+        LuksError::new(&e.to_string())
+    }
+}
+
+pub type LuksResult<T> = Result<T, LuksError>;
+// End Error boiler plate
+
+pub static LUKS_MAGIC: &[u8] = &[b'L', b'U', b'K', b'S', 0xba, 0xbe];
+/// The LUKS1 header and its eight keyslots
+pub static LUKS1_HEADER_SIZE: usize = 592;
+/// The binary part of a LUKS2 header, the JSON area follows it
+pub static LUKS2_BINARY_HEADER_SIZE: usize = 4096;
+static LUKS1_KEYSLOTS: usize = 8;
+static LUKS1_KEYSLOT_ACTIVE: u32 = 0x00ac_71f3;
+static SECTOR_SIZE: u64 = 512;
+
+pub fn is_luks(data: &[u8]) -> bool {
+    data.len() >= 8 && &data[..6] == LUKS_MAGIC
+}
+
+// Fixed length strings are null padded
+fn read_string(data: &[u8]) -> String {
+    let end = data.iter().position(|b| *b == 0).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..end]).into_owned()
+}
+
+/// A keyslot which holds a copy of the volume key
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Keyslot {
+    pub index: u32,
+    /// pbkdf2, argon2i or argon2id
+    pub kdf: String,
+    /// The hash of pbkdf2
+    pub hash: Option<String>,
+    /// pbkdf2 iterations, or the argon2 time cost
+    pub iterations: Option<u64>,
+    /// The argon2 memory cost in KiB
+    pub memory: Option<u64>
+}
+
+/// The parts of a LUKS header which describe the container
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LuksHeader {
+    pub version: u16,
+    pub uuid: String,
+    /// LUKS2 only
+    pub label: Option<String>,
+    /// aes-xts-plain64 for instance
+    pub cipher: String,
+    /// The volume key size in bits
+    pub key_size: u32,
+    /// Offset of the encrypted data from the start of the device
+    pub payload_offset: u64,
+    /// Active keyslots only
+    pub keyslots: Vec<Keyslot>
+}
+
+fn json_u64(value: &Value) -> Option<u64> {
+    // Offsets and sizes are strings since JSON numbers lose precision
+    match value {
+        Value::String(s) => s.parse().ok(),
+        value => value.as_u64()
+    }
+}
+
+impl LuksHeader {
+    /// Parses a LUKS1 header
+    pub fn from_slice(data: &[u8]) -> LuksResult<LuksHeader> {
+        if !is_luks(data) || data.len() < LUKS1_HEADER_SIZE {
+            return Err(LuksError::new("no LUKS header"))
+        }
+        let version = BigEndian::read_u16(&data[6..8]);
+        if version != 1 {
+            return Err(LuksError::new(&format!("LUKS{} headers need the JSON area", version)))
+        }
+        let hash = read_string(&data[72..104]);
+        let keyslots = data[208..208 + LUKS1_KEYSLOTS * 48].chunks_exact(48)
+            .enumerate()
+            .filter(|(_, slot)| BigEndian::read_u32(&slot[..4]) == LUKS1_KEYSLOT_ACTIVE)
+            .map(|(index, slot)| Keyslot {
+                index: index as u32,
+                kdf: "pbkdf2".to_owned(),
+                hash: Some(hash.clone()),
+                iterations: Some(BigEndian::read_u32(&slot[4..8]) as u64),
+                memory: None
+            })
+            .collect();
+        Ok(LuksHeader {
+            version,
+            uuid: read_string(&data[168..208]),
+            label: None,
+            cipher: format!("{}-{}", read_string(&data[8..40]), read_string(&data[40..72])),
+            key_size: BigEndian::read_u32(&data[108..112]) * 8,
+            payload_offset: BigEndian::read_u32(&data[104..108]) as u64 * SECTOR_SIZE,
+            keyslots
+        })
+    }
+
+    /// Parses a LUKS2 binary header and the JSON metadata which follows it
+    pub fn from_luks2(binary: &[u8], json: &str) -> LuksResult<LuksHeader> {
+        if !is_luks(binary) || binary.len() < 256 {
+            return Err(LuksError::new("no LUKS header"))
+        }
+        let metadata: Value = serde_json::from_str(json.trim_end_matches('\0'))
+            .map_err(|e| LuksError::new(&format!("invalid LUKS2 metadata: {}", e)))?;
+
+        // The first crypt segment holds the data
+        let segment = metadata["segments"].as_object()
+            .and_then(|segments| segments.values().find(|s| s["type"] == "crypt"))
+            .ok_or_else(|| LuksError::new("LUKS2 metadata has no crypt segment"))?;
+
+        let mut keyslots: Vec<Keyslot> = metadata["keyslots"].as_object()
+            .map(|keyslots| keyslots.iter()
+                .filter_map(|(index, keyslot)| {
+                    let kdf = &keyslot["kdf"];
+                    Some(Keyslot {
+                        index: index.parse().ok()?,
+                        kdf: kdf["type"].as_str()?.to_owned(),
+                        hash: kdf["hash"].as_str().map(str::to_owned),
+                        iterations: kdf["iterations"].as_u64().or_else(|| kdf["time"].as_u64()),
+                        memory: kdf["memory"].as_u64()
+                    })
+                })
+                .collect())
+            .unwrap_or_default();
+        keyslots.sort_by_key(|keyslot| keyslot.index);
+
+        let key_size = metadata["keyslots"].as_object()
+            .and_then(|keyslots| keyslots.values().find_map(|k| k["key_size"].as_u64()))
+            .unwrap_or(0) as u32 * 8;
+        let label = read_string(&binary[24..72]);
+        Ok(LuksHeader {
+            version: BigEndian::read_u16(&binary[6..8]),
+            uuid: read_string(&binary[168..208]),
+            label: if label.is_empty() { None } else { Some(label) },
+            cipher: segment["encryption"].as_str().unwrap_or_default().to_owned(),
+            key_size,
+            payload_offset: json_u64(&segment["offset"]).unwrap_or(0),
+            keyslots
+        })
+    }
+
+    /// Reads the header at offset of reader, None when there is none
+    pub fn from_reader<R>(reader: &mut R, offset: u64) -> LuksResult<Option<LuksHeader>>
+            where R: Read + Seek {
+        let mut binary = vec![0u8; LUKS2_BINARY_HEADER_SIZE];
+        reader.seek(SeekFrom::Start(offset))?;
+        let length = reader.read(&mut binary)?;
+        if length < LUKS1_HEADER_SIZE || !is_luks(&binary) {
+            return Ok(None)
+        }
+        match BigEndian::read_u16(&binary[6..8]) {
+            1 => LuksHeader::from_slice(&binary).map(Some),
+            2 => {
+                let header_size = BigEndian::read_u64(&binary[8..16]);
+                if header_size <= LUKS2_BINARY_HEADER_SIZE as u64 || header_size > 4 << 20 {
+                    return Err(LuksError::new(&format!(
+                        "invalid LUKS2 header size {}", header_size)))
+                }
+                let mut json = vec![0u8; header_size as usize - LUKS2_BINARY_HEADER_SIZE];
+                reader.seek(SeekFrom::Start(offset + LUKS2_BINARY_HEADER_SIZE as u64))?;
+                reader.read_exact(&mut json)?;
+                let end = json.iter().position(|b| *b == 0).unwrap_or(json.len());
+                LuksHeader::from_luks2(&binary, &String::from_utf8_lossy(&json[..end])).map(Some)
+            },
+            version => Err(LuksError::new(&format!("unsupported LUKS version {}", version)))
+        }
+    }
+
+    pub fn from_device(device: &Path) -> LuksResult<Option<LuksHeader>> {
+        LuksHeader::from_reader(&mut File::open(device)?, 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    static UUID: &str = "5f0c8a6e-2a1d-4e7b-9c3f-8b1e2d4a6c90";
+
+    fn luks1() -> Vec<u8> {
+        let mut data = vec![0u8; 4096];
+        data[..6].copy_from_slice(LUKS_MAGIC);
+        BigEndian::write_u16(&mut data[6..8], 1);
+        data[8..11].copy_from_slice(b"aes");
+        data[40..51].copy_from_slice(b"xts-plain64");
+        data[72..78].copy_from_slice(b"sha256");
+        BigEndian::write_u32(&mut data[104..108], 4096);
+        BigEndian::write_u32(&mut data[108..112], 64);
+        data[168..204].copy_from_slice(UUID.as_bytes());
+        for (index, active) in [true, false, true].iter().enumerate() {
+            let slot = 208 + index * 48;
+            BigEndian::write_u32(&mut data[slot..slot + 4],
+                if *active { LUKS1_KEYSLOT_ACTIVE } else { 0x0000_dead });
+            BigEndian::write_u32(&mut data[slot + 4..slot + 8], 1_000_000 + index as u32);
+        }
+        data
+    }
+
+    static LUKS2_METADATA: &str = r#"{
+        "keyslots": {
+            "1": {"type": "luks2", "key_size": 64,
+                  "kdf": {"type": "pbkdf2", "hash": "sha512", "iterations": 900000}},
+            "0": {"type": "luks2", "key_size": 64,
+                  "kdf": {"type": "argon2id", "time": 4, "memory": 1048576, "cpus": 4}}
+        },
+        "segments": {
+            "0": {"type": "crypt", "offset": "16777216", "size": "dynamic",
+                  "iv_tweak": "0", "encryption": "aes-xts-plain64", "sector_size": 4096}
+        },
+        "digests": {},
+        "config": {"json_size": "12288", "keyslots_size": "16744448"}
+    }"#;
+
+    fn luks2() -> Vec<u8> {
+        let mut data = vec![0u8; 16384 * 2];
+        data[..6].copy_from_slice(LUKS_MAGIC);
+        BigEndian::write_u16(&mut data[6..8], 2);
+        BigEndian::write_u64(&mut data[8..16], 16384);
+        data[24..28].copy_from_slice(b"data");
+        data[72..78].copy_from_slice(b"sha256");
+        data[168..204].copy_from_slice(UUID.as_bytes());
+        data[4096..4096 + LUKS2_METADATA.len()].copy_from_slice(LUKS2_METADATA.as_bytes());
+        data
+    }
+
+    #[test]
+    fn test_luks1() {
+        let header = LuksHeader::from_slice(&luks1()).unwrap();
+        assert_eq!(header.version, 1);
+        assert_eq!(header.uuid, UUID);
+        assert_eq!(header.cipher, "aes-xts-plain64");
+        assert_eq!(header.key_size, 512);
+        assert_eq!(header.payload_offset, 2 << 20);
+        assert_eq!(header.keyslots.iter().map(|k| k.index).collect::<Vec<u32>>(), vec![0, 2]);
+        assert_eq!(header.keyslots[1].iterations, Some(1_000_002));
+        assert_eq!(header.keyslots[1].hash.as_deref(), Some("sha256"));
+    }
+
+    #[test]
+    fn test_luks2() {
+        let header = LuksHeader::from_reader(&mut Cursor::new(luks2()), 0).unwrap().unwrap();
+        assert_eq!(header.version, 2);
+        assert_eq!(header.uuid, UUID);
+        assert_eq!(header.label.as_deref(), Some("data"));
+        assert_eq!(header.cipher, "aes-xts-plain64");
+        assert_eq!(header.key_size, 512);
+        assert_eq!(header.payload_offset, 16 << 20);
+        assert_eq!(header.keyslots, vec![
+            Keyslot { index: 0, kdf: "argon2id".to_owned(), hash: None,
+                      iterations: Some(4), memory: Some(1048576) },
+            Keyslot { index: 1, kdf: "pbkdf2".to_owned(), hash: Some("sha512".to_owned()),
+                      iterations: Some(900000), memory: None },
+        ]);
+    }
+
+    #[test]
+    fn test_from_reader() {
+        let mut data = vec![0u8; 4096];
+        data.extend(luks1());
+        let mut reader = Cursor::new(data);
+        assert!(LuksHeader::from_reader(&mut reader, 0).unwrap().is_none());
+        assert_eq!(LuksHeader::from_reader(&mut reader, 4096).unwrap().unwrap().version, 1);
+
+        let mut data = luks2();
+        data[4096] = b'[';
+        assert!(LuksHeader::from_reader(&mut Cursor::new(data), 0).unwrap_err()
+            .to_string().starts_with("invalid LUKS2 metadata"));
+    }
+}
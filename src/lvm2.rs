@@ -0,0 +1,786 @@
+//! Reads LVM2 physical volume labels and volume group metadata directly
+//! from disk, without the lvm tools. The label is found in one of the
+//! first four sectors, it points to the PV header which lists the metadata
+//! areas. Each metadata area holds a circular buffer of text metadata.
+
+extern crate byteorder;
+extern crate serde;
+
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use byteorder::{ByteOrder, LittleEndian};
+use serde::Serialize;
+
+use crate::sysfs::{self, SysRoot};
+
+// Error Boiler plate
+#[derive(Debug)]
+pub struct Lvm2Error {
+    details: String
+}
+
+impl Lvm2Error {
+    pub fn new(msg: &str) -> Self {
+        Self {
+            details: msg.to_string()
+        }
+    }
+}
+
+impl fmt::Display for Lvm2Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.details)
+    }
+}
+
+impl Error for Lvm2Error {
+    fn description(&self) -> &str {
+        &self.details
+    }
+}
+
+impl From<std::io::Error> for Lvm2Error {
+    fn from(e: std::io::Error) -> Self {
+        Lvm2Error::new(&e.to_string())
+    }
+}
+
+pub type Lvm2Result<T> = Result<T, Lvm2Error>;
+// End Error boiler plate
+
+pub static LABEL_ID: &[u8] = b"LABELONE";
+pub static LABEL_TYPE: &[u8] = b"LVM2 001";
+pub static MDA_MAGIC: &[u8] = b" LVM2 x[5A%r0N*>";
+static LABEL_SCAN_SECTORS: u64 = 4;
+static SECTOR_SIZE: u64 = 512;
+static MDA_HEADER_SIZE: u64 = 512;
+static INITIAL_CRC: u32 = 0xf597_a6cf;
+
+/// The crc32 variant used by LVM, without the final inversion
+pub fn calc_crc(initial: u32, data: &[u8]) -> u32 {
+    data.iter().fold(initial, |crc, byte| {
+        let mut crc = crc ^ *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+        crc
+    })
+}
+
+/// LVM formats its 32 character ids in groups of 6-4-4-4-4-4-6, None unless
+/// the id is alphanumeric
+pub(crate) fn format_id(data: &[u8]) -> Option<String> {
+    let id = &data[..32];
+    if !id.iter().all(u8::is_ascii_alphanumeric) {
+        return None
+    }
+    let mut formatted = String::with_capacity(38);
+    let mut start = 0;
+    for length in &[6, 4, 4, 4, 4, 4, 6] {
+        if start > 0 {
+            formatted.push('-');
+        }
+        formatted.extend(id[start..start + length].iter().map(|b| *b as char));
+        start += length;
+    }
+    Some(formatted)
+}
+
+// A list of (offset, size) pairs terminated by a zeroed pair
+fn read_locations(data: &[u8], offset: &mut usize) -> Vec<(u64, u64)> {
+    let mut locations = Vec::new();
+    while *offset + 16 <= data.len() {
+        let location = (LittleEndian::read_u64(&data[*offset..*offset + 8]),
+                        LittleEndian::read_u64(&data[*offset + 8..*offset + 16]));
+        *offset += 16;
+        if location.0 == 0 {
+            break
+        }
+        locations.push(location);
+    }
+    locations
+}
+
+/// The label and PV header of a physical volume
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PvLabel {
+    /// The sector the label was found in
+    pub sector: u64,
+    pub pv_uuid: String,
+    pub device_size: u64,
+    /// Offsets and sizes in bytes of the data areas, where extents live
+    pub data_areas: Vec<(u64, u64)>,
+    /// Offsets and sizes in bytes of the metadata areas
+    pub metadata_areas: Vec<(u64, u64)>
+}
+
+impl PvLabel {
+    // slice containing the sector with the label
+    pub fn from_slice(data: &[u8]) -> Lvm2Result<PvLabel> {
+        if data.len() < SECTOR_SIZE as usize || &data[..8] != LABEL_ID || &data[24..32] != LABEL_TYPE {
+            return Err(Lvm2Error::new("no LVM2 label found"))
+        }
+        let crc = LittleEndian::read_u32(&data[16..20]);
+        if calc_crc(INITIAL_CRC, &data[20..SECTOR_SIZE as usize]) != crc {
+            return Err(Lvm2Error::new("LVM2 label checksum mismatch"))
+        }
+        let mut offset = LittleEndian::read_u32(&data[20..24]) as usize;
+        if offset + 40 > data.len() {
+            return Err(Lvm2Error::new("LVM2 PV header is outside of the label sector"))
+        }
+        let pv_uuid = format_id(&data[offset..offset + 32])
+            .ok_or_else(|| Lvm2Error::new("LVM2 PV uuid is not alphanumeric"))?;
+        let device_size = LittleEndian::read_u64(&data[offset + 32..offset + 40]);
+        offset += 40;
+        let data_areas = read_locations(data, &mut offset);
+        let metadata_areas = read_locations(data, &mut offset);
+        Ok(PvLabel {
+            sector: LittleEndian::read_u64(&data[8..16]),
+            pv_uuid,
+            device_size,
+            data_areas,
+            metadata_areas
+        })
+    }
+
+    /// Looks for a label in the first sectors of the physical volume
+    /// starting at offset. None when there is no LVM2 label.
+    pub fn from_reader<R>(reader: &mut R, offset: u64) -> Lvm2Result<Option<PvLabel>>
+            where R: Read + Seek {
+        let mut sectors = vec![0u8; (LABEL_SCAN_SECTORS * SECTOR_SIZE) as usize];
+        reader.seek(SeekFrom::Start(offset))?;
+        let length = reader.read(&mut sectors)?;
+        for sector in sectors[..length].chunks_exact(SECTOR_SIZE as usize) {
+            if &sector[..8] == LABEL_ID && &sector[24..32] == LABEL_TYPE {
+                return PvLabel::from_slice(sector).map(Some)
+            }
+        }
+        Ok(None)
+    }
+}
+
+// The text of the current metadata, which may wrap around the end of the
+// circular buffer back to just after the area header
+fn read_metadata_text<R>(reader: &mut R, pv_offset: u64, area: (u64, u64))
+        -> Lvm2Result<Option<String>>
+            where R: Read + Seek {
+    let (area_offset, area_size) = area;
+    let mut header = vec![0u8; MDA_HEADER_SIZE as usize];
+    reader.seek(SeekFrom::Start(pv_offset + area_offset))?;
+    reader.read_exact(&mut header)?;
+    if &header[4..20] != MDA_MAGIC {
+        return Err(Lvm2Error::new("LVM2 metadata area header not found"))
+    }
+    if calc_crc(INITIAL_CRC, &header[4..]) != LittleEndian::read_u32(&header[..4]) {
+        return Err(Lvm2Error::new("LVM2 metadata area header checksum mismatch"))
+    }
+    let offset = LittleEndian::read_u64(&header[40..48]);
+    let size = LittleEndian::read_u64(&header[48..56]);
+    let checksum = LittleEndian::read_u32(&header[56..60]);
+    // An orphan PV, not in any volume group
+    if offset == 0 || size == 0 {
+        return Ok(None)
+    }
+    if offset >= area_size || size > area_size - MDA_HEADER_SIZE {
+        return Err(Lvm2Error::new("LVM2 metadata location is outside of the metadata area"))
+    }
+    let mut text = vec![0u8; size as usize];
+    let first = std::cmp::min(size, area_size - offset) as usize;
+    reader.seek(SeekFrom::Start(pv_offset + area_offset + offset))?;
+    reader.read_exact(&mut text[..first])?;
+    if first < text.len() {
+        reader.seek(SeekFrom::Start(pv_offset + area_offset + MDA_HEADER_SIZE))?;
+        reader.read_exact(&mut text[first..])?;
+    }
+    if calc_crc(INITIAL_CRC, &text) != checksum {
+        return Err(Lvm2Error::new("LVM2 metadata checksum mismatch"))
+    }
+    let end = text.iter().position(|b| *b == 0).unwrap_or(text.len());
+    Ok(Some(String::from_utf8_lossy(&text[..end]).into_owned()))
+}
+
+/// A value in the text metadata
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Number(i64),
+    String(String),
+    Array(Vec<Value>),
+    Section(Vec<(String, Value)>)
+}
+
+impl Value {
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Section(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None
+        }
+    }
+
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            Value::Number(n) if *n >= 0 => Some(*n as u64),
+            _ => None
+        }
+    }
+
+    pub fn sections(&self) -> Vec<(&str, &Value)> {
+        match self {
+            Value::Section(entries) => entries.iter()
+                .filter(|(_, v)| matches!(v, Value::Section(_)))
+                .map(|(k, v)| (k.as_str(), v))
+                .collect(),
+            _ => Vec::new()
+        }
+    }
+
+    fn strings(&self) -> Vec<String> {
+        match self {
+            Value::Array(values) => values.iter()
+                .filter_map(|v| v.as_str().map(str::to_owned))
+                .collect(),
+            _ => Vec::new()
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Identifier(String),
+    String(String),
+    Number(i64),
+    Symbol(char)
+}
+
+fn tokenize(text: &str) -> Lvm2Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            _ if c.is_whitespace() => {
+                chars.next();
+            },
+            '#' => {
+                while chars.peek().map(|c| *c != '\n').unwrap_or(false) {
+                    chars.next();
+                }
+            },
+            '{' | '}' | '[' | ']' | '=' | ',' => {
+                tokens.push(Token::Symbol(c));
+                chars.next();
+            },
+            '"' => {
+                chars.next();
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => value.extend(chars.next()),
+                        Some(c) => value.push(c),
+                        None => return Err(Lvm2Error::new("unterminated string in LVM2 metadata"))
+                    }
+                }
+                tokens.push(Token::String(value));
+            },
+            _ if c.is_ascii_digit() || c == '-' => {
+                let mut value = String::new();
+                while let Some(&c) = chars.peek() {
+                    if !(c.is_ascii_digit() || c == '-' || c == '.') {
+                        break
+                    }
+                    value.push(c);
+                    chars.next();
+                }
+                // Floats only appear in settings press does not use
+                let number = value.split('.').next().unwrap_or_default().parse().map_err(|_|
+                    Lvm2Error::new(&format!("invalid number {} in LVM2 metadata", value)))?;
+                tokens.push(Token::Number(number));
+            },
+            _ if c.is_alphanumeric() || "_.+-".contains(c) => {
+                let mut value = String::new();
+                while let Some(&c) = chars.peek() {
+                    if !(c.is_alphanumeric() || "_.+-".contains(c)) {
+                        break
+                    }
+                    value.push(c);
+                    chars.next();
+                }
+                tokens.push(Token::Identifier(value));
+            },
+            _ => return Err(Lvm2Error::new(&format!("unexpected {} in LVM2 metadata", c)))
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse_value(tokens: &[Token], position: &mut usize) -> Lvm2Result<Value> {
+    let token = tokens.get(*position)
+        .ok_or_else(|| Lvm2Error::new("unexpected end of LVM2 metadata"))?;
+    *position += 1;
+    match token {
+        Token::Number(n) => Ok(Value::Number(*n)),
+        Token::String(s) => Ok(Value::String(s.clone())),
+        Token::Symbol('[') => {
+            let mut values = Vec::new();
+            loop {
+                match tokens.get(*position) {
+                    Some(Token::Symbol(']')) => {
+                        *position += 1;
+                        return Ok(Value::Array(values))
+                    },
+                    Some(Token::Symbol(',')) => *position += 1,
+                    Some(_) => values.push(parse_value(tokens, position)?),
+                    None => return Err(Lvm2Error::new("unterminated array in LVM2 metadata"))
+                }
+            }
+        },
+        _ => Err(Lvm2Error::new(&format!("unexpected {:?} in LVM2 metadata", token)))
+    }
+}
+
+fn parse_section(tokens: &[Token], position: &mut usize, nested: bool)
+        -> Lvm2Result<Vec<(String, Value)>> {
+    let mut entries = Vec::new();
+    loop {
+        let key = match tokens.get(*position) {
+            Some(Token::Identifier(key)) => key.clone(),
+            Some(Token::Symbol('}')) if nested => {
+                *position += 1;
+                return Ok(entries)
+            },
+            None if !nested => return Ok(entries),
+            token => return Err(Lvm2Error::new(&format!(
+                "unexpected {:?} in LVM2 metadata", token)))
+        };
+        *position += 1;
+        match tokens.get(*position) {
+            Some(Token::Symbol('=')) => {
+                *position += 1;
+                let value = parse_value(tokens, position)?;
+                entries.push((key, value));
+            },
+            Some(Token::Symbol('{')) => {
+                *position += 1;
+                let section = parse_section(tokens, position, true)?;
+                entries.push((key, Value::Section(section)));
+            },
+            token => return Err(Lvm2Error::new(&format!(
+                "expected = or {{ after {} in LVM2 metadata, found {:?}", key, token)))
+        }
+    }
+}
+
+/// Parses the text metadata format into a tree of values
+pub fn parse_metadata(text: &str) -> Lvm2Result<Value> {
+    let tokens = tokenize(text)?;
+    let mut position = 0;
+    parse_section(&tokens, &mut position, false).map(Value::Section)
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PvMetadata {
+    /// The name within the volume group, pv0
+    pub name: String,
+    pub id: String,
+    /// The device the PV was last seen as, a hint only
+    pub device: Option<String>,
+    pub status: Vec<String>,
+    pub dev_size: u64,
+    pub pe_start: u64,
+    pub pe_count: u64
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SegmentMetadata {
+    pub start_extent: u64,
+    pub extent_count: u64,
+    pub segment_type: String,
+    /// PV names and the extent each stripe starts at
+    pub stripes: Vec<(String, u64)>
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LvMetadata {
+    pub name: String,
+    pub id: String,
+    pub status: Vec<String>,
+    pub segments: Vec<SegmentMetadata>
+}
+
+impl LvMetadata {
+    pub fn extent_count(&self) -> u64 {
+        self.segments.iter().map(|s| s.extent_count).sum()
+    }
+}
+
+/// A volume group, sizes are in bytes
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VgMetadata {
+    pub name: String,
+    pub id: String,
+    pub seqno: u64,
+    pub status: Vec<String>,
+    pub extent_size: u64,
+    pub physical_volumes: Vec<PvMetadata>,
+    pub logical_volumes: Vec<LvMetadata>
+}
+
+fn required<'a>(section: &'a Value, key: &str, name: &str) -> Lvm2Result<&'a Value> {
+    section.get(key).ok_or_else(|| Lvm2Error::new(&format!(
+        "{} is missing {} in LVM2 metadata", name, key)))
+}
+
+fn required_u64(section: &Value, key: &str, name: &str) -> Lvm2Result<u64> {
+    required(section, key, name)?.as_u64().ok_or_else(|| Lvm2Error::new(&format!(
+        "{} of {} is not a number in LVM2 metadata", key, name)))
+}
+
+fn required_str(section: &Value, key: &str, name: &str) -> Lvm2Result<String> {
+    required(section, key, name)?.as_str().map(str::to_owned).ok_or_else(|| Lvm2Error::new(
+        &format!("{} of {} is not a string in LVM2 metadata", key, name)))
+}
+
+fn status(section: &Value) -> Vec<String> {
+    section.get("status").map(Value::strings).unwrap_or_default()
+}
+
+impl VgMetadata {
+    /// Builds the volume group from parsed metadata, the volume group is
+    /// the one section at the top level
+    pub fn from_value(value: &Value) -> Lvm2Result<VgMetadata> {
+        let (name, vg) = match value.sections().first() {
+            Some(section) => *section,
+            None => return Err(Lvm2Error::new("no volume group found in LVM2 metadata"))
+        };
+        let sectors = |sectors: u64| sectors * SECTOR_SIZE;
+
+        let mut physical_volumes = Vec::new();
+        if let Some(pvs) = vg.get("physical_volumes") {
+            for (pv_name, pv) in pvs.sections() {
+                physical_volumes.push(PvMetadata {
+                    name: pv_name.to_owned(),
+                    id: required_str(pv, "id", pv_name)?,
+                    device: pv.get("device").and_then(Value::as_str).map(str::to_owned),
+                    status: status(pv),
+                    dev_size: sectors(pv.get("dev_size").and_then(Value::as_u64).unwrap_or(0)),
+                    pe_start: sectors(required_u64(pv, "pe_start", pv_name)?),
+                    pe_count: required_u64(pv, "pe_count", pv_name)?
+                });
+            }
+        }
+
+        let mut logical_volumes = Vec::new();
+        if let Some(lvs) = vg.get("logical_volumes") {
+            for (lv_name, lv) in lvs.sections() {
+                let mut segments = Vec::new();
+                for (segment_name, segment) in lv.sections() {
+                    let stripes = match segment.get("stripes") {
+                        Some(Value::Array(values)) => values.chunks(2)
+                            .filter_map(|pair| match pair {
+                                [Value::String(pv), Value::Number(extent)] =>
+                                    Some((pv.clone(), *extent as u64)),
+                                _ => None
+                            })
+                            .collect(),
+                        _ => Vec::new()
+                    };
+                    segments.push(SegmentMetadata {
+                        start_extent: required_u64(segment, "start_extent", segment_name)?,
+                        extent_count: required_u64(segment, "extent_count", segment_name)?,
+                        segment_type: required_str(segment, "type", segment_name)?,
+                        stripes
+                    });
+                }
+                logical_volumes.push(LvMetadata {
+                    name: lv_name.to_owned(),
+                    id: required_str(lv, "id", lv_name)?,
+                    status: status(lv),
+                    segments
+                });
+            }
+        }
+
+        Ok(VgMetadata {
+            name: name.to_owned(),
+            id: required_str(vg, "id", name)?,
+            seqno: required_u64(vg, "seqno", name)?,
+            status: status(vg),
+            extent_size: sectors(required_u64(vg, "extent_size", name)?),
+            physical_volumes,
+            logical_volumes
+        })
+    }
+
+    pub fn from_text(text: &str) -> Lvm2Result<VgMetadata> {
+        VgMetadata::from_value(&parse_metadata(text)?)
+    }
+
+    pub fn size(&self) -> u64 {
+        self.physical_volumes.iter().map(|pv| pv.pe_count).sum::<u64>() * self.extent_size
+    }
+
+    pub fn free_extents(&self) -> u64 {
+        let used: u64 = self.logical_volumes.iter().map(LvMetadata::extent_count).sum();
+        self.physical_volumes.iter().map(|pv| pv.pe_count).sum::<u64>().saturating_sub(used)
+    }
+
+    /// The device mapper name of a logical volume, with dashes doubled
+    pub fn dm_name(&self, logical_volume: &str) -> String {
+        format!("{}-{}", self.name.replace('-', "--"), logical_volume.replace('-', "--"))
+    }
+
+    /// Logical volumes of this group which are currently mapped
+    pub fn active_logical_volumes(&self, root: &SysRoot) -> Vec<String> {
+        let mapped: Vec<String> = root.block_devices().unwrap_or_default().iter()
+            .filter_map(|entry| sysfs::read_optional_string(&entry.path().join("dm/name")))
+            .collect();
+        self.logical_volumes.iter()
+            .filter(|lv| mapped.contains(&self.dm_name(&lv.name)))
+            .map(|lv| lv.name.clone())
+            .collect()
+    }
+}
+
+/// What LVM2 has recorded on a physical volume
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PhysicalVolume {
+    pub label: PvLabel,
+    /// None for a PV which does not belong to a volume group
+    pub volume_group: Option<VgMetadata>
+}
+
+impl PhysicalVolume {
+    /// Reads the physical volume starting at offset, None when there is no
+    /// LVM2 label. The first readable metadata area is used.
+    pub fn from_reader<R>(reader: &mut R, offset: u64) -> Lvm2Result<Option<PhysicalVolume>>
+            where R: Read + Seek {
+        let label = match PvLabel::from_reader(reader, offset)? {
+            Some(label) => label,
+            None => return Ok(None)
+        };
+        let mut volume_group = None;
+        let mut last_error = None;
+        for area in label.metadata_areas.iter() {
+            match read_metadata_text(reader, offset, *area) {
+                Ok(Some(text)) => {
+                    volume_group = Some(VgMetadata::from_text(&text)?);
+                    break
+                },
+                Ok(None) => break,
+                Err(e) => {
+                    warn!("could not read metadata area at {}: {}", area.0, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        if let (None, Some(e)) = (&volume_group, last_error) {
+            return Err(e)
+        }
+        Ok(Some(PhysicalVolume { label, volume_group }))
+    }
+
+    pub fn from_device(device: &Path) -> Lvm2Result<Option<PhysicalVolume>> {
+        PhysicalVolume::from_reader(&mut File::open(device)?, 0)
+    }
+
+    pub fn volume_group_name(&self) -> Option<&str> {
+        self.volume_group.as_ref().map(|vg| vg.name.as_str())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    pub(crate) static METADATA: &str = r#"vg0 {
+id = "Wb6tXs-9dA1-Yx2E-hD3c-Qq7R-uM0p-k4LzNn"
+seqno = 3
+format = "lvm2" # informational
+status = ["RESIZEABLE", "READ", "WRITE"]
+flags = []
+extent_size = 8192
+max_lv = 0
+max_pv = 0
+metadata_copies = 0
+
+physical_volumes {
+
+pv0 {
+id = "Ab3dEf-0123-4567-89ab-cdef-GHIJ-KLmnop"
+device = "/dev/sda2"	# Hint only
+
+status = ["ALLOCATABLE"]
+flags = []
+dev_size = 2097152
+pe_start = 2048
+pe_count = 255
+}
+}
+
+logical_volumes {
+
+root {
+id = "zZ9yY8-xX7w-W6vV-5uU4-tT3s-S2rR-1qQ0pP"
+status = ["READ", "WRITE", "VISIBLE"]
+flags = []
+creation_time = 1700000000
+creation_host = "press \"test\""
+segment_count = 1
+
+segment1 {
+start_extent = 0
+extent_count = 100
+type = "striped"
+stripe_count = 1	# linear
+
+stripes = [
+"pv0", 0
+]
+}
+}
+}
+
+}
+# Generated by LVM2 version 2.03.16(2) (2022-05-18): Mon Nov 14 22:13:20 2023
+
+contents = "Text Format Volume Group"
+version = 1
+
+description = ""
+
+creation_host = "press"	# Linux press 6.1.0 #1 SMP x86_64
+creation_time = 1700000000	# Mon Nov 14 22:13:20 2023
+"#;
+
+    /// A physical volume as pvcreate and vgcreate would lay it out, with
+    /// the metadata text wrapped around the end of the circular buffer
+    /// when wrap is set
+    pub(crate) fn physical_volume(metadata: Option<&str>, wrap: bool) -> Vec<u8> {
+        let size = 1 << 20;
+        let mut data = vec![0u8; size];
+        let (mda_offset, mda_size) = (4096usize, 8192usize);
+
+        let label = &mut data[512..1024];
+        label[..8].copy_from_slice(LABEL_ID);
+        label[8..16].copy_from_slice(&1u64.to_le_bytes());
+        label[20..24].copy_from_slice(&32u32.to_le_bytes());
+        label[24..32].copy_from_slice(LABEL_TYPE);
+        label[32..64].copy_from_slice(b"Ab3dEf0123456789abcdefGHIJKLmnop");
+        label[64..72].copy_from_slice(&(size as u64).to_le_bytes());
+        // one data area, one metadata area
+        label[72..80].copy_from_slice(&(1u64 << 20).to_le_bytes());
+        label[104..112].copy_from_slice(&(mda_offset as u64).to_le_bytes());
+        label[112..120].copy_from_slice(&(mda_size as u64).to_le_bytes());
+        let crc = calc_crc(INITIAL_CRC, &label[20..]);
+        label[16..20].copy_from_slice(&crc.to_le_bytes());
+
+        let mut header = vec![0u8; 512];
+        header[4..20].copy_from_slice(MDA_MAGIC);
+        header[20..24].copy_from_slice(&1u32.to_le_bytes());
+        header[24..32].copy_from_slice(&(mda_offset as u64).to_le_bytes());
+        header[32..40].copy_from_slice(&(mda_size as u64).to_le_bytes());
+        if let Some(text) = metadata {
+            let text = text.as_bytes();
+            let offset = if wrap { mda_size - 100 } else { 512 };
+            for (index, byte) in text.iter().enumerate() {
+                let mut position = offset + index;
+                if position >= mda_size {
+                    position = position - mda_size + 512;
+                }
+                data[mda_offset + position] = *byte;
+            }
+            header[40..48].copy_from_slice(&(offset as u64).to_le_bytes());
+            header[48..56].copy_from_slice(&(text.len() as u64).to_le_bytes());
+            header[56..60].copy_from_slice(&calc_crc(INITIAL_CRC, text).to_le_bytes());
+        }
+        let crc = calc_crc(INITIAL_CRC, &header[4..]);
+        header[..4].copy_from_slice(&crc.to_le_bytes());
+        data[mda_offset..mda_offset + 512].copy_from_slice(&header);
+        data
+    }
+
+    #[test]
+    fn test_parse() {
+        let vg = VgMetadata::from_text(METADATA).unwrap();
+        assert_eq!(vg.name, "vg0");
+        assert_eq!(vg.seqno, 3);
+        assert_eq!(vg.extent_size, 4 << 20);
+        assert_eq!(vg.status, vec!["RESIZEABLE", "READ", "WRITE"]);
+        assert_eq!(vg.physical_volumes[0].device.as_deref(), Some("/dev/sda2"));
+        assert_eq!(vg.physical_volumes[0].pe_start, 1 << 20);
+        assert_eq!(vg.size(), 255 * (4 << 20));
+        assert_eq!(vg.free_extents(), 155);
+        assert_eq!(vg.logical_volumes[0].name, "root");
+        assert_eq!(vg.logical_volumes[0].segments[0].stripes, vec![("pv0".to_owned(), 0)]);
+        assert_eq!(vg.dm_name("root"), "vg0-root");
+
+        let value = parse_metadata(METADATA).unwrap();
+        assert_eq!(value.get("version"), Some(&Value::Number(1)));
+        assert_eq!(value.get("vg0").unwrap().get("logical_volumes").unwrap()
+            .get("root").unwrap().get("creation_host").unwrap().as_str(), Some("press \"test\""));
+
+        assert!(VgMetadata::from_text("vg0 { id = ").is_err());
+        assert!(VgMetadata::from_text("vg0 { id = \"x\" }").is_err());
+    }
+
+    #[test]
+    fn test_read() {
+        for wrap in &[false, true] {
+            let mut pv = Cursor::new(physical_volume(Some(METADATA), *wrap));
+            let pv = PhysicalVolume::from_reader(&mut pv, 0).unwrap().unwrap();
+            assert_eq!(pv.label.sector, 1);
+            assert_eq!(pv.label.pv_uuid, "Ab3dEf-0123-4567-89ab-cdef-GHIJ-KLmnop");
+            assert_eq!(pv.label.metadata_areas, vec![(4096, 8192)]);
+            assert_eq!(pv.volume_group_name(), Some("vg0"));
+        }
+
+        let mut orphan = Cursor::new(physical_volume(None, false));
+        let orphan = PhysicalVolume::from_reader(&mut orphan, 0).unwrap().unwrap();
+        assert!(orphan.volume_group.is_none());
+
+        assert!(PhysicalVolume::from_reader(&mut Cursor::new(vec![0u8; 4096]), 0).unwrap().is_none());
+
+        let mut corrupt = physical_volume(Some(METADATA), false);
+        corrupt[4096 + 600] ^= 0xff;
+        assert_eq!(PhysicalVolume::from_reader(&mut Cursor::new(corrupt), 0).unwrap_err().to_string(),
+            "LVM2 metadata checksum mismatch");
+
+        let mut corrupt = physical_volume(Some(METADATA), false);
+        corrupt[550..552].copy_from_slice("é".as_bytes());
+        let crc = calc_crc(INITIAL_CRC, &corrupt[532..1024]);
+        corrupt[528..532].copy_from_slice(&crc.to_le_bytes());
+        assert_eq!(PhysicalVolume::from_reader(&mut Cursor::new(corrupt), 0).unwrap_err().to_string(),
+            "LVM2 PV uuid is not alphanumeric");
+    }
+
+    #[test]
+    fn test_active() {
+        let root = std::env::temp_dir().join(format!("press-lvm2-{}", std::process::id()));
+        let dm = root.join("sys/block/dm-3/dm");
+        std::fs::create_dir_all(&dm).unwrap();
+        std::fs::write(dm.join("name"), "vg0-root\n").unwrap();
+
+        let vg = VgMetadata::from_text(METADATA).unwrap();
+        assert_eq!(vg.active_logical_volumes(&SysRoot::new(&root)), vec!["root"]);
+        std::fs::write(dm.join("name"), "other-root\n").unwrap();
+        assert!(vg.active_logical_volumes(&SysRoot::new(&root)).is_empty());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
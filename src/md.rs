@@ -0,0 +1,418 @@
+//! Reads Linux md superblocks directly from member devices, without mdadm.
+//! Version 0.90 and 1.0 superblocks are stored near the end of the member,
+//! 1.1 at the start and 1.2 4KiB from the start.
+
+extern crate byteorder;
+extern crate serde;
+
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use byteorder::{ByteOrder, LittleEndian};
+use serde::Serialize;
+
+use crate::layout::raid::MetadataVersion;
+use crate::sysfs::{self, SysRoot};
+
+// Error Boiler plate
+#[derive(Debug)]
+pub struct MdError {
+    details: String
+}
+
+impl MdError {
+    pub fn new(msg: &str) -> Self {
+        Self {
+            details: msg.to_string()
+        }
+    }
+}
+
+impl fmt::Display for MdError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.details)
+    }
+}
+
+impl Error for MdError {
+    fn description(&self) -> &str {
+        &self.details
+    }
+}
+
+impl From<std::io::Error> for MdError {
+    fn from(e: std::io::Error) -> Self {
+        MdError::new(&e.to_string())
+    }
+}
+
+pub type MdResult<T> = Result<T, MdError>;
+// End Error boiler plate
+
+pub static MD_MAGIC: u32 = 0xa92b_4efc;
+pub static MD_SUPERBLOCK_SIZE: usize = 4096;
+static SECTOR_SIZE: u64 = 512;
+
+// dev_roles values of v1 superblocks which are not a slot in the array
+static ROLE_SPARE: u16 = 0xffff;
+static ROLE_FAULTY: u16 = 0xfffe;
+static ROLE_JOURNAL: u16 = 0xfffd;
+
+// 0.90 disk descriptor state bits
+static DISK_FAULTY: u32 = 1;
+static DISK_SYNC: u32 = 1 << 2;
+
+/// Whether data holds an md superblock of the given major version
+pub fn is_md(data: &[u8], major_version: u32) -> bool {
+    data.len() >= 8 && LittleEndian::read_u32(&data[..4]) == MD_MAGIC &&
+        LittleEndian::read_u32(&data[4..8]) == major_version
+}
+
+/// Where each superblock version is stored on a member of size bytes
+pub fn superblock_offsets(size: u64) -> Vec<(MetadataVersion, u64)> {
+    let mut offsets = vec![(MetadataVersion::V1_1, 0), (MetadataVersion::V1_2, 4096)];
+    let sectors = size / SECTOR_SIZE;
+    if sectors >= 16 {
+        offsets.push((MetadataVersion::V1_0, ((sectors - 16) & !7) * SECTOR_SIZE));
+    }
+    if sectors >= 128 {
+        offsets.push((MetadataVersion::V0_90, ((sectors & !127) - 128) * SECTOR_SIZE));
+    }
+    offsets
+}
+
+// Both versions sum little endian words, folding the carry back in
+fn calc_csum(data: &[u8]) -> u32 {
+    let mut sum: u64 = data.chunks_exact(4).map(|w| LittleEndian::read_u32(w) as u64).sum();
+    if data.len() % 4 == 2 {
+        sum += LittleEndian::read_u16(&data[data.len() - 2..]) as u64;
+    }
+    ((sum & 0xffff_ffff) + (sum >> 32)) as u32
+}
+
+// mdadm prints uuids as four groups of eight hex digits
+fn format_uuid(data: &[u8]) -> String {
+    data.chunks(4)
+        .map(|group| group.iter().map(|b| format!("{:02x}", b)).collect::<String>())
+        .collect::<Vec<String>>()
+        .join(":")
+}
+
+fn normalize_uuid(uuid: &str) -> String {
+    uuid.trim().chars()
+        .filter(char::is_ascii_hexdigit)
+        .collect::<String>()
+        .to_ascii_lowercase()
+}
+
+/// What a member is to its array
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum DeviceRole {
+    /// An active member in the given slot
+    Active(u32),
+    Spare,
+    Faulty,
+    Journal
+}
+
+/// The superblock of an md member device
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MdSuperblock {
+    pub version: MetadataVersion,
+    /// Offset of the superblock from the start of the member
+    pub offset: u64,
+    pub array_uuid: String,
+    /// homehost:name, 1.x superblocks only
+    pub name: Option<String>,
+    pub level: i32,
+    pub layout: u32,
+    /// Chunk size in bytes
+    pub chunk_size: u64,
+    pub raid_disks: u32,
+    pub role: DeviceRole,
+    /// Update count, the member with the most events holds current data
+    pub events: u64,
+    /// Offset of the array data from the start of the member
+    pub data_offset: u64
+}
+
+impl MdSuperblock {
+    /// Parses the superblock in data, which was read at offset of the member
+    pub fn from_slice(data: &[u8], version: MetadataVersion, offset: u64) -> MdResult<MdSuperblock> {
+        if data.len() < MD_SUPERBLOCK_SIZE {
+            return Err(MdError::new("md superblock is truncated"))
+        }
+        match version {
+            MetadataVersion::V0_90 => MdSuperblock::from_slice_v0(data, offset),
+            _ => MdSuperblock::from_slice_v1(data, version, offset)
+        }
+    }
+
+    fn from_slice_v0(data: &[u8], offset: u64) -> MdResult<MdSuperblock> {
+        if !is_md(data, 0) {
+            return Err(MdError::new("no md 0.90 superblock magic"))
+        }
+        let word = |index: usize| LittleEndian::read_u32(&data[index * 4..index * 4 + 4]);
+        let mut sb = data[..MD_SUPERBLOCK_SIZE].to_vec();
+        sb[38 * 4..39 * 4].copy_from_slice(&[0u8; 4]);
+        if calc_csum(&sb) != word(38) {
+            return Err(MdError::new("md 0.90 superblock checksum mismatch"))
+        }
+
+        let mut uuid = Vec::with_capacity(16);
+        for index in [5, 13, 14, 15].iter() {
+            uuid.extend_from_slice(&data[index * 4..index * 4 + 4]);
+        }
+        // this_disk descriptor: number, major, minor, raid_disk, state
+        let state = word(996);
+        let role = if state & DISK_FAULTY != 0 {
+            DeviceRole::Faulty
+        } else if state & DISK_SYNC != 0 {
+            DeviceRole::Active(word(995))
+        } else {
+            DeviceRole::Spare
+        };
+        Ok(MdSuperblock {
+            version: MetadataVersion::V0_90,
+            offset,
+            array_uuid: format_uuid(&uuid),
+            name: None,
+            level: word(7) as i32,
+            layout: word(64),
+            chunk_size: word(65) as u64,
+            raid_disks: word(10),
+            role,
+            events: (word(40) as u64) << 32 | word(39) as u64,
+            data_offset: 0
+        })
+    }
+
+    fn from_slice_v1(data: &[u8], version: MetadataVersion, offset: u64) -> MdResult<MdSuperblock> {
+        if !is_md(data, 1) {
+            return Err(MdError::new(&format!("no md {} superblock magic", version.as_str())))
+        }
+        let max_dev = LittleEndian::read_u32(&data[220..224]) as usize;
+        let length = 256 + max_dev * 2;
+        if length > MD_SUPERBLOCK_SIZE {
+            return Err(MdError::new(&format!("md superblock has {} device roles", max_dev)))
+        }
+        let mut sb = data[..length].to_vec();
+        sb[216..220].copy_from_slice(&[0u8; 4]);
+        if calc_csum(&sb) != LittleEndian::read_u32(&data[216..220]) {
+            return Err(MdError::new(&format!("md {} superblock checksum mismatch",
+                version.as_str())))
+        }
+
+        let dev_number = LittleEndian::read_u32(&data[160..164]) as usize;
+        let role = if dev_number < max_dev {
+            LittleEndian::read_u16(&data[256 + dev_number * 2..258 + dev_number * 2])
+        } else {
+            ROLE_SPARE
+        };
+        let role = match role {
+            r if r == ROLE_SPARE => DeviceRole::Spare,
+            r if r == ROLE_FAULTY => DeviceRole::Faulty,
+            r if r == ROLE_JOURNAL => DeviceRole::Journal,
+            r => DeviceRole::Active(r as u32)
+        };
+        let name_end = data[32..64].iter().position(|b| *b == 0).unwrap_or(32);
+        let name = String::from_utf8_lossy(&data[32..32 + name_end]).into_owned();
+        Ok(MdSuperblock {
+            version,
+            offset,
+            array_uuid: format_uuid(&data[16..32]),
+            name: if name.is_empty() { None } else { Some(name) },
+            level: LittleEndian::read_i32(&data[72..76]),
+            layout: LittleEndian::read_u32(&data[76..80]),
+            chunk_size: LittleEndian::read_u32(&data[88..92]) as u64 * SECTOR_SIZE,
+            raid_disks: LittleEndian::read_u32(&data[92..96]),
+            role,
+            events: LittleEndian::read_u64(&data[200..208]),
+            data_offset: LittleEndian::read_u64(&data[128..136]) * SECTOR_SIZE
+        })
+    }
+
+    /// Reads the superblock of the member which starts at offset and is size
+    /// bytes long, None when there is none. Version 1 superblocks are
+    /// preferred over 0.90 and those with a bad checksum are skipped.
+    pub fn from_reader<R>(reader: &mut R, offset: u64, size: u64) -> MdResult<Option<MdSuperblock>>
+            where R: Read + Seek {
+        let mut offsets = superblock_offsets(size);
+        offsets.sort_by_key(|(version, _)| *version == MetadataVersion::V0_90);
+        let mut data = vec![0u8; MD_SUPERBLOCK_SIZE];
+        for (version, sb_offset) in offsets {
+            if sb_offset + MD_SUPERBLOCK_SIZE as u64 > size {
+                continue
+            }
+            reader.seek(SeekFrom::Start(offset + sb_offset))?;
+            reader.read_exact(&mut data)?;
+            let major_version = if version == MetadataVersion::V0_90 { 0 } else { 1 };
+            if !is_md(&data, major_version) {
+                continue
+            }
+            match MdSuperblock::from_slice(&data, version, sb_offset) {
+                Ok(sb) => return Ok(Some(sb)),
+                Err(e) => debug!("ignoring md superblock at offset {}: {}", offset + sb_offset, e)
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn from_device(device: &Path) -> MdResult<Option<MdSuperblock>> {
+        let mut file = File::open(device)?;
+        let size = file.seek(SeekFrom::End(0))?;
+        MdSuperblock::from_reader(&mut file, 0, size)
+    }
+
+    pub fn level_name(&self) -> &'static str {
+        match self.level {
+            -4 => "multipath",
+            -1 => "linear",
+            0 => "raid0",
+            1 => "raid1",
+            4 => "raid4",
+            5 => "raid5",
+            6 => "raid6",
+            10 => "raid10",
+            _ => "unknown"
+        }
+    }
+
+    /// The md device (md0, md127) of the array this superblock belongs to
+    /// when the array is assembled, None for a stale member
+    pub fn active_array(&self, root: &SysRoot) -> Option<String> {
+        let uuid = normalize_uuid(&self.array_uuid);
+        root.block_devices().unwrap_or_default().iter()
+            .filter(|entry| {
+                let md = entry.path().join("md");
+                sysfs::read_optional_string(&md.join("uuid"))
+                    .map(|u| normalize_uuid(&u) == uuid)
+                    .unwrap_or(false) &&
+                    !matches!(sysfs::read_optional_string(&md.join("array_state")).as_deref(),
+                        Some("inactive") | Some("clear"))
+            })
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .next()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    pub(crate) static ARRAY_UUID: [u8; 16] = [
+        0x3c, 0x1f, 0x9a, 0x2e, 0x5b, 0x7d, 0x4e, 0x8f,
+        0xa6, 0xc4, 0x2d, 0x1e, 0x0f, 0x9b, 0x8a, 0x7c
+    ];
+
+    /// A v1 superblock for member dev_number of a two disk raid1
+    pub(crate) fn superblock_v1(dev_number: u32, role: u16) -> Vec<u8> {
+        let mut data = vec![0u8; MD_SUPERBLOCK_SIZE];
+        LittleEndian::write_u32(&mut data[0..4], MD_MAGIC);
+        LittleEndian::write_u32(&mut data[4..8], 1);
+        data[16..32].copy_from_slice(&ARRAY_UUID);
+        data[32..39].copy_from_slice(b"press:0");
+        LittleEndian::write_i32(&mut data[72..76], 1);
+        LittleEndian::write_u32(&mut data[92..96], 2);
+        LittleEndian::write_u64(&mut data[128..136], 2048);
+        LittleEndian::write_u32(&mut data[160..164], dev_number);
+        LittleEndian::write_u64(&mut data[200..208], 42);
+        LittleEndian::write_u32(&mut data[220..224], 3);
+        LittleEndian::write_u16(&mut data[256..258], 0);
+        LittleEndian::write_u16(&mut data[258..260], 1);
+        LittleEndian::write_u16(&mut data[260..262], role);
+        let csum = calc_csum(&data[..262]);
+        LittleEndian::write_u32(&mut data[216..220], csum);
+        data
+    }
+
+    fn superblock_v0() -> Vec<u8> {
+        let mut data = vec![0u8; MD_SUPERBLOCK_SIZE];
+        let mut word = |index: usize, value: u32| LittleEndian::write_u32(
+            &mut data[index * 4..index * 4 + 4], value);
+        word(0, MD_MAGIC);
+        word(2, 90);
+        word(5, 0x2e9a_1f3c);
+        word(7, 5);
+        word(10, 3);
+        word(39, 7);
+        word(40, 1);
+        word(64, 2);
+        word(65, 64 << 10);
+        word(995, 2);
+        word(996, DISK_SYNC | 2);
+        let csum = calc_csum(&data);
+        LittleEndian::write_u32(&mut data[38 * 4..39 * 4], csum);
+        data
+    }
+
+    #[test]
+    fn test_v1() {
+        let sb = MdSuperblock::from_slice(&superblock_v1(1, 0), MetadataVersion::V1_2, 4096)
+            .unwrap();
+        assert_eq!(sb.array_uuid, "3c1f9a2e:5b7d4e8f:a6c42d1e:0f9b8a7c");
+        assert_eq!(sb.name.as_deref(), Some("press:0"));
+        assert_eq!(sb.level_name(), "raid1");
+        assert_eq!(sb.raid_disks, 2);
+        assert_eq!(sb.role, DeviceRole::Active(1));
+        assert_eq!(sb.events, 42);
+        assert_eq!(sb.data_offset, 1 << 20);
+
+        let sb = MdSuperblock::from_slice(&superblock_v1(2, ROLE_SPARE), MetadataVersion::V1_2, 4096)
+            .unwrap();
+        assert_eq!(sb.role, DeviceRole::Spare);
+
+        let mut data = superblock_v1(0, 0);
+        data[200] = 43;
+        assert_eq!(MdSuperblock::from_slice(&data, MetadataVersion::V1_2, 4096)
+            .unwrap_err().to_string(), "md 1.2 superblock checksum mismatch");
+    }
+
+    #[test]
+    fn test_v0() {
+        let sb = MdSuperblock::from_slice(&superblock_v0(), MetadataVersion::V0_90, 0).unwrap();
+        assert_eq!(sb.array_uuid, "3c1f9a2e:00000000:00000000:00000000");
+        assert_eq!(sb.level_name(), "raid5");
+        assert_eq!(sb.chunk_size, 64 << 10);
+        assert_eq!(sb.role, DeviceRole::Active(2));
+        assert_eq!(sb.events, (1 << 32) | 7);
+        assert_eq!(sb.name, None);
+    }
+
+    #[test]
+    fn test_from_reader() {
+        // A 1.0 member of 1MiB and a 0.90 member of 2MiB, back to back
+        let mut data = vec![0u8; 3 << 20];
+        let end = superblock_offsets(1 << 20).iter()
+            .find(|(version, _)| *version == MetadataVersion::V1_0)
+            .unwrap().1;
+        assert_eq!(end, (1 << 20) - 8192);
+        data[end as usize..end as usize + 4096].copy_from_slice(&superblock_v1(0, 0));
+        let end = (1 << 20) + (2 << 20) - 65536;
+        data[end..end + 4096].copy_from_slice(&superblock_v0());
+        let mut reader = Cursor::new(data);
+
+        let sb = MdSuperblock::from_reader(&mut reader, 0, 1 << 20).unwrap().unwrap();
+        assert_eq!((sb.version, sb.offset), (MetadataVersion::V1_0, (1 << 20) - 8192));
+        let sb = MdSuperblock::from_reader(&mut reader, 1 << 20, 2 << 20).unwrap().unwrap();
+        assert_eq!(sb.version, MetadataVersion::V0_90);
+        assert!(MdSuperblock::from_reader(&mut reader, 0, 512 << 10).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_active_array() {
+        use crate::sysfs::fixture_root;
+
+        let sb = MdSuperblock::from_slice(&superblock_v1(0, 0), MetadataVersion::V1_2, 4096)
+            .unwrap();
+        assert_eq!(sb.active_array(&fixture_root()).as_deref(), Some("md0"));
+        let sb = MdSuperblock::from_slice(&superblock_v0(), MetadataVersion::V0_90, 0).unwrap();
+        assert_eq!(sb.active_array(&fixture_root()), None);
+    }
+}
@@ -0,0 +1,500 @@
+//! Content probing, in the style of blkid. Recognizes the on disk signatures
+//! of file systems, swap, LVM physical volumes, md members, LUKS containers
+//! and ISO9660 images.
+
+extern crate byteorder;
+extern crate serde;
+extern crate uuid;
+
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::layout::fs::ext::{EXT, EXT_SUPERBLOCK_OFFSET, EXT_SUPERBLOCK_SIZE, is_ext};
+use crate::layout::fs::swap::{SwapHeader, is_swap};
+use crate::luks::is_luks;
+use crate::lvm2::format_id;
+use crate::md::is_md;
+
+// Every signature probed from the start of a device fits in this window,
+// the furthest being the btrfs superblock at 64KiB
+static PROBE_WINDOW: usize = 128 * 1024;
+
+static SWAP_PAGE_SIZES: &[usize] = &[4096, 8192, 16384, 65536];
+
+// Probes which only need the start of the device
+type Probe = fn(&[u8]) -> Option<Signature>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Usage {
+    Filesystem,
+    Raid,
+    Crypto,
+    Other
+}
+
+/// A recognized signature and the location of its magic, which is what must
+/// be cleared to make the signature go away
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Signature {
+    pub fs_type: String,
+    pub usage: Usage,
+    pub uuid: Option<String>,
+    pub label: Option<String>,
+    pub version: Option<String>,
+    pub offset: u64,
+    pub magic: Vec<u8>
+}
+
+impl Signature {
+    fn new(fs_type: &str, usage: Usage, offset: u64, magic: &[u8]) -> Signature {
+        Signature {
+            fs_type: fs_type.to_owned(),
+            usage,
+            uuid: None,
+            label: None,
+            version: None,
+            offset,
+            magic: magic.to_vec()
+        }
+    }
+}
+
+// Fixed length strings are either null or space padded
+fn read_string(data: &[u8]) -> Option<String> {
+    let end = data.iter().position(|b| *b == 0).unwrap_or(data.len());
+    let value = String::from_utf8_lossy(&data[..end]).trim_end().to_owned();
+    if value.is_empty() {
+        None
+    } else {
+        Some(value)
+    }
+}
+
+fn read_uuid(data: &[u8]) -> Option<String> {
+    let uuid = Uuid::from_slice(&data[..16]).ok()?;
+    if uuid.is_nil() {
+        None
+    } else {
+        Some(uuid.to_hyphenated().to_string())
+    }
+}
+
+fn probe_ext(buffer: &[u8]) -> Option<Signature> {
+    let offset = EXT_SUPERBLOCK_OFFSET as usize;
+    let data = &buffer[offset..offset + EXT_SUPERBLOCK_SIZE];
+    if !is_ext(data) {
+        return None
+    }
+    let ext = EXT::from_slice(data).ok()?;
+    let mut signature = Signature::new(
+        ext.fs_type().name(), Usage::Filesystem, (offset + 56) as u64, &data[56..58]);
+    signature.uuid = read_uuid(ext.uuid.as_bytes());
+    signature.label = read_string(ext.volume_name.as_bytes());
+    signature.version = Some(format!("1.{}", ext.rev_level));
+    Some(signature)
+}
+
+fn probe_xfs(buffer: &[u8]) -> Option<Signature> {
+    if &buffer[..4] != b"XFSB" {
+        return None
+    }
+    let mut signature = Signature::new("xfs", Usage::Filesystem, 0, &buffer[..4]);
+    signature.uuid = read_uuid(&buffer[32..48]);
+    signature.label = read_string(&buffer[108..120]);
+    signature.version = Some((BigEndian::read_u16(&buffer[100..102]) & 0xf).to_string());
+    Some(signature)
+}
+
+fn probe_btrfs(buffer: &[u8]) -> Option<Signature> {
+    let data = &buffer[0x10000..0x11000];
+    if &data[0x40..0x48] != b"_BHRfS_M" {
+        return None
+    }
+    let mut signature = Signature::new(
+        "btrfs", Usage::Filesystem, 0x10040, &data[0x40..0x48]);
+    signature.uuid = read_uuid(&data[0x20..0x30]);
+    signature.label = read_string(&data[0x12b..0x22b]);
+    Some(signature)
+}
+
+fn probe_vfat(buffer: &[u8]) -> Option<Signature> {
+    if buffer[510..512] != [0x55, 0xaa] {
+        return None
+    }
+    let bytes_per_sector = LittleEndian::read_u16(&buffer[11..13]);
+    if !bytes_per_sector.is_power_of_two() || !(512..=4096).contains(&bytes_per_sector) {
+        return None
+    }
+    // FAT32 moved the extended boot record to make room for its own fields
+    let (version, ebr) = if &buffer[82..90] == b"FAT32   " {
+        ("FAT32", 64)
+    } else if &buffer[54..59] == b"FAT16" {
+        ("FAT16", 36)
+    } else if &buffer[54..59] == b"FAT12" {
+        ("FAT12", 36)
+    } else {
+        return None
+    };
+    let mut signature = Signature::new(
+        "vfat", Usage::Filesystem, (ebr + 18) as u64, &buffer[ebr+18..ebr+26]);
+    let volume_id = LittleEndian::read_u32(&buffer[ebr+3..ebr+7]);
+    signature.uuid = Some(format!("{:04X}-{:04X}", volume_id >> 16, volume_id & 0xffff));
+    signature.label = read_string(&buffer[ebr+7..ebr+18]).filter(|label| label != "NO NAME");
+    signature.version = Some(version.to_owned());
+    Some(signature)
+}
+
+// The NTFS label is an attribute in the MFT and is not read
+fn probe_ntfs(buffer: &[u8]) -> Option<Signature> {
+    if &buffer[3..11] != b"NTFS    " {
+        return None
+    }
+    let mut signature = Signature::new("ntfs", Usage::Filesystem, 3, &buffer[3..11]);
+    signature.uuid = Some(format!("{:016X}", LittleEndian::read_u64(&buffer[0x48..0x50])));
+    Some(signature)
+}
+
+fn probe_swap(buffer: &[u8]) -> Option<Signature> {
+    for page_size in SWAP_PAGE_SIZES {
+        let offset = page_size - 10;
+        let magic = &buffer[offset..offset + 10];
+        if is_swap(&buffer[..*page_size]) {
+            let header = SwapHeader::from_slice(&buffer[..*page_size]);
+            let mut signature = Signature::new("swap", Usage::Other, offset as u64, magic);
+            signature.version = Some(header.version.to_string());
+            signature.uuid = read_uuid(header.uuid.as_bytes());
+            signature.label = read_string(header.label.as_bytes());
+            return Some(signature)
+        } else if magic == b"SWAP-SPACE" {
+            let mut signature = Signature::new("swap", Usage::Other, offset as u64, magic);
+            signature.version = Some("0".to_owned());
+            return Some(signature)
+        }
+    }
+    None
+}
+
+fn probe_lvm2(buffer: &[u8]) -> Option<Signature> {
+    // The label may be in any of the first four sectors
+    for sector in 0..4 {
+        let offset = sector * 512;
+        let label = &buffer[offset..offset + 512];
+        if &label[..8] != b"LABELONE" || &label[24..32] != b"LVM2 001" {
+            continue
+        }
+        // The PV header follows the label within its sector
+        let pv_header = LittleEndian::read_u32(&label[20..24]) as usize;
+        if pv_header < 32 || pv_header > label.len() - 32 {
+            return None
+        }
+        let mut signature = Signature::new(
+            "LVM2_member", Usage::Raid, (offset + 24) as u64, &label[24..32]);
+        signature.uuid = Some(format_id(&label[pv_header..pv_header + 32])?);
+        signature.version = Some("LVM2 001".to_owned());
+        return Some(signature)
+    }
+    None
+}
+
+fn probe_luks(buffer: &[u8]) -> Option<Signature> {
+    if !is_luks(buffer) {
+        return None
+    }
+    let version = BigEndian::read_u16(&buffer[6..8]);
+    let mut signature = Signature::new("crypto_LUKS", Usage::Crypto, 0, &buffer[..6]);
+    signature.version = Some(version.to_string());
+    signature.uuid = read_string(&buffer[168..208]);
+    if version == 2 {
+        signature.label = read_string(&buffer[24..72]);
+    }
+    Some(signature)
+}
+
+fn probe_iso9660(buffer: &[u8]) -> Option<Signature> {
+    let data = &buffer[0x8000..0x8800];
+    if &data[1..6] != b"CD001" {
+        return None
+    }
+    let mut signature = Signature::new("iso9660", Usage::Filesystem, 0x8001, &data[1..6]);
+    signature.label = read_string(&data[40..72]);
+    // Like blkid, derive the uuid from the volume creation date
+    let date = &data[813..829];
+    if date.iter().all(u8::is_ascii_digit) && date.iter().any(|b| *b != b'0') {
+        let date = String::from_utf8_lossy(date);
+        signature.uuid = Some(format!("{}-{}-{}-{}-{}-{}-{}",
+            &date[0..4], &date[4..6], &date[6..8], &date[8..10],
+            &date[10..12], &date[12..14], &date[14..16]));
+    }
+    Some(signature)
+}
+
+// md v1 superblock, at 0 (1.1), 4KiB (1.2) or 8KiB from the end (1.0)
+fn md_v1(data: &[u8], offset: u64, minor: u32) -> Option<Signature> {
+    if !is_md(data, 1) {
+        return None
+    }
+    let mut signature = Signature::new("linux_raid_member", Usage::Raid, offset, &data[..4]);
+    signature.uuid = read_uuid(&data[16..32]);
+    signature.label = read_string(&data[32..64]);
+    signature.version = Some(format!("1.{}", minor));
+    Some(signature)
+}
+
+// md v0.90 superblock, 64KiB aligned at the end of the device
+fn md_v0(data: &[u8], offset: u64) -> Option<Signature> {
+    if !is_md(data, 0) {
+        return None
+    }
+    let mut uuid = [0u8; 16];
+    uuid[..4].copy_from_slice(&data[20..24]);
+    uuid[4..].copy_from_slice(&data[52..64]);
+    let mut signature = Signature::new("linux_raid_member", Usage::Raid, offset, &data[..4]);
+    signature.uuid = read_uuid(&uuid);
+    signature.version = Some(format!("0.{}.{}",
+        LittleEndian::read_u32(&data[8..12]), LittleEndian::read_u32(&data[12..16])));
+    Some(signature)
+}
+
+fn read_at<R>(reader: &mut R, offset: u64, length: usize) -> Result<Vec<u8>, std::io::Error>
+        where R: Read + Seek {
+    let mut buffer = vec![0u8; length];
+    reader.seek(SeekFrom::Start(offset))?;
+    reader.read_exact(&mut buffer)?;
+    Ok(buffer)
+}
+
+/// Probes size bytes of reader starting at offset for known signatures.
+/// Offsets of the returned signatures are relative to offset.
+pub fn probe_range<R>(reader: &mut R, offset: u64, size: u64)
+        -> Result<Vec<Signature>, std::io::Error>
+            where R: Read + Seek {
+    let mut signatures = Vec::new();
+
+    // Short devices are padded so every probe can index its window
+    let mut buffer = read_at(reader, offset, std::cmp::min(size, PROBE_WINDOW as u64) as usize)?;
+    buffer.resize(PROBE_WINDOW, 0);
+
+    let probes: &[Probe] = &[
+        probe_luks, probe_lvm2, probe_xfs, probe_ext, probe_btrfs,
+        probe_swap, probe_ntfs, probe_vfat, probe_iso9660
+    ];
+    signatures.extend(probes.iter().filter_map(|probe| probe(&buffer)));
+    signatures.extend(md_v1(&buffer, 0, 1));
+    signatures.extend(md_v1(&buffer[4096..], 4096, 2));
+
+    let sectors = size / 512;
+    if sectors >= 16 {
+        let end = ((sectors - 16) & !7) * 512;
+        signatures.extend(md_v1(&read_at(reader, offset + end, 4096)?, end, 0));
+    }
+    if sectors >= 128 {
+        let end = ((sectors & !127) - 128) * 512;
+        signatures.extend(md_v0(&read_at(reader, offset + end, 4096)?, end));
+    }
+    Ok(signatures)
+}
+
+/// Probes the whole of reader for known signatures
+pub fn probe<R>(reader: &mut R) -> Result<Vec<Signature>, std::io::Error>
+        where R: Read + Seek {
+    let size = reader.seek(SeekFrom::End(0))?;
+    probe_range(reader, 0, size)
+}
+
+pub fn probe_device(device: &Path) -> Result<Vec<Signature>, std::io::Error> {
+    probe(&mut File::open(device)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use crate::md::MD_MAGIC;
+
+    fn image() -> Vec<u8> {
+        vec![0u8; 1 << 20]
+    }
+
+    fn probe_image(image: Vec<u8>) -> Vec<Signature> {
+        probe(&mut Cursor::new(image)).unwrap()
+    }
+
+    #[test]
+    fn test_empty() {
+        assert!(probe_image(image()).is_empty());
+        assert!(probe_image(vec![0u8; 4096]).is_empty());
+
+        // An ext magic with an impossible block size
+        let mut data = image();
+        data[1024 + 24..1024 + 28].copy_from_slice(&40u32.to_le_bytes());
+        data[1024 + 56..1024 + 58].copy_from_slice(&0xef53u16.to_le_bytes());
+        assert!(probe_image(data).is_empty());
+    }
+
+    #[test]
+    fn test_xfs() {
+        let mut data = image();
+        data[..4].copy_from_slice(b"XFSB");
+        data[32..48].copy_from_slice(
+            Uuid::parse_str("0b5c1c4a-1d4e-4f0a-8f5e-2a9b6c3d7e8f").unwrap().as_bytes());
+        data[100..102].copy_from_slice(&[0xb4, 0xa5]);
+        data[108..112].copy_from_slice(b"data");
+        let signatures = probe_image(data);
+        assert_eq!(signatures.len(), 1);
+        assert_eq!(signatures[0].fs_type, "xfs");
+        assert_eq!(signatures[0].uuid.as_deref(), Some("0b5c1c4a-1d4e-4f0a-8f5e-2a9b6c3d7e8f"));
+        assert_eq!(signatures[0].label.as_deref(), Some("data"));
+        assert_eq!(signatures[0].version.as_deref(), Some("5"));
+    }
+
+    #[test]
+    fn test_vfat() {
+        let mut data = image();
+        data[11..13].copy_from_slice(&[0x00, 0x02]);
+        data[67..71].copy_from_slice(&0x1234_abcdu32.to_le_bytes());
+        data[71..82].copy_from_slice(b"EFI        ");
+        data[82..90].copy_from_slice(b"FAT32   ");
+        data[510..512].copy_from_slice(&[0x55, 0xaa]);
+        let signatures = probe_image(data);
+        assert_eq!(signatures[0].fs_type, "vfat");
+        assert_eq!(signatures[0].uuid.as_deref(), Some("1234-ABCD"));
+        assert_eq!(signatures[0].label.as_deref(), Some("EFI"));
+        assert_eq!(signatures[0].version.as_deref(), Some("FAT32"));
+        assert_eq!(signatures[0].offset, 82);
+    }
+
+    fn data_lvm2() -> Vec<u8> {
+        let mut data = image();
+        data[512..520].copy_from_slice(b"LABELONE");
+        data[532..536].copy_from_slice(&32u32.to_le_bytes());
+        data[536..544].copy_from_slice(b"LVM2 001");
+        data[544..576].copy_from_slice(b"Ab3dEf0123456789abcdefGHIJKLmnop");
+        data
+    }
+
+    #[test]
+    fn test_lvm2() {
+        let signatures = probe_image(data_lvm2());
+        assert_eq!(signatures[0].fs_type, "LVM2_member");
+        assert_eq!(signatures[0].uuid.as_deref(), Some("Ab3dEf-0123-4567-89ab-cdef-GHIJ-KLmnop"));
+        assert_eq!(signatures[0].offset, 536);
+
+        // A PV header offset outside of the sector or an id which is not
+        // alphanumeric is not a label
+        let mut corrupt = data_lvm2();
+        corrupt[532..536].copy_from_slice(&0x7fff_0000u32.to_le_bytes());
+        assert!(probe_image(corrupt).is_empty());
+        let mut corrupt = data_lvm2();
+        corrupt[550..552].copy_from_slice("é".as_bytes());
+        assert!(probe_image(corrupt).is_empty());
+    }
+
+    #[test]
+    fn test_luks() {
+        let mut data = image();
+        data[..6].copy_from_slice(&[b'L', b'U', b'K', b'S', 0xba, 0xbe]);
+        data[6..8].copy_from_slice(&[0, 2]);
+        data[24..30].copy_from_slice(b"cryptd");
+        data[168..204].copy_from_slice(b"9a1e4a1c-7c3b-4b5d-a0c4-6a4f2d2e8b1f");
+        let signatures = probe_image(data);
+        assert_eq!(signatures[0].fs_type, "crypto_LUKS");
+        assert_eq!(signatures[0].version.as_deref(), Some("2"));
+        assert_eq!(signatures[0].label.as_deref(), Some("cryptd"));
+        assert_eq!(signatures[0].uuid.as_deref(), Some("9a1e4a1c-7c3b-4b5d-a0c4-6a4f2d2e8b1f"));
+    }
+
+    #[test]
+    fn test_md() {
+        let uuid = Uuid::parse_str("5d2e0c3a-8f41-4b7e-9c6d-1e2f3a4b5c6d").unwrap();
+        // 1.2, 4KiB from the start
+        let mut data = image();
+        data[4096..4100].copy_from_slice(&MD_MAGIC.to_le_bytes());
+        data[4100..4104].copy_from_slice(&1u32.to_le_bytes());
+        data[4112..4128].copy_from_slice(uuid.as_bytes());
+        data[4128..4136].copy_from_slice(b"press:md");
+        let signatures = probe_image(data);
+        assert_eq!(signatures[0].fs_type, "linux_raid_member");
+        assert_eq!(signatures[0].version.as_deref(), Some("1.2"));
+        assert_eq!(signatures[0].label.as_deref(), Some("press:md"));
+        assert_eq!(signatures[0].uuid, Some(uuid.to_string()));
+
+        // 1.0, at the end
+        let mut data = image();
+        let end = data.len() - 8192;
+        data[end..end+4].copy_from_slice(&MD_MAGIC.to_le_bytes());
+        data[end+4..end+8].copy_from_slice(&1u32.to_le_bytes());
+        let signatures = probe_image(data);
+        assert_eq!(signatures[0].version.as_deref(), Some("1.0"));
+        assert_eq!(signatures[0].offset, end as u64);
+
+        // 0.90, 64KiB from the end
+        let mut data = image();
+        let end = data.len() - 65536;
+        data[end..end+4].copy_from_slice(&MD_MAGIC.to_le_bytes());
+        data[end+8..end+12].copy_from_slice(&90u32.to_le_bytes());
+        data[end+20..end+24].copy_from_slice(&uuid.as_bytes()[..4]);
+        data[end+52..end+64].copy_from_slice(&uuid.as_bytes()[4..]);
+        let signatures = probe_image(data);
+        assert_eq!(signatures[0].version.as_deref(), Some("0.90.0"));
+        assert_eq!(signatures[0].uuid, Some(uuid.to_string()));
+    }
+
+    #[test]
+    fn test_iso9660() {
+        let mut data = image();
+        data[0x8000] = 1;
+        data[0x8001..0x8006].copy_from_slice(b"CD001");
+        data[0x8028..0x8030].copy_from_slice(b"PRESS   ");
+        data[0x8000+813..0x8000+829].copy_from_slice(b"2024010212304500");
+        let signatures = probe_image(data);
+        assert_eq!(signatures[0].fs_type, "iso9660");
+        assert_eq!(signatures[0].label.as_deref(), Some("PRESS"));
+        assert_eq!(signatures[0].uuid.as_deref(), Some("2024-01-02-12-30-45-00"));
+    }
+
+    #[test]
+    fn test_range() {
+        let mut data = image();
+        data[0x10000 + 0x10040..0x10000 + 0x10048].copy_from_slice(b"_BHRfS_M");
+        data[0x10000 + 0x1012b..0x10000 + 0x1012f].copy_from_slice(b"home");
+        let mut reader = Cursor::new(data);
+        assert!(probe(&mut reader).unwrap().is_empty());
+        let signatures = probe_range(&mut reader, 0x10000, 0x20000).unwrap();
+        assert_eq!(signatures[0].fs_type, "btrfs");
+        assert_eq!(signatures[0].label.as_deref(), Some("home"));
+        assert_eq!(signatures[0].offset, 0x10040);
+    }
+
+    #[test]
+    fn test_created() {
+        use crate::layout::fs::{FileSystem, FileSystemType};
+        if std::process::Command::new("mke2fs").arg("-V").output().is_err() ||
+                std::process::Command::new("mkswap").arg("-V").output().is_err() {
+            return
+        }
+        let image = std::env::temp_dir().join(format!("press-probe-{}.img", std::process::id()));
+        for (fs_type, name, version) in &[(FileSystemType::Ext4, "ext4", "1.1"),
+                                          (FileSystemType::Swap, "swap", "1")] {
+            std::fs::File::create(&image).unwrap().set_len(16 << 20).unwrap();
+            let mut file_system = FileSystem::new(*fs_type);
+            file_system.label = Some("probed".to_owned());
+            file_system.uuid = Some("2f6c7e1a-9b3d-4c5e-8a7f-0d1e2c3b4a59".to_owned());
+            file_system.create(&image).unwrap();
+
+            let signatures = probe_device(&image).unwrap();
+            assert_eq!(signatures.len(), 1);
+            assert_eq!(signatures[0].fs_type, *name);
+            assert_eq!(signatures[0].label.as_deref(), Some("probed"));
+            assert_eq!(signatures[0].uuid.as_deref(), file_system.uuid.as_deref());
+            assert_eq!(signatures[0].version.as_deref(), Some(*version));
+        }
+        std::fs::remove_file(&image).unwrap();
+    }
+}
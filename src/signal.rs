@@ -0,0 +1,108 @@
+//! Deferred handling of SIGINT, SIGTERM and SIGHUP. While a `SignalGuard`
+//! is alive the signals are recorded instead of killing press, so a
+//! deployment can stop cleanly and unmount the target on the way out. The
+//! operations which check `interrupted` and stop are:
+//!
+//! * running external tools, mdadm, lvm, cryptsetup and mount, before each
+//!   command is started. A command which is running receives the signal
+//!   from the terminal itself.
+//! * creating a file system, natively or with mkfs
+//! * reading a tarball or disk image, between reads
+//! * writing and reading back a disk image, between chunks
+//! * mounting, before each mount
+//!
+//! `MountTree::unmount_all` is not interruptible, it is the cleanup.
+
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+static SIGNALS: &[libc::c_int] = &[libc::SIGINT, libc::SIGTERM, libc::SIGHUP];
+
+extern "C" fn on_signal(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// Restores the dispositions the signals had before it was installed and
+/// forgets a signal received meanwhile
+#[derive(Debug)]
+pub struct SignalGuard {
+    previous: Vec<(libc::c_int, libc::sighandler_t)>
+}
+
+/// Records SIGINT, SIGTERM and SIGHUP instead of dying until the returned
+/// guard is dropped
+pub fn install_signal_handlers() -> SignalGuard {
+    INTERRUPTED.store(false, Ordering::SeqCst);
+    let handler: extern "C" fn(libc::c_int) = on_signal;
+    let previous = SIGNALS.iter()
+        .map(|signal| (*signal, unsafe { libc::signal(*signal, handler as libc::sighandler_t) }))
+        .collect();
+    SignalGuard { previous }
+}
+
+impl Drop for SignalGuard {
+    fn drop(&mut self) {
+        for (signal, handler) in self.previous.iter() {
+            unsafe {
+                libc::signal(*signal, *handler);
+            }
+        }
+        INTERRUPTED.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Whether one of the handled signals has been received
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Fails once a signal has been received. The error is not of kind
+/// `Interrupted`, which readers and writers retry.
+pub fn check_interrupted() -> io::Result<()> {
+    if interrupted() {
+        return Err(io::Error::other("interrupted"))
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn disposition(signal: libc::c_int) -> libc::sighandler_t {
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            libc::sigaction(signal, std::ptr::null(), &mut action);
+            action.sa_sigaction
+        }
+    }
+
+    // Signal dispositions and the flag are process wide, the guard is
+    // exercised in a child so the tests running alongside are not interrupted
+    #[test]
+    fn test_signal_guard() {
+        let pid = unsafe { libc::fork() };
+        assert!(pid >= 0);
+        if pid == 0 {
+            let handler: extern "C" fn(libc::c_int) = on_signal;
+            let before = disposition(libc::SIGHUP);
+            let guard = install_signal_handlers();
+            let checks = [
+                disposition(libc::SIGHUP) == handler as libc::sighandler_t,
+                !interrupted() && check_interrupted().is_ok(),
+                unsafe { libc::raise(libc::SIGHUP) } == 0,
+                interrupted() && check_interrupted().is_err(),
+                { drop(guard); disposition(libc::SIGHUP) == before },
+                !interrupted()
+            ];
+            let failed = checks.iter().position(|ok| !ok).map(|i| i as i32 + 1).unwrap_or(0);
+            unsafe { libc::_exit(failed) }
+        }
+        let mut status = 0;
+        assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+        assert!(libc::WIFEXITED(status));
+        assert_eq!(libc::WEXITSTATUS(status), 0, "check {} failed", libc::WEXITSTATUS(status));
+    }
+}
@@ -1,10 +1,16 @@
 extern crate serde;
+extern crate schemars;
 
 use serde::de::{self, Visitor, Deserializer, Deserialize};
 use std::fmt;
 use std::error::Error;
 use std::str::FromStr;
 
+use schemars::JsonSchema;
+use schemars::gen::SchemaGenerator;
+use schemars::schema::Schema;
+use serde_json::json;
+
 // Error Boiler plate
 #[derive(Debug)]
 pub struct SizeParseError {
@@ -96,7 +102,7 @@ pub fn get_multiplier(suffix: &str) -> SizeParseResult<u64> {
     Ok(v)
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct Size {
     _bytes: u64
 }
@@ -133,7 +139,7 @@ impl FromStr for Size {
 }
 
 // Deserializer
-struct SizeVistor;
+pub(crate) struct SizeVistor;
 
 impl<'de> Visitor<'de> for SizeVistor {
     type Value = Size;
@@ -148,7 +154,7 @@ impl<'de> Visitor<'de> for SizeVistor {
         E: serde::de::Error {
         match Size::from_str(s) {
             Ok(s) => Ok(s),
-            Err(_) => Err(E::custom(format!("Could not convert {}", s)))
+            Err(e) => Err(E::custom(format!("invalid size \"{}\": {}", s, e)))
         }
     }
 
@@ -157,6 +163,16 @@ impl<'de> Visitor<'de> for SizeVistor {
         E: serde::de::Error {
             Ok(Size::new(value))
     }
+
+    // TOML integers are signed
+    fn visit_i64<E>(self, value: i64) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error {
+        if value < 0 {
+            return Err(E::custom(format!("invalid size {}: sizes cannot be negative", value)))
+        }
+        Ok(Size::new(value as u64))
+    }
 }
 
 impl<'de> Deserialize<'de> for Size {
@@ -168,6 +184,105 @@ impl<'de> Deserialize<'de> for Size {
     }
 }
 
+// Matches the strings accepted by parse_bytes, 100 MiB or 100MiB
+pub(crate) static SIZE_PATTERN: &str = "^[0-9]+ ?(([kKmMgGtTpP][iI]?)?[bB]|[kKmMgGtTpP])?$";
+
+impl JsonSchema for Size {
+    fn schema_name() -> String {
+        "Size".to_owned()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        serde_json::from_value(json!({
+            "description": "A size in bytes, or a string with a unit (512MiB, 1 GB)",
+            "anyOf": [
+                {"type": "integer", "minimum": 0},
+                {"type": "string", "pattern": SIZE_PATTERN}
+            ]
+        })).unwrap()
+    }
+}
+
+/// A size which may depend on the space available to it
+#[derive(Debug)]
+pub enum SizeRequest {
+    Fixed(Size),
+    /// Whatever space remains once every fixed size has been allocated
+    Fill
+}
+
+impl SizeRequest {
+    pub fn is_fill(&self) -> bool {
+        matches!(self, SizeRequest::Fill)
+    }
+
+    /// The size in bytes, if it is known before allocation
+    pub fn fixed_bytes(&self) -> Option<u64> {
+        match self {
+            SizeRequest::Fixed(size) => Some(size.bytes()),
+            SizeRequest::Fill => None
+        }
+    }
+}
+
+struct SizeRequestVisitor;
+
+impl<'de> Visitor<'de> for SizeRequestVisitor {
+    type Value = SizeRequest;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a size or \"fill\"")
+    }
+
+    fn visit_str<E>(self, s: &str) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error {
+        // Lowercase only, as the schema has it
+        if s == "fill" {
+            return Ok(SizeRequest::Fill)
+        }
+        SizeVistor.visit_str(s).map(SizeRequest::Fixed)
+    }
+
+    fn visit_u64<E>(self, value: u64) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error {
+        SizeVistor.visit_u64(value).map(SizeRequest::Fixed)
+    }
+
+    fn visit_i64<E>(self, value: i64) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error {
+        SizeVistor.visit_i64(value).map(SizeRequest::Fixed)
+    }
+}
+
+impl<'de> Deserialize<'de> for SizeRequest {
+    fn deserialize<D>(deserializer: D) -> Result<SizeRequest, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_any(SizeRequestVisitor)
+    }
+}
+
+impl JsonSchema for SizeRequest {
+    fn schema_name() -> String {
+        "SizeRequest".to_owned()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        serde_json::from_value(json!({
+            "description": "A size, or \"fill\" to use the remaining space",
+            "anyOf": [
+                {"type": "integer", "minimum": 0},
+                {"type": "string", "pattern": SIZE_PATTERN},
+                {"type": "string", "enum": ["fill"]}
+            ]
+        })).unwrap()
+    }
+}
+
 // Tests
 #[test]
 fn test_get_multiplier() {
@@ -226,6 +341,36 @@ fn test_de() {
 mod test {
     use super::*;
 
+    #[test]
+    fn test_size_request() {
+        #[derive(Debug, serde::Deserialize)]
+        struct Requests {
+            r1: SizeRequest,
+            r2: SizeRequest,
+            r3: SizeRequest
+        }
+
+        let requests: Requests = serde_json::from_str(
+            r#"{"r1": "10 GiB", "r2": 4096, "r3": "fill"}"#).unwrap();
+        assert_eq!(requests.r1.fixed_bytes(), Some(10 << 30));
+        assert_eq!(requests.r2.fixed_bytes(), Some(4096));
+        assert!(requests.r3.is_fill());
+        assert!(serde_json::from_str::<SizeRequest>(r#""Fill""#).is_err());
+    }
+
+    #[test]
+    fn test_size_pattern() {
+        let pattern = regex::Regex::new(SIZE_PATTERN).unwrap();
+        for s in ["100", "100 MiB", "1GB", "10k", "512 b"].iter() {
+            assert!(pattern.is_match(s), "{}", s);
+            assert!(parse_bytes(s).is_ok(), "{}", s);
+        }
+        for s in ["1 XiB", "10ib"].iter() {
+            assert!(!pattern.is_match(s), "{}", s);
+            assert!(parse_bytes(s).is_err(), "{}", s);
+        }
+    }
+
     #[test]
     fn test_as_symbol() {
         let s1: Size = "1 MiB".parse().unwrap();
@@ -11,13 +11,65 @@ use serde::{Serialize, Deserialize};
 
 // Methods for interactive with the Linux System Filesystem
 pub static LINUX_SYSFS_BLOCK_DEVICE_PATH: &'static str = "/sys/block";
+pub static LINUX_ROOT: &str = "/";
+
+/// The root under which /sys, /proc and /run are found. Discovery normally
+/// runs against the live system, but can be pointed at a copy of these
+/// trees so that it can be exercised without real hardware.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SysRoot {
+    root: PathBuf
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct BlockDeviceGeometry {
     pub logical_block_size: u64,
     pub logical_blocks: u64,
-    pub size: u64
+    pub size: u64,
+    pub physical_block_size: u64,
+    pub minimum_io_size: u64,
+    pub optimal_io_size: u64,
+    pub alignment_offset: u64
+}
+
+/// How a block device is attached to the system, derived from the
+/// device's position in the sysfs device tree.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Transport {
+    Ata,
+    Sas,
+    Scsi,
+    Nvme,
+    NvmeOverFabrics,
+    FibreChannel,
+    Iscsi,
+    Usb,
+    Virtio,
+    Mmc,
+    Loop,
+    DeviceMapper,
+    Md,
+    Virtual,
+    Unknown
+}
+
+/// Descriptive attributes of a block device which are not needed to
+/// compute a layout, but are used to select between devices
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BlockDeviceAttributes {
+    pub rotational: bool,
+    pub removable: bool,
+    pub read_only: bool,
+    pub discard_granularity: u64,
+    pub discard_max_bytes: u64,
+    pub model: Option<String>,
+    pub vendor: Option<String>,
+    pub serial: Option<String>,
+    pub wwid: Option<String>,
+    pub transport: Transport
 }
 
 // Generic attempts
@@ -43,39 +95,353 @@ pub struct BlockDeviceGeometry {
 //     Ok(buf.trim().parse()?)
 // }
 
-pub fn read_u64(path: &PathBuf) -> Result<u64, Box<std::error::Error>> {
+pub fn read_u64(path: &Path) -> Result<u64, Box<dyn std::error::Error>> {
+    Ok(read_string(path)?.parse()?)
+}
+
+pub fn read_string(path: &Path) -> Result<String, Box<dyn std::error::Error>> {
     let mut buf = String::new();
-    let mut fp: File = File::open(path.to_str().unwrap())?;
+    let mut fp: File = File::open(path)?;
     fp.read_to_string(&mut buf)?;
-    Ok(buf.trim().parse()?)
+    Ok(buf.trim().to_owned())
+}
+
+// Many attributes are only present for some drivers, an absent or
+// empty attribute is treated as unknown
+pub fn read_optional_string(path: &Path) -> Option<String> {
+    match read_string(path) {
+        Ok(ref s) if s.is_empty() => None,
+        Ok(s) => Some(s),
+        Err(_) => None
+    }
+}
+
+pub fn read_u64_or(path: &Path, default: u64) -> u64 {
+    read_u64(path).unwrap_or(default)
 }
 
 pub fn kernel_path_to_sys(kernel_path: &str) -> String {
-    "/sys".to_owned() + kernel_path
+    SysRoot::default().kernel_path_to_sys(kernel_path)
+}
+
+impl SysRoot {
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        Self {
+            root: root.as_ref().to_path_buf()
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn sys(&self) -> PathBuf {
+        self.root.join("sys")
+    }
+
+    pub fn proc(&self) -> PathBuf {
+        self.root.join("proc")
+    }
+
+    /// The udev database, one file per device named b<major>:<minor>
+    pub fn udev_data(&self) -> PathBuf {
+        self.root.join("run/udev/data")
+    }
+
+    pub fn block(&self) -> PathBuf {
+        self.sys().join("block")
+    }
+
+    /// Every block device, disks and partitions, keyed by kernel name
+    pub fn class_block(&self) -> PathBuf {
+        self.sys().join("class/block")
+    }
+
+    /// Converts a kernel DEVPATH (/devices/...) to a path under this root
+    pub fn kernel_path_to_sys(&self, kernel_path: &str) -> String {
+        self.sys()
+            .join(kernel_path.trim_start_matches('/'))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    /// Converts a sysfs device path under this root back to a kernel DEVPATH
+    pub fn sys_to_kernel_path(&self, sys_device_path: &Path) -> Option<String> {
+        let canonical = std::fs::canonicalize(sys_device_path).ok()?;
+        let sys = std::fs::canonicalize(self.sys()).ok()?;
+        let relative = canonical.strip_prefix(sys).ok()?;
+        Some(format!("/{}", relative.to_string_lossy()))
+    }
+
+    /// The sysfs directory of a block device given its kernel name (sda1)
+    pub fn device_path(&self, name: &str) -> PathBuf {
+        self.class_block().join(name)
+    }
+
+    /// Reads the dev attribute of a block device, "8:0" for instance
+    pub fn device_number(&self, name: &str) -> Result<(u64, u64), Box<dyn std::error::Error>> {
+        let dev = read_string(&self.device_path(name).join("dev"))?;
+        let mut split = dev.splitn(2, ':');
+        let major = split.next().unwrap_or_default().parse()?;
+        let minor = split.next().unwrap_or_default().parse()?;
+        Ok((major, minor))
+    }
+
+    pub fn block_devices(&self) -> Result<Vec<std::fs::DirEntry>, Box<dyn std::error::Error>> {
+        let mut v: Vec<std::fs::DirEntry> = std::fs::read_dir(self.block())?
+            .filter_map(|e| e.ok())
+            .collect();
+        v.sort_by_key(|dir| dir.path());
+        Ok(v)
+    }
+}
+
+impl Default for SysRoot {
+    fn default() -> Self {
+        SysRoot::new(LINUX_ROOT)
+    }
 }
 
 impl BlockDeviceGeometry {
     pub fn from_device(sys_device_path: &str) -> Result<BlockDeviceGeometry,
-        Box<std::error::Error>> {
-        let size_path = Path::new(sys_device_path).join("size");
-        let lba_size_path = Path::new(sys_device_path)
-            .join("queue/logical_block_size");
-        let logical_blocks = read_u64(&size_path)?;
-        let logical_block_size = read_u64(&lba_size_path)?;
+        Box<dyn std::error::Error>> {
+        let device = Path::new(sys_device_path);
+        let queue = device.join("queue");
+        // The size attribute is always in 512 byte sectors, even on 4Kn disks
+        let size = read_u64(&device.join("size"))? * 512;
+        let logical_block_size = read_u64(&queue.join("logical_block_size"))?;
         Ok( BlockDeviceGeometry {
-            logical_block_size: logical_block_size,
-            logical_blocks: logical_blocks,
-            size: logical_blocks * logical_block_size
+            logical_block_size,
+            logical_blocks: size / logical_block_size,
+            size,
+            physical_block_size: read_u64_or(
+                &queue.join("physical_block_size"), logical_block_size),
+            minimum_io_size: read_u64_or(&queue.join("minimum_io_size"), 0),
+            optimal_io_size: read_u64_or(&queue.join("optimal_io_size"), 0),
+            alignment_offset: read_u64_or(&device.join("alignment_offset"), 0)
         })
     }
+
+    /// The preferred alignment, in bytes, for partition boundaries.
+    /// Devices which report an optimal transfer size are aligned to it,
+    /// otherwise the larger of the minimum io size and physical block size
+    /// is used.
+    pub fn io_alignment(&self) -> u64 {
+        if self.optimal_io_size > 0 {
+            return self.optimal_io_size
+        }
+        *[self.minimum_io_size, self.physical_block_size, self.logical_block_size]
+            .iter()
+            .max()
+            .unwrap()
+    }
+}
+
+impl Transport {
+    /// Storage reached over a network or shared fabric rather than a local bus
+    pub fn is_fabric(&self) -> bool {
+        matches!(self,
+            Transport::FibreChannel | Transport::Iscsi | Transport::NvmeOverFabrics)
+    }
+
+    /// Determine the transport from the canonical sysfs path of a device,
+    /// /sys/devices/pci0000:00/0000:00:1f.2/ata1/host0/... for instance
+    pub fn from_device(sys_device_path: &str) -> Transport {
+        let device = Path::new(sys_device_path);
+        let canonical = std::fs::canonicalize(device)
+            .unwrap_or_else(|_| device.to_path_buf());
+
+        let name = canonical.file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let components: Vec<String> = canonical.components()
+            .map(|c| c.as_os_str().to_string_lossy().into_owned())
+            .collect();
+        let has = |prefix: &str| components.iter().any(|c| c.starts_with(prefix));
+
+        if name.starts_with("nvme") {
+            // NVMe namespaces link to their controller which reports the
+            // transport it is attached with
+            return match read_optional_string(
+                    &device.join("device/transport")).as_deref() {
+                Some("fc") => Transport::FibreChannel,
+                Some("tcp") | Some("rdma") => Transport::NvmeOverFabrics,
+                _ => Transport::Nvme
+            }
+        }
+        if name.starts_with("loop") {
+            return Transport::Loop
+        }
+        if name.starts_with("dm-") {
+            return Transport::DeviceMapper
+        }
+        if name.starts_with("md") {
+            return Transport::Md
+        }
+
+        if has("rport-") {
+            Transport::FibreChannel
+        } else if has("session") {
+            Transport::Iscsi
+        } else if has("end_device-") {
+            Transport::Sas
+        } else if has("usb") {
+            Transport::Usb
+        } else if has("ata") {
+            Transport::Ata
+        } else if has("mmc") {
+            Transport::Mmc
+        } else if has("virtio") {
+            Transport::Virtio
+        } else if has("target") {
+            Transport::Scsi
+        } else if has("virtual") {
+            Transport::Virtual
+        } else {
+            Transport::Unknown
+        }
+    }
+}
+
+// The SCSI unit serial number VPD page is a four byte header followed
+// by the serial in ASCII
+fn read_vpd_serial(path: &Path) -> Option<String> {
+    let data = std::fs::read(path).ok()?;
+    if data.len() <= 4 {
+        return None
+    }
+    let serial = String::from_utf8_lossy(&data[4..])
+        .trim_matches(|c: char| c.is_whitespace() || c == '\0')
+        .to_owned();
+    if serial.is_empty() {
+        None
+    } else {
+        Some(serial)
+    }
+}
+
+impl BlockDeviceAttributes {
+    pub fn from_device(sys_device_path: &str) -> Result<BlockDeviceAttributes,
+        Box<dyn std::error::Error>> {
+        let device = Path::new(sys_device_path);
+        let queue = device.join("queue");
+        Ok(BlockDeviceAttributes {
+            rotational: read_u64(&queue.join("rotational"))? == 1,
+            removable: read_u64_or(&device.join("removable"), 0) == 1,
+            read_only: read_u64_or(&device.join("ro"), 0) == 1,
+            discard_granularity: read_u64_or(
+                &queue.join("discard_granularity"), 0),
+            discard_max_bytes: read_u64_or(&queue.join("discard_max_bytes"), 0),
+            model: read_optional_string(&device.join("device/model")),
+            vendor: read_optional_string(&device.join("device/vendor")),
+            // nvme controllers and virtio disks expose the serial directly,
+            // scsi disks only through the vpd page
+            serial: read_optional_string(&device.join("device/serial"))
+                .or_else(|| read_optional_string(&device.join("serial")))
+                .or_else(|| read_vpd_serial(&device.join("device/vpd_pg80"))),
+            wwid: read_optional_string(&device.join("wwid"))
+                .or_else(|| read_optional_string(&device.join("device/wwid"))),
+            transport: Transport::from_device(sys_device_path)
+        })
+    }
+
+    /// Discard support is a good indicator of flash storage when the
+    /// rotational flag is unreliable (virtual and hardware raid devices)
+    pub fn supports_discard(&self) -> bool {
+        self.discard_max_bytes > 0
+    }
 }
 
 pub fn get_block_devices() -> Vec<std::fs::DirEntry> {
-    let mut v: Vec<std::fs::DirEntry> = std::fs::read_dir(
-        LINUX_SYSFS_BLOCK_DEVICE_PATH)
-            .unwrap()
-            .filter_map(|e| e.ok())
+    SysRoot::default().block_devices().unwrap()
+}
+
+/// A copy of /sys, /proc and the udev database describing a SATA SSD, an
+/// NVMe drive, an md raid1 across both, a multipathed fibre channel LUN and
+/// a loop device
+#[cfg(test)]
+pub(crate) fn fixture_root() -> SysRoot {
+    SysRoot::new(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/sysroot"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn geometry(physical: u64, minimum: u64, optimal: u64) -> BlockDeviceGeometry {
+        BlockDeviceGeometry {
+            logical_block_size: 512,
+            logical_blocks: 2048,
+            size: 2048 * 512,
+            physical_block_size: physical,
+            minimum_io_size: minimum,
+            optimal_io_size: optimal,
+            alignment_offset: 0
+        }
+    }
+
+    #[test]
+    fn test_io_alignment() {
+        assert_eq!(geometry(512, 512, 0).io_alignment(), 512);
+        assert_eq!(geometry(4096, 512, 0).io_alignment(), 4096);
+        assert_eq!(geometry(4096, 65536, 0).io_alignment(), 65536);
+        assert_eq!(geometry(4096, 65536, 1 << 20).io_alignment(), 1 << 20);
+    }
+
+    #[test]
+    fn test_block_devices() {
+        let names: Vec<String> = fixture_root().block_devices().unwrap()
+            .iter()
+            .map(|e| e.file_name().to_string_lossy().into_owned())
             .collect();
-    v.sort_by_key(|dir| dir.path());
-    v
+        assert_eq!(names, vec!["dm-0", "loop0", "md0", "nvme0n1", "sda", "sdb", "sdc"]);
+    }
+
+    #[test]
+    fn test_kernel_paths() {
+        let root = fixture_root();
+        let devpath = root.sys_to_kernel_path(&root.device_path("sda1")).unwrap();
+        assert_eq!(devpath,
+            "/devices/pci0000:00/0000:00:17.0/ata1/host0/target0:0:0/0:0:0:0/block/sda/sda1");
+        assert!(Path::new(&root.kernel_path_to_sys(&devpath)).join("partition").exists());
+        assert_eq!(root.device_number("nvme0n1p2").unwrap(), (259, 2));
+    }
+
+    #[test]
+    fn test_fixture_geometry() {
+        let root = fixture_root();
+        let sda = root.device_path("sda");
+        let geometry = BlockDeviceGeometry::from_device(sda.to_str().unwrap()).unwrap();
+        assert_eq!(geometry.logical_blocks, 976773168);
+        assert_eq!(geometry.size, 976773168 * 512);
+        assert_eq!(geometry.io_alignment(), 512);
+    }
+
+    #[test]
+    fn test_fixture_attributes() {
+        let root = fixture_root();
+        let attributes = |name: &str| BlockDeviceAttributes::from_device(
+            root.device_path(name).to_str().unwrap()).unwrap();
+
+        let sda = attributes("sda");
+        assert_eq!(sda.transport, Transport::Ata);
+        assert!(!sda.rotational);
+        assert!(sda.supports_discard());
+        assert_eq!(sda.serial.as_deref(), Some("S3Z1NB0K123456A"));
+        assert_eq!(sda.vendor.as_deref(), Some("ATA"));
+
+        let nvme = attributes("nvme0n1");
+        assert_eq!(nvme.transport, Transport::Nvme);
+        assert_eq!(nvme.serial.as_deref(), Some("S4EMNX0M912345"));
+        assert_eq!(nvme.wwid.as_deref(), Some("eui.0025388991b12345"));
+
+        let sdb = attributes("sdb");
+        assert_eq!(sdb.transport, Transport::FibreChannel);
+        assert!(sdb.rotational);
+        assert_eq!(sdb.model.as_deref(), Some("HSV210"));
+
+        assert_eq!(attributes("md0").transport, Transport::Md);
+        assert_eq!(attributes("dm-0").transport, Transport::DeviceMapper);
+        assert_eq!(attributes("loop0").transport, Transport::Loop);
+    }
 }
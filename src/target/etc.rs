@@ -0,0 +1,372 @@
+//! Generates the target's /etc/fstab, /etc/crypttab and
+//! /etc/mdadm/mdadm.conf. Devices are identified by what was read back from
+//! them after they were created, never by what the configuration asked for,
+//! so the files match what the kernel will find at boot.
+
+use std::error::Error;
+use std::fmt;
+use std::path::Path;
+
+use crate::layout::crypt::{CrypttabEntry, Encryption};
+use crate::layout::fs::{FileSystem, FstabEntry, MountBy};
+use crate::layout::raid::RaidArray;
+use crate::luks::LuksHeader;
+use crate::md::MdSuperblock;
+use crate::probe::{probe_device, Usage};
+use crate::udev::UdevDatabase;
+use super::mount::mount_depth;
+
+// Error Boiler plate
+#[derive(Debug)]
+pub struct EtcError {
+    details: String
+}
+
+impl EtcError {
+    pub fn new(msg: &str) -> Self {
+        Self {
+            details: msg.to_string()
+        }
+    }
+}
+
+impl fmt::Display for EtcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.details)
+    }
+}
+
+impl Error for EtcError {
+    fn description(&self) -> &str {
+        &self.details
+    }
+}
+
+impl From<std::io::Error> for EtcError {
+    fn from(error: std::io::Error) -> Self {
+        EtcError::new(&error.to_string())
+    }
+}
+
+pub type EtcResult<T> = Result<T, EtcError>;
+// End Error boiler plate
+
+/// The identifiers of a file system or swap area, as read from its device
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DeviceIds {
+    pub uuid: Option<String>,
+    pub label: Option<String>,
+    /// Only set for partitions
+    pub part_uuid: Option<String>
+}
+
+/// The partition UUID udev recorded for a partition, by kernel name. MBR
+/// partitions have the disk signature and partition number instead.
+pub fn partition_uuid(name: &str, udev: &UdevDatabase) -> Option<String> {
+    udev.get_block_device(name).ok()?
+        .property("ID_PART_ENTRY_UUID")
+        .map(|uuid| uuid.to_owned())
+}
+
+impl DeviceIds {
+    /// Probes the file system or swap area on device. The partition UUID is
+    /// looked up when device is a partition udev knows about.
+    pub fn read(device: &Path, udev: &UdevDatabase) -> EtcResult<DeviceIds> {
+        let signature = probe_device(device)?.into_iter()
+            .find(|s| s.usage == Usage::Filesystem || s.fs_type == "swap")
+            .ok_or_else(|| EtcError::new(
+                &format!("no file system found on {}", device.display())))?;
+        let name = std::fs::canonicalize(device).ok()
+            .and_then(|path| path.file_name().map(|name| name.to_string_lossy().into_owned()));
+        Ok(DeviceIds {
+            uuid: signature.uuid,
+            label: signature.label,
+            part_uuid: name.and_then(|name| partition_uuid(&name, udev))
+        })
+    }
+
+    /// The fstab device: UUID=..., PARTUUID=... or LABEL=...
+    pub fn spec(&self, mount_by: MountBy) -> EtcResult<String> {
+        let value = match mount_by {
+            MountBy::Uuid => &self.uuid,
+            MountBy::Partuuid => &self.part_uuid,
+            MountBy::Label => &self.label
+        };
+        match value {
+            Some(value) => Ok(format!("{}={}", mount_by.tag(), value)),
+            None => Err(EtcError::new(&format!("the device has no {}", mount_by.tag())))
+        }
+    }
+}
+
+/// The contents of the generated files, built up as devices are created
+#[derive(Debug, Default)]
+pub struct SystemFiles {
+    fstab: Vec<FstabEntry>,
+    crypttab: Vec<CrypttabEntry>,
+    arrays: Vec<String>
+}
+
+impl SystemFiles {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the fstab entries of a file system and its subvolumes
+    pub fn add_file_system(&mut self, file_system: &FileSystem, ids: &DeviceIds) -> EtcResult<()> {
+        let spec = ids.spec(file_system.mount_by.unwrap_or_default())?;
+        self.fstab.extend(file_system.fstab_entries(&spec));
+        Ok(())
+    }
+
+    /// Adds a LUKS container, header is read from the formatted device
+    pub fn add_encryption(&mut self, encryption: &Encryption, header: &LuksHeader) {
+        self.crypttab.push(encryption.crypttab_entry(&header.uuid));
+    }
+
+    /// Adds a raid array, superblock is read from any of its members
+    pub fn add_raid_array(&mut self, array: &RaidArray, superblock: &MdSuperblock) {
+        let mut line = format!("ARRAY {} metadata={} UUID={}", array.device_path().display(),
+            superblock.version.as_str(), superblock.array_uuid);
+        if let Some(ref name) = superblock.name {
+            line.push_str(&format!(" name={}", name));
+        }
+        self.arrays.push(line);
+    }
+
+    /// File systems parents first, swap last, so mount -a can work through
+    /// the file in order
+    pub fn fstab(&self) -> String {
+        let mut entries: Vec<&FstabEntry> = self.fstab.iter().collect();
+        entries.sort_by_key(|entry| (entry.vfs_type == "swap", mount_depth(&entry.file)));
+        let mut fstab = String::from("# /etc/fstab, generated by press\n\
+            # <file system> <mount point> <type> <options> <dump> <pass>\n");
+        for entry in entries {
+            fstab.push_str(&format!("{}\n", entry));
+        }
+        fstab
+    }
+
+    pub fn crypttab(&self) -> String {
+        let mut crypttab = String::from("# /etc/crypttab, generated by press\n\
+            # <name> <device> <key file> <options>\n");
+        for entry in self.crypttab.iter() {
+            crypttab.push_str(&format!("{}\n", entry));
+        }
+        crypttab
+    }
+
+    pub fn mdadm_conf(&self) -> String {
+        let mut conf = String::from("# /etc/mdadm/mdadm.conf, generated by press\n\
+            MAILADDR root\n");
+        for array in self.arrays.iter() {
+            conf.push_str(&format!("{}\n", array));
+        }
+        conf
+    }
+
+    /// Writes the files below the target root. fstab is always written,
+    /// crypttab and mdadm.conf only when there is something in them.
+    pub fn write(&self, root: &Path) -> EtcResult<()> {
+        let etc = root.join("etc");
+        let mut files = vec![(etc.join("fstab"), self.fstab())];
+        if !self.crypttab.is_empty() {
+            files.push((etc.join("crypttab"), self.crypttab()));
+        }
+        if !self.arrays.is_empty() {
+            files.push((etc.join("mdadm/mdadm.conf"), self.mdadm_conf()));
+        }
+        for (path, contents) in files {
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            info!("Writing {}", path.display());
+            std::fs::write(&path, contents).map_err(|e| EtcError::new(
+                &format!("could not write {}: {}", path.display(), e)))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+    use crate::config::PressConfiguration;
+    use crate::md::tests::superblock_v1;
+    use crate::layout::raid::MetadataVersion;
+    use crate::sysfs::fixture_root;
+
+    static CONFIGURATION: &str = r#"
+        {
+            "disks": [
+                {
+                    "target": "/dev/sda",
+                    "partitions": [
+                        {
+                            "name": "esp",
+                            "size": "512MiB",
+                            "file_system": {
+                                "fs_type": "vfat", "mount_point": "/boot/efi",
+                                "mount_options": "umask=0077", "mount_by": "partuuid"
+                            }
+                        },
+                        {"name": "swap", "size": "8GiB", "file_system": {"fs_type": "swap"}},
+                        {"name": "raid_a", "size": "100GiB"}
+                    ]
+                },
+                {
+                    "target": "/dev/sdb",
+                    "partitions": [{"name": "raid_b", "size": "100GiB"}]
+                }
+            ],
+            "raid_arrays": [
+                {
+                    "name": "md0", "level": "raid1", "members": ["raid_a", "raid_b"],
+                    "encryption": {"name": "crypt_md0", "key": {"keyfile": "/etc/keys/md0.key"}}
+                }
+            ],
+            "volume_groups": [
+                {
+                    "name": "vg0",
+                    "physical_volumes": ["md0"],
+                    "logical_volumes": [
+                        {
+                            "name": "root",
+                            "size": "50GiB",
+                            "file_system": {"fs_type": "ext4", "mount_point": "/"}
+                        },
+                        {
+                            "name": "home",
+                            "size": "40GiB",
+                            "encryption": {
+                                "name": "crypt_home", "key": {"passphrase_env": "HOME_KEY"},
+                                "options": ["discard"]
+                            },
+                            "file_system": {
+                                "fs_type": "btrfs", "label": "home", "mount_by": "label",
+                                "mount_point": "/home", "mount_options": "noatime",
+                                "subvolumes": [{"path": "@snapshots", "mount_point": "/home/.snapshots"}]
+                            }
+                        }
+                    ]
+                }
+            ]
+        }
+    "#;
+
+    fn golden(name: &str) -> String {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/etc").join(name);
+        std::fs::read_to_string(path).unwrap()
+    }
+
+    // What would have been read back from each device after creating it
+    fn ids(name: &str) -> DeviceIds {
+        let ids = |uuid: &str, label: Option<&str>, part_uuid: Option<&str>| DeviceIds {
+            uuid: Some(uuid.to_owned()),
+            label: label.map(|l| l.to_owned()),
+            part_uuid: part_uuid.map(|p| p.to_owned())
+        };
+        match name {
+            "esp" => ids("1A2B-3C4D", None, Some("4e5f6a7b-8c9d-4eaf-b0c1-d2e3f4a5b6c7")),
+            "swap" => ids("9e8f7a6b-5c4d-4e3f-a2b1-c0d9e8f7a6b5", None,
+                Some("5f6a7b8c-9d0e-4fa0-b1c2-d3e4f5a6b7c8")),
+            "root" => ids("6a7b8c9d-aebf-40c1-92d3-e4f5a6b7c8d9", None, None),
+            "home" => ids("0b1c2d3e-4f5a-4b6c-8d7e-9f0a1b2c3d4e", Some("home"), None),
+            _ => unreachable!()
+        }
+    }
+
+    fn luks_header(uuid: &str) -> LuksHeader {
+        LuksHeader {
+            version: 2,
+            uuid: uuid.to_owned(),
+            label: None,
+            cipher: "aes-xts-plain64".to_owned(),
+            key_size: 512,
+            payload_offset: 16 << 20,
+            keyslots: Vec::new()
+        }
+    }
+
+    fn system_files() -> SystemFiles {
+        let configuration: PressConfiguration = serde_json::from_str(CONFIGURATION).unwrap();
+        configuration.validate().unwrap();
+        let mut files = SystemFiles::new();
+        for partition in configuration.disks.iter().flat_map(|d| d.partitions.iter()) {
+            if let Some(ref file_system) = partition.file_system {
+                files.add_file_system(file_system, &ids(partition.name.as_ref().unwrap())).unwrap();
+            }
+        }
+        let superblock = MdSuperblock::from_slice(&superblock_v1(0, 0),
+            MetadataVersion::V1_2, 4096).unwrap();
+        for array in configuration.raid_arrays.iter() {
+            files.add_raid_array(array, &superblock);
+            if let Some(ref encryption) = array.encryption {
+                files.add_encryption(encryption,
+                    &luks_header("5f0c8a6e-2a1d-4e7b-9c3f-8b1e2d4a6c90"));
+            }
+        }
+        for volume in configuration.volume_groups.iter().flat_map(|g| g.logical_volumes.iter()) {
+            if let Some(ref encryption) = volume.encryption {
+                files.add_encryption(encryption,
+                    &luks_header("7c2e4a6b-8d0f-4a1c-b3e5-d7f9a1c3e5b7"));
+            }
+            if let Some(ref file_system) = volume.file_system {
+                files.add_file_system(file_system, &ids(&volume.name)).unwrap();
+            }
+        }
+        files
+    }
+
+    #[test]
+    fn test_golden_files() {
+        let files = system_files();
+        assert_eq!(files.fstab(), golden("fstab"));
+        assert_eq!(files.crypttab(), golden("crypttab"));
+        assert_eq!(files.mdadm_conf(), golden("mdadm.conf"));
+
+        let root = std::env::temp_dir().join(format!("press-etc-{}", std::process::id()));
+        files.write(&root).unwrap();
+        assert_eq!(std::fs::read_to_string(root.join("etc/mdadm/mdadm.conf")).unwrap(),
+            golden("mdadm.conf"));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_missing_ids() {
+        let file_system: FileSystem = serde_json::from_str(
+            r#"{"fs_type": "ext4", "mount_point": "/", "mount_by": "partuuid"}"#).unwrap();
+        let error = SystemFiles::new().add_file_system(&file_system, &ids("root")).unwrap_err();
+        assert_eq!(error.to_string(), "the device has no PARTUUID");
+    }
+
+    #[test]
+    fn test_read() {
+        let udev = UdevDatabase::new(fixture_root());
+        assert_eq!(partition_uuid("nvme0n1p1", &udev).as_deref(),
+            Some("4e5f6a7b-8c9d-4eaf-b0c1-d2e3f4a5b6c7"));
+        assert_eq!(partition_uuid("nvme0n1", &udev), None);
+
+        if Command::new("mke2fs").arg("-V").output().is_err() {
+            return
+        }
+        let image = std::env::temp_dir().join(format!("press-etc-{}.img", std::process::id()));
+        std::fs::File::create(&image).unwrap().set_len(4 << 20).unwrap();
+        let uuid = "6a7b8c9d-aebf-40c1-92d3-e4f5a6b7c8d9";
+        let status = Command::new("mke2fs")
+            .args(["-q", "-F", "-t", "ext4", "-L", "root", "-U", uuid])
+            .arg(&image)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let ids = DeviceIds::read(&image, &udev).unwrap();
+        std::fs::remove_file(&image).unwrap();
+        assert_eq!(ids, DeviceIds {
+            uuid: Some(uuid.to_owned()),
+            label: Some("root".to_owned()),
+            part_uuid: None
+        });
+        assert_eq!(ids.spec(MountBy::Label).unwrap(), "LABEL=root");
+    }
+}
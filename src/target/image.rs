@@ -0,0 +1,512 @@
+//! Writes raw disk images to a target device. A configured checksum is
+//! verified before anything is written. The image is decompressed as it is
+//! read and written in large aligned chunks with O_DIRECT, bypassing the
+//! page cache. On devices which read back zeroes after a discard the device
+//! is discarded first and zero blocks of the image are skipped. The written
+//! data is read back and hashed, and a GPT in the image has its backup moved
+//! to the end of a larger target.
+
+use std::alloc::{self, Layout};
+use std::error::Error;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+use std::os::unix::fs::{FileExt, FileTypeExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use crate::gpt;
+use crate::layout::selector::DiskSelector;
+use crate::loopdev::{BLKRRPART, ioctl};
+use crate::signal;
+use super::stream::{Checksum, ChecksumAlgorithm, Compression, Hasher, Progress, SourceReader,
+                    read_checksum};
+
+// Error Boiler plate
+#[derive(Debug)]
+pub struct ImageError {
+    details: String
+}
+
+impl ImageError {
+    pub fn new(msg: &str) -> Self {
+        Self {
+            details: msg.to_string()
+        }
+    }
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.details)
+    }
+}
+
+impl Error for ImageError {
+    fn description(&self) -> &str {
+        &self.details
+    }
+}
+
+pub type ImageResult<T> = Result<T, ImageError>;
+// End Error boiler plate
+
+const BLKSSZGET: libc::c_ulong = 0x1268;
+const BLKDISCARD: libc::c_ulong = 0x1277;
+const BLKGETSIZE64: libc::c_ulong = 0x8008_1272;
+
+/// The size of each write
+pub const WRITE_SIZE: usize = 4 << 20;
+/// O_DIRECT buffers, offsets and lengths are aligned to this, a multiple of
+/// every logical block size
+pub const ALIGNMENT: usize = 4096;
+
+/// A raw disk image written to a whole disk instead of partitioning it
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct DiskImage {
+    pub path: String,
+    /// The disk to write to, a device path or selection rules
+    pub target: Option<DiskSelector>,
+    /// Detected from the content unless set
+    pub compression: Option<Compression>,
+    /// sha256:<hex> or sha512:<hex> of the image as stored
+    pub checksum: Option<String>
+}
+
+/// The outcome of writing an image
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageWritten {
+    /// The size of the image after decompression
+    pub bytes: u64,
+    /// Bytes of zeroes left to the discard instead of being written
+    pub skipped: u64,
+    /// The checksum of the image as stored
+    pub checksum: Checksum,
+    /// The sha256 of the image data, as read back from the device
+    pub digest: Checksum,
+    pub gpt_relocated: bool
+}
+
+/// A zeroed buffer aligned for O_DIRECT
+struct AlignedBuffer {
+    pointer: *mut u8,
+    layout: Layout
+}
+
+impl AlignedBuffer {
+    fn new(size: usize) -> Self {
+        let layout = Layout::from_size_align(size, ALIGNMENT).unwrap();
+        let pointer = unsafe { alloc::alloc_zeroed(layout) };
+        if pointer.is_null() {
+            alloc::handle_alloc_error(layout);
+        }
+        Self { pointer, layout }
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.pointer, self.layout.size()) }
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.pointer, self.layout.size()) }
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.pointer, self.layout) }
+    }
+}
+
+fn is_zero(data: &[u8]) -> bool {
+    data.iter().all(|b| *b == 0)
+}
+
+/// Reads until buffer is full or the stream ends
+fn fill<R: Read>(reader: &mut R, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e)
+        }
+    }
+    Ok(filled)
+}
+
+/// The device being written, or a regular file standing in for one
+struct Target {
+    /// Opened with O_DIRECT where the file system or driver allows it
+    direct: File,
+    buffered: File,
+    block_device: bool,
+    size: u64,
+    lba_size: u64
+}
+
+impl Target {
+    fn open(device: &Path) -> io::Result<Target> {
+        let buffered = OpenOptions::new().read(true).write(true).open(device)?;
+        let direct = match OpenOptions::new().read(true).write(true)
+                .custom_flags(libc::O_DIRECT).open(device) {
+            Ok(direct) => direct,
+            // tmpfs for one does not support O_DIRECT
+            Err(ref e) if e.raw_os_error() == Some(libc::EINVAL) => {
+                warn!("{} does not support O_DIRECT, writing through the page cache",
+                    device.display());
+                buffered.try_clone()?
+            },
+            Err(e) => return Err(e)
+        };
+        let block_device = buffered.metadata()?.file_type().is_block_device();
+        let (size, lba_size) = if block_device {
+            let mut size = 0u64;
+            ioctl(&buffered, BLKGETSIZE64, &mut size as *mut u64 as libc::c_ulong)?;
+            let mut lba_size: libc::c_int = 0;
+            ioctl(&buffered, BLKSSZGET, &mut lba_size as *mut libc::c_int as libc::c_ulong)?;
+            (size, lba_size as u64)
+        } else {
+            (buffered.metadata()?.len(), 512)
+        };
+        Ok(Target { direct, buffered, block_device, size, lba_size })
+    }
+
+    fn discard(&self, offset: u64, length: u64) -> io::Result<()> {
+        if self.block_device {
+            let range = [offset, length];
+            ioctl(&self.buffered, BLKDISCARD, range.as_ptr() as libc::c_ulong).map(|_| ())
+        } else {
+            let rc = unsafe {
+                libc::fallocate(self.buffered.as_raw_fd(),
+                    libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                    offset as libc::off_t, length as libc::off_t)
+            };
+            if rc < 0 {
+                return Err(io::Error::last_os_error())
+            }
+            Ok(())
+        }
+    }
+
+    /// Aligned data goes through O_DIRECT, only the end of an image which is
+    /// not a multiple of the alignment goes through the page cache
+    fn write_at(&self, data: &[u8], offset: u64) -> io::Result<()> {
+        let aligned = data.len() - data.len() % ALIGNMENT;
+        self.direct.write_all_at(&data[..aligned], offset)?;
+        self.buffered.write_all_at(&data[aligned..], offset + aligned as u64)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.direct.sync_all()?;
+        self.buffered.sync_all()
+    }
+
+    /// Whether discarded blocks read back as zeroes. A pattern is written
+    /// to a few blocks first so blocks which were zero already do not count.
+    /// The whole device is discarded in the process.
+    fn discard_zeroes(&self) -> bool {
+        if self.size < ALIGNMENT as u64 {
+            return false
+        }
+        let last = (self.size / ALIGNMENT as u64 - 1) * ALIGNMENT as u64;
+        let samples = [0, (last / 2) & !(ALIGNMENT as u64 - 1), last];
+        let mut buffer = AlignedBuffer::new(ALIGNMENT);
+        buffer.as_mut_slice().iter_mut().for_each(|b| *b = 0xa5);
+        let discarded = samples.iter().try_for_each(|offset| self.write_at(buffer.as_slice(), *offset))
+            .and_then(|_| self.sync())
+            .and_then(|_| self.discard(0, self.size));
+        if let Err(e) = discarded {
+            debug!("Not skipping zero blocks, discard failed: {}", e);
+            return false
+        }
+        samples.iter().all(|offset| {
+            self.direct.read_exact_at(buffer.as_mut_slice(), *offset).is_ok() &&
+                is_zero(buffer.as_slice())
+        })
+    }
+
+    /// Writes a chunk of the image, leaving out runs of zero blocks when
+    /// skip_zeroes is set. Returns the number of bytes skipped.
+    fn write_chunk(&self, data: &[u8], offset: u64, skip_zeroes: bool) -> io::Result<u64> {
+        let mut skipped = 0;
+        let mut run_start = None;
+        for start in (0..data.len()).step_by(ALIGNMENT) {
+            let end = (start + ALIGNMENT).min(data.len());
+            if skip_zeroes && is_zero(&data[start..end]) {
+                if let Some(run) = run_start.take() {
+                    self.write_at(&data[run..start], offset + run as u64)?;
+                }
+                skipped += (end - start) as u64;
+            } else if run_start.is_none() {
+                run_start = Some(start);
+            }
+        }
+        if let Some(run) = run_start {
+            self.write_at(&data[run..], offset + run as u64)?;
+        }
+        Ok(skipped)
+    }
+
+    /// The sha256 of the first length bytes of the device
+    fn digest(&self, length: u64) -> io::Result<Checksum> {
+        let mut hasher = Hasher::new(ChecksumAlgorithm::Sha256);
+        let mut buffer = AlignedBuffer::new(WRITE_SIZE);
+        let mut offset = 0;
+        while offset < length {
+            signal::check_interrupted()?;
+            let chunk = (length - offset).min(WRITE_SIZE as u64) as usize;
+            let aligned = chunk - chunk % ALIGNMENT;
+            let data = &mut buffer.as_mut_slice()[..chunk];
+            self.direct.read_exact_at(&mut data[..aligned], offset)?;
+            self.buffered.read_exact_at(&mut data[aligned..], offset + aligned as u64)?;
+            hasher.update(data);
+            offset += chunk as u64;
+        }
+        Ok(hasher.finish())
+    }
+}
+
+impl DiskImage {
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: path.to_owned(),
+            target: None,
+            compression: None,
+            checksum: None
+        }
+    }
+
+    pub fn check(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if self.path.is_empty() {
+            errors.push("the image path is empty".to_owned());
+        }
+        if let Some(ref checksum) = self.checksum {
+            if let Err(e) = Checksum::parse(checksum) {
+                errors.push(e.to_string());
+            }
+        }
+        if let Some(DiskSelector::Rules(ref rules)) = self.target {
+            errors.extend(rules.check());
+        }
+        errors
+    }
+
+    /// Writes the image to device, which must be at least as large as the
+    /// decompressed image. Everything on device is lost. A configured
+    /// checksum is verified before anything is written, which takes an extra
+    /// read of the image, and once more as it is written. An image which can
+    /// only be read once, a pipe for instance, is only verified as it is
+    /// written.
+    pub fn write(&self, device: &Path, progress: &mut dyn FnMut(&Progress)) -> ImageResult<ImageWritten> {
+        let expected = match self.checksum {
+            Some(ref checksum) => Some(Checksum::parse(checksum)
+                .map_err(|e| ImageError::new(&e.to_string()))?),
+            None => None
+        };
+        let source_error = |e: io::Error| ImageError::new(&format!("{}: {}", self.path, e));
+        let device_error = |e: io::Error| ImageError::new(&format!("{}: {}", device.display(), e));
+        let mut file = File::open(&self.path).map_err(source_error)?;
+        let metadata = file.metadata().map_err(source_error)?;
+        match expected {
+            Some(ref expected) if metadata.is_file() => {
+                info!("Verifying {}", self.path);
+                let computed = read_checksum(&mut file, expected.algorithm).map_err(source_error)?;
+                expected.verify(&computed).map_err(|e| ImageError::new(
+                    &format!("{}: {}", self.path, e)))?;
+                file.seek(SeekFrom::Start(0)).map_err(source_error)?;
+            },
+            _ => ()
+        }
+        let total = Some(metadata.len());
+        let algorithm = expected.as_ref().map(|c| c.algorithm).unwrap_or(ChecksumAlgorithm::Sha256);
+        let mut source = BufReader::with_capacity(WRITE_SIZE,
+            SourceReader::new(file, algorithm, total, progress));
+        let compression = match self.compression {
+            Some(compression) => compression,
+            None => Compression::detect(source.fill_buf().map_err(source_error)?)
+        };
+
+        let target = Target::open(device).map_err(device_error)?;
+        let skip_zeroes = target.discard_zeroes();
+        info!("Writing {} ({}) to {}{}", self.path, compression.name(), device.display(),
+            if skip_zeroes { ", skipping zero blocks" } else { "" });
+        let mut hasher = Hasher::new(ChecksumAlgorithm::Sha256);
+        let mut buffer = AlignedBuffer::new(WRITE_SIZE);
+        let (mut bytes, mut skipped) = (0u64, 0u64);
+        {
+            let mut decoder = compression.decoder(&mut source).map_err(source_error)?;
+            loop {
+                signal::check_interrupted().map_err(device_error)?;
+                let filled = fill(&mut decoder, buffer.as_mut_slice()).map_err(source_error)?;
+                if filled == 0 {
+                    break
+                }
+                if bytes + filled as u64 > target.size {
+                    return Err(ImageError::new(&format!("{} is larger than {} ({} bytes)",
+                        self.path, device.display(), target.size)))
+                }
+                let data = &buffer.as_slice()[..filled];
+                hasher.update(data);
+                skipped += target.write_chunk(data, bytes, skip_zeroes).map_err(device_error)?;
+                bytes += filled as u64;
+                if filled < WRITE_SIZE {
+                    break
+                }
+            }
+        }
+        target.sync().map_err(device_error)?;
+
+        // Whatever follows the compressed stream is part of the checksum all the same
+        io::copy(&mut source, &mut io::sink()).map_err(source_error)?;
+        let checksum = source.into_inner().finish();
+        if let Some(expected) = expected {
+            expected.verify(&checksum).map_err(|e| ImageError::new(
+                &format!("{}: {}", self.path, e)))?;
+        }
+        let written = hasher.finish();
+        let digest = target.digest(bytes).map_err(device_error)?;
+        if digest != written {
+            return Err(ImageError::new(&format!("{} reads back {} but the image is {}",
+                device.display(), digest, written)))
+        }
+        info!("Wrote {} bytes to {}, {} bytes of zeroes skipped, {}", bytes, device.display(),
+            skipped, digest);
+
+        let mut buffered = &target.buffered;
+        let gpt_relocated = gpt::relocate_backup(&mut buffered, target.size, target.lba_size)
+            .map_err(device_error)?;
+        target.sync().map_err(device_error)?;
+        if target.block_device {
+            if let Err(e) = ioctl(&target.buffered, BLKRRPART, 0) {
+                warn!("Could not reread the partitions of {}: {}", device.display(), e);
+            }
+        }
+        Ok(ImageWritten { bytes, skipped, checksum, digest, gpt_relocated })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpt::GPTHeader;
+    use crate::gpt::tests::gpt_disk;
+
+    fn staging(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("press-image-{}-{}", name, std::process::id()))
+    }
+
+    fn sha256(data: &[u8]) -> Checksum {
+        let mut hasher = Hasher::new(ChecksumAlgorithm::Sha256);
+        hasher.update(data);
+        hasher.finish()
+    }
+
+    // A target which has been used before
+    fn target(path: &Path, size: usize) {
+        std::fs::write(path, vec![0xffu8; size]).unwrap();
+    }
+
+    #[test]
+    fn test_check() {
+        let mut image: DiskImage = serde_json::from_str(r#"{
+            "path": "", "target": {"model": "SAMSUNG["}, "checksum": "sha256:xyz"
+        }"#).unwrap();
+        let errors = image.check();
+        assert_eq!(errors.len(), 3);
+        assert_eq!(errors[0], "the image path is empty");
+        assert_eq!(errors[1], "xyz is not a hex digest");
+        assert!(errors[2].starts_with("invalid model expression SAMSUNG["), "{}", errors[2]);
+        image.path = "/srv/disk.img.zst".to_owned();
+        image.target = Some(DiskSelector::Path("/dev/sda".to_owned()));
+        image.checksum = None;
+        assert!(image.check().is_empty());
+    }
+
+    #[test]
+    fn test_write_chunk() {
+        let path = staging("chunk");
+        target(&path, 64 << 10);
+        let target = Target::open(&path).unwrap();
+        // O_DIRECT needs an aligned buffer
+        let mut buffer = AlignedBuffer::new(WRITE_SIZE);
+        let data = &mut buffer.as_mut_slice()[..20_000];
+        data[5000] = 1;
+        data[19_999] = 2;
+        let data = &buffer.as_slice()[..20_000];
+        // Blocks 0, 2 and 3 are zero, the last block is short
+        assert_eq!(target.write_chunk(data, 8192, true).unwrap(), 3 * 4096);
+        let mut read = vec![0u8; 20_000];
+        target.buffered.read_exact_at(&mut read, 8192).unwrap();
+        assert!(read[..4096].iter().all(|b| *b == 0xff));
+        assert_eq!(&read[4096..8192], &data[4096..8192]);
+        assert!(read[8192..16384].iter().all(|b| *b == 0xff));
+        assert_eq!(&read[16384..], &data[16384..]);
+        assert_eq!(target.write_chunk(data, 8192, false).unwrap(), 0);
+        assert_eq!(target.digest(8192 + 20_000).unwrap(),
+            sha256(&[&[0xffu8; 8192][..], data].concat()));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_write() {
+        // An 8MiB GPT disk with some data on its partition, written to 32MiB
+        let mut disk = gpt_disk(16384);
+        for (index, byte) in disk[(1 << 20) + 100..(3 << 20)].iter_mut().enumerate() {
+            *byte = (index % 251) as u8;
+        }
+        let image_path = staging("disk.img.zst");
+        std::fs::write(&image_path, zstd::stream::encode_all(&disk[..], 3).unwrap()).unwrap();
+        let device = staging("disk");
+        target(&device, 32 << 20);
+
+        let mut image = DiskImage::new(&image_path.to_string_lossy());
+        image.checksum = Some(sha256(&std::fs::read(&image_path).unwrap()).to_string());
+        let mut updates = Vec::new();
+        let written = image.write(&device, &mut |p: &Progress| updates.push(*p)).unwrap();
+        assert_eq!(written.bytes, disk.len() as u64);
+        assert_eq!(written.digest, sha256(&disk));
+        assert_eq!(Some(written.checksum.to_string()), image.checksum);
+        assert!(written.gpt_relocated);
+        assert_eq!(updates.last().unwrap().percent(), Some(100));
+
+        let result = std::fs::read(&device).unwrap();
+        let lbas = (32 << 20) / 512;
+        // Holes punched into a file read back as zeroes, so they are skipped
+        assert!(written.skipped > 4 << 20, "{}", written.skipped);
+        assert!(result[8 << 20..(32 << 20) - 512 * 33].iter().all(|b| *b == 0));
+        assert!(result[1024..(16384 - 33) * 512] == disk[1024..(16384 - 33) * 512]);
+        let primary = GPTHeader::from_slice(&result[512..]);
+        assert_eq!(primary.backup_lba, lbas - 1);
+        let backup = GPTHeader::from_slice(&result[(lbas as usize - 1) * 512..]);
+        assert_eq!((backup.current_lba, backup.partition_entry_lba), (lbas - 1, lbas - 33));
+
+        // Too large for the target
+        target(&device, 4 << 20);
+        let error = image.write(&device, &mut |_: &Progress| ()).unwrap_err().to_string();
+        assert!(error.ends_with("(4194304 bytes)"), "{}", error);
+
+        // An uncompressed image which is not a multiple of the alignment
+        let raw: Vec<u8> = (0..(1 << 20) + 512).map(|i| (i % 13) as u8).collect();
+        std::fs::write(&image_path, &raw).unwrap();
+        target(&device, 2 << 20);
+        let written = image.write(&device, &mut |_: &Progress| ()).unwrap_err().to_string();
+        assert!(written.contains("checksum mismatch"), "{}", written);
+        // Nothing is written from an image with the wrong checksum
+        assert!(std::fs::read(&device).unwrap().iter().all(|b| *b == 0xff));
+        image.checksum = None;
+        let written = image.write(&device, &mut |_: &Progress| ()).unwrap();
+        assert_eq!((written.bytes, written.skipped, written.gpt_relocated), (raw.len() as u64, 0, false));
+        assert_eq!(written.digest, sha256(&raw));
+        assert!(std::fs::read(&device).unwrap()[..raw.len()] == raw[..]);
+
+        std::fs::remove_file(&device).unwrap();
+        std::fs::remove_file(&image_path).unwrap();
+    }
+}
@@ -0,0 +1,6 @@
+//! Preparing the target system once its storage has been laid out
+pub mod etc;
+pub mod image;
+pub mod mount;
+pub mod rootfs;
+pub mod stream;
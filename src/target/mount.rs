@@ -0,0 +1,300 @@
+//! Assembles the target system under a staging directory. File systems are
+//! mounted parents first and the pseudo file systems needed to chroot are
+//! bound from the host. The mounts and their options are those of
+//! `FileSystem::mounts`. Everything is unmounted in reverse order, when the
+//! tree is dropped at the latest, which covers errors and interruptions.
+//! Signals are recorded instead of killing press while a tree exists.
+
+use std::error::Error;
+use std::fmt;
+use std::path::{Component, Path, PathBuf};
+
+use crate::command;
+use crate::layout::fs::Mount;
+use crate::signal::{self, SignalGuard};
+
+// Error Boiler plate
+#[derive(Debug)]
+pub struct MountError {
+    details: String
+}
+
+impl MountError {
+    pub fn new(msg: &str) -> Self {
+        Self {
+            details: msg.to_string()
+        }
+    }
+}
+
+impl fmt::Display for MountError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.details)
+    }
+}
+
+impl Error for MountError {
+    fn description(&self) -> &str {
+        &self.details
+    }
+}
+
+pub type MountResult<T> = Result<T, MountError>;
+// End Error boiler plate
+
+/// Host directories bound into the target so commands can be run in a chroot
+pub static SYSTEM_BINDS: &[&str] = &["/dev", "/proc", "/sys", "/run"];
+
+fn check_interrupted() -> MountResult<()> {
+    signal::check_interrupted().map_err(|e| MountError::new(&e.to_string()))
+}
+
+/// The number of directories below / of a mount point, / itself is 0
+pub fn mount_depth(mount_point: &str) -> usize {
+    Path::new(mount_point).components()
+        .filter(|c| matches!(c, Component::Normal(_)))
+        .count()
+}
+
+/// Orders mounts so every mount point is mounted after the mount points
+/// it is below: /, /boot, /home, /boot/efi
+pub fn sort_mounts<T>(mounts: &mut [(T, Mount)]) {
+    mounts.sort_by(|(_, a), (_, b)| mount_depth(&a.mount_point).cmp(&mount_depth(&b.mount_point))
+        .then_with(|| a.mount_point.cmp(&b.mount_point)));
+}
+
+#[derive(Debug)]
+struct Mounted {
+    target: PathBuf,
+    /// Recursive binds carry the host's submounts, /dev/pts for instance
+    recursive: bool
+}
+
+/// The mounts which make up the target system, in the order they were made
+#[derive(Debug)]
+pub struct MountTree {
+    root: PathBuf,
+    mounted: Vec<Mounted>,
+    /// Dropped after everything has been unmounted
+    _signals: SignalGuard
+}
+
+impl MountTree {
+    /// A tree staged at root, which is created when missing. Signals are
+    /// handled from here on, see `signal::install_signal_handlers`.
+    pub fn new<P: AsRef<Path>>(root: P) -> MountResult<MountTree> {
+        let root = root.as_ref().to_path_buf();
+        std::fs::create_dir_all(&root).map_err(|e| MountError::new(
+            &format!("could not create {}: {}", root.display(), e)))?;
+        Ok(MountTree {
+            root,
+            mounted: Vec::new(),
+            _signals: signal::install_signal_handlers()
+        })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Where a mount point of the target system is in the staging directory
+    pub fn target(&self, mount_point: &str) -> MountResult<PathBuf> {
+        let relative = Path::new(mount_point.trim_start_matches('/'));
+        if relative.components().any(|c| !matches!(c, Component::Normal(_))) {
+            return Err(MountError::new(&format!("invalid mount point {}", mount_point)))
+        }
+        Ok(self.root.join(relative))
+    }
+
+    /// The directories currently mounted, innermost last
+    pub fn mounted(&self) -> Vec<&Path> {
+        self.mounted.iter().map(|m| m.target.as_path()).collect()
+    }
+
+    fn mount_point(&self, mount_point: &str) -> MountResult<PathBuf> {
+        check_interrupted()?;
+        let target = self.target(mount_point)?;
+        std::fs::create_dir_all(&target).map_err(|e| MountError::new(
+            &format!("could not create {}: {}", target.display(), e)))?;
+        Ok(target)
+    }
+
+    /// Mounts a file system or subvolume of device at its mount point
+    pub fn mount(&mut self, device: &Path, mount: &Mount) -> MountResult<()> {
+        let target = self.mount_point(&mount.mount_point)?;
+        let mut arguments = vec!["-t".to_owned(), mount.fs_type.name().to_owned()];
+        if !mount.options.is_empty() {
+            arguments.extend(vec!["-o".to_owned(), mount.options.join(",")]);
+        }
+        arguments.push(device.to_string_lossy().into_owned());
+        arguments.push(target.to_string_lossy().into_owned());
+        info!("Mounting {} at {}", device.display(), target.display());
+        command::run("mount", &arguments).map_err(|e| MountError::new(&e.to_string()))?;
+        self.mounted.push(Mounted { target, recursive: false });
+        Ok(())
+    }
+
+    /// Mounts every file system in mount point order
+    pub fn mount_all<D: AsRef<Path>>(&mut self, mounts: &mut [(D, Mount)]) -> MountResult<()> {
+        sort_mounts(mounts);
+        for (device, mount) in mounts.iter() {
+            self.mount(device.as_ref(), mount)?;
+        }
+        Ok(())
+    }
+
+    /// Binds a host directory, with its submounts, at the same path in the
+    /// target. Slave propagation keeps unmounts in the target from reaching
+    /// the host.
+    pub fn bind(&mut self, source: &str) -> MountResult<()> {
+        let target = self.mount_point(source)?;
+        let target_str = target.to_string_lossy().into_owned();
+        info!("Binding {} at {}", source, target.display());
+        command::run("mount", &["--rbind".to_owned(), source.to_owned(), target_str.clone()])
+            .map_err(|e| MountError::new(&e.to_string()))?;
+        self.mounted.push(Mounted { target, recursive: true });
+        command::run("mount", &["--make-rslave".to_owned(), target_str])
+            .map_err(|e| MountError::new(&e.to_string()))
+    }
+
+    /// Binds /dev, /proc, /sys and /run for running commands in a chroot
+    pub fn bind_system(&mut self) -> MountResult<()> {
+        for source in SYSTEM_BINDS.iter() {
+            self.bind(source)?;
+        }
+        Ok(())
+    }
+
+    /// Unmounts everything in reverse order. Every mount is attempted, the
+    /// first failure is returned. Signals do not stop it, this is how an
+    /// interrupted deployment cleans up.
+    pub fn unmount_all(&mut self) -> MountResult<()> {
+        let mut result = Ok(());
+        while let Some(mounted) = self.mounted.pop() {
+            let mut arguments = Vec::new();
+            if mounted.recursive {
+                arguments.push("--recursive".to_owned());
+            }
+            arguments.push(mounted.target.to_string_lossy().into_owned());
+            info!("Unmounting {}", mounted.target.display());
+            if let Err(e) = command::run_cleanup("umount", &arguments) {
+                warn!("{}", e);
+                if result.is_ok() {
+                    result = Err(MountError::new(&e.to_string()));
+                }
+            }
+        }
+        result
+    }
+}
+
+impl Drop for MountTree {
+    fn drop(&mut self) {
+        if !self.mounted.is_empty() {
+            let _ = self.unmount_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+    use crate::layout::fs::FileSystemType;
+
+    fn mount(mount_point: &str) -> Mount {
+        Mount {
+            mount_point: mount_point.to_owned(),
+            fs_type: FileSystemType::Ext4,
+            options: Vec::new()
+        }
+    }
+
+    fn staging(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("press-mount-{}-{}", name, std::process::id()))
+    }
+
+    // An ext4 image mounted through a loop device
+    fn image(path: &Path, format: bool) -> bool {
+        std::fs::File::create(path).unwrap().set_len(4 << 20).unwrap();
+        !format || Command::new("mke2fs").args(["-q", "-F", "-t", "ext4"]).arg(path)
+            .output().map(|o| o.status.success()).unwrap_or(false)
+    }
+
+    fn is_mounted(path: &Path) -> bool {
+        std::fs::read_to_string("/proc/self/mountinfo").unwrap().lines()
+            .any(|line| line.split(' ').nth(4) == Some(&path.to_string_lossy()))
+    }
+
+    #[test]
+    fn test_sort_mounts() {
+        let mut mounts: Vec<(&str, Mount)> = ["/home/user", "/boot/efi", "/", "/boot", "/home"]
+            .iter()
+            .map(|m| ("/dev/null", mount(m)))
+            .collect();
+        sort_mounts(&mut mounts);
+        let order: Vec<&str> = mounts.iter().map(|(_, m)| m.mount_point.as_str()).collect();
+        assert_eq!(order, vec!["/", "/boot", "/home", "/boot/efi", "/home/user"]);
+    }
+
+    #[test]
+    fn test_target() {
+        let tree = MountTree::new(staging("target")).unwrap();
+        assert_eq!(tree.target("/").unwrap(), tree.root());
+        assert_eq!(tree.target("/boot/efi").unwrap(), tree.root().join("boot/efi"));
+        assert!(tree.target("/../etc").is_err());
+        std::fs::remove_dir(tree.root()).unwrap();
+    }
+
+    #[test]
+    fn test_mount_tree() {
+        let images = staging("images");
+        std::fs::create_dir_all(&images).unwrap();
+        let (root_image, boot_image, blank) =
+            (images.join("root.img"), images.join("boot.img"), images.join("blank.img"));
+        if !image(&root_image, true) || !image(&boot_image, true) || !image(&blank, false) {
+            std::fs::remove_dir_all(&images).unwrap();
+            return
+        }
+        let loop_mount = |device: &Path, mount_point: &str| {
+            let mut mount = mount(mount_point);
+            mount.options = vec!["loop".to_owned()];
+            (device.to_path_buf(), mount)
+        };
+
+        let root = staging("tree");
+        let mut tree = MountTree::new(&root).unwrap();
+        // Mounting needs root, skip when the image cannot be mounted
+        if tree.mount(&root_image, &loop_mount(&root_image, "/").1).is_err() {
+            std::fs::remove_dir_all(&images).unwrap();
+            std::fs::remove_dir_all(&root).unwrap();
+            return
+        }
+        tree.unmount_all().unwrap();
+
+        // /boot fails on the blank image and / is unmounted on the way out
+        let mut mounts = vec![loop_mount(&blank, "/boot"), loop_mount(&root_image, "/")];
+        assert!(tree.mount_all(&mut mounts).is_err());
+        assert_eq!(tree.mounted(), vec![root.as_path()]);
+        tree.unmount_all().unwrap();
+        assert!(!is_mounted(&root));
+
+        let mut mounts = vec![loop_mount(&boot_image, "/boot"), loop_mount(&root_image, "/")];
+        tree.mount_all(&mut mounts).unwrap();
+        tree.bind_system().unwrap();
+        assert_eq!(tree.mounted(), vec![
+            root.clone(), root.join("boot"), root.join("dev"), root.join("proc"),
+            root.join("sys"), root.join("run")
+        ]);
+        assert!(is_mounted(&root.join("boot")));
+        assert!(root.join("proc/self/mountinfo").exists());
+
+        // Dropping the tree unmounts everything, as an error would
+        drop(tree);
+        assert!(!is_mounted(&root));
+        assert!(!is_mounted(&root.join("dev")));
+        assert!(!root.join("boot").exists());
+        std::fs::remove_dir_all(&root).unwrap();
+        std::fs::remove_dir_all(&images).unwrap();
+    }
+}
@@ -0,0 +1,427 @@
+//! Deploys an operating system by extracting a root file system tarball
+//! into the mounted target. A configured checksum is verified before
+//! anything is extracted. The tarball is decompressed as it is read and GNU
+//! tar restores ownership, permissions, extended attributes, ACLs and hard
+//! links.
+
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use crate::signal;
+use super::stream::{Checksum, ChecksumAlgorithm, Compression, Progress, SourceReader, read_checksum};
+
+// Error Boiler plate
+#[derive(Debug)]
+pub struct RootfsError {
+    details: String
+}
+
+impl RootfsError {
+    pub fn new(msg: &str) -> Self {
+        Self {
+            details: msg.to_string()
+        }
+    }
+}
+
+impl fmt::Display for RootfsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.details)
+    }
+}
+
+impl Error for RootfsError {
+    fn description(&self) -> &str {
+        &self.details
+    }
+}
+
+pub type RootfsResult<T> = Result<T, RootfsError>;
+// End Error boiler plate
+
+/// Read buffer for the tarball, large enough to keep tar and the decoder busy
+const READ_BUFFER_SIZE: usize = 1 << 20;
+
+/// A root file system tarball
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Tarball {
+    pub path: String,
+    /// Detected from the content unless set
+    pub compression: Option<Compression>,
+    /// sha256:<hex> or sha512:<hex> of the tarball as stored
+    pub checksum: Option<String>,
+    /// Additional arguments passed to tar as is, --exclude for instance
+    #[serde(default)]
+    pub extra_options: Vec<String>
+}
+
+impl Tarball {
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: path.to_owned(),
+            compression: None,
+            checksum: None,
+            extra_options: Vec::new()
+        }
+    }
+
+    pub fn check(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if self.path.is_empty() {
+            errors.push("the tarball path is empty".to_owned());
+        }
+        if let Some(ref checksum) = self.checksum {
+            if let Err(e) = Checksum::parse(checksum) {
+                errors.push(e.to_string());
+            }
+        }
+        errors
+    }
+
+    /// Arguments for extracting an uncompressed tarball read from stdin into
+    /// root. Owners are restored by number, the target's ids need not match
+    /// the host's.
+    pub fn tar_arguments(&self, root: &Path) -> Vec<String> {
+        let mut arguments: Vec<String> = [
+            "--extract", "--file=-", "--preserve-permissions", "--same-owner", "--numeric-owner",
+            "--xattrs", "--xattrs-include=*", "--acls"
+        ].iter().map(|a| a.to_string()).collect();
+        arguments.push(format!("--directory={}", root.display()));
+        arguments.extend(self.extra_options.iter().cloned());
+        arguments
+    }
+
+    /// Extracts the tarball into root, which is usually the root of a
+    /// `MountTree`. The checksum of the tarball is returned. A configured
+    /// checksum is verified before extracting, which takes an extra read of
+    /// the tarball, and once more as it is extracted. A tarball which can
+    /// only be read once, a pipe for instance, is only verified as it is
+    /// extracted and a mismatch leaves files behind which must not be
+    /// trusted.
+    pub fn deploy(&self, root: &Path, progress: &mut dyn FnMut(&Progress)) -> RootfsResult<Checksum> {
+        let expected = match self.checksum {
+            Some(ref checksum) => Some(Checksum::parse(checksum)
+                .map_err(|e| RootfsError::new(&e.to_string()))?),
+            None => None
+        };
+        let io_error = |e: io::Error| RootfsError::new(&format!("{}: {}", self.path, e));
+        let mut file = File::open(&self.path).map_err(io_error)?;
+        let metadata = file.metadata().map_err(io_error)?;
+        match expected {
+            Some(ref expected) if metadata.is_file() => {
+                info!("Verifying {}", self.path);
+                let computed = read_checksum(&mut file, expected.algorithm).map_err(io_error)?;
+                expected.verify(&computed).map_err(|e| RootfsError::new(
+                    &format!("{}: {}", self.path, e)))?;
+                file.seek(SeekFrom::Start(0)).map_err(io_error)?;
+            },
+            _ => ()
+        }
+        let total = Some(metadata.len());
+        let algorithm = expected.as_ref().map(|c| c.algorithm).unwrap_or(ChecksumAlgorithm::Sha256);
+        let mut source = BufReader::with_capacity(READ_BUFFER_SIZE,
+            SourceReader::new(file, algorithm, total, progress));
+        let compression = match self.compression {
+            Some(compression) => compression,
+            None => Compression::detect(source.fill_buf().map_err(io_error)?)
+        };
+
+        let arguments = self.tar_arguments(root);
+        info!("Extracting {} ({}) into {}", self.path, compression.name(), root.display());
+        debug!("Running tar {}", arguments.join(" "));
+        let mut child = Command::new("tar")
+            .args(&arguments)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| RootfsError::new(&format!("could not run tar: {}", e)))?;
+        // stderr is drained alongside, tar stops writing to a full pipe and
+        // so stops reading stdin
+        let mut stderr = child.stderr.take();
+        let errors = thread::spawn(move || {
+            let mut errors = Vec::new();
+            if let Some(ref mut stderr) = stderr {
+                let _ = stderr.read_to_end(&mut errors);
+            }
+            errors
+        });
+        let copied = match child.stdin.take() {
+            Some(mut stdin) => compression.decoder(&mut source)
+                .and_then(|mut decoder| io::copy(&mut decoder, &mut stdin)),
+            None => Err(io::Error::other("tar has no stdin"))
+        };
+        let status = child.wait()
+            .map_err(|e| RootfsError::new(&format!("could not run tar: {}", e)))?;
+        let errors = errors.join().unwrap_or_default();
+        // tar gets the signal too, its complaints are beside the point
+        signal::check_interrupted().map_err(io_error)?;
+        if !status.success() {
+            return Err(RootfsError::new(&format!("tar {} failed ({}): {}", arguments.join(" "),
+                status, String::from_utf8_lossy(&errors).trim())))
+        }
+        match copied {
+            // tar stops reading at the end of archive marker, the rest is padding
+            Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => (),
+            Err(e) => return Err(io_error(e)),
+            Ok(_) => ()
+        }
+
+        // Whatever follows the archive is part of the checksum all the same
+        io::copy(&mut source, &mut io::sink()).map_err(io_error)?;
+        let computed = source.into_inner().finish();
+        info!("Extracted {}, {}", self.path, computed);
+        if let Some(expected) = expected {
+            expected.verify(&computed).map_err(|e| RootfsError::new(
+                &format!("{}: {}", self.path, e)))?;
+        }
+        Ok(computed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+    use std::io::Write;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+    use std::path::PathBuf;
+    use std::sync::mpsc;
+    use std::time::Duration;
+    use super::super::stream::Hasher;
+
+    fn staging(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("press-rootfs-{}-{}", name, std::process::id()))
+    }
+
+    fn path_string(path: &Path) -> CString {
+        CString::new(path.as_os_str().as_bytes()).unwrap()
+    }
+
+    fn set_xattr(path: &Path, name: &str, value: &[u8]) -> bool {
+        let name = CString::new(name).unwrap();
+        unsafe {
+            libc::setxattr(path_string(path).as_ptr(), name.as_ptr(),
+                value.as_ptr() as *const libc::c_void, value.len(), 0) == 0
+        }
+    }
+
+    fn get_xattr(path: &Path, name: &str) -> Option<Vec<u8>> {
+        let name = CString::new(name).unwrap();
+        let mut value = vec![0u8; 256];
+        let length = unsafe {
+            libc::getxattr(path_string(path).as_ptr(), name.as_ptr(),
+                value.as_mut_ptr() as *mut libc::c_void, value.len())
+        };
+        if length < 0 {
+            return None
+        }
+        value.truncate(length as usize);
+        Some(value)
+    }
+
+    // A POSIX ACL in the kernel's xattr format granting uid 1234 read access
+    fn access_acl() -> Vec<u8> {
+        let mut acl = 2u32.to_le_bytes().to_vec();
+        for (tag, perm, id) in [(0x01u16, 6u16, u32::MAX), (0x02, 4, 1234), (0x04, 4, u32::MAX),
+                                (0x10, 4, u32::MAX), (0x20, 0, u32::MAX)].iter() {
+            acl.extend(&tag.to_le_bytes());
+            acl.extend(&perm.to_le_bytes());
+            acl.extend(&id.to_le_bytes());
+        }
+        acl
+    }
+
+    fn chown(path: &Path, uid: u32, gid: u32) {
+        assert_eq!(unsafe { libc::lchown(path_string(path).as_ptr(), uid, gid) }, 0);
+    }
+
+    fn set_mode(path: &Path, mode: u32) {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).unwrap();
+    }
+
+    // A small root file system with the metadata tar has to restore
+    fn source_tree(source: &Path) -> bool {
+        std::fs::create_dir_all(source.join("etc")).unwrap();
+        std::fs::create_dir_all(source.join("usr/bin")).unwrap();
+        std::fs::create_dir_all(source.join("home/user")).unwrap();
+        std::fs::write(source.join("etc/shadow"), "root:*:19000::::::\n").unwrap();
+        chown(&source.join("etc/shadow"), 0, 42);
+        set_mode(&source.join("etc/shadow"), 0o640);
+        std::fs::write(source.join("usr/bin/su"), vec![0x7fu8; 70_000]).unwrap();
+        set_mode(&source.join("usr/bin/su"), 0o4755);
+        std::fs::hard_link(source.join("usr/bin/su"), source.join("usr/bin/su-link")).unwrap();
+        std::os::unix::fs::symlink("usr/bin", source.join("bin")).unwrap();
+        let notes = source.join("home/user/notes");
+        std::fs::write(&notes, "notes\n").unwrap();
+        chown(&source.join("home/user"), 1000, 1000);
+        chown(&notes, 1000, 1000);
+        set_mode(&source.join("home/user"), 0o700);
+        set_xattr(&notes, "user.press", b"deployed") &&
+            set_xattr(&notes, "system.posix_acl_access", &access_acl())
+    }
+
+    fn create_tarball(source: &Path, tarball: &Path) {
+        let status = Command::new("tar")
+            .args(["--create", "--xattrs", "--xattrs-include=*", "--acls", "--numeric-owner"])
+            .arg(format!("--file={}", tarball.display()))
+            .arg(format!("--directory={}", source.display()))
+            .arg(".")
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    fn compress(tarball: &Path, compression: Compression) -> PathBuf {
+        let data = std::fs::read(tarball).unwrap();
+        let compressed = match compression {
+            Compression::None => return tarball.to_path_buf(),
+            Compression::Gzip => {
+                let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+                gzip.write_all(&data).unwrap();
+                gzip.finish().unwrap()
+            },
+            Compression::Xz => {
+                let mut xz = xz2::write::XzEncoder::new(Vec::new(), 1);
+                xz.write_all(&data).unwrap();
+                xz.finish().unwrap()
+            },
+            Compression::Zstd => zstd::stream::encode_all(&data[..], 1).unwrap()
+        };
+        let path = tarball.with_extension(format!("tar.{}", compression.name()));
+        std::fs::write(&path, compressed).unwrap();
+        path
+    }
+
+    fn checksum(path: &Path) -> Checksum {
+        let mut data = Vec::new();
+        File::open(path).unwrap().read_to_end(&mut data).unwrap();
+        let mut hasher = Hasher::new(ChecksumAlgorithm::Sha256);
+        hasher.update(&data);
+        hasher.finish()
+    }
+
+    fn verify_tree(root: &Path) {
+        let metadata = |path: &str| std::fs::symlink_metadata(root.join(path)).unwrap();
+        let shadow = metadata("etc/shadow");
+        assert_eq!((shadow.uid(), shadow.gid(), shadow.mode() & 0o7777), (0, 42, 0o640));
+        let su = metadata("usr/bin/su");
+        assert_eq!(su.mode() & 0o7777, 0o4755);
+        assert_eq!(su.len(), 70_000);
+        assert_eq!(su.ino(), metadata("usr/bin/su-link").ino());
+        assert_eq!(su.nlink(), 2);
+        assert_eq!(std::fs::read_link(root.join("bin")).unwrap(), Path::new("usr/bin"));
+        let home = metadata("home/user");
+        assert_eq!((home.uid(), home.gid(), home.mode() & 0o7777), (1000, 1000, 0o700));
+        let notes = root.join("home/user/notes");
+        assert_eq!(metadata("home/user/notes").uid(), 1000);
+        assert_eq!(get_xattr(&notes, "user.press").as_deref(), Some(&b"deployed"[..]));
+        assert_eq!(get_xattr(&notes, "system.posix_acl_access"), Some(access_acl()));
+    }
+
+    #[test]
+    fn test_check() {
+        let mut tarball = Tarball::new("");
+        tarball.checksum = Some("sha1:da39a3ee5e6b4b0d3255bfef95601890afd80709".to_owned());
+        assert_eq!(tarball.check(), vec![
+            "the tarball path is empty",
+            "unsupported checksum algorithm sha1, expected sha256 or sha512",
+        ]);
+        let mut tarball = Tarball::new("/srv/rootfs.tar.zst");
+        tarball.extra_options = vec!["--exclude=./dev/*".to_owned()];
+        assert_eq!(tarball.tar_arguments(Path::new("/mnt/target")).join(" "),
+            "--extract --file=- --preserve-permissions --same-owner --numeric-owner --xattrs \
+            --xattrs-include=* --acls --directory=/mnt/target --exclude=./dev/*");
+    }
+
+    #[test]
+    fn test_deploy() {
+        // Restoring owners needs root
+        if unsafe { libc::geteuid() } != 0 {
+            return
+        }
+        let source = staging("source");
+        if !source_tree(&source) {
+            // No extended attributes on the temporary directory
+            std::fs::remove_dir_all(&source).unwrap();
+            return
+        }
+        let tarball = staging("image.tar");
+        create_tarball(&source, &tarball);
+        std::fs::remove_dir_all(&source).unwrap();
+
+        for compression in [Compression::None, Compression::Gzip, Compression::Xz,
+                            Compression::Zstd].iter() {
+            let path = compress(&tarball, *compression);
+            let root = staging(compression.name());
+            std::fs::create_dir_all(&root).unwrap();
+            let mut deploy = Tarball::new(&path.to_string_lossy());
+            deploy.checksum = Some(checksum(&path).to_string());
+            let mut updates = Vec::new();
+            let computed = deploy.deploy(&root, &mut |p: &Progress| updates.push(*p)).unwrap();
+            assert_eq!(computed, checksum(&path));
+            assert_eq!(updates.last().unwrap().percent(), Some(100), "{}", compression.name());
+            verify_tree(&root);
+            std::fs::remove_dir_all(&root).unwrap();
+
+            // Nothing is extracted from a tarball with the wrong checksum
+            let root = staging("mismatch");
+            std::fs::create_dir_all(&root).unwrap();
+            deploy.checksum = Some(format!("sha256:{}", "0".repeat(64)));
+            let error = deploy.deploy(&root, &mut |_: &Progress| ()).unwrap_err().to_string();
+            assert!(error.contains("checksum mismatch"), "{}", error);
+            assert_eq!(std::fs::read_dir(&root).unwrap().count(), 0);
+            std::fs::remove_dir_all(&root).unwrap();
+            if *compression != Compression::None {
+                std::fs::remove_file(&path).unwrap();
+            }
+        }
+
+        // Not an archive at all
+        std::fs::write(&tarball, vec![0x55u8; 4096]).unwrap();
+        let root = staging("invalid");
+        std::fs::create_dir_all(&root).unwrap();
+        let error = Tarball::new(&tarball.to_string_lossy()).deploy(&root, &mut |_: &Progress| ())
+            .unwrap_err().to_string();
+        assert!(error.starts_with("tar --extract"), "{}", error);
+        std::fs::remove_dir_all(&root).unwrap();
+        std::fs::remove_file(&tarball).unwrap();
+    }
+
+    #[test]
+    fn test_deploy_stderr() {
+        // Far more than a pipe holds is listed on stderr
+        let source = staging("listed");
+        std::fs::create_dir_all(&source).unwrap();
+        for i in 0..1000 {
+            std::fs::write(source.join(format!("{:0>100}", i)), "").unwrap();
+        }
+        let tarball = staging("listed.tar");
+        create_tarball(&source, &tarball);
+        std::fs::remove_dir_all(&source).unwrap();
+
+        let root = staging("listed-root");
+        std::fs::create_dir_all(&root).unwrap();
+        let mut deploy = Tarball::new(&tarball.to_string_lossy());
+        deploy.extra_options = vec!["--verbose".to_owned(), "--index-file=/dev/stderr".to_owned()];
+        let (sender, receiver) = mpsc::channel();
+        let target = root.clone();
+        thread::spawn(move || {
+            let _ = sender.send(deploy.deploy(&target, &mut |_: &Progress| ()).map(|_| ()));
+        });
+        receiver.recv_timeout(Duration::from_secs(60)).expect("tar is stuck").unwrap();
+        assert_eq!(std::fs::read_dir(&root).unwrap().count(), 1000);
+        std::fs::remove_dir_all(&root).unwrap();
+        std::fs::remove_file(&tarball).unwrap();
+    }
+}
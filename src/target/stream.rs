@@ -0,0 +1,367 @@
+//! Reading deployment sources: recognizing and undoing compression,
+//! checksumming what was read and reporting progress along the way.
+
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read};
+
+use schemars::JsonSchema;
+use serde::Deserialize;
+use sha2::{Digest, Sha256, Sha512};
+
+use crate::signal;
+
+// Error Boiler plate
+#[derive(Debug)]
+pub struct StreamError {
+    details: String
+}
+
+impl StreamError {
+    pub fn new(msg: &str) -> Self {
+        Self {
+            details: msg.to_string()
+        }
+    }
+}
+
+impl fmt::Display for StreamError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.details)
+    }
+}
+
+impl Error for StreamError {
+    fn description(&self) -> &str {
+        &self.details
+    }
+}
+
+pub type StreamResult<T> = Result<T, StreamError>;
+// End Error boiler plate
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    None,
+    Gzip,
+    Xz,
+    Zstd
+}
+
+impl Compression {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Gzip => "gzip",
+            Compression::Xz => "xz",
+            Compression::Zstd => "zstd"
+        }
+    }
+
+    /// Recognizes the compression from the first bytes of a stream, anything
+    /// unknown is taken to be uncompressed
+    pub fn detect(magic: &[u8]) -> Compression {
+        if magic.starts_with(&[0x1f, 0x8b]) {
+            Compression::Gzip
+        } else if magic.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Compression::Xz
+        } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Compression::Zstd
+        } else {
+            Compression::None
+        }
+    }
+
+    /// Wraps reader in a decoder. Concatenated gzip members and xz streams,
+    /// as written by pigz and pixz, are read to the end.
+    pub fn decoder<'a, R: Read + 'a>(&self, reader: R) -> io::Result<Box<dyn Read + 'a>> {
+        Ok(match self {
+            Compression::None => Box::new(reader),
+            Compression::Gzip => Box::new(flate2::read::MultiGzDecoder::new(reader)),
+            Compression::Xz => Box::new(xz2::read::XzDecoder::new_multi_decoder(reader)),
+            Compression::Zstd => Box::new(zstd::stream::read::Decoder::new(reader)?)
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChecksumAlgorithm {
+    Sha256,
+    Sha512
+}
+
+impl ChecksumAlgorithm {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ChecksumAlgorithm::Sha256 => "sha256",
+            ChecksumAlgorithm::Sha512 => "sha512"
+        }
+    }
+
+    /// The digest length in bytes
+    pub fn digest_len(&self) -> usize {
+        match self {
+            ChecksumAlgorithm::Sha256 => 32,
+            ChecksumAlgorithm::Sha512 => 64
+        }
+    }
+}
+
+/// An expected or computed digest, written sha256:<hex> or sha512:<hex>. A
+/// bare hex digest is recognized by its length.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Checksum {
+    pub algorithm: ChecksumAlgorithm,
+    pub digest: Vec<u8>
+}
+
+fn parse_hex(value: &str) -> Option<Vec<u8>> {
+    if !value.len().is_multiple_of(2) || !value.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None
+    }
+    (0..value.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&value[i..i + 2], 16).ok())
+        .collect()
+}
+
+impl Checksum {
+    pub fn parse(value: &str) -> StreamResult<Checksum> {
+        let (algorithm, hex) = match value.split_once(':') {
+            Some(("sha256", hex)) => (Some(ChecksumAlgorithm::Sha256), hex),
+            Some(("sha512", hex)) => (Some(ChecksumAlgorithm::Sha512), hex),
+            Some((algorithm, _)) => return Err(StreamError::new(
+                &format!("unsupported checksum algorithm {}, expected sha256 or sha512", algorithm))),
+            None => (None, value)
+        };
+        let digest = parse_hex(hex).ok_or_else(|| StreamError::new(
+            &format!("{} is not a hex digest", hex)))?;
+        let algorithm = match algorithm {
+            Some(algorithm) => algorithm,
+            None => [ChecksumAlgorithm::Sha256, ChecksumAlgorithm::Sha512].iter()
+                .find(|algorithm| algorithm.digest_len() == digest.len())
+                .copied()
+                .ok_or_else(|| StreamError::new(
+                    &format!("{} is neither a sha256 nor a sha512 digest", hex)))?
+        };
+        if digest.len() != algorithm.digest_len() {
+            return Err(StreamError::new(&format!("a {} digest is {} hex digits",
+                algorithm.name(), algorithm.digest_len() * 2)))
+        }
+        Ok(Checksum { algorithm, digest })
+    }
+
+    /// Fails unless computed has the same digest
+    pub fn verify(&self, computed: &Checksum) -> StreamResult<()> {
+        if self != computed {
+            return Err(StreamError::new(&format!("checksum mismatch, expected {} but read {}",
+                self, computed)))
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:", self.algorithm.name())?;
+        for byte in self.digest.iter() {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// Computes a checksum incrementally
+pub enum Hasher {
+    Sha256(Sha256),
+    Sha512(Sha512)
+}
+
+impl Hasher {
+    pub fn new(algorithm: ChecksumAlgorithm) -> Self {
+        match algorithm {
+            ChecksumAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            ChecksumAlgorithm::Sha512 => Hasher::Sha512(Sha512::new())
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(hasher) => hasher.update(data),
+            Hasher::Sha512(hasher) => hasher.update(data)
+        }
+    }
+
+    pub fn finish(self) -> Checksum {
+        match self {
+            Hasher::Sha256(hasher) => Checksum {
+                algorithm: ChecksumAlgorithm::Sha256,
+                digest: hasher.finalize().to_vec()
+            },
+            Hasher::Sha512(hasher) => Checksum {
+                algorithm: ChecksumAlgorithm::Sha512,
+                digest: hasher.finalize().to_vec()
+            }
+        }
+    }
+}
+
+/// Reads reader to the end and returns its checksum
+pub fn read_checksum<R: Read>(reader: &mut R, algorithm: ChecksumAlgorithm) -> io::Result<Checksum> {
+    let mut hasher = Hasher::new(algorithm);
+    let mut buffer = vec![0u8; 1 << 20];
+    loop {
+        match reader.read(&mut buffer) {
+            Ok(0) => return Ok(hasher.finish()),
+            Ok(read) => hasher.update(&buffer[..read]),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e)
+        }
+    }
+}
+
+/// How much of a source has been read
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Progress {
+    pub bytes: u64,
+    /// The size of the source, when it is known
+    pub total: Option<u64>
+}
+
+impl Progress {
+    pub fn percent(&self) -> Option<u64> {
+        self.total.filter(|total| *total > 0)
+            .map(|total| (self.bytes.min(total) * 100) / total)
+    }
+}
+
+/// A progress callback which logs every ten percent, or every GiB when the
+/// size of the source is unknown
+pub fn log_progress(name: &str) -> impl FnMut(&Progress) {
+    let name = name.to_owned();
+    let mut logged = None;
+    move |progress: &Progress| {
+        let step = match progress.percent() {
+            Some(percent) => percent / 10,
+            None => progress.bytes >> 30
+        };
+        if logged != Some(step) {
+            logged = Some(step);
+            match progress.percent() {
+                Some(percent) => info!("{}: {}% ({} bytes)", name, percent, progress.bytes),
+                None => info!("{}: {} bytes", name, progress.bytes)
+            }
+        }
+    }
+}
+
+/// Passes reads through, checksumming the data and reporting progress. Reads
+/// fail once a signal has been received.
+pub struct SourceReader<'a, R> {
+    inner: R,
+    hasher: Hasher,
+    progress: Progress,
+    callback: &'a mut dyn FnMut(&Progress)
+}
+
+impl<'a, R: Read> SourceReader<'a, R> {
+    pub fn new(inner: R, algorithm: ChecksumAlgorithm, total: Option<u64>,
+               callback: &'a mut dyn FnMut(&Progress)) -> Self {
+        Self {
+            inner,
+            hasher: Hasher::new(algorithm),
+            progress: Progress { bytes: 0, total },
+            callback
+        }
+    }
+
+    pub fn progress(&self) -> Progress {
+        self.progress
+    }
+
+    /// The checksum of everything read so far
+    pub fn finish(self) -> Checksum {
+        self.hasher.finish()
+    }
+}
+
+impl<'a, R: Read> Read for SourceReader<'a, R> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        signal::check_interrupted()?;
+        let read = self.inner.read(buffer)?;
+        self.hasher.update(&buffer[..read]);
+        self.progress.bytes += read as u64;
+        (self.callback)(&self.progress);
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    // sha256 of "press\n"
+    static PRESS_SHA256: &str = "69be40572366a1055945f239569ca532ac45fedd529119677325675c240096a4";
+
+    #[test]
+    fn test_checksum() {
+        let checksum = Checksum::parse(PRESS_SHA256).unwrap();
+        assert_eq!(checksum.algorithm, ChecksumAlgorithm::Sha256);
+        assert_eq!(checksum.to_string(), format!("sha256:{}", PRESS_SHA256));
+        assert_eq!(Checksum::parse(&checksum.to_string()).unwrap(), checksum);
+        assert_eq!(Checksum::parse(&format!("sha512:{}", PRESS_SHA256)).unwrap_err().to_string(),
+            "a sha512 digest is 128 hex digits");
+        assert_eq!(Checksum::parse("md5:d41d8cd98f00b204e9800998ecf8427e").unwrap_err().to_string(),
+            "unsupported checksum algorithm md5, expected sha256 or sha512");
+        assert_eq!(Checksum::parse("abc").unwrap_err().to_string(), "abc is not a hex digest");
+        assert_eq!(Checksum::parse("abcd").unwrap_err().to_string(),
+            "abcd is neither a sha256 nor a sha512 digest");
+    }
+
+    #[test]
+    fn test_source_reader() {
+        let data = vec![7u8; 1 << 20];
+        let mut updates = Vec::new();
+        let mut callback = |progress: &Progress| updates.push(*progress);
+        let mut reader = SourceReader::new(&data[..], ChecksumAlgorithm::Sha512,
+            Some(data.len() as u64), &mut callback);
+        io::copy(&mut reader, &mut io::sink()).unwrap();
+        let computed = reader.finish();
+        let mut hasher = Hasher::new(ChecksumAlgorithm::Sha512);
+        hasher.update(&data);
+        assert_eq!(computed, hasher.finish());
+        assert_eq!(updates.last().unwrap().percent(), Some(100));
+        assert!(updates.windows(2).all(|w| w[0].bytes <= w[1].bytes));
+
+        let expected = Checksum::parse(PRESS_SHA256).unwrap();
+        assert!(expected.verify(&computed).unwrap_err().to_string()
+            .starts_with("checksum mismatch, expected sha256:69be"));
+        let mut hasher = Hasher::new(ChecksumAlgorithm::Sha256);
+        hasher.update(b"press\n");
+        expected.verify(&hasher.finish()).unwrap();
+        expected.verify(&read_checksum(&mut &b"press\n"[..], ChecksumAlgorithm::Sha256).unwrap())
+            .unwrap();
+    }
+
+    #[test]
+    fn test_compression() {
+        let data: Vec<u8> = (0..100_000u32).flat_map(|i| i.to_le_bytes().to_vec()).collect();
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        gzip.write_all(&data).unwrap();
+        let mut xz = xz2::write::XzEncoder::new(Vec::new(), 1);
+        xz.write_all(&data).unwrap();
+        let compressed = vec![
+            (Compression::None, data.clone()),
+            (Compression::Gzip, gzip.finish().unwrap()),
+            (Compression::Xz, xz.finish().unwrap()),
+            (Compression::Zstd, zstd::stream::encode_all(&data[..], 1).unwrap())
+        ];
+        for (compression, compressed) in compressed {
+            assert_eq!(Compression::detect(&compressed), compression, "{}", compression.name());
+            let mut decompressed = Vec::new();
+            compression.decoder(&compressed[..]).unwrap().read_to_end(&mut decompressed).unwrap();
+            assert!(decompressed == data, "{}", compression.name());
+        }
+    }
+}
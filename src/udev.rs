@@ -2,8 +2,13 @@ extern crate serde;
 extern crate libudev;
 
 use std::collections::HashMap;
+use std::path::Path;
+use std::process::Command;
+use std::time::{Duration, Instant};
 use serde::Serialize;
 
+use crate::sysfs::{self, SysRoot};
+
 // There is realy no reason to have all of this data stored in memory
 // I'll likely create a final struct with all of the data the application
 // needs and selectively query these sources
@@ -21,6 +26,39 @@ impl UdevBlockDeviceInfo {
     pub fn properties(&self) -> &HashMap<String, String> {
         &self.udev_properties
     }
+
+    pub fn property(&self, name: &str) -> Option<&str> {
+        self.udev_properties.get(name).map(|v| v.as_str())
+    }
+
+    /// The /dev/disk/by-* symlinks udev created for this device
+    pub fn devlinks(&self) -> Vec<&str> {
+        match self.property("DEVLINKS") {
+            Some(links) => links.split_whitespace().collect(),
+            None => Vec::new()
+        }
+    }
+}
+
+/// Decodes the \xNN escapes udev uses for whitespace and other unsafe
+/// characters in property values (ID_PART_ENTRY_NAME for instance)
+pub fn unescape(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && i + 3 < bytes.len() && bytes[i + 1] == b'x' {
+            let hex = std::str::from_utf8(&bytes[i + 2..i + 4]).unwrap_or("");
+            if let Ok(b) = u8::from_str_radix(hex, 16) {
+                decoded.push(b);
+                i += 4;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
 }
 
 pub fn get_block_device(device: libudev::Device) -> UdevBlockDeviceInfo {
@@ -47,7 +85,7 @@ pub fn get_block_device(device: libudev::Device) -> UdevBlockDeviceInfo {
             }
 }
 
-fn _get_block_devices(enumerator: &mut libudev::Enumerator) -> Result<Vec<UdevBlockDeviceInfo>, Box<std::error::Error>> {
+fn _get_block_devices(enumerator: &mut libudev::Enumerator) -> Result<Vec<UdevBlockDeviceInfo>, Box<dyn std::error::Error>> {
     let mut devices = Vec::new();
     for d in enumerator.scan_devices()? {
         devices.push(get_block_device(d))
@@ -55,29 +93,192 @@ fn _get_block_devices(enumerator: &mut libudev::Enumerator) -> Result<Vec<UdevBl
     Ok(devices)
 }
 
-fn _get_enumerator(context: &libudev::Context) -> Result<libudev::Enumerator, Box<std::error::Error>> {
+fn _get_enumerator(context: &libudev::Context) -> Result<libudev::Enumerator, Box<dyn std::error::Error>> {
     let mut enumerator = libudev::Enumerator::new(&context)?;
     enumerator.match_subsystem("block")?;
     Ok(enumerator)
 }
 
-pub fn get_block_devices_with_property(name: &str, value: &str) -> Result<Vec<UdevBlockDeviceInfo>, Box<std::error::Error>> {
+pub fn get_block_devices_with_property(name: &str, value: &str) -> Result<Vec<UdevBlockDeviceInfo>, Box<dyn std::error::Error>> {
     let context = libudev::Context::new()?;
     let mut enumerator = _get_enumerator(&context)?;
     enumerator.match_property(name, value)?;
     Ok(_get_block_devices(&mut enumerator)?)
 }
 
-pub fn get_block_devices() -> Result<Vec<UdevBlockDeviceInfo>, Box<std::error::Error>> {
+pub fn get_block_devices() -> Result<Vec<UdevBlockDeviceInfo>, Box<dyn std::error::Error>> {
     let context = libudev::Context::new()?;
     let mut enumerator = _get_enumerator(&context)?;
     Ok(_get_block_devices(&mut enumerator)?)
 }
 
-pub fn get_disks() -> Result<Vec<UdevBlockDeviceInfo>, Box<std::error::Error>> {
+pub fn get_disks() -> Result<Vec<UdevBlockDeviceInfo>, Box<dyn std::error::Error>> {
     Ok(get_block_devices_with_property("DEVTYPE", "disk")?)
 }
 
-pub fn get_partitions() -> Result<Vec<UdevBlockDeviceInfo>, Box<std::error::Error>> {
+pub fn get_partitions() -> Result<Vec<UdevBlockDeviceInfo>, Box<dyn std::error::Error>> {
     Ok(get_block_devices_with_property("DEVTYPE", "partition")?)
 }
+
+/// Waits for udev to process every queued event, so device nodes and links
+/// of newly created devices exist
+pub fn settle(timeout: Duration) -> Result<(), std::io::Error> {
+    let output = Command::new("udevadm")
+        .arg("settle")
+        .arg(format!("--timeout={}", timeout.as_secs().max(1)))
+        .output()?;
+    if !output.status.success() {
+        return Err(std::io::Error::other(format!("udevadm settle failed ({}): {}",
+            output.status, String::from_utf8_lossy(&output.stderr).trim())))
+    }
+    Ok(())
+}
+
+/// Polls for a device node or link until it exists, returning false when
+/// it does not appear within the timeout
+pub fn wait_for_device(path: &Path, timeout: Duration) -> bool {
+    let start = Instant::now();
+    loop {
+        if path.exists() {
+            return true
+        }
+        if start.elapsed() >= timeout {
+            return false
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// Reads block device properties from the sysfs uevent files and the udev
+/// database under a `SysRoot`. libudev is bound to the live system, this
+/// produces the same properties from any root.
+pub struct UdevDatabase {
+    root: SysRoot
+}
+
+impl UdevDatabase {
+    pub fn new(root: SysRoot) -> Self {
+        Self {
+            root
+        }
+    }
+
+    pub fn root(&self) -> &SysRoot {
+        &self.root
+    }
+
+    /// Looks up a block device by kernel name (sda, nvme0n1p1)
+    pub fn get_block_device(&self, name: &str) -> Result<UdevBlockDeviceInfo, Box<dyn std::error::Error>> {
+        let sys_device_path = self.root.device_path(name);
+        let mut hm = HashMap::new();
+
+        hm.insert("SUBSYSTEM".to_owned(), "block".to_owned());
+        if let Some(devpath) = self.root.sys_to_kernel_path(&sys_device_path) {
+            hm.insert("DEVPATH".to_owned(), devpath);
+        }
+        for line in sysfs::read_string(&sys_device_path.join("uevent"))?.lines() {
+            let mut split = line.splitn(2, '=');
+            if let (Some(key), Some(value)) = (split.next(), split.next()) {
+                hm.insert(key.to_owned(), value.to_owned());
+            }
+        }
+        let devname = format!("/dev/{}", hm.get("DEVNAME").map(|n| n.as_str()).unwrap_or(name));
+        hm.insert("DEVNAME".to_owned(), devname.clone());
+
+        let (major, minor) = self.root.device_number(name)?;
+        let db_path = self.root.udev_data().join(format!("b{}:{}", major, minor));
+        // Devices which have not been processed by udev have no database entry
+        if Path::new(&db_path).exists() {
+            let mut devlinks = Vec::new();
+            for line in sysfs::read_string(&db_path)?.lines() {
+                if let Some(link) = line.strip_prefix("S:") {
+                    devlinks.push(format!("/dev/{}", link));
+                } else if let Some(property) = line.strip_prefix("E:") {
+                    let mut split = property.splitn(2, '=');
+                    if let (Some(key), Some(value)) = (split.next(), split.next()) {
+                        hm.insert(key.to_owned(), value.to_owned());
+                    }
+                }
+            }
+            if !devlinks.is_empty() {
+                hm.insert("DEVLINKS".to_owned(), devlinks.join(" "));
+            }
+        }
+
+        Ok(UdevBlockDeviceInfo {
+            name: devname,
+            udev_properties: hm
+        })
+    }
+
+    pub fn get_block_devices(&self) -> Result<Vec<UdevBlockDeviceInfo>, Box<dyn std::error::Error>> {
+        let mut names: Vec<String> = std::fs::read_dir(self.root.class_block())?
+            .filter_map(|e| e.ok())
+            .map(|e| e.file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        let mut devices = Vec::with_capacity(names.len());
+        for name in names {
+            devices.push(self.get_block_device(&name)?);
+        }
+        Ok(devices)
+    }
+
+    pub fn get_block_devices_with_property(&self, name: &str, value: &str) -> Result<Vec<UdevBlockDeviceInfo>, Box<dyn std::error::Error>> {
+        Ok(self.get_block_devices()?
+            .into_iter()
+            .filter(|d| d.property(name) == Some(value))
+            .collect())
+    }
+
+    pub fn get_disks(&self) -> Result<Vec<UdevBlockDeviceInfo>, Box<dyn std::error::Error>> {
+        self.get_block_devices_with_property("DEVTYPE", "disk")
+    }
+
+    pub fn get_partitions(&self) -> Result<Vec<UdevBlockDeviceInfo>, Box<dyn std::error::Error>> {
+        self.get_block_devices_with_property("DEVTYPE", "partition")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sysfs::fixture_root;
+
+    #[test]
+    fn test_database_device() {
+        let db = UdevDatabase::new(fixture_root());
+        let sda1 = db.get_block_device("sda1").unwrap();
+        assert_eq!(sda1.name(), "/dev/sda1");
+        assert_eq!(sda1.property("DEVTYPE"), Some("partition"));
+        assert_eq!(sda1.property("PARTN"), Some("1"));
+        assert_eq!(sda1.property("ID_FS_TYPE"), Some("vfat"));
+        assert_eq!(sda1.property("DEVPATH"),
+            Some("/devices/pci0000:00/0000:00:17.0/ata1/host0/target0:0:0/0:0:0:0/block/sda/sda1"));
+        assert!(sda1.devlinks().contains(&"/dev/disk/by-uuid/1A2B-3C4D"));
+    }
+
+    #[test]
+    fn test_database_enumeration() {
+        let db = UdevDatabase::new(fixture_root());
+        let disks: Vec<String> = db.get_disks().unwrap()
+            .iter()
+            .map(|d| d.name().to_owned())
+            .collect();
+        assert_eq!(disks, vec!["/dev/dm-0", "/dev/loop0", "/dev/md0", "/dev/nvme0n1",
+                               "/dev/sda", "/dev/sdb", "/dev/sdc"]);
+        assert_eq!(db.get_partitions().unwrap().len(), 4);
+        let members = db.get_block_devices_with_property(
+            "ID_FS_TYPE", "linux_raid_member").unwrap();
+        assert_eq!(members.len(), 2);
+    }
+
+    #[test]
+    fn test_wait_for_device() {
+        let root = fixture_root();
+        assert!(wait_for_device(&root.device_path("md0"), Duration::from_secs(0)));
+        let start = Instant::now();
+        assert!(!wait_for_device(&root.device_path("md1"), Duration::from_millis(200)));
+        assert!(start.elapsed() >= Duration::from_millis(200));
+    }
+}
@@ -0,0 +1,406 @@
+//! Erases stale signatures from a disk before it is repartitioned, in the
+//! manner of `wipefs -a`. Only the magic of each signature is cleared, which
+//! is enough for blkid, mdadm and lvm to stop recognizing it.
+
+extern crate byteorder;
+extern crate serde;
+
+use std::fs::OpenOptions;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use byteorder::{ByteOrder, LittleEndian};
+use crc::crc32;
+use serde::Serialize;
+use crate::gpt::{GPTHeader, GPT_HEADERSIZE, GPT_PARTITION_SIZE, GPT_SIGNATURE};
+use crate::lvm2::PhysicalVolume;
+use crate::mbr::{MBR, has_mbr};
+use crate::md::MdSuperblock;
+use crate::probe::probe_range;
+use crate::sysfs::SysRoot;
+
+/// A signature which was erased, at offset bytes from the start of the disk
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Erased {
+    pub fs_type: String,
+    pub offset: u64,
+    pub magic: Vec<u8>
+}
+
+fn read_at<R>(reader: &mut R, offset: u64, length: usize) -> Result<Vec<u8>, std::io::Error>
+        where R: Read + Seek {
+    let mut buffer = vec![0u8; length];
+    reader.seek(SeekFrom::Start(offset))?;
+    reader.read_exact(&mut buffer)?;
+    Ok(buffer)
+}
+
+// The kernel does not read larger partition entry arrays either
+static MAX_ENTRY_ARRAY_SIZE: u64 = 4 << 20;
+
+// Whether the byte range lies within a device of size bytes
+fn within(start: u64, length: u64, size: u64) -> bool {
+    start.checked_add(length).is_some_and(|end| end <= size)
+}
+
+// A (start, length) byte range of LBAs first to last, None when it overflows
+fn lba_range(first: u64, last: u64, lba_size: u64) -> Option<(u64, u64)> {
+    let start = first.checked_mul(lba_size)?;
+    let length = last.checked_sub(first)?.checked_add(1)?.checked_mul(lba_size)?;
+    Some((start, length))
+}
+
+// None unless there is a GPT header at header_lba whose header and entry
+// array checksums are valid. A corrupt or stale table is no table.
+fn gpt_partitions<R>(reader: &mut R, header_lba: u64, size: u64, lba_size: u64)
+        -> Result<Option<Vec<(u64, u64)>>, std::io::Error>
+            where R: Read + Seek {
+    let buffer = read_at(reader, header_lba * lba_size, lba_size as usize)?;
+    if buffer.len() < GPT_HEADERSIZE as usize || LittleEndian::read_u64(&buffer[..8]) != GPT_SIGNATURE {
+        return Ok(None)
+    }
+    let header = GPTHeader::from_slice(&buffer);
+    if header.calc_crc32() != header.header_crc32 {
+        debug!("ignoring the GPT header at LBA {}, its checksum is invalid", header_lba);
+        return Ok(None)
+    }
+    let entry_size = header.size_of_partition as u64;
+    let array_size = header.number_of_partions as u64 * entry_size;
+    let array_start = header.partition_entry_lba.checked_mul(lba_size);
+    let array_start = match array_start {
+        Some(start) if entry_size >= GPT_PARTITION_SIZE as u64 &&
+            array_size <= MAX_ENTRY_ARRAY_SIZE && within(start, array_size, size) => start,
+        _ => {
+            debug!("ignoring the GPT header at LBA {}, its partition entry array is invalid",
+                header_lba);
+            return Ok(None)
+        }
+    };
+    let entries = read_at(reader, array_start, array_size as usize)?;
+    if crc32::checksum_ieee(&entries) != header.partition_entry_crc32 {
+        debug!("ignoring the GPT header at LBA {}, its partition entry checksum is invalid",
+            header_lba);
+        return Ok(None)
+    }
+    Ok(Some(entries.chunks_exact(entry_size as usize)
+        .filter(|entry| entry[..16].iter().any(|b| *b != 0))
+        .filter_map(|entry| lba_range(LittleEndian::read_u64(&entry[32..40]),
+                                      LittleEndian::read_u64(&entry[40..48]), lba_size))
+        .collect()))
+}
+
+/// Byte ranges of the partitions in the current partition table, read from
+/// the primary GPT, the backup GPT or the MBR, whichever is found first
+pub fn partition_ranges<R>(reader: &mut R, size: u64, lba_size: u64)
+        -> Result<Vec<(u64, u64)>, std::io::Error>
+            where R: Read + Seek {
+    if size < lba_size * 2 {
+        return Ok(Vec::new())
+    }
+    if let Some(ranges) = gpt_partitions(reader, 1, size, lba_size)? {
+        return Ok(ranges)
+    }
+    if let Some(ranges) = gpt_partitions(reader, size / lba_size - 1, size, lba_size)? {
+        return Ok(ranges)
+    }
+    let buffer = read_at(reader, 0, 512)?;
+    if !has_mbr(&buffer) {
+        return Ok(Vec::new())
+    }
+    let mbr = MBR::new(&buffer);
+    if mbr.is_protective() {
+        return Ok(Vec::new())
+    }
+    Ok(mbr.partition_records.iter()
+        .filter(|p| p.os_type != 0 && p.size_in_lba != 0)
+        .map(|p| (p.starting_lba as u64 * lba_size, p.size_in_lba as u64 * lba_size))
+        .collect())
+}
+
+fn erase<F>(device: &mut F, fs_type: &str, offset: u64, length: usize, erased: &mut Vec<Erased>)
+        -> Result<(), std::io::Error>
+            where F: Read + Write + Seek {
+    let magic = read_at(device, offset, length)?;
+    device.seek(SeekFrom::Start(offset))?;
+    device.write_all(&vec![0u8; length])?;
+    info!("erased {} signature at offset 0x{:x} ({})", fs_type, offset,
+        magic.iter().map(|b| format!("{:02x}", b)).collect::<Vec<String>>().join(" "));
+    erased.push(Erased { fs_type: fs_type.to_owned(), offset, magic });
+    Ok(())
+}
+
+/// Erases every known signature on the device and within each partition
+/// of its current partition table, followed by the partition tables
+/// themselves, including the backup GPT at the end of the device
+pub fn wipe<F>(device: &mut F, lba_size: u64) -> Result<Vec<Erased>, std::io::Error>
+        where F: Read + Write + Seek {
+    let size = device.seek(SeekFrom::End(0))?;
+    let mut erased = Vec::new();
+
+    let mut ranges = vec![(0, size)];
+    ranges.extend(partition_ranges(device, size, lba_size)?
+        .into_iter()
+        .filter(|(start, length)| within(*start, *length, size)));
+
+    for (start, length) in ranges {
+        for signature in probe_range(device, start, length)? {
+            erase(device, &signature.fs_type, start + signature.offset,
+                  signature.magic.len(), &mut erased)?;
+        }
+    }
+
+    if size >= lba_size * 2 {
+        for lba in &[1, size / lba_size - 1] {
+            let offset = lba * lba_size;
+            if LittleEndian::read_u64(&read_at(device, offset, 8)?) == GPT_SIGNATURE {
+                erase(device, "gpt", offset, 8, &mut erased)?;
+            }
+        }
+        if has_mbr(&read_at(device, 0, 512)?) {
+            erase(device, "dos", 510, 2, &mut erased)?;
+        }
+    }
+    device.flush()?;
+    Ok(erased)
+}
+
+/// Volume groups with mapped logical volumes which have a physical volume
+/// on the device or on one of its partitions
+pub fn active_volume_groups<R>(device: &mut R, lba_size: u64, root: &SysRoot)
+        -> Result<Vec<String>, std::io::Error>
+            where R: Read + Seek {
+    let size = device.seek(SeekFrom::End(0))?;
+    let mut starts = vec![0];
+    starts.extend(partition_ranges(device, size, lba_size)?.iter().map(|(start, _)| *start));
+    let mut active = Vec::new();
+    for start in starts {
+        // Unreadable metadata is exactly what wiping is for
+        let volume_group = match PhysicalVolume::from_reader(device, start) {
+            Ok(Some(pv)) => pv.volume_group,
+            Ok(None) => None,
+            Err(e) => {
+                debug!("ignoring LVM2 metadata at offset {}: {}", start, e);
+                None
+            }
+        };
+        if let Some(volume_group) = volume_group {
+            if !volume_group.active_logical_volumes(root).is_empty() &&
+                    !active.contains(&volume_group.name) {
+                active.push(volume_group.name);
+            }
+        }
+    }
+    Ok(active)
+}
+
+/// Assembled md arrays with a member on the device or on one of its
+/// partitions. Members of arrays which are not assembled are stale.
+pub fn active_arrays<R>(device: &mut R, lba_size: u64, root: &SysRoot)
+        -> Result<Vec<String>, std::io::Error>
+            where R: Read + Seek {
+    let size = device.seek(SeekFrom::End(0))?;
+    let mut ranges = vec![(0, size)];
+    ranges.extend(partition_ranges(device, size, lba_size)?
+        .into_iter()
+        .filter(|(start, length)| within(*start, *length, size)));
+    let mut active = Vec::new();
+    for (start, length) in ranges {
+        let superblock = match MdSuperblock::from_reader(device, start, length) {
+            Ok(superblock) => superblock,
+            Err(e) => {
+                debug!("ignoring md superblock at offset {}: {}", start, e);
+                None
+            }
+        };
+        if let Some(array) = superblock.and_then(|sb| sb.active_array(root)) {
+            if !active.contains(&array) {
+                active.push(array);
+            }
+        }
+    }
+    Ok(active)
+}
+
+/// Wipes a device, refusing when it is part of an active volume group or
+/// of an assembled md array
+pub fn wipe_device(device: &Path, lba_size: u64) -> Result<Vec<Erased>, std::io::Error> {
+    let mut file = OpenOptions::new().read(true).write(true).open(device)?;
+    let root = SysRoot::default();
+    let active = active_volume_groups(&mut file, lba_size, &root)?;
+    if !active.is_empty() {
+        return Err(std::io::Error::other(format!(
+            "refusing to wipe {}, it holds physical volumes of the active volume groups {}",
+            device.display(), active.join(", "))))
+    }
+    let active = active_arrays(&mut file, lba_size, &root)?;
+    if !active.is_empty() {
+        return Err(std::io::Error::other(format!(
+            "refusing to wipe {}, it holds members of the assembled md arrays {}",
+            device.display(), active.join(", "))))
+    }
+    let erased = wipe(&mut file, lba_size)?;
+    file.sync_all()?;
+    Ok(erased)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use uuid::Uuid;
+    use crate::probe::probe;
+
+    static MD_MAGIC: [u8; 4] = [0xfc, 0x4e, 0x2b, 0xa9];
+
+    // Sets the checksums of a GPT header
+    fn seal(header: &mut [u8], entries_crc: u32) {
+        header[88..92].copy_from_slice(&entries_crc.to_le_bytes());
+        let crc = GPTHeader::from_slice(header).calc_crc32();
+        header[16..20].copy_from_slice(&crc.to_le_bytes());
+    }
+
+    // A GPT disk with one partition at 1MiB, which holds an md 1.0 member
+    fn disk() -> Vec<u8> {
+        let size = 8usize << 20;
+        let mut data = vec![0u8; size];
+        data[510..512].copy_from_slice(&[0x55, 0xaa]);
+        data[450] = 0xee;
+        data[454..458].copy_from_slice(&1u32.to_le_bytes());
+        data[458..462].copy_from_slice(&(size as u32 / 512 - 1).to_le_bytes());
+        for header in &[512, size - 512] {
+            let header = *header;
+            data[header..header+8].copy_from_slice(&GPT_SIGNATURE.to_le_bytes());
+            let entries: u64 = if header == 512 { 2 } else { size as u64 / 512 - 33 };
+            data[header+72..header+80].copy_from_slice(&entries.to_le_bytes());
+            data[header+80..header+84].copy_from_slice(&128u32.to_le_bytes());
+            data[header+84..header+88].copy_from_slice(&128u32.to_le_bytes());
+        }
+        let partition_type = Uuid::new_v4();
+        for entries in &[1024, size - 33 * 512] {
+            let entry = *entries;
+            data[entry..entry+16].copy_from_slice(partition_type.as_bytes());
+            data[entry+32..entry+40].copy_from_slice(&2048u64.to_le_bytes());
+            data[entry+40..entry+48].copy_from_slice(&6143u64.to_le_bytes());
+        }
+        let entries_crc = crc32::checksum_ieee(&data[1024..1024 + 128 * 128]);
+        for header in &[512, size - 512] {
+            seal(&mut data[*header..*header + 512], entries_crc);
+        }
+        // md 1.0 superblock 8KiB from the end of the 2MiB partition
+        let md = (3 << 20) - 8192;
+        data[md..md+4].copy_from_slice(&MD_MAGIC);
+        data[md+4..md+8].copy_from_slice(&1u32.to_le_bytes());
+        // md 0.90 superblock at the end of the disk
+        let md = size - 65536;
+        data[md..md+4].copy_from_slice(&MD_MAGIC);
+        // swap in the space after the partition
+        data[(4 << 20) + 4086..(4 << 20) + 4096].copy_from_slice(b"SWAPSPACE2");
+        data
+    }
+
+    #[test]
+    fn test_partition_ranges() {
+        let mut device = Cursor::new(disk());
+        assert_eq!(partition_ranges(&mut device, 8 << 20, 512).unwrap(),
+                   vec![(1 << 20, 2 << 20)]);
+
+        // Falls back to the backup GPT
+        let mut data = disk();
+        data[512..520].copy_from_slice(&[0u8; 8]);
+        let mut device = Cursor::new(data);
+        assert_eq!(partition_ranges(&mut device, 8 << 20, 512).unwrap(),
+                   vec![(1 << 20, 2 << 20)]);
+
+        // and so does a primary header with an invalid checksum
+        let mut data = disk();
+        data[512 + 40] ^= 1;
+        let mut device = Cursor::new(data);
+        assert_eq!(partition_ranges(&mut device, 8 << 20, 512).unwrap(),
+                   vec![(1 << 20, 2 << 20)]);
+
+        // Checksummed garbage is no partition table either
+        let mut data = disk();
+        let backup = (8 << 20) - 512;
+        data[backup..backup + 8].copy_from_slice(&[0u8; 8]);
+        data[592..600].copy_from_slice(&[0xff; 8]);
+        let crc = crc32::checksum_ieee(&data[1024..1024 + 128 * 128]);
+        seal(&mut data[512..1024], crc);
+        let mut device = Cursor::new(data.clone());
+        assert!(partition_ranges(&mut device, 8 << 20, 512).unwrap().is_empty());
+        assert!(!wipe(&mut device, 512).unwrap().is_empty());
+        // Partitions whose byte ranges overflow are left out
+        data[592..596].copy_from_slice(&128u32.to_le_bytes());
+        data[596..600].copy_from_slice(&128u32.to_le_bytes());
+        data[1024 + 40..1024 + 48].copy_from_slice(&u64::MAX.to_le_bytes());
+        data[1024 + 128..1024 + 144].copy_from_slice(&[1u8; 16]);
+        data[1024 + 160..1024 + 168].copy_from_slice(&(u64::MAX / 256).to_le_bytes());
+        data[1024 + 168..1024 + 176].copy_from_slice(&(u64::MAX / 256).to_le_bytes());
+        let crc = crc32::checksum_ieee(&data[1024..1024 + 128 * 128]);
+        seal(&mut data[512..1024], crc);
+        let mut device = Cursor::new(data);
+        assert!(partition_ranges(&mut device, 8 << 20, 512).unwrap().is_empty());
+        assert!(!wipe(&mut device, 512).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_active_volume_groups() {
+        use crate::lvm2::tests::{METADATA, physical_volume};
+
+        // A PV in the old partition of the disk
+        let mut data = disk();
+        let pv = physical_volume(Some(METADATA), false);
+        data[1 << 20..2 << 20].copy_from_slice(&pv);
+
+        let root = std::env::temp_dir().join(format!("press-wipe-{}", std::process::id()));
+        let dm = root.join("sys/block/dm-0/dm");
+        std::fs::create_dir_all(&dm).unwrap();
+        std::fs::write(dm.join("name"), "vg0-root\n").unwrap();
+        let mut device = Cursor::new(data);
+        assert_eq!(active_volume_groups(&mut device, 512, &SysRoot::new(&root)).unwrap(),
+                   vec!["vg0"]);
+        std::fs::remove_dir_all(&root).unwrap();
+        assert!(active_volume_groups(&mut device, 512, &SysRoot::new(&root)).unwrap().is_empty());
+
+        let erased = wipe(&mut device, 512).unwrap();
+        assert!(erased.iter().any(|e| e.fs_type == "LVM2_member" &&
+                                      e.offset == (1 << 20) + 512 + 24));
+    }
+
+    #[test]
+    fn test_active_arrays() {
+        use crate::md::tests::superblock_v1;
+        use crate::sysfs::fixture_root;
+
+        // A 1.2 member of the fixture's md0 in the old partition
+        let mut data = disk();
+        data[(1 << 20) + 4096..(1 << 20) + 8192].copy_from_slice(&superblock_v1(0, 0));
+        let mut device = Cursor::new(data);
+        assert_eq!(active_arrays(&mut device, 512, &fixture_root()).unwrap(), vec!["md0"]);
+
+        // The md 1.0 and 0.90 magics of disk() have no valid superblock
+        let mut device = Cursor::new(disk());
+        assert!(active_arrays(&mut device, 512, &fixture_root()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_wipe() {
+        let mut device = Cursor::new(disk());
+        let erased = wipe(&mut device, 512).unwrap();
+        let erased: Vec<(&str, u64)> = erased.iter()
+            .map(|e| (e.fs_type.as_str(), e.offset))
+            .collect();
+        assert_eq!(erased, vec![
+            ("linux_raid_member", (8 << 20) - 65536),
+            ("linux_raid_member", (3 << 20) - 8192),
+            ("gpt", 512),
+            ("gpt", (8 << 20) - 512),
+            ("dos", 510)
+        ]);
+        assert!(probe(&mut device).unwrap().is_empty());
+        assert!(partition_ranges(&mut device, 8 << 20, 512).unwrap().is_empty());
+        // Signatures outside of old partitions are left in place
+        assert_eq!(&device.get_ref()[(4 << 20) + 4086..(4 << 20) + 4096], b"SWAPSPACE2");
+        assert!(wipe(&mut device, 512).unwrap().is_empty());
+    }
+}